@@ -0,0 +1,508 @@
+use eyre::{Context, Result};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::config::{COLLECTIONS_DIR, VECTOR_STORE_FILE};
+use crate::crypt::SALT_FILE;
+use crate::lock::LOCK_FILE;
+use crate::process::{OBJECTS_DIR, Processor};
+use crate::sync::{local_path, memory_files};
+use crate::vector_store::{ImportSummary, MergeStrategy, StoreFormat, VectorStore};
+
+/// Where an archive is unpacked inside the memory directory before merging.
+const STAGING_DIR: &str = "import.tmp";
+const BLOCK: usize = 512;
+/// Every zstd frame starts with these bytes.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("{0} is not a memory archive: {1}")]
+    Malformed(PathBuf, String),
+    #[error(
+        "{0} was encrypted with a different passphrase salt than this memory; import it with --merge replace"
+    )]
+    SaltMismatch(PathBuf),
+}
+
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// What `import` did with the tracked files and vector entries it found.
+#[derive(Debug, Default)]
+pub struct ArchiveImport {
+    pub added: usize,
+    /// Files whose local history the archive's replaced.
+    pub replaced: usize,
+    /// Files whose local history was kept over the archive's.
+    pub kept: usize,
+    pub entries: ImportSummary,
+}
+
+/// Whether `path` starts like a zstd-compressed archive, as opposed to a
+/// JSON Lines export.
+pub fn is_archive(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| magic == ZSTD_MAGIC)
+}
+
+/// Bundles the whole of `memory_dir`, histories, blobs and every vector
+/// store collection, into a zstd-compressed tar at `output`. Files stay as
+/// they are on disk, so an encrypted memory exports encrypted.
+pub fn export(memory_dir: &Path, output: &Path, level: i32) -> Result<ExportSummary> {
+    let file =
+        File::create(output).wrap_err_with(|| format!("Failed to create {}", output.display()))?;
+    let mut encoder = zstd::Encoder::new(BufWriter::new(file), level)?;
+    let mut summary = ExportSummary::default();
+    for (name, path) in memory_files(memory_dir)? {
+        let mut file =
+            File::open(&path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |age| age.as_secs());
+        write_header(&mut encoder, &name, size, mtime)?;
+        let copied = io::copy(&mut (&mut file).take(size), &mut encoder)
+            .wrap_err_with(|| format!("Failed to archive {}", path.display()))?;
+        if copied != size {
+            eyre::bail!("{} changed while being archived", path.display());
+        }
+        pad(&mut encoder, size)?;
+        debug!("[{}] Archived {} bytes", name, size);
+        summary.files += 1;
+        summary.bytes += size;
+    }
+    // A tar ends with two empty blocks.
+    encoder.write_all(&[0; 2 * BLOCK])?;
+    encoder
+        .finish()?
+        .flush()
+        .wrap_err_with(|| format!("Failed to write {}", output.display()))?;
+    info!(
+        "Exported {} files ({} bytes) to {}",
+        summary.files,
+        summary.bytes,
+        output.display()
+    );
+    Ok(summary)
+}
+
+/// Merges an archive written by `export` into `memory_dir`. A file tracked
+/// on both sides keeps its local history with `Skip` and takes the
+/// archive's with `Overwrite`, along with the matching vector entries, so
+/// histories and stores stay consistent; `Replace` clears the memory first.
+pub async fn import(
+    memory_dir: &Path,
+    archive: &Path,
+    strategy: MergeStrategy,
+) -> Result<ArchiveImport> {
+    let staging = memory_dir.join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)
+            .wrap_err_with(|| format!("Failed to clear {}", staging.display()))?;
+    }
+    fs::create_dir_all(&staging)
+        .wrap_err_with(|| format!("Failed to create {}", staging.display()))?;
+
+    let result = match unpack(archive, &staging) {
+        Ok(()) => merge(memory_dir, &staging, archive, strategy).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        warn!("Failed to remove {}: {}", staging.display(), e);
+    }
+    result
+}
+
+async fn merge(
+    memory_dir: &Path,
+    staging: &Path,
+    archive: &Path,
+    strategy: MergeStrategy,
+) -> Result<ArchiveImport> {
+    let local_salt = fs::read(memory_dir.join(SALT_FILE)).ok();
+    let archived_salt = fs::read(staging.join(SALT_FILE)).ok();
+    if strategy != MergeStrategy::Replace
+        && let (Some(local), Some(archived)) = (&local_salt, &archived_salt)
+        && local != archived
+    {
+        return Err(ArchiveError::SaltMismatch(archive.to_path_buf()).into());
+    }
+    if strategy == MergeStrategy::Replace {
+        clear(memory_dir)?;
+    }
+
+    let originals: HashMap<String, PathBuf> = Processor::tracked_files(memory_dir)?
+        .into_iter()
+        .map(|file| (file.alias, file.original_path))
+        .collect();
+    let mut summary = ArchiveImport::default();
+    let mut kept = HashSet::new();
+    let mut overwritten = Vec::new();
+    for entry in fs::read_dir(staging)? {
+        let staged = entry?.path();
+        if !staged.join("history.json").exists() {
+            continue;
+        }
+        let Some(alias) = staged.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let target = memory_dir.join(alias);
+        if target.exists() {
+            if strategy == MergeStrategy::Skip {
+                debug!("[{}] Keeping the local history", alias);
+                kept.insert(alias.to_string());
+                summary.kept += 1;
+                continue;
+            }
+            debug!("[{}] Replacing the local history", alias);
+            overwritten.extend(originals.get(alias).cloned());
+            fs::remove_dir_all(&target)
+                .wrap_err_with(|| format!("Failed to remove {}", target.display()))?;
+            summary.replaced += 1;
+        } else {
+            summary.added += 1;
+        }
+        fs::rename(&staged, &target)
+            .wrap_err_with(|| format!("Failed to move {} into memory", alias))?;
+    }
+
+    // Blobs are named by their content, so one already here is the same blob.
+    let staged_objects = staging.join(OBJECTS_DIR);
+    if staged_objects.is_dir() {
+        let objects = memory_dir.join(OBJECTS_DIR);
+        fs::create_dir_all(&objects)?;
+        for entry in fs::read_dir(&staged_objects)? {
+            let blob = entry?.path();
+            let Some(name) = blob.file_name() else {
+                continue;
+            };
+            if !objects.join(name).exists() {
+                fs::rename(&blob, objects.join(name))?;
+            }
+        }
+    }
+
+    for staged in staged_stores(staging)? {
+        let relative = staged.strip_prefix(staging)?;
+        let mut store = VectorStore::load(memory_dir.join(relative))?;
+        store.delete_by_paths(&overwritten)?;
+        let imported = store.import_store(&staged, strategy, |entry| {
+            entry
+                .alias
+                .as_ref()
+                .is_none_or(|alias| !kept.contains(alias))
+        })?;
+        summary.entries.added += imported.added;
+        summary.entries.replaced += imported.replaced;
+        summary.entries.skipped += imported.skipped;
+    }
+
+    if local_salt.is_none() && archived_salt.is_some() {
+        fs::rename(staging.join(SALT_FILE), memory_dir.join(SALT_FILE))?;
+    }
+    if !kept.is_empty() {
+        Processor::sweep_objects(memory_dir).await?;
+    }
+    info!(
+        "Imported {} new files from {}, replaced {}, kept {}",
+        summary.added,
+        archive.display(),
+        summary.replaced,
+        summary.kept
+    );
+    Ok(summary)
+}
+
+/// Removes everything in `memory_dir` but the lock and the staging area.
+fn clear(memory_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(memory_dir)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name == LOCK_FILE || name == STAGING_DIR)
+        {
+            continue;
+        }
+        let removed = match path.is_dir() {
+            true => fs::remove_dir_all(&path),
+            false => fs::remove_file(&path),
+        };
+        removed.wrap_err_with(|| format!("Failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The vector stores of every collection unpacked into `staging`, in
+/// either format.
+fn staged_stores(staging: &Path) -> Result<Vec<PathBuf>> {
+    let formats = [StoreFormat::Files, StoreFormat::Sqlite];
+    let mut stores = Vec::new();
+    for format in formats {
+        let default = staging
+            .join(VECTOR_STORE_FILE)
+            .with_extension(format.extension());
+        if default.exists() {
+            stores.push(default);
+        }
+    }
+    let collections = staging.join(COLLECTIONS_DIR);
+    if collections.is_dir() {
+        for entry in fs::read_dir(&collections)? {
+            let path = entry?.path();
+            if formats.iter().any(|format| {
+                path.extension()
+                    .is_some_and(|ext| ext == format.extension())
+            }) {
+                stores.push(path);
+            }
+        }
+    }
+    stores.sort();
+    Ok(stores)
+}
+
+/// Writes a ustar header for a regular file, preceded by a pax header when
+/// `name` doesn't fit the 100 bytes ustar allows.
+fn write_header(writer: &mut impl Write, name: &str, size: u64, mtime: u64) -> io::Result<()> {
+    if name.len() > 100 {
+        // The length prefix counts itself, so settle it by iterating.
+        let record_len = |digits: usize| digits + " path=".len() + name.len() + 1;
+        let mut len = record_len(1);
+        while len != record_len(len.to_string().len()) {
+            len = record_len(len.to_string().len());
+        }
+        let record = format!("{len} path={name}\n");
+        writer.write_all(&header(b"././@PaxHeader", record.len() as u64, mtime, b'x'))?;
+        writer.write_all(record.as_bytes())?;
+        pad(writer, record.len() as u64)?;
+    }
+    let short = &name.as_bytes()[..name.len().min(100)];
+    writer.write_all(&header(short, size, mtime, b'0'))
+}
+
+fn header(name: &[u8], size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut block = [0; BLOCK];
+    block[..name.len()].copy_from_slice(name);
+    block[100..108].copy_from_slice(b"0000644\0");
+    block[108..116].copy_from_slice(b"0000000\0");
+    block[116..124].copy_from_slice(b"0000000\0");
+    block[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+    block[136..148].copy_from_slice(format!("{mtime:011o}\0").as_bytes());
+    block[156] = kind;
+    block[257..265].copy_from_slice(b"ustar\x0000");
+    // The checksum is taken with its own field as spaces.
+    block[148..156].fill(b' ');
+    let checksum: u32 = block.iter().map(|&b| b as u32).sum();
+    block[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    block
+}
+
+/// Fills the rest of the last block of a `size`-byte entry.
+fn pad(writer: &mut impl Write, size: u64) -> io::Result<()> {
+    let rest = (BLOCK - size as usize % BLOCK) % BLOCK;
+    writer.write_all(&[0; BLOCK][..rest])
+}
+
+/// Extracts the regular files of the archive at `path` under `dir`.
+fn unpack(path: &Path, dir: &Path) -> Result<()> {
+    let malformed = |reason: &str| ArchiveError::Malformed(path.to_path_buf(), reason.to_string());
+    let file = File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = zstd::Decoder::new(BufReader::new(file))
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+    let mut long_name = None;
+    let mut block = [0; BLOCK];
+    loop {
+        reader
+            .read_exact(&mut block)
+            .map_err(|_| malformed("truncated"))?;
+        if block.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        let size = parse_octal(&block[124..136]).ok_or_else(|| malformed("bad entry size"))?;
+        let mut data = (&mut reader).take(size);
+        match block[156] {
+            b'x' => {
+                let mut records = String::new();
+                data.read_to_string(&mut records)?;
+                long_name = records
+                    .lines()
+                    .find_map(|record| record.split_once(" path="))
+                    .map(|(_, name)| name.to_string());
+            }
+            b'0' | 0 => {
+                let name = match long_name.take() {
+                    Some(name) => name,
+                    None => field(&block[..100]).ok_or_else(|| malformed("bad file name"))?,
+                };
+                let target = local_path(dir, &name)?;
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut out = File::create(&target)
+                    .wrap_err_with(|| format!("Failed to create {}", target.display()))?;
+                io::copy(&mut data, &mut out)?;
+            }
+            _ => {
+                io::copy(&mut data, &mut io::sink())?;
+            }
+        }
+        if data.limit() != 0 {
+            return Err(malformed("truncated").into());
+        }
+        let rest = (BLOCK - size as usize % BLOCK) % BLOCK;
+        reader
+            .read_exact(&mut block[..rest])
+            .map_err(|_| malformed("truncated"))?;
+    }
+}
+
+/// A NUL-terminated header field.
+fn field(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8(bytes[..end].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+fn parse_octal(bytes: &[u8]) -> Option<u64> {
+    let digits = field(bytes)?;
+    u64::from_str_radix(digits.trim(), 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{ProcessMode, ProcessorConfig};
+    use crate::shutdown::CancellationToken;
+    use crate::sync::local_manifest;
+    use crate::vector_store::VectorEntry;
+    use std::collections::BTreeSet;
+
+    /// A memory with two tracked files, one named past ustar's 100 bytes,
+    /// and a vector entry for each.
+    async fn memory(sources: &Path, memory_dir: &Path) {
+        let mut paths = BTreeSet::new();
+        for (name, content) in [("notes.txt", "short"), (&*"long".repeat(30), "long")] {
+            let path = sources.join(name);
+            fs::write(&path, content).unwrap();
+            paths.insert(path);
+        }
+        let config = ProcessorConfig {
+            memory_dir: memory_dir.to_path_buf(),
+            ..Default::default()
+        };
+        Processor::process_all(
+            &paths,
+            ProcessMode::Full,
+            &config,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        let mut store = VectorStore::load(memory_dir.join(VECTOR_STORE_FILE)).unwrap();
+        for (i, file) in Processor::tracked_files(memory_dir)
+            .unwrap()
+            .into_iter()
+            .enumerate()
+        {
+            let mut embedding = vec![0.0; 2];
+            embedding[i] = 1.0;
+            store
+                .add(VectorEntry {
+                    id: file.alias.clone(),
+                    alias: Some(file.alias),
+                    embedding,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        store.save().unwrap();
+    }
+
+    fn hashes(memory_dir: &Path) -> Vec<(String, String)> {
+        local_manifest(memory_dir)
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|(name, file)| (name, file.hash))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn exported_memory_is_imported_back() {
+        let sources = tempfile::tempdir().unwrap();
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        memory(sources.path(), a.path()).await;
+        assert!(hashes(a.path()).iter().any(|(name, _)| name.len() > 100));
+
+        let archive = sources.path().join("memory.tar.zst");
+        let exported = export(a.path(), &archive, 3).unwrap();
+        assert_eq!(exported.files, hashes(a.path()).len());
+        assert!(is_archive(&archive));
+
+        let imported = import(b.path(), &archive, MergeStrategy::Skip)
+            .await
+            .unwrap();
+        assert_eq!((imported.added, imported.entries.added), (2, 2));
+        // The imported entries are saved anew, under a store generation of their own.
+        let without_store = |memory_dir: &Path| {
+            let mut hashes = hashes(memory_dir);
+            hashes.retain(|(name, _)| name != VECTOR_STORE_FILE);
+            hashes
+        };
+        assert_eq!(without_store(b.path()), without_store(a.path()));
+        let ids = |memory_dir: &Path| {
+            let store = VectorStore::load(memory_dir.join(VECTOR_STORE_FILE)).unwrap();
+            store
+                .iter()
+                .map(|entry| entry.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(b.path()), ids(a.path()));
+        assert!(!b.path().join(STAGING_DIR).exists());
+
+        // Importing into the memory it came from keeps every local history.
+        let again = import(a.path(), &archive, MergeStrategy::Skip)
+            .await
+            .unwrap();
+        assert_eq!((again.added, again.kept), (0, 2));
+    }
+
+    #[tokio::test]
+    async fn truncated_archives_are_rejected() {
+        let sources = tempfile::tempdir().unwrap();
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        memory(sources.path(), a.path()).await;
+        let archive = sources.path().join("memory.tar.zst");
+        export(a.path(), &archive, 3).unwrap();
+
+        let mut tar = Vec::new();
+        zstd::Decoder::new(File::open(&archive).unwrap())
+            .unwrap()
+            .read_to_end(&mut tar)
+            .unwrap();
+        tar.truncate(BLOCK + 10);
+        fs::write(&archive, zstd::encode_all(&tar[..], 3).unwrap()).unwrap();
+        let error = import(b.path(), &archive, MergeStrategy::Skip)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ArchiveError>(),
+            Some(ArchiveError::Malformed(..))
+        ));
+    }
+}
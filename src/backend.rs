@@ -0,0 +1,416 @@
+use eyre::{Context, Result};
+use log::{debug, info, trace, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::bert::Pooling;
+use crate::sync::error_body;
+
+pub const DEFAULT_OPENAI_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_OPENAI_MODEL: &str = "text-embedding-3-small";
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+pub const DEFAULT_OLLAMA_MODEL: &str = "nomic-embed-text";
+/// Input limit of OpenAI's embedding models, in tokens.
+const OPENAI_MAX_TOKENS: usize = 8191;
+/// Ollama truncates to the model's context, which is at least this much for
+/// the common embedding models.
+const OLLAMA_MAX_TOKENS: usize = 512;
+/// Inputs sent per request; OpenAI accepts up to 2048.
+const REQUEST_BATCH_SIZE: usize = 64;
+/// Requests are tried this often before an error is given up on.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each one after.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+/// Tokens are counted as this many bytes each when there is no tokenizer,
+/// erring towards more tokens than most tokenizers make.
+const BYTES_PER_TOKEN: usize = 3;
+
+#[derive(Error, Debug)]
+pub enum BackendError {
+    #[error("unknown embedding backend {0:?}, expected local, openai, ollama or onnx")]
+    Unknown(String),
+    #[error("the onnx backend needs a build with --features onnx")]
+    NoOnnx,
+    #[error("{url} answered HTTP {status}: {body}")]
+    Http {
+        url: String,
+        status: u16,
+        body: String,
+    },
+    #[error("{url} returned {got} embeddings for {sent} inputs")]
+    Count {
+        url: String,
+        got: usize,
+        sent: usize,
+    },
+}
+
+/// Where embeddings are computed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    /// A BERT-style model run in-process with candle.
+    #[default]
+    Local,
+    /// Any server speaking OpenAI's `/embeddings` API.
+    OpenAi,
+    /// An Ollama server.
+    Ollama,
+    /// A model exported to ONNX, run in-process with ONNX Runtime.
+    Onnx,
+}
+
+impl BackendKind {
+    /// The model used when none other than the local default is configured.
+    pub fn default_model(self) -> Option<&'static str> {
+        match self {
+            Self::Local | Self::Onnx => None,
+            Self::OpenAi => Some(DEFAULT_OPENAI_MODEL),
+            Self::Ollama => Some(DEFAULT_OLLAMA_MODEL),
+        }
+    }
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = BackendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => Ok(Self::Local),
+            "openai" => Ok(Self::OpenAi),
+            "ollama" => Ok(Self::Ollama),
+            "onnx" => Ok(Self::Onnx),
+            _ => Err(BackendError::Unknown(s.to_string())),
+        }
+    }
+}
+
+/// Turns text into embeddings for a `Digester`, which caches, normalizes
+/// and stores what comes out. Anything implementing it can be handed to
+/// `Digester::from_embedder`, a stub as much as a real model.
+pub trait Embedder: Send + Sync {
+    /// One embedding per input, in order.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>>;
+
+    /// Names the model in store metadata; embeddings are only ever compared
+    /// with others of the same id and revision.
+    fn model_id(&self) -> &str;
+
+    /// Pinned revision of the model, if it has any.
+    fn revision(&self) -> Option<&str> {
+        None
+    }
+
+    /// How token embeddings are pooled, for models run here rather than
+    /// behind an API.
+    fn pooling(&self) -> Option<Pooling> {
+        None
+    }
+
+    /// Length of every embedding.
+    fn dimension(&self) -> usize;
+
+    /// Longest input, in tokens, the model reads in full.
+    fn max_tokens(&self) -> usize;
+
+    /// Tokens `text` takes up, without special tokens. Estimated from its
+    /// length unless the backend has the model's tokenizer.
+    fn count_tokens(&self, text: &str) -> usize {
+        text.len().div_ceil(BYTES_PER_TOKEN)
+    }
+
+    /// Tokens the model adds to every input.
+    fn special_tokens(&self) -> usize {
+        0
+    }
+
+    /// Byte spans of the tokens of `text`, `None` without a tokenizer.
+    fn token_offsets(&self, _text: &str) -> Result<Option<Vec<(usize, usize)>>> {
+        Ok(None)
+    }
+}
+
+/// Embeddings from an OpenAI-compatible `/embeddings` endpoint: OpenAI
+/// itself or servers such as vLLM, llama.cpp and LiteLLM.
+pub struct OpenAiBackend {
+    client: Client,
+    url: String,
+    model: String,
+    model_id: String,
+    dimension: usize,
+}
+
+impl OpenAiBackend {
+    /// Connects to the API at `base_url` (up to and including `/v1`) with
+    /// the key in `OUROBOROS_API_KEY` or `OPENAI_API_KEY`, if any, and embeds
+    /// a probe to learn the model's dimension.
+    pub fn new(base_url: Option<&str>, model: &str) -> Result<Self> {
+        let base_url = base_url.unwrap_or(DEFAULT_OPENAI_URL).trim_end_matches('/');
+        let api_key = ["OUROBOROS_API_KEY", "OPENAI_API_KEY"]
+            .into_iter()
+            .find_map(|var| std::env::var(var).ok().filter(|key| !key.is_empty()));
+        let mut backend = Self {
+            client: Client::new(api_key),
+            url: format!("{base_url}/embeddings"),
+            model: model.to_string(),
+            model_id: format!("openai:{model}"),
+            dimension: 0,
+        };
+        backend.dimension = backend.embed_batch(&["dimension probe"])?.remove(0).len();
+        info!(
+            "Using {} at {} ({} dimensions)",
+            model, base_url, backend.dimension
+        );
+        Ok(backend)
+    }
+}
+
+#[derive(Deserialize)]
+struct OpenAiResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl Embedder for OpenAiBackend {
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(REQUEST_BATCH_SIZE) {
+            let body = json!({ "model": self.model, "input": batch });
+            let mut response: OpenAiResponse = self.client.post(&self.url, &body)?;
+            if response.data.len() != batch.len() {
+                return Err(BackendError::Count {
+                    url: self.url.clone(),
+                    got: response.data.len(),
+                    sent: batch.len(),
+                }
+                .into());
+            }
+            response.data.sort_by_key(|e| e.index);
+            embeddings.extend(response.data.into_iter().map(|e| e.embedding));
+        }
+        Ok(embeddings)
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn max_tokens(&self) -> usize {
+        OPENAI_MAX_TOKENS
+    }
+}
+
+/// Embeddings from an Ollama server's `/api/embed`, which takes a batch of
+/// inputs per request, unlike the older `/api/embeddings`.
+pub struct OllamaBackend {
+    client: Client,
+    url: String,
+    model: String,
+    model_id: String,
+    dimension: usize,
+}
+
+impl OllamaBackend {
+    /// Connects to the server at `base_url` and embeds a probe to learn the
+    /// model's dimension, failing early if the model isn't pulled.
+    pub fn new(base_url: Option<&str>, model: &str) -> Result<Self> {
+        let base_url = base_url.unwrap_or(DEFAULT_OLLAMA_URL).trim_end_matches('/');
+        let mut backend = Self {
+            client: Client::new(None),
+            url: format!("{base_url}/api/embed"),
+            model: model.to_string(),
+            model_id: format!("ollama:{model}"),
+            dimension: 0,
+        };
+        backend.dimension = backend.embed_batch(&["dimension probe"])?.remove(0).len();
+        info!(
+            "Using {} on Ollama at {} ({} dimensions)",
+            model, base_url, backend.dimension
+        );
+        Ok(backend)
+    }
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl Embedder for OllamaBackend {
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(REQUEST_BATCH_SIZE) {
+            let body = json!({ "model": self.model, "input": batch });
+            let response: OllamaResponse = self.client.post(&self.url, &body)?;
+            if response.embeddings.len() != batch.len() {
+                return Err(BackendError::Count {
+                    url: self.url.clone(),
+                    got: response.embeddings.len(),
+                    sent: batch.len(),
+                }
+                .into());
+            }
+            embeddings.extend(response.embeddings);
+        }
+        Ok(embeddings)
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn max_tokens(&self) -> usize {
+        OLLAMA_MAX_TOKENS
+    }
+}
+
+/// JSON over HTTP, retrying what might succeed on a later attempt.
+struct Client {
+    agent: ureq::Agent,
+    api_key: Option<String>,
+}
+
+impl Client {
+    fn new(api_key: Option<String>) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            api_key,
+        }
+    }
+
+    /// POSTs `body` to `url` and parses the answer. Rate limits, server
+    /// errors and failed connections are retried with exponential backoff,
+    /// honouring `Retry-After`; other errors fail at once.
+    fn post<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+    ) -> Result<T> {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            trace!("POST {} (attempt {})", url, attempt);
+            let mut request = self.agent.post(url);
+            if let Some(api_key) = &self.api_key {
+                request = request.set("Authorization", &format!("Bearer {api_key}"));
+            }
+            let wait = match request.send_json(body) {
+                Ok(response) => {
+                    return response
+                        .into_json()
+                        .wrap_err_with(|| format!("Failed to parse the response of {url}"));
+                }
+                Err(ureq::Error::Status(status, response))
+                    if attempt < MAX_ATTEMPTS && (status == 429 || status >= 500) =>
+                {
+                    let retry_after = response
+                        .header("Retry-After")
+                        .and_then(|seconds| seconds.parse().ok())
+                        .map(Duration::from_secs);
+                    warn!("{} answered HTTP {}, retrying", url, status);
+                    retry_after.unwrap_or(backoff)
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    return Err(BackendError::Http {
+                        url: url.to_string(),
+                        status,
+                        body: error_body(response),
+                    }
+                    .into());
+                }
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    warn!("Request to {} failed ({}), retrying", url, e);
+                    backoff
+                }
+                Err(e) => return Err(e).wrap_err_with(|| format!("Request to {url} failed")),
+            };
+            debug!("Waiting {:?} before retrying {}", wait, url);
+            std::thread::sleep(wait);
+            backoff *= 2;
+        }
+        unreachable!("the last attempt returns")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Json;
+    use axum::http::StatusCode;
+
+    /// Serves `app` on a local port for as long as the runtime lives.
+    fn serve(runtime: &tokio::runtime::Runtime, app: axum::Router) -> String {
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        runtime.spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    /// Answers with the embeddings in reverse, each `[input length, index]`.
+    async fn reversed(Json(body): Json<serde_json::Value>) -> Json<serde_json::Value> {
+        let inputs = body["input"].as_array().unwrap();
+        let data: Vec<_> = inputs
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, input)| {
+                let len = input.as_str().unwrap().len();
+                json!({ "index": index, "embedding": [len as f32, index as f32] })
+            })
+            .collect();
+        Json(json!({ "data": data }))
+    }
+
+    #[test]
+    fn openai_embeddings_come_back_in_input_order() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let app = axum::Router::new().route("/v1/embeddings", axum::routing::post(reversed));
+        let url = serve(&runtime, app);
+        let backend = OpenAiBackend::new(Some(&format!("{url}/v1/")), "test").unwrap();
+        assert_eq!(backend.dimension(), 2);
+
+        let texts: Vec<String> = (0..REQUEST_BATCH_SIZE + 6).map(|i| "x".repeat(i)).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let embeddings = backend.embed_batch(&texts).unwrap();
+        assert_eq!(embeddings.len(), texts.len());
+        for (i, embedding) in embeddings.iter().enumerate() {
+            assert_eq!(embedding[0], i as f32);
+        }
+    }
+
+    #[test]
+    fn long_error_bodies_are_cut_at_a_char_boundary() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let app = axum::Router::new().fallback(|| async {
+            // Two bytes a character, so byte 500 falls inside the first one past it.
+            (StatusCode::BAD_REQUEST, format!("x{}", "é".repeat(400)))
+        });
+        let url = serve(&runtime, app);
+        let error = Client::new(None)
+            .post::<serde_json::Value>(&url, &json!({}))
+            .unwrap_err();
+        match error.downcast_ref::<BackendError>() {
+            Some(BackendError::Http { status, body, .. }) => {
+                assert_eq!(*status, 400);
+                assert_eq!(body.len(), 499);
+            }
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use eyre::{Context, Result};
+use hf_hub::api::sync::{Api, ApiRepo};
+use hf_hub::{Repo, RepoType};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokenizers::{PaddingDirection, PaddingParams, PaddingStrategy, PostProcessor, Tokenizer};
+
+use crate::backend::Embedder;
+use crate::digest::{DigestError, DigesterConfig};
+
+const DEFAULT_MAX_TOKENS: usize = 512;
+
+/// How token embeddings are reduced to one vector per input.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    /// Average of all non-padding tokens, special tokens included, as in
+    /// sentence-transformers' mean pooling.
+    #[default]
+    Mean,
+    /// The `[CLS]` token's embedding, used by models such as BGE.
+    Cls,
+}
+
+impl Pooling {
+    /// Reads the mode from a sentence-transformers `1_Pooling/config.json`.
+    fn from_sentence_transformers(config: &serde_json::Value) -> Option<Self> {
+        let enabled = |key: &str| config.get(key).and_then(|v| v.as_bool()) == Some(true);
+        if enabled("pooling_mode_cls_token") {
+            Some(Self::Cls)
+        } else if enabled("pooling_mode_mean_tokens") {
+            Some(Self::Mean)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for Pooling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Mean => "mean",
+            Self::Cls => "cls",
+        })
+    }
+}
+
+impl std::str::FromStr for Pooling {
+    type Err = DigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mean" => Ok(Self::Mean),
+            "cls" => Ok(Self::Cls),
+            _ => Err(DigestError::Pooling(s.to_string())),
+        }
+    }
+}
+
+/// Where model files come from: the Hugging Face hub (and its local cache) or a plain directory.
+pub(crate) enum ModelFiles {
+    Hub(Box<ApiRepo>),
+    Local(PathBuf),
+}
+
+/// The pieces of a BERT-style model every loader needs.
+pub(crate) struct BertFiles {
+    pub config: Config,
+    pub tokenizer: Tokenizer,
+    pub max_tokens: usize,
+    pub weights: VarBuilder<'static>,
+}
+
+impl ModelFiles {
+    /// Files of `model`, read from `model_path` (or `model` itself when it is a
+    /// directory) without contacting the hub.
+    pub(crate) fn open(
+        model: &str,
+        revision: Option<&str>,
+        model_path: Option<&Path>,
+    ) -> Result<Self> {
+        let local_dir = model_path
+            .map(Path::to_path_buf)
+            .or_else(|| Some(PathBuf::from(model)).filter(|p| p.is_dir()));
+        match local_dir {
+            Some(dir) => {
+                debug!("Reading model files from {}", dir.display());
+                Ok(Self::Local(dir))
+            }
+            None => {
+                let api = Api::new().wrap_err("Failed to initialize Hugging Face API")?;
+                let model = model.to_string();
+                Ok(Self::Hub(Box::new(api.repo(match revision {
+                    Some(revision) => {
+                        Repo::with_revision(model, RepoType::Model, revision.to_string())
+                    }
+                    None => Repo::new(model, RepoType::Model),
+                }))))
+            }
+        }
+    }
+
+    /// Reads the config, tokenizer and weights of a BERT-style model.
+    pub(crate) fn load_bert(&self, device: &Device) -> Result<BertFiles> {
+        let (mut raw_config, tokenizer) = self.load_tokenizer()?;
+        let max_tokens = resolve_max_tokens(&raw_config, &tokenizer);
+        if let Some(fields) = raw_config.as_object_mut() {
+            fields
+                .entry("max_position_embeddings")
+                .or_insert(DEFAULT_MAX_TOKENS.into());
+        }
+        let config: Config =
+            serde_json::from_value(raw_config).wrap_err("Failed to parse model config")?;
+
+        // Older repos only ship PyTorch weights.
+        let weights = match self.get("model.safetensors") {
+            Ok(weights_path) => unsafe {
+                VarBuilder::from_mmaped_safetensors(&[weights_path], DTYPE, device)?
+            },
+            Err(e) => {
+                debug!("No model.safetensors ({}), trying pytorch_model.bin", e);
+                let weights_path = self.get("pytorch_model.bin")?;
+                VarBuilder::from_pth(weights_path, DTYPE, device)?
+            }
+        };
+        Ok(BertFiles {
+            config,
+            tokenizer,
+            max_tokens,
+            weights,
+        })
+    }
+
+    /// Reads the raw `config.json` and the tokenizer, set to pad batches on the right.
+    pub(crate) fn load_tokenizer(&self) -> Result<(serde_json::Value, Tokenizer)> {
+        let config_path = self.get("config.json")?;
+        let tokenizer_path = self.get("tokenizer.json")?;
+
+        let raw_config: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(&config_path).wrap_err("Failed to read model config")?,
+        )
+        .wrap_err("Failed to parse model config")?;
+        let mut tokenizer = Tokenizer::from_file(&tokenizer_path)
+            .map_err(|e| DigestError::Tokenizer(e.to_string()))?;
+        // Batches are padded to their longest input; keep the repo's own padding if it has one,
+        // but always on the right, so CLS pooling finds `[CLS]` at position 0.
+        match tokenizer.get_padding_mut() {
+            Some(padding) => padding.direction = PaddingDirection::Right,
+            None => {
+                tokenizer.with_padding(Some(PaddingParams {
+                    strategy: PaddingStrategy::BatchLongest,
+                    ..Default::default()
+                }));
+            }
+        }
+        Ok((raw_config, tokenizer))
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<PathBuf> {
+        match self {
+            Self::Hub(repo) => repo
+                .get(name)
+                .wrap_err_with(|| format!("Failed to fetch {name}")),
+            Self::Local(dir) => {
+                let path = dir.join(name);
+                if path.exists() {
+                    Ok(path)
+                } else {
+                    Err(DigestError::MissingModelFile(path).into())
+                }
+            }
+        }
+    }
+}
+
+/// A BERT-style model from the hub or a local directory, run with candle.
+pub struct BertBackend {
+    model_id: String,
+    revision: Option<String>,
+    model: BertModel,
+    tokenizer: Tokenizer,
+    /// `tokenizer` without truncation or padding, so token counts and offsets
+    /// cover the whole text however long it is.
+    counter: Tokenizer,
+    device: Device,
+    dimension: usize,
+    max_tokens: usize,
+    pooling: Pooling,
+}
+
+impl BertBackend {
+    /// Loads `config.model` at `config.revision`.
+    pub fn load(config: &DigesterConfig) -> Result<Self> {
+        match &config.revision {
+            Some(revision) => info!("Loading embedding model {}@{}...", config.model, revision),
+            None => info!("Loading embedding model {}...", config.model),
+        }
+        let device = config.device.resolve()?;
+        let repo = ModelFiles::open(
+            &config.model,
+            config.revision.as_deref(),
+            config.model_path.as_deref(),
+        )?;
+        let BertFiles {
+            config: bert_config,
+            tokenizer,
+            max_tokens,
+            weights,
+        } = repo.load_bert(&device)?;
+        let model =
+            BertModel::load(weights, &bert_config).wrap_err("Failed to load BERT weights")?;
+        let pooling = match config.pooling {
+            Some(pooling) => pooling,
+            None => detect_pooling(&repo),
+        };
+        let counter = unpadded(&tokenizer)?;
+
+        debug!(
+            "Embedding model loaded on {:?} ({} dimensions, {:?} pooling)",
+            device, bert_config.hidden_size, pooling
+        );
+        Ok(Self {
+            model_id: config.model.clone(),
+            revision: config.revision.clone(),
+            model,
+            tokenizer,
+            counter,
+            device,
+            dimension: bert_config.hidden_size,
+            max_tokens,
+            pooling,
+        })
+    }
+}
+
+impl Embedder for BertBackend {
+    /// Embeds `texts` in a single forward pass, padding them to the longest input.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(|e| DigestError::Encode(e.to_string()))?;
+
+        let mut ids = Vec::with_capacity(encodings.len());
+        let mut masks = Vec::with_capacity(encodings.len());
+        for encoding in &encodings {
+            ids.push(Tensor::new(encoding.get_ids(), &self.device)?);
+            masks.push(Tensor::new(encoding.get_attention_mask(), &self.device)?);
+        }
+        let input_ids = Tensor::stack(&ids, 0)?;
+        let attention_mask = Tensor::stack(&masks, 0)?;
+        let token_type_ids = input_ids.zeros_like()?;
+
+        let output = self
+            .model
+            .forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+        let pooled = match self.pooling {
+            // Average only over real tokens so padding doesn't dilute shorter inputs.
+            Pooling::Mean => {
+                let mask = attention_mask.to_dtype(output.dtype())?.unsqueeze(2)?;
+                let summed = output.broadcast_mul(&mask)?.sum(1)?;
+                summed.broadcast_div(&mask.sum(1)?)?
+            }
+            // Padding is on the right, so position 0 is always `[CLS]`.
+            Pooling::Cls => output.narrow(1, 0, 1)?.squeeze(1)?,
+        };
+        Ok(pooled.to_vec2::<f32>()?)
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    fn pooling(&self) -> Option<Pooling> {
+        Some(self.pooling)
+    }
+
+    /// From the model's `hidden_size`.
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        match self.counter.encode(text, false) {
+            Ok(encoding) => encoding.get_ids().len(),
+            // A token per byte overestimates, so chunks measured this way still fit.
+            Err(_) => text.len(),
+        }
+    }
+
+    fn special_tokens(&self) -> usize {
+        self.tokenizer
+            .get_post_processor()
+            .map_or(0, |processor| processor.added_tokens(false))
+    }
+
+    fn token_offsets(&self, text: &str) -> Result<Option<Vec<(usize, usize)>>> {
+        let encoding = self
+            .counter
+            .encode(text, false)
+            .map_err(|e| DigestError::Encode(e.to_string()))?;
+        Ok(Some(encoding.get_offsets().to_vec()))
+    }
+}
+
+/// `tokenizer` without truncation or padding, for counting the tokens of whole texts.
+pub(crate) fn unpadded(tokenizer: &Tokenizer) -> Result<Tokenizer> {
+    let mut counter = tokenizer.clone();
+    counter
+        .with_truncation(None)
+        .map_err(|e| DigestError::Tokenizer(e.to_string()))?
+        .with_padding(None);
+    Ok(counter)
+}
+
+pub(crate) fn detect_pooling(repo: &ModelFiles) -> Pooling {
+    let detected = repo
+        .get("1_Pooling/config.json")
+        .ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .and_then(|config| Pooling::from_sentence_transformers(&config));
+    match detected {
+        Some(pooling) => pooling,
+        None => {
+            debug!("No sentence-transformers pooling config, using mean pooling");
+            Pooling::default()
+        }
+    }
+}
+
+/// The smaller of the model's `max_position_embeddings` and the tokenizer's
+/// truncation length, falling back to 512 when neither is configured.
+pub(crate) fn resolve_max_tokens(raw_config: &serde_json::Value, tokenizer: &Tokenizer) -> usize {
+    let from_config = raw_config
+        .get("max_position_embeddings")
+        .and_then(|v| v.as_u64())
+        .map(|n| n as usize);
+    let from_tokenizer = tokenizer.get_truncation().map(|t| t.max_length);
+
+    let max_tokens = match (from_config, from_tokenizer) {
+        (Some(a), Some(b)) => a.min(b),
+        (Some(n), None) | (None, Some(n)) => n,
+        (None, None) => {
+            info!("Model config has no sequence limit, assuming {DEFAULT_MAX_TOKENS} tokens");
+            return DEFAULT_MAX_TOKENS;
+        }
+    };
+    info!(
+        "Using max sequence length of {} tokens (config: {:?}, tokenizer: {:?})",
+        max_tokens, from_config, from_tokenizer
+    );
+    max_tokens
+}
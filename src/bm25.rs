@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+/// Term frequency saturation; higher values let repeated terms count longer.
+const K1: f32 = 1.2;
+/// How strongly scores are normalized by document length, from 0 to 1.
+const B: f32 = 0.75;
+
+/// Inverted index scoring documents addressed by index with Okapi BM25.
+/// Documents can only be appended; removing one means rebuilding.
+#[derive(Debug, Clone, Default)]
+pub struct Bm25Index {
+    /// `postings[term]` lists each document holding the term with its count.
+    postings: HashMap<String, Vec<(usize, u32)>>,
+    doc_lens: Vec<u32>,
+    total_len: u64,
+}
+
+impl Bm25Index {
+    /// Builds an index over `len` documents.
+    pub fn build<'a>(len: usize, text: impl Fn(usize) -> &'a str) -> Self {
+        let mut index = Self::default();
+        for doc in 0..len {
+            index.insert(doc, text(doc));
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.doc_lens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.doc_lens.is_empty()
+    }
+
+    /// Adds the next document; `doc` must equal the current `len()`.
+    pub fn insert(&mut self, doc: usize, text: &str) {
+        debug_assert_eq!(doc, self.doc_lens.len());
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        let mut len = 0;
+        for term in tokenize(text) {
+            *counts.entry(term).or_default() += 1;
+            len += 1;
+        }
+        for (term, count) in counts {
+            self.postings.entry(term).or_default().push((doc, count));
+        }
+        self.doc_lens.push(len);
+        self.total_len += u64::from(len);
+    }
+
+    /// The `limit` best scoring documents for `query` that `keep` accepts,
+    /// best first. Documents sharing no term with the query are never returned.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        keep: impl Fn(usize) -> bool,
+    ) -> Vec<(usize, f32)> {
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        for term in query_terms(query) {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let idf = self.idf(postings.len());
+            for &(doc, count) in postings {
+                *scores.entry(doc).or_default() += idf * self.term_weight(doc, count);
+            }
+        }
+
+        let mut hits: Vec<_> = scores.into_iter().filter(|&(doc, _)| keep(doc)).collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hits.truncate(limit);
+        hits
+    }
+
+    /// BM25 score of a single document for `query`.
+    pub fn score(&self, query: &str, doc: usize) -> f32 {
+        query_terms(query)
+            .into_iter()
+            .filter_map(|term| {
+                let postings = self.postings.get(&term)?;
+                let &(_, count) = postings.iter().find(|(d, _)| *d == doc)?;
+                Some(self.idf(postings.len()) * self.term_weight(doc, count))
+            })
+            .sum()
+    }
+
+    /// Inverse document frequency, kept positive even for terms in most documents.
+    fn idf(&self, docs_with_term: usize) -> f32 {
+        let n = self.doc_lens.len() as f32;
+        let df = docs_with_term as f32;
+        ((n - df + 0.5) / (df + 0.5) + 1.0).ln()
+    }
+
+    fn term_weight(&self, doc: usize, count: u32) -> f32 {
+        let avg_len = self.total_len as f32 / self.doc_lens.len().max(1) as f32;
+        let len_norm = 1.0 - B + B * self.doc_lens[doc] as f32 / avg_len.max(1.0);
+        let tf = count as f32;
+        tf * (K1 + 1.0) / (tf + K1 * len_norm)
+    }
+}
+
+/// Lowercased alphanumeric runs, so `parse_config` yields `parse` and `config`.
+pub(crate) fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Distinct terms of a query, so repeating a word doesn't weigh it twice.
+pub(crate) fn query_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<_> = tokenize(query).collect();
+    terms.sort_unstable();
+    terms.dedup();
+    terms
+}
@@ -0,0 +1,205 @@
+use eyre::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::crypt;
+
+/// Manifest of the run in progress, kept in the memory directory.
+pub const MANIFEST_FILE: &str = "run.json";
+/// Completed files are written to the manifest at most this often.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Size and modification time of a file when it was processed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_ns: u128,
+}
+
+/// Which paths of a run are still pending and which are done.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RunManifest {
+    pub pending: BTreeSet<PathBuf>,
+    pub done: BTreeMap<PathBuf, FileStamp>,
+}
+
+/// Tracks a `Processor` run in a manifest, so a run that is interrupted or
+/// fails can be resumed: files it finished are skipped by the next run as
+/// long as their size and mtime still match, without reading their history.
+/// The manifest is removed once a run completes.
+pub struct Checkpoint {
+    /// `None` when checkpointing is off, e.g. for scans and dry runs.
+    path: Option<PathBuf>,
+    previous: HashMap<PathBuf, FileStamp>,
+    state: Mutex<State>,
+}
+
+struct State {
+    manifest: RunManifest,
+    last_flush: Instant,
+    dirty: bool,
+}
+
+impl Checkpoint {
+    /// A checkpoint that records and skips nothing.
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            previous: HashMap::new(),
+            state: Mutex::new(State {
+                manifest: RunManifest::default(),
+                last_flush: Instant::now(),
+                dirty: false,
+            }),
+        }
+    }
+
+    /// Starts a run over `paths`, picking up what an unfinished run left in
+    /// `memory_dir`, and writes the new manifest. Files the unfinished run
+    /// did stay done until they are seen changed.
+    pub fn start(memory_dir: &Path, paths: &BTreeSet<PathBuf>) -> Result<Self> {
+        let path = memory_dir.join(MANIFEST_FILE);
+        let previous = match Self::load(&path) {
+            Ok(Some(manifest)) => {
+                info!(
+                    "Resuming an unfinished run: {} files done, {} pending",
+                    manifest.done.len(),
+                    manifest.pending.len()
+                );
+                manifest.done.into_iter().collect()
+            }
+            Ok(None) => HashMap::new(),
+            Err(e) => {
+                warn!("Ignoring unreadable {}: {:?}", path.display(), e);
+                HashMap::new()
+            }
+        };
+        let mut manifest = RunManifest::default();
+        for path in paths {
+            if let Some(stamp) = previous.get(path) {
+                manifest.done.insert(path.clone(), *stamp);
+            } else {
+                manifest.pending.insert(path.clone());
+            }
+        }
+        let checkpoint = Self {
+            path: Some(path),
+            previous,
+            state: Mutex::new(State {
+                manifest,
+                last_flush: Instant::now(),
+                dirty: true,
+            }),
+        };
+        checkpoint.flush()?;
+        Ok(checkpoint)
+    }
+
+    fn load(path: &Path) -> Result<Option<RunManifest>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data =
+            crypt::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Whether an interrupted run already finished `path` as it is now.
+    pub fn is_done(&self, path: &Path, stamp: FileStamp) -> bool {
+        self.previous.get(path) == Some(&stamp)
+    }
+
+    /// Marks `path` done as of `stamp`, writing the manifest if the last write is old enough.
+    pub fn complete(&self, path: &Path, stamp: FileStamp) {
+        if self.path.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.manifest.pending.remove(path);
+        state.manifest.done.insert(path.to_path_buf(), stamp);
+        state.dirty = true;
+        if state.last_flush.elapsed() >= FLUSH_INTERVAL
+            && let Err(e) = self.write(&mut state)
+        {
+            warn!("Failed to write checkpoint: {:?}", e);
+        }
+    }
+
+    /// Writes the manifest if anything changed since the last write.
+    pub fn flush(&self) -> Result<()> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+        self.write(&mut self.state.lock().unwrap())
+    }
+
+    /// Ends the run. A complete run removes the manifest; an incomplete one
+    /// leaves it for the next run to resume from.
+    pub fn finish(&self, complete: bool) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !complete {
+            return self.flush();
+        }
+        match std::fs::remove_file(path) {
+            Ok(()) => debug!("Run complete, removed {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to remove {}", path.display()));
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, state: &mut State) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        state.last_flush = Instant::now();
+        if !std::mem::take(&mut state.dirty) && path.exists() {
+            return Ok(());
+        }
+        let data = crypt::seal(&serde_json::to_vec(&state.manifest)?)?;
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, data)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_interrupted_run_is_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (PathBuf::from("/data/a.txt"), PathBuf::from("/data/b.txt"));
+        let paths = BTreeSet::from([a.clone(), b.clone()]);
+        let stamp = FileStamp {
+            size: 12,
+            mtime_ns: 1_700_000_000_000_000_000,
+        };
+
+        let run = Checkpoint::start(dir.path(), &paths).unwrap();
+        run.complete(&a, stamp);
+        run.finish(false).unwrap();
+        let manifest = Checkpoint::load(&dir.path().join(MANIFEST_FILE))
+            .unwrap()
+            .unwrap();
+        assert_eq!(manifest.pending, BTreeSet::from([b.clone()]));
+
+        let resumed = Checkpoint::start(dir.path(), &paths).unwrap();
+        assert!(resumed.is_done(&a, stamp));
+        assert!(!resumed.is_done(&a, FileStamp { size: 13, ..stamp }));
+        assert!(!resumed.is_done(&b, stamp));
+        resumed.complete(&b, stamp);
+        resumed.finish(true).unwrap();
+        assert!(!dir.path().join(MANIFEST_FILE).exists());
+    }
+}
@@ -0,0 +1,827 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+use tree_sitter::{Node, Parser};
+
+#[derive(Error, Debug)]
+pub enum ChunkError {
+    #[error("unknown chunking strategy {0:?}, expected fixed, sentences or tokens")]
+    Strategy(String),
+    #[error("token offsets end at byte {covered} of {len}, the tokenizer cut the text short")]
+    Uncovered { covered: usize, len: usize },
+}
+
+/// How text is cut into chunks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    /// Windows of `size` bytes, see `chunk_text`.
+    #[default]
+    Fixed,
+    /// Whole sentences and paragraphs merged up to `size` tokens, see `chunk_sentences`.
+    Sentences,
+    /// Windows of exactly `size` tokens, see `chunk_tokens`.
+    Tokens,
+}
+
+impl std::str::FromStr for ChunkStrategy {
+    type Err = ChunkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fixed" => Ok(Self::Fixed),
+            "sentences" => Ok(Self::Sentences),
+            "tokens" => Ok(Self::Tokens),
+            _ => Err(ChunkError::Strategy(s.to_string())),
+        }
+    }
+}
+
+/// Chunking parameters. `size` and `overlap` are bytes of UTF-8 text for
+/// `Fixed` chunks and tokens for the strategies that count them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    pub strategy: ChunkStrategy,
+    pub size: usize,
+    pub overlap: usize,
+    /// Source code in a language `Language` knows is cut at its top-level
+    /// definitions by `chunk_code` instead.
+    pub code: bool,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        // ~250 tokens for English prose, comfortably inside a 512-token BERT window
+        Self {
+            strategy: ChunkStrategy::Fixed,
+            size: 1000,
+            overlap: 200,
+            code: true,
+        }
+    }
+}
+
+/// Where a chunk sits in its source text. Lines are 1-based and inclusive.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChunkSpan {
+    pub index: usize,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    pub text: &'a str,
+    pub span: ChunkSpan,
+    /// Name of the definition the chunk holds, for code chunks.
+    pub symbol: Option<&'a str>,
+}
+
+/// Splits `text` into windows of at most `config.size` bytes, each starting up
+/// to `config.overlap` bytes before the previous one ended. Window edges prefer
+/// newlines, then whitespace, so lines and words aren't cut when avoidable.
+pub fn chunk_text<'a>(text: &'a str, config: &ChunkConfig) -> Vec<Chunk<'a>> {
+    let size = config.size.max(1);
+    let overlap = config.overlap.min(size / 2);
+    let mut chunks = Vec::new();
+
+    let mut start = 0;
+    let mut lines = LineCounter::default();
+    while start < text.len() {
+        let mut end = floor_char_boundary(text, (start + size).min(text.len()));
+        if end < text.len() {
+            let window = &text[start..end];
+            let split = window
+                .rfind('\n')
+                .or_else(|| window.rfind(char::is_whitespace))
+                .filter(|&pos| pos >= size / 2);
+            if let Some(pos) = split {
+                end = start + pos + 1;
+            }
+        }
+        if end <= start {
+            end = ceil_char_boundary(text, start + 1);
+        }
+
+        chunks.push(lines.chunk(text, chunks.len(), start, end));
+
+        if end == text.len() {
+            break;
+        }
+        // Begin the overlap at a line or word start when one falls inside it.
+        let back = ceil_char_boundary(text, end.saturating_sub(overlap));
+        let tail = &text[back..end];
+        let skip = tail
+            .find('\n')
+            .or_else(|| tail.find(char::is_whitespace))
+            .map_or(0, |pos| pos + 1);
+        let next = if back + skip < end { back + skip } else { back };
+        start = ceil_char_boundary(text, next.max(start + 1));
+    }
+
+    chunks
+}
+
+/// Splits `text` at blank lines and sentence ends (`.`, `!` or `?` followed
+/// by whitespace), then merges the pieces into chunks of at most
+/// `config.size` tokens as measured by `count_tokens`. Each chunk after the
+/// first repeats whole sentences of the previous one, up to `config.overlap`
+/// tokens. A sentence too long for a chunk on its own is split further at
+/// lines and words.
+pub fn chunk_sentences<'a>(
+    text: &'a str,
+    config: &ChunkConfig,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<Chunk<'a>> {
+    let size = config.size.max(1);
+    let mut units = Vec::new();
+    for (start, end) in sentence_ranges(text) {
+        fit_range(text, start, end, size, &count_tokens, &mut units);
+    }
+
+    let mut chunks = Vec::new();
+    let mut lines = LineCounter::default();
+    let mut first = 0;
+    while first < units.len() {
+        let (mut last, mut tokens) = (first, units[first].2);
+        while last + 1 < units.len() && tokens + units[last + 1].2 <= size {
+            last += 1;
+            tokens += units[last].2;
+        }
+        let (start, end) = (units[first].0, units[last].1);
+        chunks.push(lines.chunk(text, chunks.len(), start, end));
+        if last + 1 == units.len() {
+            break;
+        }
+        // Back up over trailing sentences that fit the overlap, always moving forward.
+        let mut next = last + 1;
+        let mut overlap = 0;
+        while next - 1 > first && overlap + units[next - 1].2 <= config.overlap {
+            next -= 1;
+            overlap += units[next].2;
+        }
+        first = next;
+    }
+    chunks
+}
+
+/// Splits `text` into windows of `config.size` tokens, each repeating the
+/// last `config.overlap` tokens of the previous one. `offsets` are the byte
+/// ranges of the tokens of `text`, in order, as a tokenizer reports them.
+/// A chunk runs up to the start of the token after it, so together the
+/// chunks cover the whole text. Fails if the offsets stop short of the end
+/// of `text`, as they do from a truncating tokenizer, rather than stretch
+/// the last chunk over the rest.
+pub fn chunk_tokens<'a>(
+    text: &'a str,
+    offsets: &[(usize, usize)],
+    config: &ChunkConfig,
+) -> Result<Vec<Chunk<'a>>, ChunkError> {
+    let covered = offsets.iter().map(|&(_, end)| end).max().unwrap_or(0);
+    if covered < text.len() && !text[floor_char_boundary(text, covered)..].trim().is_empty() {
+        return Err(ChunkError::Uncovered {
+            covered,
+            len: text.len(),
+        });
+    }
+    let size = config.size.max(1);
+    let step = size - config.overlap.min(size / 2);
+    let mut chunks = Vec::new();
+    let mut lines = LineCounter::default();
+    let mut first = 0;
+    while first < offsets.len() {
+        let next = (first + size).min(offsets.len());
+        let start = match first {
+            0 => 0,
+            _ => floor_char_boundary(text, offsets[first].0.min(text.len())),
+        };
+        let end = match offsets.get(next) {
+            Some(&(next_start, _)) => ceil_char_boundary(text, next_start),
+            None => text.len(),
+        };
+        if end > start {
+            chunks.push(lines.chunk(text, chunks.len(), start, end));
+        }
+        if next == offsets.len() {
+            break;
+        }
+        first += step;
+    }
+    Ok(chunks)
+}
+
+/// Programming languages `chunk_code` finds the definitions of, each parsed
+/// with its tree-sitter grammar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    Python,
+    JavaScript,
+    TypeScript,
+    Tsx,
+    Go,
+    Java,
+    Kotlin,
+    CSharp,
+    Scala,
+    Swift,
+    C,
+    Cpp,
+    Ruby,
+}
+
+impl Language {
+    /// The language of a source file, by its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        let language = match extension.as_str() {
+            "rs" => Self::Rust,
+            "py" | "pyi" => Self::Python,
+            "js" | "jsx" | "mjs" | "cjs" => Self::JavaScript,
+            "ts" | "mts" | "cts" => Self::TypeScript,
+            "tsx" => Self::Tsx,
+            "go" => Self::Go,
+            "java" => Self::Java,
+            "kt" | "kts" => Self::Kotlin,
+            "cs" => Self::CSharp,
+            "scala" | "sc" => Self::Scala,
+            "swift" => Self::Swift,
+            "c" | "h" => Self::C,
+            "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => Self::Cpp,
+            "rb" => Self::Ruby,
+            _ => return None,
+        };
+        Some(language)
+    }
+
+    fn grammar(self) -> tree_sitter::Language {
+        match self {
+            Self::Rust => tree_sitter_rust::LANGUAGE.into(),
+            Self::Python => tree_sitter_python::LANGUAGE.into(),
+            Self::JavaScript => tree_sitter_javascript::LANGUAGE.into(),
+            Self::TypeScript => tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(),
+            Self::Tsx => tree_sitter_typescript::LANGUAGE_TSX.into(),
+            Self::Go => tree_sitter_go::LANGUAGE.into(),
+            Self::Java => tree_sitter_java::LANGUAGE.into(),
+            Self::Kotlin => tree_sitter_kotlin_ng::LANGUAGE.into(),
+            Self::CSharp => tree_sitter_c_sharp::LANGUAGE.into(),
+            Self::Scala => tree_sitter_scala::LANGUAGE.into(),
+            Self::Swift => tree_sitter_swift::LANGUAGE.into(),
+            Self::C => tree_sitter_c::LANGUAGE.into(),
+            Self::Cpp => tree_sitter_cpp::LANGUAGE.into(),
+            Self::Ruby => tree_sitter_ruby::LANGUAGE.into(),
+        }
+    }
+
+    /// Kinds of the nodes that are definitions.
+    fn definitions(self) -> &'static [&'static str] {
+        match self {
+            Self::Rust => &[
+                "function_item",
+                "struct_item",
+                "enum_item",
+                "trait_item",
+                "impl_item",
+                "mod_item",
+                "union_item",
+                "macro_definition",
+            ],
+            Self::Python => &["function_definition", "class_definition"],
+            Self::JavaScript => &[
+                "function_declaration",
+                "generator_function_declaration",
+                "class_declaration",
+            ],
+            Self::TypeScript | Self::Tsx => &[
+                "function_declaration",
+                "generator_function_declaration",
+                "class_declaration",
+                "abstract_class_declaration",
+                "interface_declaration",
+                "enum_declaration",
+            ],
+            Self::Go => &[
+                "function_declaration",
+                "method_declaration",
+                "type_declaration",
+            ],
+            Self::Java => &[
+                "class_declaration",
+                "interface_declaration",
+                "enum_declaration",
+                "record_declaration",
+                "annotation_type_declaration",
+            ],
+            Self::Kotlin => &[
+                "class_declaration",
+                "object_declaration",
+                "function_declaration",
+            ],
+            Self::CSharp => &[
+                "class_declaration",
+                "struct_declaration",
+                "interface_declaration",
+                "enum_declaration",
+                "record_declaration",
+            ],
+            Self::Scala => &[
+                "class_definition",
+                "object_definition",
+                "trait_definition",
+                "function_definition",
+                "enum_definition",
+            ],
+            Self::Swift => &[
+                "class_declaration",
+                "protocol_declaration",
+                "function_declaration",
+            ],
+            Self::C => &[
+                "function_definition",
+                "struct_specifier",
+                "enum_specifier",
+                "union_specifier",
+            ],
+            Self::Cpp => &[
+                "function_definition",
+                "struct_specifier",
+                "enum_specifier",
+                "union_specifier",
+                "class_specifier",
+            ],
+            Self::Ruby => &["method", "singleton_method", "class", "module"],
+        }
+    }
+
+    /// Kinds of the nodes that wrap a definition along with its decorators,
+    /// `export` or `template` header.
+    fn wrappers(self) -> &'static [&'static str] {
+        match self {
+            Self::Python => &["decorated_definition"],
+            Self::JavaScript => &["export_statement"],
+            Self::TypeScript | Self::Tsx => &["export_statement", "ambient_declaration"],
+            Self::Cpp => &["template_declaration"],
+            _ => &[],
+        }
+    }
+
+    /// Kinds of the nodes whose body holds definitions as if at the top level.
+    fn namespaces(self) -> &'static [&'static str] {
+        match self {
+            Self::CSharp => &["namespace_declaration"],
+            Self::Cpp => &["namespace_definition"],
+            _ => &[],
+        }
+    }
+
+    /// The definition `node` is or wraps, if any.
+    fn definition(self, node: Node<'_>) -> Option<Node<'_>> {
+        if self.definitions().contains(&node.kind()) {
+            return Some(node);
+        }
+        if !self.wrappers().contains(&node.kind()) {
+            return None;
+        }
+        let mut cursor = node.walk();
+        node.named_children(&mut cursor)
+            .find_map(|child| self.definition(child))
+    }
+}
+
+/// The name of `definition`, e.g. `main` or, for a Rust impl block, its
+/// whole `impl ... for ...` header.
+fn symbol<'a>(text: &'a str, definition: Node<'_>) -> Option<&'a str> {
+    if definition.kind() == "impl_item" {
+        let mut cursor = definition.walk();
+        let mut children = definition.children(&mut cursor);
+        let start = children.find(|child| child.kind() == "impl")?.start_byte();
+        let end = children
+            .find(|child| matches!(child.kind(), "where_clause" | "declaration_list"))
+            .map_or(definition.end_byte(), |child| child.start_byte());
+        return Some(text[start..end].trim_end());
+    }
+    let name = match definition.child_by_field_name("name") {
+        Some(name) => name,
+        // A C function is named by the innermost of its declarators.
+        None if definition.child_by_field_name("declarator").is_some() => {
+            let mut declarator = definition.child_by_field_name("declarator")?;
+            while let Some(inner) = declarator.child_by_field_name("declarator") {
+                declarator = inner;
+            }
+            declarator
+        }
+        // A Go type declaration is named by its first spec.
+        None => {
+            let mut cursor = definition.walk();
+            definition
+                .named_children(&mut cursor)
+                .find_map(|child| child.child_by_field_name("name"))?
+        }
+    };
+    Some(&text[name.byte_range()])
+}
+
+/// Whether `node` is a comment or attribute that belongs to the definition below it.
+fn attached(node: Node<'_>) -> bool {
+    node.kind().contains("comment") || node.kind() == "attribute_item"
+}
+
+/// Pushes the start of each definition among the named children of `parent`
+/// onto `boundaries`, with its name. A definition starts at the line it's on,
+/// or at the first of the comments and attributes directly above it.
+fn find_definitions<'a>(
+    text: &'a str,
+    language: Language,
+    parent: Node<'_>,
+    boundaries: &mut Vec<(usize, Option<&'a str>)>,
+) {
+    let line_start = |offset: usize| text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let mut attached_from: Option<Node<'_>> = None;
+    let mut previous: Option<Node<'_>> = None;
+    let mut cursor = parent.walk();
+    for node in parent.named_children(&mut cursor) {
+        let own_line = text[line_start(node.start_byte())..node.start_byte()]
+            .trim()
+            .is_empty();
+        // Some grammars end line comments after their newline, some before it.
+        let adjacent = previous.is_some_and(|previous| {
+            let end = previous.end_byte();
+            let gap_start = end - usize::from(text[..end].ends_with('\n'));
+            text[gap_start..node.start_byte()].matches('\n').count() <= 1
+        });
+        if !adjacent || !own_line {
+            attached_from = None;
+        }
+        if let Some(definition) = language.definition(node).filter(|_| own_line) {
+            let start = line_start(attached_from.take().unwrap_or(node).start_byte());
+            let symbol = symbol(text, definition);
+            match boundaries.last_mut() {
+                Some(last) if last.0 == start => last.1 = symbol,
+                _ => boundaries.push((start, symbol)),
+            }
+        } else if language.namespaces().contains(&node.kind()) {
+            if let Some(body) = node.child_by_field_name("body") {
+                find_definitions(text, language, body, boundaries);
+            }
+            attached_from = None;
+        } else if attached(node) && own_line {
+            attached_from.get_or_insert(node);
+        } else {
+            attached_from = None;
+        }
+        previous = Some(node);
+    }
+}
+
+/// Splits source code at its top-level definitions, so each chunk holds one
+/// whole function, type or block of them, named in `Chunk::symbol`. Comments
+/// and attributes directly above a definition stay with it; what comes before
+/// the first one is a chunk of its own. A definition over `size` as measured
+/// by `measure` is split further at lines, each piece keeping its name.
+/// Text the grammar can't parse is split by `measure` alone.
+pub fn chunk_code<'a>(
+    text: &'a str,
+    language: Language,
+    size: usize,
+    measure: impl Fn(&str) -> usize,
+) -> Vec<Chunk<'a>> {
+    let mut boundaries: Vec<(usize, Option<&str>)> = vec![(0, None)];
+    let mut parser = Parser::new();
+    let tree = parser
+        .set_language(&language.grammar())
+        .ok()
+        .and_then(|_| parser.parse(text, None));
+    if let Some(tree) = &tree {
+        find_definitions(text, language, tree.root_node(), &mut boundaries);
+    }
+
+    let mut units = Vec::new();
+    let mut symbols = Vec::new();
+    for (i, &(start, symbol)) in boundaries.iter().enumerate() {
+        let end = boundaries.get(i + 1).map_or(text.len(), |next| next.0);
+        let mut pieces = Vec::new();
+        fit_range(text, start, end, size.max(1), &measure, &mut pieces);
+        // Halving leaves pieces well under the size; join them back up to it.
+        let mut pieces = pieces.into_iter();
+        let Some(mut current) = pieces.next() else {
+            continue;
+        };
+        for piece in pieces {
+            if current.2 + piece.2 <= size {
+                current = (current.0, piece.1, current.2 + piece.2);
+            } else {
+                units.push(std::mem::replace(&mut current, piece));
+                symbols.push(symbol);
+            }
+        }
+        units.push(current);
+        symbols.push(symbol);
+    }
+
+    let mut lines = LineCounter::default();
+    units
+        .iter()
+        .zip(symbols)
+        .filter(|((start, end, _), _)| start < end)
+        .enumerate()
+        .map(|(index, (&(start, end, _), symbol))| Chunk {
+            symbol,
+            ..lines.chunk(text, index, start, end)
+        })
+        .collect()
+}
+
+/// Byte ranges of the sentences of `text`, covering all of it; the
+/// whitespace after a sentence belongs to it.
+fn sentence_ranges(text: &str) -> Vec<(usize, usize)> {
+    let bytes = text.as_bytes();
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let paragraph_end = bytes[i] == b'\n' && bytes.get(i + 1) == Some(&b'\n');
+        let sentence_end = matches!(bytes[i], b'.' | b'!' | b'?')
+            && bytes.get(i + 1).is_some_and(u8::is_ascii_whitespace);
+        i += 1;
+        if paragraph_end || sentence_end {
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            ranges.push((start, i));
+            start = i;
+        }
+    }
+    if start < bytes.len() {
+        ranges.push((start, bytes.len()));
+    }
+    ranges
+}
+
+/// Pushes `text[start..end]` with its token count onto `units`, halving it
+/// at lines and words first for as long as it has more than `size` tokens.
+fn fit_range(
+    text: &str,
+    start: usize,
+    end: usize,
+    size: usize,
+    count_tokens: &impl Fn(&str) -> usize,
+    units: &mut Vec<(usize, usize, usize)>,
+) {
+    let tokens = count_tokens(&text[start..end]);
+    if tokens <= size || end - start <= 1 {
+        units.push((start, end, tokens));
+        return;
+    }
+    let halves = ChunkConfig {
+        strategy: ChunkStrategy::Fixed,
+        size: (end - start).div_ceil(2),
+        overlap: 0,
+        code: false,
+    };
+    for piece in chunk_text(&text[start..end], &halves) {
+        let (piece_start, piece_end) = (start + piece.span.start_byte, start + piece.span.end_byte);
+        fit_range(text, piece_start, piece_end, size, count_tokens, units);
+    }
+}
+
+/// Turns byte ranges of a text into `Chunk`s, counting lines as it goes.
+/// Ranges must come in order of their start.
+#[derive(Default)]
+struct LineCounter {
+    pos: usize,
+    line: usize,
+}
+
+impl LineCounter {
+    fn chunk<'a>(&mut self, text: &'a str, index: usize, start: usize, end: usize) -> Chunk<'a> {
+        if self.line == 0 {
+            self.line = 1;
+        }
+        self.line += text[self.pos..start].matches('\n').count();
+        self.pos = start;
+        let chunk = &text[start..end];
+        Chunk {
+            text: chunk,
+            span: ChunkSpan {
+                index,
+                start_byte: start,
+                end_byte: end,
+                start_line: self.line,
+                end_line: self.line + chunk.trim_end_matches('\n').matches('\n').count(),
+            },
+            symbol: None,
+        }
+    }
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while index < text.len() && !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index.min(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Byte ranges of the whitespace-separated words of `text`.
+    fn word_offsets(text: &str) -> Vec<(usize, usize)> {
+        let mut offsets = Vec::new();
+        let mut start = None;
+        for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+            match (c.is_whitespace(), start) {
+                (true, Some(s)) => {
+                    offsets.push((s, i));
+                    start = None;
+                }
+                (false, None) => start = Some(i),
+                _ => {}
+            }
+        }
+        offsets
+    }
+
+    fn config(size: usize, overlap: usize) -> ChunkConfig {
+        ChunkConfig {
+            strategy: ChunkStrategy::Tokens,
+            size,
+            overlap,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn token_windows_overlap_and_cover_the_text() {
+        let text = "one two three four five six seven\n";
+        let chunks = chunk_tokens(text, &word_offsets(text), &config(3, 1)).unwrap();
+        let texts: Vec<_> = chunks.iter().map(|chunk| chunk.text).collect();
+        assert_eq!(
+            texts,
+            ["one two three ", "three four five ", "five six seven\n"]
+        );
+    }
+
+    #[test]
+    fn truncated_offsets_are_refused() {
+        let text = "one two three four five six seven";
+        let offsets = word_offsets(text);
+        let error = chunk_tokens(text, &offsets[..4], &config(3, 1)).unwrap_err();
+        assert!(matches!(
+            error,
+            ChunkError::Uncovered {
+                covered: 18,
+                len: 33
+            }
+        ));
+        // Trailing whitespace past the last token is not a truncation.
+        assert!(chunk_tokens("one two \n", &word_offsets("one two"), &config(3, 1)).is_ok());
+    }
+
+    /// The symbol and first line of each chunk of `text`, cut at its definitions only.
+    fn definitions(text: &str, language: Language) -> Vec<(Option<&str>, &str)> {
+        chunk_code(text, language, usize::MAX, str::len)
+            .into_iter()
+            .map(|chunk| (chunk.symbol, chunk.text.lines().next().unwrap_or("")))
+            .collect()
+    }
+
+    #[test]
+    fn rust_definitions_keep_their_comments_and_attributes() {
+        let text = "use std::fmt;\n\n/// A point.\n#[derive(Debug)]\npub struct Point {\n    x: i32,\n}\n\n// Not attached.\n\nimpl fmt::Display for Point where Point: Sized {\n    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {\n        write!(f, \"{}\", self.x)\n    }\n}\n\npub(crate) async fn run() {}\n";
+        assert_eq!(
+            definitions(text, Language::Rust),
+            [
+                (None, "use std::fmt;"),
+                (Some("Point"), "/// A point."),
+                (
+                    Some("impl fmt::Display for Point"),
+                    "impl fmt::Display for Point where Point: Sized {"
+                ),
+                (Some("run"), "pub(crate) async fn run() {}"),
+            ]
+        );
+        let chunks = chunk_code(text, Language::Rust, usize::MAX, str::len);
+        assert!(chunks[1].text.ends_with("// Not attached.\n\n"));
+        assert_eq!(chunks[2].span.start_line, 11);
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.text).collect::<String>(),
+            text
+        );
+    }
+
+    #[test]
+    fn definitions_are_found_in_each_language() {
+        let cases: [(Language, &str, &[Option<&str>]); 11] = [
+            (
+                Language::Python,
+                "import os\n\n@cache\ndef load(path):\n    return path\n\nclass Store:\n    def get(self):\n        pass\n",
+                &[None, Some("load"), Some("Store")],
+            ),
+            (
+                Language::Go,
+                "package main\n\ntype Server struct{}\n\n// Start starts it.\nfunc (s *Server) Start() error {\n\treturn nil\n}\n",
+                &[None, Some("Server"), Some("Start")],
+            ),
+            (
+                Language::C,
+                "#include <stdio.h>\n\nstatic int *lookup(const char *key) {\n    return 0;\n}\n\nint main(void) {\n    return 0;\n}\n",
+                &[None, Some("lookup"), Some("main")],
+            ),
+            (
+                Language::Cpp,
+                "namespace app {\n\ntemplate <typename T>\nclass Box {};\n\nvoid Box::open() {}\n\n}\n",
+                &[None, Some("Box"), Some("Box::open")],
+            ),
+            (
+                Language::TypeScript,
+                "import x from 'x';\n\nexport interface Options {\n  size: number;\n}\n\nexport default function build(options: Options) {}\n",
+                &[None, Some("Options"), Some("build")],
+            ),
+            (
+                Language::CSharp,
+                "using System;\n\nnamespace App {\n    public class Store {\n    }\n}\n",
+                &[None, Some("Store")],
+            ),
+            (
+                Language::Java,
+                "package app;\n\n/** A store. */\n@Singleton\npublic final class Store {\n}\n",
+                &[None, Some("Store")],
+            ),
+            (
+                Language::Kotlin,
+                "package app\n\ndata class Point(val x: Int)\n\nfun main() {}\n",
+                &[None, Some("Point"), Some("main")],
+            ),
+            (
+                Language::Scala,
+                "package app\n\nobject Main {\n}\n\ntrait Store\n",
+                &[None, Some("Main"), Some("Store")],
+            ),
+            (
+                Language::Swift,
+                "import Foundation\n\nprotocol Store {}\n\nfunc main() {}\n",
+                &[None, Some("Store"), Some("main")],
+            ),
+            (
+                Language::Ruby,
+                "require 'json'\n\nmodule Cache\n  def self.get; end\nend\n\ndef helper\nend\n",
+                &[None, Some("Cache"), Some("helper")],
+            ),
+        ];
+        for (language, text, expected) in cases {
+            let symbols: Vec<_> = definitions(text, language)
+                .into_iter()
+                .map(|(symbol, _)| symbol)
+                .collect();
+            assert_eq!(symbols, expected, "{language:?}");
+        }
+    }
+
+    #[test]
+    fn long_definitions_are_split_and_keep_their_name() {
+        let body: String = (0..40).map(|i| format!("    let x{i} = {i};\n")).collect();
+        let text = format!("fn long() {{\n{body}}}\n\nfn short() {{}}\n");
+        let chunks = chunk_code(&text, Language::Rust, 200, str::len);
+        assert!(chunks.len() > 3);
+        assert!(chunks.iter().all(|chunk| chunk.text.len() <= 200));
+        let (last, pieces) = chunks.split_last().unwrap();
+        assert!(pieces.iter().all(|chunk| chunk.symbol == Some("long")));
+        assert_eq!(last.symbol, Some("short"));
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.text).collect::<String>(),
+            text
+        );
+    }
+
+    #[test]
+    fn code_that_does_not_parse_is_still_chunked() {
+        let text = "fn broken( {\n\nstruct";
+        let chunks = chunk_code(text, Language::Rust, usize::MAX, str::len);
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.text).collect::<String>(),
+            text
+        );
+    }
+
+    #[test]
+    fn languages_are_told_by_extension() {
+        for (path, language) in [
+            ("src/main.rs", Some(Language::Rust)),
+            ("app.tsx", Some(Language::Tsx)),
+            ("App.kt", Some(Language::Kotlin)),
+            ("lib.HPP", Some(Language::Cpp)),
+            ("notes.md", None),
+        ] {
+            assert_eq!(Language::from_path(Path::new(path)), language, "{path}");
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::crypt::{self, read_prefix};
+
+/// Every zstd frame starts with these bytes, which is how compressed files are
+/// told apart from ones stored before compression was enabled.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Leads files stored uncompressed, so content that is itself a zstd frame
+/// isn't mistaken for a compressed file. Files stored before this header
+/// have neither.
+const RAW_MAGIC: [u8; 8] = *b"OURORAW1";
+
+/// zstd's own default, a good balance of speed and ratio for text.
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Compresses `data` at `level` (framed as is when `None`), then encrypts it
+/// if a key is installed.
+pub fn encode(data: &[u8], level: Option<i32>) -> io::Result<Vec<u8>> {
+    match level {
+        Some(level) => crypt::seal(&zstd::encode_all(data, level)?),
+        None => {
+            let mut framed = Vec::with_capacity(RAW_MAGIC.len() + data.len());
+            framed.extend_from_slice(&RAW_MAGIC);
+            framed.extend_from_slice(data);
+            crypt::seal(&framed)
+        }
+    }
+}
+
+/// Decrypts `data` if sealed, then unframes or decompresses it as its header
+/// says.
+pub fn decode(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut data = crypt::open(data)?;
+    if data.starts_with(&RAW_MAGIC) {
+        data.drain(..RAW_MAGIC.len());
+        Ok(data)
+    } else if data.starts_with(&ZSTD_MAGIC) {
+        zstd::decode_all(data.as_slice())
+    } else {
+        Ok(data)
+    }
+}
+
+/// Reads a stored file, decrypting and decompressing it as needed.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    decode(std::fs::read(path)?)
+}
+
+/// Streams `src` into `dest`, compressing at `level` unless it is `None` and
+/// encrypting if a key is installed.
+pub fn copy_encoded(src: impl Read, dest: &Path, level: Option<i32>) -> io::Result<()> {
+    let mut reader = BufReader::new(src);
+    let mut writer = crypt::writer(BufWriter::new(File::create(dest)?))?;
+    match level {
+        Some(level) => zstd::stream::copy_encode(&mut reader, &mut writer, level)?,
+        None => {
+            writer.write_all(&RAW_MAGIC)?;
+            io::copy(&mut reader, &mut writer)?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Streams a stored file into `dest`, decrypting and decompressing it as needed.
+pub fn copy_decoded(src: &Path, dest: &Path) -> io::Result<()> {
+    let mut reader = crypt::reader(BufReader::new(File::open(src)?))?;
+    let mut magic = [0u8; RAW_MAGIC.len()];
+    let read = read_prefix(&mut reader, &mut magic)?;
+    let magic = &magic[..read];
+    let mut writer = BufWriter::new(File::create(dest)?);
+    if magic == RAW_MAGIC {
+        io::copy(&mut reader, &mut writer)?;
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        zstd::stream::copy_decode(magic.chain(reader), &mut writer)?;
+    } else {
+        writer.write_all(magic)?;
+        io::copy(&mut reader, &mut writer)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"the same line, again and again\nthe same line, again and again\n";
+
+    #[test]
+    fn encoded_data_decodes_to_the_original() {
+        for level in [Some(DEFAULT_LEVEL), None] {
+            let encoded = encode(TEXT, level).unwrap();
+            let unframed = crypt::unframe(&encoded);
+            assert_eq!(unframed.starts_with(&ZSTD_MAGIC), level.is_some());
+            assert_eq!(decode(encoded).unwrap(), TEXT);
+        }
+    }
+
+    #[test]
+    fn streamed_copies_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stored, restored) = (dir.path().join("stored"), dir.path().join("restored"));
+        for level in [Some(DEFAULT_LEVEL), None] {
+            copy_encoded(TEXT, &stored, level).unwrap();
+            assert_eq!(read(&stored).unwrap(), TEXT);
+            copy_decoded(&stored, &restored).unwrap();
+            assert_eq!(std::fs::read(&restored).unwrap(), TEXT);
+        }
+    }
+
+    #[test]
+    fn zstd_files_stored_uncompressed_come_back_as_they_were() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stored, restored) = (dir.path().join("stored"), dir.path().join("restored"));
+        let zstd_file = zstd::encode_all(TEXT, DEFAULT_LEVEL).unwrap();
+        assert_eq!(
+            decode(encode(&zstd_file, None).unwrap()).unwrap(),
+            zstd_file
+        );
+        copy_encoded(zstd_file.as_slice(), &stored, None).unwrap();
+        assert_eq!(read(&stored).unwrap(), zstd_file);
+        copy_decoded(&stored, &restored).unwrap();
+        assert_eq!(std::fs::read(&restored).unwrap(), zstd_file);
+    }
+
+    #[test]
+    fn files_stored_before_the_header_still_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stored, restored) = (dir.path().join("stored"), dir.path().join("restored"));
+        for legacy in [
+            TEXT.to_vec(),
+            zstd::encode_all(TEXT, DEFAULT_LEVEL).unwrap(),
+            Vec::new(),
+        ] {
+            std::fs::write(&stored, &legacy).unwrap();
+            let text = if legacy.is_empty() { &b""[..] } else { TEXT };
+            assert_eq!(read(&stored).unwrap(), text);
+            copy_decoded(&stored, &restored).unwrap();
+            assert_eq!(std::fs::read(&restored).unwrap(), text);
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use eyre::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::backend::BackendKind;
+use crate::bert::Pooling;
+use crate::chunk::{ChunkConfig, ChunkStrategy};
+use crate::compress;
+use crate::crypt::Key;
+use crate::digest::{DEFAULT_MODEL, DeviceChoice, DigesterConfig, LongInput};
+use crate::process::{
+    DEFAULT_CHUNK_SIZE, DEFAULT_CONCURRENCY, DEFAULT_MEMORY_DIR, ProcessorConfig, RetentionPolicy,
+};
+use crate::project::Project;
+use crate::storage::FileStorage;
+use crate::sync::Remote;
+use crate::vector_store::{DEFAULT_KEYWORD_WEIGHT, Metric, StoreFormat};
+
+/// Name of the vector store inside the memory directory, `vectors.db` when
+/// stores are kept in SQLite.
+pub const VECTOR_STORE_FILE: &str = "vectors.bin";
+/// The collection kept in `VECTOR_STORE_FILE`; every other one is stored as
+/// `COLLECTIONS_DIR/<name>.bin`, or `.db`.
+pub const DEFAULT_COLLECTION: &str = "default";
+pub const COLLECTIONS_DIR: &str = "collections";
+
+/// Read from the working directory when no other config file is given, and
+/// from a project's `.ouroboros/` otherwise.
+pub const CONFIG_FILE: &str = "ouroboros.toml";
+
+/// Name of the memory directory inside the platform data directory.
+pub const DATA_DIR_NAME: &str = "ouroboros";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("invalid value {value:?} for {var}")]
+    InvalidEnv { var: &'static str, value: String },
+    #[error("invalid collection name {0:?}, use letters, digits, '-' and '_'")]
+    InvalidCollection(String),
+    #[error("no sync_url configured, set it in the config or OUROBOROS_SYNC_URL")]
+    NoSyncUrl,
+}
+
+/// Settings from `ouroboros.toml`. Every key is optional:
+///
+/// ```toml
+/// memory_dir = "memory"
+/// concurrency = 16
+/// io_chunk_size = 8388608
+/// max_file_size = 104857600
+/// skip_binary = true
+/// skip_mime = ["image/*", "application/zip"]
+/// chunk_strategy = "fixed"
+/// chunk_size = 1000
+/// chunk_overlap = 200
+/// chunk_code = true
+/// ignore = ["**/target", "**/.git", "*.log"]
+/// follow_symlinks = true
+/// compression_level = 3
+/// key_file = "/path/to/ouroboros.key"
+/// keep_versions = 20
+/// keep_days = 90
+/// keep_archived = false
+/// keyword_weight = 0.3
+/// collection = "default"
+/// model = "sentence-transformers/all-MiniLM-L6-v2"
+/// revision = "main"
+/// model_path = "/models/all-MiniLM-L6-v2"
+/// pooling = "mean"
+/// normalize = false
+/// device = "auto"
+/// backend = "local"
+/// long_input = "window"
+/// metric = "cosine"
+/// store_format = "files"
+/// backend_url = "http://localhost:11434"
+/// sync_url = "s3://my-bucket/ouroboros"
+/// sync_endpoint = "http://localhost:9000"
+/// sync_region = "us-east-1"
+/// ```
+///
+/// Without `memory_dir`, memory lives in the enclosing project's
+/// `.ouroboros/memory`, in `./memory` if that already exists, and otherwise in
+/// the platform data directory (`~/.local/share/ouroboros` on
+/// Linux, `~/Library/Application Support/ouroboros` on macOS, `%APPDATA%` on
+/// Windows).
+///
+/// Each can be overridden by the matching `OUROBOROS_*` environment variable
+/// (`OUROBOROS_MEMORY_DIR`, `OUROBOROS_IGNORE` as a comma-separated list, ...).
+/// A passphrase is only taken from `OUROBOROS_PASSPHRASE`, never from the file,
+/// and sync credentials only from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub memory_dir: PathBuf,
+    /// Files the Processor works on at once.
+    pub concurrency: usize,
+    /// Files smaller than this many bytes are read whole, larger ones streamed.
+    pub io_chunk_size: usize,
+    /// Files larger than this many bytes are not versioned.
+    pub max_file_size: Option<u64>,
+    /// Binary files, and files of these MIME types (`image/*` matches every
+    /// image), are not versioned.
+    pub skip_binary: bool,
+    pub skip_mime: Vec<String>,
+    /// How digestion cuts text into chunks: `fixed`, `sentences` or `tokens`.
+    #[serde(deserialize_with = "parse_value")]
+    pub chunk_strategy: ChunkStrategy,
+    /// Digestion chunk size and overlap, in bytes for fixed chunks and in
+    /// tokens otherwise.
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    /// Whether source code is chunked at its definitions.
+    pub chunk_code: bool,
+    /// Globs of paths never ingested.
+    pub ignore: Vec<String>,
+    /// Whether symlinks inside ingested directories are followed.
+    pub follow_symlinks: bool,
+    /// zstd level for stored versions and diffs, 0 to store them uncompressed.
+    pub compression_level: i32,
+    /// Encrypts memory with the 32-byte key in this file.
+    pub key_file: Option<PathBuf>,
+    /// Retention for `gc`: the newest N versions and those younger than N days survive.
+    pub keep_versions: Option<usize>,
+    pub keep_days: Option<u64>,
+    /// Keeps the vector entries of superseded versions, archived, for
+    /// searching the memory as of an earlier time.
+    pub keep_archived: bool,
+    /// Share of a search score that comes from BM25 keyword matching, 0 to 1.
+    pub keyword_weight: f32,
+    /// Vector store collection to work on; each holds its own entries.
+    pub collection: String,
+    pub model: String,
+    /// Branch, tag or commit of `model`, recorded in the stores it digests into.
+    pub revision: Option<String>,
+    /// Directory the model is loaded from instead of the hub.
+    pub model_path: Option<PathBuf>,
+    /// Pooling of token embeddings, `mean` or `cls`; the model's own setting when unset.
+    pub pooling: Option<Pooling>,
+    /// L2-normalize embeddings, recorded in the stores they go into.
+    pub normalize: bool,
+    #[serde(deserialize_with = "parse_value")]
+    pub device: DeviceChoice,
+    /// Where embeddings are computed: local, openai, ollama or onnx, and the API
+    /// of a remote backend when not its usual one. The API key comes from
+    /// `OUROBOROS_API_KEY` or `OPENAI_API_KEY` only, keeping it out of the file.
+    #[serde(deserialize_with = "parse_value")]
+    pub backend: BackendKind,
+    pub backend_url: Option<String>,
+    /// Inputs longer than the model reads are averaged over windows or truncated.
+    #[serde(deserialize_with = "parse_value")]
+    pub long_input: LongInput,
+    /// Scoring of searches, cosine, dot or euclidean, recorded in the stores
+    /// digested into. Stores keep their own when unset.
+    pub metric: Option<Metric>,
+    /// How vector stores are kept: `files` or, built with the `sqlite`
+    /// feature, `sqlite`. Stores already on disk in the other format are
+    /// left where they are and not read.
+    #[serde(deserialize_with = "parse_value")]
+    pub store_format: StoreFormat,
+    /// S3 bucket and prefix `sync` pushes memory to and pulls it from, and
+    /// the endpoint of an S3-compatible store other than AWS.
+    pub sync_url: Option<String>,
+    pub sync_endpoint: Option<String>,
+    pub sync_region: Option<String>,
+    /// The project the working directory is in, whose ignore file applies.
+    #[serde(skip)]
+    pub project: Option<Project>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let chunking = ChunkConfig::default();
+        Self {
+            memory_dir: default_memory_dir(),
+            concurrency: DEFAULT_CONCURRENCY,
+            io_chunk_size: DEFAULT_CHUNK_SIZE,
+            max_file_size: None,
+            skip_binary: false,
+            skip_mime: Vec::new(),
+            chunk_strategy: chunking.strategy,
+            chunk_size: chunking.size,
+            chunk_overlap: chunking.overlap,
+            chunk_code: chunking.code,
+            ignore: Vec::new(),
+            follow_symlinks: true,
+            compression_level: compress::DEFAULT_LEVEL,
+            key_file: None,
+            keep_versions: None,
+            keep_days: None,
+            keep_archived: false,
+            keyword_weight: DEFAULT_KEYWORD_WEIGHT,
+            collection: DEFAULT_COLLECTION.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            revision: None,
+            model_path: None,
+            pooling: None,
+            normalize: false,
+            device: DeviceChoice::default(),
+            backend: BackendKind::default(),
+            backend_url: None,
+            long_input: LongInput::default(),
+            metric: None,
+            store_format: StoreFormat::default(),
+            sync_url: None,
+            sync_endpoint: None,
+            sync_region: None,
+            project: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path`, or when `None` `ouroboros.toml` or else the config of the
+    /// project the working directory is in, then applies environment
+    /// overrides. Without a config file the defaults are used, but an explicit
+    /// `path` must exist.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let project = Project::current();
+        let mut config = match (path, &project) {
+            (Some(path), _) => Self::read(path)?,
+            (None, _) if Path::new(CONFIG_FILE).exists() => Self::read(Path::new(CONFIG_FILE))?,
+            (None, Some(project)) if project.config_path().exists() => Self::read_project(project)?,
+            (None, _) => Self::default(),
+        };
+        config.project = project;
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read config {}", path.display()))?;
+        let config = toml::from_str(&data)
+            .wrap_err_with(|| format!("Failed to parse config {}", path.display()))?;
+        debug!("Loaded config from {}", path.display());
+        Ok(config)
+    }
+
+    /// Reads a project's config, whose relative paths are relative to its
+    /// `.ouroboros/` rather than the working directory.
+    fn read_project(project: &Project) -> Result<Self> {
+        let mut config = Self::read(&project.config_path())?;
+        let dir = project.dir();
+        config.memory_dir = dir.join(&config.memory_dir);
+        config.key_file = config.key_file.map(|key_file| dir.join(key_file));
+        config.model_path = config.model_path.map(|model_path| dir.join(model_path));
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(memory_dir) = env("OUROBOROS_MEMORY_DIR")? {
+            self.memory_dir = memory_dir;
+        }
+        if let Some(concurrency) = env("OUROBOROS_CONCURRENCY")? {
+            self.concurrency = concurrency;
+        }
+        if let Some(io_chunk_size) = env("OUROBOROS_IO_CHUNK_SIZE")? {
+            self.io_chunk_size = io_chunk_size;
+        }
+        if let Some(max_file_size) = env("OUROBOROS_MAX_FILE_SIZE")? {
+            self.max_file_size = Some(max_file_size);
+        }
+        if let Some(skip_binary) = env("OUROBOROS_SKIP_BINARY")? {
+            self.skip_binary = skip_binary;
+        }
+        if let Some(skip_mime) = env::<String>("OUROBOROS_SKIP_MIME")? {
+            self.skip_mime = comma_list(&skip_mime);
+        }
+        if let Some(chunk_strategy) = env("OUROBOROS_CHUNK_STRATEGY")? {
+            self.chunk_strategy = chunk_strategy;
+        }
+        if let Some(chunk_size) = env("OUROBOROS_CHUNK_SIZE")? {
+            self.chunk_size = chunk_size;
+        }
+        if let Some(chunk_overlap) = env("OUROBOROS_CHUNK_OVERLAP")? {
+            self.chunk_overlap = chunk_overlap;
+        }
+        if let Some(chunk_code) = env("OUROBOROS_CHUNK_CODE")? {
+            self.chunk_code = chunk_code;
+        }
+        if let Some(ignore) = env::<String>("OUROBOROS_IGNORE")? {
+            self.ignore = comma_list(&ignore);
+        }
+        if let Some(follow_symlinks) = env("OUROBOROS_FOLLOW_SYMLINKS")? {
+            self.follow_symlinks = follow_symlinks;
+        }
+        if let Some(compression_level) = env("OUROBOROS_COMPRESSION_LEVEL")? {
+            self.compression_level = compression_level;
+        }
+        if let Some(key_file) = env("OUROBOROS_KEY_FILE")? {
+            self.key_file = Some(key_file);
+        }
+        if let Some(keep_versions) = env("OUROBOROS_KEEP_VERSIONS")? {
+            self.keep_versions = Some(keep_versions);
+        }
+        if let Some(keep_days) = env("OUROBOROS_KEEP_DAYS")? {
+            self.keep_days = Some(keep_days);
+        }
+        if let Some(keep_archived) = env("OUROBOROS_KEEP_ARCHIVED")? {
+            self.keep_archived = keep_archived;
+        }
+        if let Some(keyword_weight) = env("OUROBOROS_KEYWORD_WEIGHT")? {
+            self.keyword_weight = keyword_weight;
+        }
+        if let Some(collection) = env("OUROBOROS_COLLECTION")? {
+            self.collection = collection;
+        }
+        if let Some(model) = env("OUROBOROS_MODEL")? {
+            self.model = model;
+        }
+        if let Some(revision) = env("OUROBOROS_REVISION")? {
+            self.revision = Some(revision);
+        }
+        if let Some(model_path) = env("OUROBOROS_MODEL_PATH")? {
+            self.model_path = Some(model_path);
+        }
+        if let Some(pooling) = env("OUROBOROS_POOLING")? {
+            self.pooling = Some(pooling);
+        }
+        if let Some(normalize) = env("OUROBOROS_NORMALIZE")? {
+            self.normalize = normalize;
+        }
+        if let Some(device) = env("OUROBOROS_DEVICE")? {
+            self.device = device;
+        }
+        if let Some(backend) = env("OUROBOROS_BACKEND")? {
+            self.backend = backend;
+        }
+        if let Some(backend_url) = env("OUROBOROS_BACKEND_URL")? {
+            self.backend_url = Some(backend_url);
+        }
+        if let Some(long_input) = env("OUROBOROS_LONG_INPUT")? {
+            self.long_input = long_input;
+        }
+        if let Some(metric) = env("OUROBOROS_METRIC")? {
+            self.metric = Some(metric);
+        }
+        if let Some(store_format) = env("OUROBOROS_STORE_FORMAT")? {
+            self.store_format = store_format;
+        }
+        if let Some(sync_url) = env("OUROBOROS_SYNC_URL")? {
+            self.sync_url = Some(sync_url);
+        }
+        if let Some(sync_endpoint) = env("OUROBOROS_SYNC_ENDPOINT")? {
+            self.sync_endpoint = Some(sync_endpoint);
+        }
+        if let Some(sync_region) = env("OUROBOROS_SYNC_REGION")? {
+            self.sync_region = Some(sync_region);
+        }
+        Ok(())
+    }
+
+    /// The key memory is encrypted with: the key file if one is set, otherwise
+    /// one derived from `OUROBOROS_PASSPHRASE`. `None` leaves memory in plaintext.
+    pub fn encryption_key(&self) -> Result<Option<Key>> {
+        if let Some(key_file) = &self.key_file {
+            return Key::from_file(key_file).map(Some);
+        }
+        match env::<String>("OUROBOROS_PASSPHRASE")? {
+            Some(passphrase) => Key::from_passphrase(&passphrase, &self.memory_dir).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The vector store of the configured collection.
+    pub fn vector_store_path(&self) -> Result<PathBuf> {
+        self.collection_path(&self.collection)
+    }
+
+    /// The vector store of the collection `name`.
+    pub fn collection_path(&self, name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(ConfigError::InvalidCollection(name.to_string()).into());
+        }
+        let extension = self.store_format.extension();
+        if name == DEFAULT_COLLECTION {
+            return Ok(self
+                .memory_dir
+                .join(VECTOR_STORE_FILE)
+                .with_extension(extension));
+        }
+        Ok(self
+            .memory_dir
+            .join(COLLECTIONS_DIR)
+            .join(name)
+            .with_extension(extension))
+    }
+
+    /// Names of the collections that have a store on disk in the configured
+    /// format, sorted.
+    pub fn collections(&self) -> Result<Vec<String>> {
+        let extension = self.store_format.extension();
+        let mut names = Vec::new();
+        if self.collection_path(DEFAULT_COLLECTION)?.exists() {
+            names.push(DEFAULT_COLLECTION.to_string());
+        }
+        let dir = self.memory_dir.join(COLLECTIONS_DIR);
+        if dir.is_dir() {
+            for entry in std::fs::read_dir(&dir)
+                .wrap_err_with(|| format!("Failed to list collections in {}", dir.display()))?
+            {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == extension)
+                    && let Some(name) = path.file_stem().and_then(|stem| stem.to_str())
+                {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// The bucket memory is synced with.
+    pub fn remote(&self) -> Result<Remote> {
+        let Some(url) = &self.sync_url else {
+            return Err(ConfigError::NoSyncUrl.into());
+        };
+        Remote::new(
+            url,
+            self.sync_endpoint.as_deref(),
+            self.sync_region.as_deref(),
+        )
+    }
+
+    /// An empty `FileStorage` with the configured ignore globs, the project's
+    /// ignore file and symlink handling.
+    pub fn file_storage(&self) -> Result<FileStorage> {
+        let storage =
+            FileStorage::with_ignore(&self.ignore)?.with_follow_symlinks(self.follow_symlinks);
+        Ok(match &self.project {
+            Some(project) => storage.with_ignore_file(project.root(), &project.ignore_path()),
+            None => storage,
+        })
+    }
+
+    pub fn processor(&self) -> ProcessorConfig {
+        ProcessorConfig::new(&self.memory_dir)
+            .with_concurrency(self.concurrency)
+            .with_chunk_size(self.io_chunk_size)
+            .with_max_file_size(self.max_file_size)
+            .with_skip_binary(self.skip_binary)
+            .with_skip_mime(self.skip_mime.clone())
+            .with_compression((self.compression_level != 0).then_some(self.compression_level))
+    }
+
+    pub fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_last: self.keep_versions,
+            max_age_days: self.keep_days,
+        }
+    }
+
+    pub fn chunking(&self) -> ChunkConfig {
+        ChunkConfig {
+            strategy: self.chunk_strategy,
+            size: self.chunk_size,
+            overlap: self.chunk_overlap,
+            code: self.chunk_code,
+        }
+    }
+
+    /// The digester settings of the config.
+    pub fn digester(&self) -> DigesterConfig {
+        DigesterConfig {
+            backend: self.backend,
+            api_url: self.backend_url.clone(),
+            device: self.device,
+            model: self.model.clone(),
+            revision: self.revision.clone(),
+            model_path: self.model_path.clone(),
+            pooling: self.pooling,
+            normalize: self.normalize,
+            long_input: self.long_input,
+            metric: self.metric,
+        }
+    }
+}
+
+/// The memory of the project the working directory is in, or `./memory` when
+/// it exists, so memories made before the data directory default keep
+/// working, otherwise `<data dir>/ouroboros`. Falls back to `./memory` on
+/// platforms without a data directory.
+pub fn default_memory_dir() -> PathBuf {
+    if let Some(project) = Project::current() {
+        return project.memory_dir();
+    }
+    let local = PathBuf::from(DEFAULT_MEMORY_DIR);
+    if local.is_dir() {
+        return local;
+    }
+    match dirs::data_dir() {
+        Some(data_dir) => data_dir.join(DATA_DIR_NAME),
+        None => local,
+    }
+}
+
+/// The non-empty items of a comma-separated list.
+fn comma_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parses the environment variable `var`, treating unset or empty as absent.
+fn env<T: FromStr>(var: &'static str) -> Result<Option<T>> {
+    let Some(value) = std::env::var(var).ok().filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    match value.parse() {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(_) => Err(ConfigError::InvalidEnv { var, value }.into()),
+    }
+}
+
+fn parse_value<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_store::{VectorEntry, VectorStore};
+
+    #[test]
+    fn collections_are_stored_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            memory_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        for (collection, id) in [("default", "a"), ("code", "b"), ("notes_2", "c")] {
+            let mut store = VectorStore::load(config.collection_path(collection).unwrap()).unwrap();
+            store
+                .add(VectorEntry {
+                    id: id.to_string(),
+                    embedding: vec![1.0, 0.0],
+                    ..Default::default()
+                })
+                .unwrap();
+            store.save().unwrap();
+        }
+
+        assert_eq!(
+            config.collections().unwrap(),
+            ["code", "default", "notes_2"]
+        );
+        let code = VectorStore::load(config.collection_path("code").unwrap()).unwrap();
+        let ids: Vec<_> = code.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, ["b"]);
+        assert_eq!(
+            config.vector_store_path().unwrap(),
+            dir.path().join(VECTOR_STORE_FILE)
+        );
+    }
+
+    #[test]
+    fn collection_names_cannot_leave_the_memory_dir() {
+        let config = Config::default();
+        for name in ["", "../escape", "a/b", "dot.bin"] {
+            assert!(config.collection_path(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn embedding_settings_are_read_from_the_file() {
+        let config: Config = toml::from_str(
+            "model = \"local-model\"\nrevision = \"v2\"\nmodel_path = \"/models/local\"\npooling = \"cls\"\nnormalize = true\n",
+        )
+        .unwrap();
+        let digester = config.digester();
+        assert_eq!(digester.model, "local-model");
+        assert_eq!(digester.revision.as_deref(), Some("v2"));
+        assert_eq!(digester.model_path, Some(PathBuf::from("/models/local")));
+        assert_eq!(digester.pooling, Some(Pooling::Cls));
+        assert!(digester.normalize);
+    }
+}
@@ -0,0 +1,538 @@
+use argon2::Argon2;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{KeyInit, OsRng};
+use eyre::{Context, Result};
+use log::{debug, warn};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use thiserror::Error;
+
+/// Written before the stream nonce of every encrypted file.
+const MAGIC: &[u8; 8] = b"OUROENC1";
+/// Written before plaintext when no key is installed, so plaintext that
+/// happens to start with `MAGIC` isn't taken for a sealed file. Files written
+/// before this header have neither.
+pub(crate) const PLAIN_MAGIC: &[u8; 8] = b"OUROPLN1";
+/// The STREAM construction takes 5 bytes of the 24-byte XChaCha20 nonce for its counter.
+const NONCE_LEN: usize = 19;
+/// Plaintext bytes sealed per segment; each segment grows by a 16-byte tag.
+const SEGMENT: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+/// Random salt for passphrase keys, created next to the memory it protects.
+pub const SALT_FILE: &str = "encryption.salt";
+const SALT_LEN: usize = 16;
+
+static KEY: OnceLock<Key> = OnceLock::new();
+/// Set by `accept_plaintext`.
+static ACCEPT_PLAINTEXT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Error, Debug)]
+pub enum CryptError {
+    #[error("key file {0} must hold 32 bytes, raw or hex-encoded")]
+    KeyFile(PathBuf),
+    #[error("failed to derive key from passphrase: {0}")]
+    Kdf(String),
+    #[error("data is encrypted but no key is configured")]
+    NoKey,
+    #[error("decryption failed, wrong key or corrupted data")]
+    Decrypt,
+    #[error(
+        "data is not encrypted but a key is configured; run `ouroboros encrypt` to seal memory written before encryption was enabled"
+    )]
+    Unsealed,
+}
+
+impl From<CryptError> for io::Error {
+    fn from(e: CryptError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// A 256-bit XChaCha20-Poly1305 key.
+#[derive(Clone)]
+pub struct Key(chacha20poly1305::Key);
+
+impl Key {
+    /// Reads a key file holding 32 raw bytes or 64 hex digits.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)
+            .wrap_err_with(|| format!("Failed to read key file {}", path.display()))?;
+        let bytes = match std::str::from_utf8(&data).map(str::trim) {
+            Ok(text) if text.len() == 64 => decode_hex(text),
+            _ => data.clone(),
+        };
+        if bytes.len() != 32 {
+            return Err(CryptError::KeyFile(path.to_path_buf()).into());
+        }
+        Ok(Self(*chacha20poly1305::Key::from_slice(&bytes)))
+    }
+
+    /// Derives a key from `passphrase` with Argon2id, salted by the salt file
+    /// in `memory_dir` (created on first use).
+    pub fn from_passphrase(passphrase: &str, memory_dir: &Path) -> Result<Self> {
+        let salt_path = memory_dir.join(SALT_FILE);
+        let salt = if salt_path.exists() {
+            std::fs::read(&salt_path)
+                .wrap_err_with(|| format!("Failed to read {}", salt_path.display()))?
+        } else {
+            let mut salt = vec![0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            std::fs::create_dir_all(memory_dir).wrap_err("Failed to create memory directory")?;
+            std::fs::write(&salt_path, &salt)
+                .wrap_err_with(|| format!("Failed to write {}", salt_path.display()))?;
+            debug!("Created {}", salt_path.display());
+            salt
+        };
+
+        let mut key = chacha20poly1305::Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| CryptError::Kdf(e.to_string()))?;
+        Ok(Self(key))
+    }
+}
+
+/// Makes `key` the one every later read and write goes through. Files are
+/// stored in plaintext until a key is installed.
+pub fn install(key: Key) {
+    if KEY.set(key).is_err() {
+        warn!("An encryption key is already installed, ignoring the new one");
+    }
+}
+
+/// Whether a key is installed, so files are encrypted.
+pub fn is_enabled() -> bool {
+    KEY.get().is_some()
+}
+
+/// Lets reads take unsealed data even with a key installed, until the
+/// process exits. Only meant for sealing memory written before encryption
+/// was enabled; otherwise a key means every file must be sealed with it.
+pub fn accept_plaintext() {
+    ACCEPT_PLAINTEXT.store(true, Ordering::Relaxed);
+}
+
+fn plaintext_accepted() -> bool {
+    ACCEPT_PLAINTEXT.load(Ordering::Relaxed)
+}
+
+/// Encrypts `data` with the installed key, or frames it as plaintext without one.
+pub fn seal(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut writer = writer(Vec::with_capacity(data.len() + MAGIC.len() + NONCE_LEN))?;
+    writer.write_all(data)?;
+    writer.finish()
+}
+
+/// Decrypts `data` if it was sealed, otherwise returns the plaintext. Fails
+/// on plaintext when a key is installed.
+pub fn open(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    open_with(data, KEY.get(), plaintext_accepted())
+}
+
+fn open_with(mut data: Vec<u8>, key: Option<&Key>, plaintext: bool) -> io::Result<Vec<u8>> {
+    if is_sealed(&data) {
+        let mut plain = Vec::with_capacity(data.len());
+        reader_with(data.as_slice(), key, plaintext)?.read_to_end(&mut plain)?;
+        return Ok(plain);
+    }
+    if key.is_some() && !plaintext {
+        return Err(CryptError::Unsealed.into());
+    }
+    if data.starts_with(PLAIN_MAGIC) {
+        data.drain(..PLAIN_MAGIC.len());
+    }
+    Ok(data)
+}
+
+/// Whether `data` starts like a file sealed by `seal` or `writer`.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Reads a file, decrypting it if needed.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    open(std::fs::read(path)?)
+}
+
+/// Plaintext `data` without the header `writer` puts before it when no key
+/// is installed.
+pub fn unframe(data: &[u8]) -> &[u8] {
+    data.strip_prefix(PLAIN_MAGIC).unwrap_or(data)
+}
+
+/// Seals every file under `dir` that isn't yet with the installed key,
+/// leaving out the paths `skip` picks, and returns how many it sealed. Each
+/// file is replaced by renaming a sealed copy over it.
+pub fn seal_files(dir: &Path, skip: &impl Fn(&Path) -> bool) -> Result<usize> {
+    let key = KEY.get().ok_or(CryptError::NoKey)?;
+    seal_files_with(dir, key, skip)
+}
+
+fn seal_files_with(dir: &Path, key: &Key, skip: &impl Fn(&Path) -> bool) -> Result<usize> {
+    let mut sealed = 0;
+    for entry in
+        std::fs::read_dir(dir).wrap_err_with(|| format!("Failed to list {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if skip(&path) {
+            continue;
+        }
+        if path.is_dir() {
+            sealed += seal_files_with(&path, key, skip)?;
+            continue;
+        }
+        let data =
+            std::fs::read(&path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        if is_sealed(&data) {
+            continue;
+        }
+        let plain = open_with(data, Some(key), true)?;
+        let mut writer = writer_with(Vec::with_capacity(plain.len() + 64), Some(key))?;
+        writer.write_all(&plain)?;
+        let mut temp = path.clone().into_os_string();
+        temp.push(".seal.tmp");
+        std::fs::write(&temp, writer.finish()?)
+            .and_then(|()| std::fs::rename(&temp, &path))
+            .wrap_err_with(|| format!("Failed to seal {}", path.display()))?;
+        debug!("Sealed {}", path.display());
+        sealed += 1;
+    }
+    Ok(sealed)
+}
+
+/// Wraps `inner` so everything written to it is encrypted with the installed
+/// key. `finish` must be called to seal the final segment.
+pub fn writer<W: Write>(inner: W) -> io::Result<SealWriter<W>> {
+    writer_with(inner, KEY.get())
+}
+
+fn writer_with<W: Write>(mut inner: W, key: Option<&Key>) -> io::Result<SealWriter<W>> {
+    let sealer = match key {
+        Some(key) => {
+            let mut nonce = [0u8; NONCE_LEN];
+            OsRng.fill_bytes(&mut nonce);
+            inner.write_all(MAGIC)?;
+            inner.write_all(&nonce)?;
+            let aead = XChaCha20Poly1305::new(&key.0);
+            Some(EncryptorBE32::from_aead(aead, nonce.as_slice().into()))
+        }
+        None => {
+            inner.write_all(PLAIN_MAGIC)?;
+            None
+        }
+    };
+    Ok(SealWriter {
+        inner,
+        sealer,
+        pending: Vec::new(),
+    })
+}
+
+/// Wraps `inner` so sealed data is decrypted as it is read; plaintext passes
+/// through, unless a key is installed, which makes it an error.
+pub fn reader<R: Read>(inner: R) -> io::Result<OpenReader<R>> {
+    reader_with(inner, KEY.get(), plaintext_accepted())
+}
+
+/// Like `reader`, but takes plaintext even with a key installed, for files
+/// that come from outside memory, such as exports.
+pub fn reader_or_plaintext<R: Read>(inner: R) -> io::Result<OpenReader<R>> {
+    reader_with(inner, KEY.get(), true)
+}
+
+fn reader_with<R: Read>(
+    mut inner: R,
+    key: Option<&Key>,
+    plaintext: bool,
+) -> io::Result<OpenReader<R>> {
+    let mut magic = [0u8; MAGIC.len()];
+    let read = read_prefix(&mut inner, &mut magic)?;
+    if magic[..read] != MAGIC[..] {
+        if key.is_some() && !plaintext {
+            return Err(CryptError::Unsealed.into());
+        }
+        let framed = magic[..read] == PLAIN_MAGIC[..];
+        return Ok(OpenReader {
+            inner,
+            opener: None,
+            sealed: false,
+            ahead: None,
+            plain: if framed {
+                Vec::new()
+            } else {
+                magic[..read].to_vec()
+            },
+            pos: 0,
+        });
+    }
+
+    let key = key.ok_or(CryptError::NoKey)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    if read_prefix(&mut inner, &mut nonce)? != NONCE_LEN {
+        return Err(CryptError::Decrypt.into());
+    }
+    let aead = XChaCha20Poly1305::new(&key.0);
+    Ok(OpenReader {
+        inner,
+        opener: Some(DecryptorBE32::from_aead(aead, nonce.as_slice().into())),
+        sealed: true,
+        ahead: None,
+        plain: Vec::new(),
+        pos: 0,
+    })
+}
+
+pub struct SealWriter<W: Write> {
+    inner: W,
+    sealer: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    /// Seals the last segment and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(sealer) = self.sealer.take() {
+            let segment = sealer
+                .encrypt_last(self.pending.as_slice())
+                .map_err(|_| io::Error::other("encryption failed"))?;
+            self.inner.write_all(&segment)?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(sealer) = self.sealer.as_mut() else {
+            return self.inner.write(buf);
+        };
+        self.pending.extend_from_slice(buf);
+        // Keep up to a full segment pending so `finish` has one to seal as the last.
+        while self.pending.len() > SEGMENT {
+            let segment = sealer
+                .encrypt_next(&self.pending[..SEGMENT])
+                .map_err(|_| io::Error::other("encryption failed"))?;
+            self.inner.write_all(&segment)?;
+            self.pending.drain(..SEGMENT);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct OpenReader<R: Read> {
+    inner: R,
+    opener: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    sealed: bool,
+    /// The byte read past the previous segment.
+    ahead: Option<u8>,
+    /// Decrypted bytes not yet handed out, or the sniffed prefix of plaintext.
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> OpenReader<R> {
+    /// Decrypts the next segment into `plain`. Reading one byte past a full
+    /// segment tells whether it is the last one.
+    fn next_segment(&mut self, opener: DecryptorBE32<XChaCha20Poly1305>) -> io::Result<()> {
+        let mut segment = Vec::with_capacity(SEGMENT + TAG_LEN + 1);
+        segment.extend(self.ahead.take());
+        let start = segment.len();
+        segment.resize(SEGMENT + TAG_LEN + 1, 0);
+        let read = read_prefix(&mut self.inner, &mut segment[start..])?;
+        segment.truncate(start + read);
+
+        let mut opener = opener;
+        self.plain = if segment.len() > SEGMENT + TAG_LEN {
+            self.ahead = segment.pop();
+            let plain = opener
+                .decrypt_next(segment.as_slice())
+                .map_err(|_| CryptError::Decrypt)?;
+            self.opener = Some(opener);
+            plain
+        } else {
+            opener
+                .decrypt_last(segment.as_slice())
+                .map_err(|_| CryptError::Decrypt)?
+        };
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.plain.len() {
+                let n = buf.len().min(self.plain.len() - self.pos);
+                buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if !self.sealed {
+                return self.inner.read(buf);
+            }
+            match self.opener.take() {
+                Some(opener) => self.next_segment(opener)?,
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Fills as much of `buf` as the reader has, returning how many bytes were read.
+pub(crate) fn read_prefix(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn decode_hex(text: &str) -> Vec<u8> {
+    (0..text.len())
+        .step_by(2)
+        .filter_map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Key {
+        Key(*chacha20poly1305::Key::from_slice(&[byte; 32]))
+    }
+
+    fn seal_with(data: &[u8], key: &Key) -> Vec<u8> {
+        let mut writer = writer_with(Vec::new(), Some(key)).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn open_stream(data: &[u8], key: Option<&Key>) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        reader_with(data, key, false)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut writer = writer_with(Vec::new(), None).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn is_unsealed_error(error: &io::Error) -> bool {
+        matches!(
+            error.get_ref().and_then(|e| e.downcast_ref::<CryptError>()),
+            Some(CryptError::Unsealed)
+        )
+    }
+
+    #[test]
+    fn sealed_data_opens_to_the_original() {
+        let key = key(7);
+        // Empty, within one segment, exactly one segment, and spanning several.
+        for len in [0, 100, SEGMENT, 3 * SEGMENT + 5] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let sealed = seal_with(&data, &key);
+            assert!(sealed.starts_with(MAGIC));
+            assert_eq!(open_stream(&sealed, Some(&key)).unwrap(), data, "len {len}");
+        }
+    }
+
+    #[test]
+    fn plaintext_is_framed_and_refused_with_a_key() {
+        // Plaintext that looks like a sealed file still reads back as written.
+        let data = [MAGIC.as_slice(), b" but not encrypted"].concat();
+        let framed = frame(&data);
+        assert!(!is_sealed(&framed));
+        assert_eq!(open_stream(&framed, None).unwrap(), data);
+        assert_eq!(open_with(framed.clone(), None, false).unwrap(), data);
+        // As do files written before plaintext was framed.
+        let legacy = b"written before the header".to_vec();
+        assert_eq!(open_stream(&legacy, None).unwrap(), legacy);
+
+        for (unsealed, plain) in [(framed, data), (legacy.clone(), legacy)] {
+            let error = open_stream(&unsealed, Some(&key(7))).unwrap_err();
+            assert!(is_unsealed_error(&error), "{error}");
+            let error = open_with(unsealed.clone(), Some(&key(7)), false).unwrap_err();
+            assert!(is_unsealed_error(&error), "{error}");
+            assert_eq!(open_with(unsealed, Some(&key(7)), true).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn unsealed_files_are_sealed_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = key(7);
+        std::fs::create_dir(dir.path().join("objects")).unwrap();
+        let files = [
+            ("objects/framed", frame(b"one"), b"one".as_slice()),
+            ("legacy", b"two".to_vec(), b"two"),
+            ("sealed", seal_with(b"three", &key), b"three"),
+        ];
+        for (name, data, _) in &files {
+            std::fs::write(dir.path().join(name), data).unwrap();
+        }
+        std::fs::write(dir.path().join(".lock"), "1234").unwrap();
+        let skip = |path: &Path| path.ends_with(".lock");
+
+        assert_eq!(seal_files_with(dir.path(), &key, &skip).unwrap(), 2);
+        for (name, _, plain) in files {
+            let data = std::fs::read(dir.path().join(name)).unwrap();
+            assert!(is_sealed(&data), "{name}");
+            assert_eq!(open_with(data, Some(&key), false).unwrap(), plain);
+        }
+        assert_eq!(std::fs::read(dir.path().join(".lock")).unwrap(), b"1234");
+        assert_eq!(seal_files_with(dir.path(), &key, &skip).unwrap(), 0);
+    }
+
+    #[test]
+    fn wrong_key_or_tampering_fails() {
+        let data = vec![1u8; 2 * SEGMENT];
+        let mut sealed = seal_with(&data, &key(7));
+        assert!(open_stream(&sealed, Some(&key(8))).is_err());
+        assert!(open_stream(&sealed, None).is_err());
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(open_stream(&sealed, Some(&key(7))).is_err());
+    }
+
+    #[test]
+    fn truncated_stream_fails() {
+        let data = vec![1u8; 2 * SEGMENT];
+        let sealed = seal_with(&data, &key(7));
+        // Dropping the last segment must not pass for a shorter file.
+        let cut = MAGIC.len() + NONCE_LEN + SEGMENT + TAG_LEN;
+        assert!(open_stream(&sealed[..cut], Some(&key(7))).is_err());
+    }
+
+    #[test]
+    fn key_files_accept_raw_and_hex() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = dir.path().join("raw.key");
+        std::fs::write(&raw, [0xabu8; 32]).unwrap();
+        let hex = dir.path().join("hex.key");
+        std::fs::write(&hex, format!("{}\n", "ab".repeat(32))).unwrap();
+        assert_eq!(
+            Key::from_file(&raw).unwrap().0,
+            Key::from_file(&hex).unwrap().0
+        );
+
+        let short = dir.path().join("short.key");
+        std::fs::write(&short, [0u8; 16]).unwrap();
+        assert!(Key::from_file(&short).is_err());
+    }
+}
@@ -0,0 +1,1131 @@
+use candle_core::Device;
+use eyre::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, trace, warn};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use thiserror::Error;
+
+use crate::backend::{BackendKind, Embedder, OllamaBackend, OpenAiBackend};
+use crate::bert::{BertBackend, Pooling};
+use crate::chunk::{
+    Chunk, ChunkConfig, ChunkStrategy, Language, chunk_code, chunk_sentences, chunk_text,
+    chunk_tokens,
+};
+use crate::compress;
+use crate::extract::{Extracted, extract};
+#[cfg(feature = "onnx")]
+use crate::onnx::OnnxBackend;
+use crate::process::{Processor, TrackedFile};
+use crate::rerank::Reranker;
+use crate::shutdown::CancellationToken;
+use crate::storage::FileStorage;
+use crate::vector_store::{
+    Labels, Metric, SCHEMA_VERSION, StoreHeader, VectorEntry, VectorStore, normalize, now_millis,
+};
+
+pub const DEFAULT_MODEL: &str = "sentence-transformers/all-MiniLM-L6-v2";
+const QUERY_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(256).unwrap();
+const PREVIEW_CHARS: usize = 100;
+/// Chunks embedded per forward pass when digesting.
+const EMBED_BATCH_SIZE: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum DigestError {
+    #[error("failed to load tokenizer: {0}")]
+    Tokenizer(String),
+    #[error("failed to tokenize input: {0}")]
+    Encode(String),
+    #[error("model file not found: {0}")]
+    MissingModelFile(PathBuf),
+    #[error("unknown device {0:?}, expected auto, cpu, cuda[:N] or metal[:N]")]
+    Device(String),
+    #[error("unknown pooling {0:?}, expected mean or cls")]
+    Pooling(String),
+    #[error("unknown long input handling {0:?}, expected window or truncate")]
+    LongInput(String),
+    #[error("reranking was requested but no reranker is loaded")]
+    NoReranker,
+}
+
+/// What `digest_file` did with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestOutcome {
+    /// Number of chunks embedded.
+    Stored(usize),
+    /// The file had no meaningful content (empty or whitespace-only).
+    Skipped,
+    /// The store already holds every chunk of the file as it is now.
+    Unchanged,
+}
+
+/// Counts of what `digest_all` did with each tracked file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DigestSummary {
+    pub stored: usize,
+    pub chunks: usize,
+    pub skipped: usize,
+    /// Already embedded at their latest version, or unchanged since they were.
+    pub current: usize,
+    /// Entries dropped because their file is no longer tracked.
+    pub removed: usize,
+    /// Versioned but filtered out by `DigestPatterns` or not valid UTF-8.
+    pub excluded: usize,
+    /// Stopped early because of an interrupt.
+    pub interrupted: bool,
+}
+
+/// Counts of what `re_embed` did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReembedSummary {
+    pub entries: usize,
+    /// Chunks longer than the new model reads, windowed or truncated as
+    /// `LongInput` says.
+    pub oversized: usize,
+    /// Stopped early because of an interrupt, leaving the target incomplete.
+    pub interrupted: bool,
+}
+
+/// Include/exclude globs deciding which versioned files are embedded, matched
+/// against each file's original path (e.g. `*.md`, `**/docs/**`).
+/// With no include patterns every file is a candidate.
+#[derive(Debug, Default, Clone)]
+pub struct DigestPatterns {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl DigestPatterns {
+    pub fn new(include: &[&str], exclude: &[&str]) -> Result<Self> {
+        Ok(Self {
+            include: Self::build(include)?,
+            exclude: Self::build(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|set| set.is_match(path))
+            && !self.exclude.as_ref().is_some_and(|set| set.is_match(path))
+    }
+
+    fn build(patterns: &[&str]) -> Result<Option<GlobSet>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern).wrap_err_with(|| format!("Invalid pattern {pattern}"))?);
+        }
+        Ok(Some(builder.build()?))
+    }
+}
+
+/// Hardware to run the embedding model on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceChoice {
+    /// CUDA if available, then Metal, then CPU.
+    #[default]
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl DeviceChoice {
+    /// CUDA and Metal need the crate's `cuda`/`metal` features; without them
+    /// `Auto` falls back to the CPU and explicit choices fail.
+    pub fn resolve(self) -> Result<Device> {
+        let device = match self {
+            Self::Auto if candle_core::utils::cuda_is_available() => Device::new_cuda(0)?,
+            Self::Auto if candle_core::utils::metal_is_available() => Device::new_metal(0)?,
+            Self::Auto | Self::Cpu => Device::Cpu,
+            Self::Cuda(ordinal) => Device::new_cuda(ordinal)
+                .wrap_err_with(|| format!("CUDA device {ordinal} is unavailable"))?,
+            Self::Metal(ordinal) => Device::new_metal(ordinal)
+                .wrap_err_with(|| format!("Metal device {ordinal} is unavailable"))?,
+        };
+        Ok(device)
+    }
+}
+
+impl std::str::FromStr for DeviceChoice {
+    type Err = DigestError;
+
+    /// Parses `auto`, `cpu`, `cuda`, `cuda:N`, `metal` or `metal:N`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, ordinal) = match s.split_once(':') {
+            Some((kind, ordinal)) => {
+                let ordinal = ordinal
+                    .parse()
+                    .map_err(|_| DigestError::Device(s.to_string()))?;
+                (kind, ordinal)
+            }
+            None => (s, 0),
+        };
+        match kind.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "cpu" => Ok(Self::Cpu),
+            "cuda" => Ok(Self::Cuda(ordinal)),
+            "metal" => Ok(Self::Metal(ordinal)),
+            _ => Err(DigestError::Device(s.to_string())),
+        }
+    }
+}
+
+/// What becomes of inputs longer than the model reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LongInput {
+    /// Embed overlapping windows of the input and average them.
+    #[default]
+    Window,
+    /// Embed only the start of the input, with a warning.
+    Truncate,
+}
+
+impl std::str::FromStr for LongInput {
+    type Err = DigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "window" => Ok(Self::Window),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(DigestError::LongInput(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DigesterConfig {
+    /// Where embeddings are computed; everything below `model` only applies
+    /// to the local and onnx backends, and `device` only to the local one.
+    pub backend: BackendKind,
+    /// Base URL of a remote backend's API, its usual one when `None`.
+    pub api_url: Option<String>,
+    pub device: DeviceChoice,
+    /// Hugging Face repo of a BERT-style embedding model, e.g.
+    /// `BAAI/bge-small-en-v1.5`, or the model name on a remote backend.
+    pub model: String,
+    /// Branch, tag or commit of `model`; the repo's default branch when `None`.
+    pub revision: Option<String>,
+    /// Directory holding `config.json`, `tokenizer.json` and the weights. When
+    /// set the hub is never contacted and `model` only names the model in store
+    /// metadata. A `model` that is itself an existing directory is treated the same.
+    pub model_path: Option<PathBuf>,
+    /// Pooling strategy; when `None` it's read from the model's
+    /// `1_Pooling/config.json`, falling back to mean pooling.
+    pub pooling: Option<Pooling>,
+    /// Scale every embedding to unit length, letting stores score by dot product.
+    pub normalize: bool,
+    pub long_input: LongInput,
+    /// Metric the stores this digests into are searched by; theirs is kept
+    /// when `None`.
+    pub metric: Option<Metric>,
+}
+
+impl Default for DigesterConfig {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::default(),
+            api_url: None,
+            device: DeviceChoice::default(),
+            model: DEFAULT_MODEL.to_string(),
+            revision: None,
+            model_path: None,
+            pooling: None,
+            normalize: false,
+            long_input: LongInput::default(),
+            metric: None,
+        }
+    }
+}
+
+/// Hit/miss counters of the query embedding cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+}
+
+struct QueryCache {
+    entries: LruCache<String, Vec<f32>>,
+    hits: u64,
+    misses: u64,
+}
+
+pub struct Digester {
+    embedder: Box<dyn Embedder>,
+    normalize: bool,
+    long_input: LongInput,
+    metric: Option<Metric>,
+    chunking: ChunkConfig,
+    keep_archived: bool,
+    /// Given to every entry digested; files keep their earlier ones when `None`.
+    labels: Option<Labels>,
+    cache: Mutex<QueryCache>,
+    reranker: Option<Reranker>,
+}
+
+impl Digester {
+    pub fn new() -> Result<Self> {
+        Self::with_config(DigesterConfig::default())
+    }
+
+    /// Loads the configured backend. Remote backends left with the local
+    /// default model use their own default instead.
+    pub fn with_config(mut config: DigesterConfig) -> Result<Self> {
+        if let Some(default) = config.backend.default_model()
+            && config.model == DEFAULT_MODEL
+        {
+            config.model = default.to_string();
+        }
+        let embedder: Box<dyn Embedder> = match config.backend {
+            BackendKind::Local => Box::new(BertBackend::load(&config)?),
+            BackendKind::OpenAi => Box::new(OpenAiBackend::new(
+                config.api_url.as_deref(),
+                &config.model,
+            )?),
+            BackendKind::Ollama => Box::new(OllamaBackend::new(
+                config.api_url.as_deref(),
+                &config.model,
+            )?),
+            #[cfg(feature = "onnx")]
+            BackendKind::Onnx => Box::new(OnnxBackend::load(&config)?),
+            #[cfg(not(feature = "onnx"))]
+            BackendKind::Onnx => return Err(crate::backend::BackendError::NoOnnx.into()),
+        };
+        Ok(Self::from_embedder(embedder)
+            .with_normalize(config.normalize)
+            .with_long_input(config.long_input)
+            .with_metric(config.metric))
+    }
+
+    /// Digests with `embedder` and the default chunking, without normalizing.
+    pub fn from_embedder(embedder: Box<dyn Embedder>) -> Self {
+        Self {
+            embedder,
+            normalize: false,
+            long_input: LongInput::default(),
+            metric: None,
+            chunking: ChunkConfig::default(),
+            keep_archived: false,
+            labels: None,
+            cache: Mutex::new(QueryCache {
+                entries: LruCache::new(QUERY_CACHE_SIZE),
+                hits: 0,
+                misses: 0,
+            }),
+            reranker: None,
+        }
+    }
+
+    /// Scales every embedding to unit length, letting stores score by dot product.
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    pub fn with_long_input(mut self, long_input: LongInput) -> Self {
+        self.long_input = long_input;
+        self
+    }
+
+    /// Has stores digested into, and searches, score by `metric`.
+    pub fn with_metric(mut self, metric: Option<Metric>) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Replaces the chunking strategy and size/overlap used when digesting files.
+    pub fn with_chunking(mut self, chunking: ChunkConfig) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Archives the entries of a file's previous content when it is digested
+    /// again, instead of removing them.
+    pub fn with_archived(mut self, keep_archived: bool) -> Self {
+        self.keep_archived = keep_archived;
+        self
+    }
+
+    /// Tags the entries of every file digested with `labels`, replacing the
+    /// ones they had. With `None`, files keep their tags when re-digested.
+    pub fn with_labels(mut self, labels: Option<Labels>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Attaches a cross-encoder that searches can rerank their hits with.
+    pub fn with_reranker(mut self, reranker: Reranker) -> Self {
+        self.reranker = Some(reranker);
+        self
+    }
+
+    pub fn reranker(&self) -> Option<&Reranker> {
+        self.reranker.as_ref()
+    }
+
+    /// Embeds a natural-language search query. Trims surrounding whitespace so
+    /// `"foo"` and `"foo "` share a cache entry.
+    pub fn embed_query(&self, query: &str) -> Result<Vec<f32>> {
+        self.generate_embedding(query.trim())
+    }
+
+    /// Embeds `text`, memoizing the result so repeated queries skip the forward pass.
+    pub fn generate_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        {
+            let mut cache = self.cache();
+            if let Some(embedding) = cache.entries.get(text).cloned() {
+                cache.hits += 1;
+                trace!("Query cache hit for {:?}", text);
+                return Ok(embedding);
+            }
+            cache.misses += 1;
+        }
+
+        let embedding = self.generate_embeddings_batch(&[text])?.remove(0);
+        self.cache()
+            .entries
+            .put(text.to_string(), embedding.clone());
+        Ok(embedding)
+    }
+
+    /// Embeds `texts` in as few backend calls as it allows. Unlike
+    /// `generate_embedding` the results aren't cached. Texts longer than the
+    /// model reads are windowed or truncated as `LongInput` says.
+    pub fn generate_embeddings_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut inputs = Vec::with_capacity(texts.len());
+        let mut counts = Vec::with_capacity(texts.len());
+        for text in texts {
+            let pieces = self.fit(text)?;
+            counts.push(pieces.len());
+            inputs.extend(pieces);
+        }
+        trace!("Embedding batch of {} inputs", inputs.len());
+        let mut raw = self.embedder.embed_batch(&inputs)?.into_iter();
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for count in counts {
+            let mut embedding = raw.next().expect("one embedding per input");
+            for window in raw.by_ref().take(count - 1) {
+                embedding.iter_mut().zip(window).for_each(|(a, b)| *a += b);
+            }
+            if count > 1 {
+                embedding.iter_mut().for_each(|x| *x /= count as f32);
+            }
+            if self.normalize {
+                normalize(&mut embedding);
+            }
+            embeddings.push(embedding);
+        }
+        Ok(embeddings)
+    }
+
+    /// `text` as it is when the model reads it whole, otherwise its windows
+    /// or its start. Without a tokenizer, token boundaries are spread
+    /// evenly over the text by the estimated token count.
+    fn fit<'a>(&self, text: &'a str) -> Result<Vec<&'a str>> {
+        let budget = self.token_budget();
+        let offsets = match self.embedder.token_offsets(text)? {
+            Some(offsets) if offsets.len() <= budget => return Ok(vec![text]),
+            Some(offsets) => offsets,
+            None => {
+                let tokens = self.count_tokens(text);
+                if tokens <= budget {
+                    return Ok(vec![text]);
+                }
+                let bound = |i: usize| text.floor_char_boundary(i * text.len() / tokens);
+                (0..tokens).map(|i| (bound(i), bound(i + 1))).collect()
+            }
+        };
+
+        let span = |start: usize, end: usize| &text[offsets[start].0..offsets[end - 1].1];
+        match self.long_input {
+            LongInput::Truncate => {
+                warn!(
+                    "Input of {} tokens is longer than the {} the model reads, embedding its start only",
+                    offsets.len(),
+                    budget
+                );
+                Ok(vec![&text[..offsets[budget - 1].1]])
+            }
+            LongInput::Window => {
+                // Consecutive windows share a quarter of their tokens.
+                let stride = (budget - budget / 4).max(1);
+                let mut windows = Vec::new();
+                let mut start = 0;
+                loop {
+                    let end = (start + budget).min(offsets.len());
+                    windows.push(span(start, end));
+                    if end == offsets.len() {
+                        break;
+                    }
+                    start += stride;
+                }
+                trace!(
+                    "Embedding input of {} tokens as {} windows",
+                    offsets.len(),
+                    windows.len()
+                );
+                Ok(windows)
+            }
+        }
+    }
+
+    /// Embeds the `latest` copy of every file tracked under `memory_dir` that
+    /// `patterns` selects, by the text `extract` gets out of it. Files it can't
+    /// make text of are left versioned only. Files already embedded at their
+    /// latest version are skipped, and entries of files no longer tracked are
+    /// dropped, so the store follows intermediate memory. Once `cancel` fires
+    /// the file being embedded is finished and stored, and the rest left alone.
+    pub fn digest_all(
+        &self,
+        memory_dir: &Path,
+        store: &mut VectorStore,
+        patterns: &DigestPatterns,
+        cancel: &CancellationToken,
+    ) -> Result<DigestSummary> {
+        self.check_compatible(store)?;
+        let tracked_files = Processor::tracked_files(memory_dir)?;
+
+        let mut summary = DigestSummary::default();
+        let aliases: HashSet<&str> = tracked_files.iter().map(|t| t.alias.as_str()).collect();
+        summary.removed = store.delete_where(|entry| {
+            entry
+                .alias
+                .as_deref()
+                .is_some_and(|alias| !aliases.contains(alias))
+        })?;
+
+        let pb = files_bar(tracked_files.len())?;
+        for tracked in pb.wrap_iter(tracked_files.into_iter()) {
+            if cancel.is_cancelled() {
+                summary.interrupted = true;
+                pb.abandon_with_message("[INTERRUPTED]");
+                warn!("Digestion interrupted after {} files.", pb.position());
+                break;
+            }
+            pb.set_message(tracked.alias.clone());
+            if !patterns.matches(&tracked.original_path) {
+                trace!("[{}] Excluded from digestion.", tracked.alias);
+                summary.excluded += 1;
+                continue;
+            }
+            if store.is_current(&tracked.original_path, tracked.version) {
+                trace!("[{}] v{} already digested.", tracked.alias, tracked.version);
+                summary.current += 1;
+                continue;
+            }
+
+            let data = match compress::read(&tracked.latest) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Failed to read {}: {}", tracked.latest.display(), e);
+                    continue;
+                }
+            };
+            let content = match extract(&tracked.original_path, data) {
+                Ok(content) => content,
+                Err(e) => {
+                    debug!("[{}] {}, skipping digestion.", tracked.alias, e);
+                    summary.excluded += 1;
+                    continue;
+                }
+            };
+            match self.digest_content(&tracked.original_path, Some(&tracked), &content, store) {
+                Ok(DigestOutcome::Stored(chunks)) => {
+                    summary.stored += 1;
+                    summary.chunks += chunks;
+                }
+                Ok(DigestOutcome::Skipped) => summary.skipped += 1,
+                Ok(DigestOutcome::Unchanged) => summary.current += 1,
+                Err(e) => warn!("Failed to digest {}: {:?}", tracked.alias, e),
+            }
+        }
+        pb.finish_with_message("[DONE]");
+
+        info!(
+            "Digestion finished: {} stored ({} chunks), {} up to date, {} skipped, {} excluded, {} stale entries removed.",
+            summary.stored,
+            summary.chunks,
+            summary.current,
+            summary.skipped,
+            summary.excluded,
+            summary.removed
+        );
+        Ok(summary)
+    }
+
+    /// Embeds every file in `storage` as it is on disk, like `digest_file`,
+    /// without going through intermediate memory. Files it can't make text
+    /// of are counted as excluded. Once `cancel` fires the file being
+    /// embedded is finished and stored, and the rest left alone.
+    pub fn digest_storage(
+        &self,
+        storage: &FileStorage,
+        store: &mut VectorStore,
+        cancel: &CancellationToken,
+    ) -> Result<DigestSummary> {
+        self.check_compatible(store)?;
+        let mut summary = DigestSummary::default();
+        let pb = files_bar(storage.len())?;
+        for path in pb.wrap_iter(storage.paths().iter()) {
+            if cancel.is_cancelled() {
+                summary.interrupted = true;
+                pb.abandon_with_message("[INTERRUPTED]");
+                warn!("Digestion interrupted after {} files.", pb.position());
+                break;
+            }
+            let file_name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            pb.set_message(file_name.clone());
+
+            let data = match std::fs::read(path) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let content = match extract(path, data) {
+                Ok(content) => content,
+                Err(e) => {
+                    debug!("[{}] {}, skipping digestion.", file_name, e);
+                    summary.excluded += 1;
+                    continue;
+                }
+            };
+            match self.digest_content(path, None, &content, store) {
+                Ok(DigestOutcome::Stored(chunks)) => {
+                    summary.stored += 1;
+                    summary.chunks += chunks;
+                }
+                Ok(DigestOutcome::Skipped) => summary.skipped += 1,
+                Ok(DigestOutcome::Unchanged) => summary.current += 1,
+                Err(e) => warn!("Failed to digest {}: {:?}", path.display(), e),
+            }
+        }
+        pb.finish_with_message("[DONE]");
+
+        info!(
+            "Digestion finished: {} stored ({} chunks), {} unchanged, {} skipped, {} excluded.",
+            summary.stored, summary.chunks, summary.current, summary.skipped, summary.excluded
+        );
+        Ok(summary)
+    }
+
+    /// Splits the content of `path` into chunks and records one entry per chunk in `store`.
+    /// Empty and whitespace-only files are skipped rather than stored as degenerate vectors.
+    pub fn digest_file(&self, path: &Path, store: &mut VectorStore) -> Result<DigestOutcome> {
+        let data = std::fs::read(path)
+            .wrap_err_with(|| format!("Failed to read {} for digestion", path.display()))?;
+        let content = extract(path, data)
+            .wrap_err_with(|| format!("Failed to read {} for digestion", path.display()))?;
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.digest_content(&path, None, &content, store)
+    }
+
+    /// Like `digest_file`, but reads the latest stored version of `tracked`
+    /// and records its alias and version in the entries.
+    pub fn digest_tracked(
+        &self,
+        tracked: &TrackedFile,
+        store: &mut VectorStore,
+    ) -> Result<DigestOutcome> {
+        let data = compress::read(&tracked.latest)
+            .wrap_err_with(|| format!("Failed to read {}", tracked.latest.display()))?;
+        let content = extract(&tracked.original_path, data).wrap_err_with(|| {
+            format!(
+                "Failed to read {} for digestion",
+                tracked.original_path.display()
+            )
+        })?;
+        self.digest_content(&tracked.original_path, Some(tracked), &content, store)
+    }
+
+    /// Embeds `extracted`, the text of `source`. `tracked` is the stored file
+    /// it was read from, if any, and is recorded in the entries.
+    fn digest_content(
+        &self,
+        source: &Path,
+        tracked: Option<&TrackedFile>,
+        extracted: &Extracted,
+        store: &mut VectorStore,
+    ) -> Result<DigestOutcome> {
+        let content = extracted.text.as_str();
+        let file_name = source
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        if content.trim().is_empty() {
+            debug!("[{}] Skipping digestion of empty file.", file_name);
+            return Ok(DigestOutcome::Skipped);
+        }
+
+        self.claim(store)?;
+        // Keyed by source as well, so identical files don't replace each other's entries.
+        let mut hasher = Sha256::new();
+        hasher.update(source.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(content.as_bytes());
+        let entry_key = format!("{:x}", hasher.finalize());
+        let chunks: Vec<_> = self
+            .chunk(source, content)
+            .into_iter()
+            .filter(|chunk| !chunk.text.trim().is_empty())
+            .map(|chunk| {
+                let id = format!("{}-{}", entry_key, chunk.span.index);
+                (chunk, id, content_hash(chunk.text))
+            })
+            .collect();
+
+        let version = tracked.map(|tracked| tracked.version);
+        let stored_at = tracked.map(|tracked| tracked.processed_at.clone());
+        if let Some(labels) = &self.labels {
+            store.set_file_labels(source, labels.clone());
+        }
+        let labels = store.file_labels(source).cloned().unwrap_or_default();
+        let existing: HashMap<&str, &VectorEntry> = store
+            .iter()
+            .filter(|entry| entry.source_path.as_deref() == Some(source) && !entry.is_archived())
+            .map(|entry| (entry.id.as_str(), entry))
+            .collect();
+        let unchanged = existing.len() == chunks.len()
+            && chunks.iter().all(|(_, id, hash)| {
+                existing.get(id.as_str()).is_some_and(|entry| {
+                    entry.content_hash == *hash
+                        && entry.version == version
+                        && entry.stored_at == stored_at
+                        && entry.tags == labels.tags
+                        && entry.metadata == labels.metadata
+                })
+            });
+        if unchanged {
+            debug!("[{}] Unchanged, not re-embedding.", file_name);
+            return Ok(DigestOutcome::Unchanged);
+        }
+
+        // Chunks already embedded, from this file or any other, keep their embedding.
+        let wanted: HashSet<&str> = chunks.iter().map(|(_, _, hash)| hash.as_str()).collect();
+        let mut known: HashMap<String, Vec<f32>> = HashMap::new();
+        for entry in store.iter() {
+            if wanted.contains(entry.content_hash.as_str())
+                && !known.contains_key(&entry.content_hash)
+                && let Some(embedding) = store.embedding(entry)
+            {
+                known.insert(entry.content_hash.clone(), embedding.into_owned());
+            }
+        }
+
+        // Saved once the whole file is in, rather than after every chunk.
+        let (stored, reused) = store.batch(|store| {
+            let replaced = store.supersede_paths(&[source.to_path_buf()], self.keep_archived)?;
+            if replaced > 0 {
+                debug!(
+                    "[{}] Replacing {} stale vector entries.",
+                    file_name, replaced
+                );
+            }
+            let digested_at = now_millis();
+            let mut stored = 0;
+            let mut reused = 0;
+            for batch in chunks.chunks(EMBED_BATCH_SIZE) {
+                let texts: Vec<&str> = batch
+                    .iter()
+                    .filter(|(_, _, hash)| !known.contains_key(hash))
+                    .map(|(chunk, _, _)| chunk.text)
+                    .collect();
+                let mut embeddings = if texts.is_empty() {
+                    Vec::new()
+                } else {
+                    self.generate_embeddings_batch(&texts)?
+                }
+                .into_iter();
+                for (chunk, id, hash) in batch {
+                    let embedding = match known.get(hash) {
+                        Some(embedding) => {
+                            reused += 1;
+                            embedding.clone()
+                        }
+                        None => embeddings.next().expect("one embedding per text"),
+                    };
+                    store.upsert(VectorEntry {
+                        id: id.clone(),
+                        file_name: file_name.clone(),
+                        source_path: Some(source.to_path_buf()),
+                        alias: tracked.map(|tracked| tracked.alias.clone()),
+                        version,
+                        stored_at: stored_at.clone(),
+                        digested_at,
+                        archived: None,
+                        content_preview: chunk.text.chars().take(PREVIEW_CHARS).collect(),
+                        content: chunk.text.to_string(),
+                        content_hash: hash.clone(),
+                        embedding,
+                        span: chunk.span,
+                        section: extracted
+                            .section_at(chunk.span.start_byte)
+                            .map(String::from),
+                        symbol: chunk.symbol.map(String::from),
+                        tags: labels.tags.clone(),
+                        metadata: labels.metadata.clone(),
+                        last_accessed: Default::default(),
+                    })?;
+                    stored += 1;
+                }
+            }
+            Ok((stored, reused))
+        })?;
+
+        info!(
+            "[{}] Digested {} chunks into vector store ({} unchanged).",
+            file_name, stored, reused
+        );
+        Ok(DigestOutcome::Stored(stored))
+    }
+
+    /// Embeds the text of every entry of `store`, archived ones included,
+    /// into `target`, keeping their ids, spans and timestamps so only the
+    /// embeddings change. Chunks are reused as they are: their text comes
+    /// from the entry or, in stores older than that, is restored from the
+    /// version in `memory_dir` it was digested from.
+    pub async fn re_embed(
+        &self,
+        memory_dir: &Path,
+        store: &VectorStore,
+        target: &mut VectorStore,
+        cancel: &CancellationToken,
+    ) -> Result<ReembedSummary> {
+        let mut summary = ReembedSummary::default();
+        let mut texts = Vec::with_capacity(store.len());
+        for entry in store.iter() {
+            if cancel.is_cancelled() {
+                summary.interrupted = true;
+                return Ok(summary);
+            }
+            texts.push(VectorStore::fetch_content(memory_dir, entry).await?);
+        }
+        let budget = self.token_budget();
+        summary.oversized = texts
+            .iter()
+            .filter(|text| self.count_tokens(text) > budget)
+            .count();
+
+        self.claim(target)?;
+        if self.metric.is_none() {
+            target.set_metric(store.metric());
+        }
+        for path in store
+            .iter()
+            .filter_map(|entry| entry.source_path.as_deref())
+        {
+            if let Some(labels) = store.file_labels(path) {
+                target.set_file_labels(path, labels.clone());
+            }
+        }
+        let pb = entries_bar(store.len())?;
+        let entries: Vec<_> = store.iter().zip(&texts).collect();
+        target.batch(|target| {
+            for batch in entries.chunks(EMBED_BATCH_SIZE) {
+                if cancel.is_cancelled() {
+                    summary.interrupted = true;
+                    break;
+                }
+                let batch_texts: Vec<&str> = batch.iter().map(|(_, text)| text.as_str()).collect();
+                let embeddings = self.generate_embeddings_batch(&batch_texts)?;
+                for ((entry, _), embedding) in batch.iter().zip(embeddings) {
+                    target.add(VectorEntry {
+                        embedding,
+                        ..(*entry).clone()
+                    })?;
+                }
+                summary.entries += batch.len();
+                pb.inc(batch.len() as u64);
+            }
+            Ok(())
+        })?;
+        pb.finish_and_clear();
+        Ok(summary)
+    }
+
+    /// Splits `text`, the content of `path`, the way digestion does.
+    /// Token-counted chunks are capped at `token_budget` tokens whatever the
+    /// configured size.
+    pub fn chunk<'a>(&self, path: &Path, text: &'a str) -> Vec<Chunk<'a>> {
+        let language = Language::from_path(path).filter(|_| self.chunking.code);
+        match (self.chunking.strategy, language) {
+            (ChunkStrategy::Fixed, Some(language)) => {
+                chunk_code(text, language, self.chunking.size, str::len)
+            }
+            (_, Some(language)) => {
+                let size = self.chunking.size.min(self.token_budget());
+                chunk_code(text, language, size, |text| self.count_tokens(text))
+            }
+            (ChunkStrategy::Fixed, None) => chunk_text(text, &self.chunking),
+            (ChunkStrategy::Sentences, None) => {
+                let config = ChunkConfig {
+                    size: self.chunking.size.min(self.token_budget()),
+                    ..self.chunking
+                };
+                chunk_sentences(text, &config, |text| self.count_tokens(text))
+            }
+            (ChunkStrategy::Tokens, None) => {
+                let config = ChunkConfig {
+                    size: self.chunking.size.min(self.token_budget()),
+                    ..self.chunking
+                };
+                let chunks = self.embedder.token_offsets(text).and_then(|offsets| {
+                    let chunks = offsets.map(|offsets| chunk_tokens(text, &offsets, &config));
+                    Ok(chunks.transpose()?)
+                });
+                match chunks {
+                    Ok(Some(chunks)) => chunks,
+                    // Without a tokenizer, sentences measured in estimated tokens come closest.
+                    Ok(None) => chunk_sentences(text, &config, |text| self.count_tokens(text)),
+                    Err(e) => {
+                        warn!("Failed to chunk by tokens, falling back to sentences: {e}");
+                        chunk_sentences(text, &config, str::len)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tokens `text` takes up, without the special tokens the model adds.
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.embedder.count_tokens(text)
+    }
+
+    /// Tokens of text that fit one input along with the special tokens.
+    pub fn token_budget(&self) -> usize {
+        let special = self.embedder.special_tokens();
+        self.max_tokens().saturating_sub(special).max(1)
+    }
+
+    /// Longest input, in tokens, the loaded model can attend to.
+    pub fn max_tokens(&self) -> usize {
+        self.embedder.max_tokens()
+    }
+
+    pub fn long_input(&self) -> LongInput {
+        self.long_input
+    }
+
+    pub fn metric(&self) -> Option<Metric> {
+        self.metric
+    }
+
+    /// Whether embeddings are scaled to unit length.
+    pub fn normalizes(&self) -> bool {
+        self.normalize
+    }
+
+    /// Length of the embeddings this model produces.
+    pub fn dimension(&self) -> usize {
+        self.embedder.dimension()
+    }
+
+    pub fn model_id(&self) -> &str {
+        self.embedder.model_id()
+    }
+
+    /// What this digester's embeddings are recorded as in a store.
+    pub fn header(&self) -> StoreHeader {
+        StoreHeader {
+            schema_version: SCHEMA_VERSION,
+            model: self.model_id().to_string(),
+            revision: self.embedder.revision().map(str::to_string),
+            dimension: self.dimension(),
+            normalized: self.normalize,
+            pooling: self.embedder.pooling(),
+        }
+    }
+
+    /// Records this digester's embeddings and metric in `store`, failing if
+    /// it holds embeddings from a different model.
+    fn claim(&self, store: &mut VectorStore) -> Result<()> {
+        store.claim_header(&self.header())?;
+        if let Some(metric) = self.metric {
+            store.set_metric(metric);
+        }
+        Ok(())
+    }
+
+    /// Fails if `store` holds embeddings from a different model, whose scores
+    /// against this digester's queries would be meaningless.
+    pub fn check_compatible(&self, store: &VectorStore) -> Result<()> {
+        store.check_header(&self.header())
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let cache = self.cache();
+        CacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            len: cache.entries.len(),
+        }
+    }
+
+    pub fn clear_cache(&self) {
+        let mut cache = self.cache();
+        cache.entries.clear();
+        cache.hits = 0;
+        cache.misses = 0;
+    }
+
+    fn cache(&self) -> MutexGuard<'_, QueryCache> {
+        // A poisoned cache only ever holds complete entries, so keep using it.
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Identifies a chunk by its text, so unchanged chunks keep their embedding.
+fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+/// A progress bar over `len` vector entries.
+fn entries_bar(len: usize) -> Result<ProgressBar> {
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} entries ({percent}%) {msg}")?
+        .progress_chars("#>-"));
+    Ok(pb)
+}
+
+/// A progress bar over `len` files.
+fn files_bar(len: usize) -> Result<ProgressBar> {
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files ({percent}%) {msg}")?
+        .progress_chars("#>-"));
+    Ok(pb)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Embeds every text as the same unit vector, for digesting without a model.
+    pub(crate) struct ConstantEmbedder;
+
+    impl Embedder for ConstantEmbedder {
+        fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+            Ok(texts.iter().map(|_| vec![1.0, 0.0, 0.0]).collect())
+        }
+
+        fn model_id(&self) -> &str {
+            "constant"
+        }
+
+        fn dimension(&self) -> usize {
+            3
+        }
+
+        fn max_tokens(&self) -> usize {
+            512
+        }
+    }
+
+    fn digest(content: &str) -> (DigestOutcome, VectorStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, content).unwrap();
+        let mut store = VectorStore::load(dir.path().join("vectors.bin")).unwrap();
+        let digester = Digester::from_embedder(Box::new(ConstantEmbedder));
+        let outcome = digester.digest_file(&path, &mut store).unwrap();
+        (outcome, store)
+    }
+
+    #[test]
+    fn empty_file_creates_no_entry() {
+        let (outcome, store) = digest("");
+        assert_eq!(outcome, DigestOutcome::Skipped);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn whitespace_only_file_creates_no_entry() {
+        let (outcome, store) = digest(" \n\t\r\n  \n");
+        assert_eq!(outcome, DigestOutcome::Skipped);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn file_with_text_creates_an_entry() {
+        let (outcome, store) = digest("Some text worth remembering.");
+        assert_eq!(outcome, DigestOutcome::Stored(1));
+        assert_eq!(store.len(), 1);
+    }
+
+    /// A model of another name and size, to switch to.
+    struct WiderEmbedder;
+
+    impl Embedder for WiderEmbedder {
+        fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+            Ok(texts.iter().map(|_| vec![0.0, 1.0, 0.0, 0.0]).collect())
+        }
+
+        fn model_id(&self) -> &str {
+            "wider"
+        }
+
+        fn dimension(&self) -> usize {
+            4
+        }
+
+        fn max_tokens(&self) -> usize {
+            512
+        }
+    }
+
+    #[tokio::test]
+    async fn re_embedded_store_replaces_the_old_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("file.txt");
+        std::fs::write(&source, "Some text worth remembering.").unwrap();
+        let path = dir.path().join("vectors.bin");
+        let mut store = VectorStore::load(&path).unwrap();
+        Digester::from_embedder(Box::new(ConstantEmbedder))
+            .digest_file(&source, &mut store)
+            .unwrap();
+        store.save().unwrap();
+
+        let staging = VectorStore::staging_path(&path);
+        let mut target = VectorStore::load(&staging).unwrap();
+        let digester = Digester::from_embedder(Box::new(WiderEmbedder));
+        let summary = digester
+            .re_embed(dir.path(), &store, &mut target, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(summary.entries, 1);
+        assert!(!summary.interrupted);
+        target.save_as(&path).unwrap();
+        VectorStore::remove_files(&staging).unwrap();
+
+        let reloaded = VectorStore::load(&path).unwrap();
+        assert_eq!(reloaded.header(), Some(&digester.header()));
+        let (old, new) = (
+            store.iter().next().unwrap(),
+            reloaded.iter().next().unwrap(),
+        );
+        assert_eq!(
+            (&new.id, &new.span, &new.content),
+            (&old.id, &old.span, &old.content)
+        );
+        assert_eq!(reloaded.dimension(), 4);
+        assert!(!staging.exists());
+    }
+}
@@ -0,0 +1,481 @@
+use eyre::{Context, Result, bail};
+use log::debug;
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::crypt;
+use crate::vector_store::{Metric, dot, normalize};
+
+/// Leads every embedding file, followed by the dimension and the encoding as
+/// little-endian `u32`s, so the rows after it stay aligned for `f32`.
+const EMBEDDINGS_MAGIC: &[u8; 8] = b"OUROEMB1";
+const HEADER_LEN: usize = 16;
+const ENCODING_F32: u32 = 0;
+/// Rows as int8 codes: all scales first, then `dim` codes per row.
+const ENCODING_INT8: u32 = 1;
+
+/// The embeddings of a vector store as one row-major matrix, one row per
+/// entry. Full-precision rows loaded from a plaintext file stay
+/// memory-mapped, so opening a large store costs no more than its metadata;
+/// the first change copies them into memory. Quantized rows take a quarter
+/// of the space and are always held in memory.
+#[derive(Debug, Default)]
+pub struct EmbeddingMatrix {
+    dim: usize,
+    rows: usize,
+    data: Rows,
+    /// Length of each row, computed on the first exact score needing it and
+    /// dropped by any change, so brute-force search doesn't redo it per query.
+    norms: OnceLock<Vec<f32>>,
+}
+
+#[derive(Debug)]
+enum Rows {
+    Owned(Vec<f32>),
+    /// The whole file, and where its header starts, past the plaintext
+    /// header `crypt` writes.
+    Mapped {
+        map: Mmap,
+        start: usize,
+    },
+    /// Each row as `dim` int8 codes that scale back to the original with the
+    /// row's entry in `scales`.
+    Quantized {
+        codes: Vec<i8>,
+        scales: Vec<f32>,
+    },
+}
+
+impl Default for Rows {
+    fn default() -> Self {
+        Self::Owned(Vec::new())
+    }
+}
+
+impl EmbeddingMatrix {
+    /// Opens the file at `path`, mapping full-precision rows unless
+    /// encryption is on or this platform isn't little-endian, in which case
+    /// they are read into memory.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)
+            .wrap_err_with(|| format!("Failed to open embeddings {}", path.display()))?;
+        let mut prefix = [0u8; crypt::PLAIN_MAGIC.len() + HEADER_LEN];
+        let read = crypt::read_prefix(&mut file, &mut prefix)
+            .wrap_err_with(|| format!("Failed to read embeddings {}", path.display()))?;
+        let prefix = &prefix[..read];
+        // With a key, `crypt` decides whether unsealed files may be read at all.
+        let plain = !crypt::is_sealed(prefix) && !crypt::is_enabled();
+        let quantized = plain && parse_header(crypt::unframe(prefix), path)?.1 == ENCODING_INT8;
+        if !plain || quantized || cfg!(target_endian = "big") {
+            let data = crypt::read(path)
+                .wrap_err_with(|| format!("Failed to read embeddings {}", path.display()))?;
+            return Self::decode(&data, path);
+        }
+
+        // SAFETY: the file is only ever replaced by renaming a new one over
+        // it, never written in place, so the mapped bytes don't change.
+        let map = unsafe { Mmap::map(&file) }
+            .wrap_err_with(|| format!("Failed to map embeddings {}", path.display()))?;
+        let start = map.len() - crypt::unframe(&map).len();
+        let (dim, _, body) = parse_header(&map[start..], path)?;
+        let rows = (body.len() / 4).checked_div(dim).unwrap_or(0);
+        debug!("Mapped {} embeddings from {}", rows, path.display());
+        Ok(Self {
+            dim,
+            rows,
+            data: Rows::Mapped { map, start },
+            norms: OnceLock::new(),
+        })
+    }
+
+    /// Reads a whole embedding file that was loaded into memory.
+    fn decode(data: &[u8], path: &Path) -> Result<Self> {
+        let (dim, encoding, body) = parse_header(data, path)?;
+        let floats = |bytes: &[u8]| -> Vec<f32> {
+            bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        if encoding == ENCODING_F32 {
+            let values = floats(body);
+            return Ok(Self {
+                dim,
+                rows: values.len().checked_div(dim).unwrap_or(0),
+                data: Rows::Owned(values),
+                norms: OnceLock::new(),
+            });
+        }
+        let rows = body.len().checked_div(4 + dim).unwrap_or(0);
+        let (scales, codes) = body.split_at(rows * 4);
+        Ok(Self {
+            dim,
+            rows,
+            data: Rows::Quantized {
+                codes: codes.iter().map(|&b| b as i8).collect(),
+                scales: floats(scales),
+            },
+            norms: OnceLock::new(),
+        })
+    }
+
+    /// Writes the matrix to `path`, encrypted if a key is installed. The new
+    /// file is renamed over the old one, so a mapping of it stays valid.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("emb.tmp");
+        let file = File::create(&tmp)
+            .wrap_err_with(|| format!("Failed to create embeddings {}", tmp.display()))?;
+        let mut writer = crypt::writer(BufWriter::new(file))?;
+        let encoding = if self.is_quantized() {
+            ENCODING_INT8
+        } else {
+            ENCODING_F32
+        };
+        writer.write_all(EMBEDDINGS_MAGIC)?;
+        writer.write_all(&(self.dim as u32).to_le_bytes())?;
+        writer.write_all(&encoding.to_le_bytes())?;
+        match &self.data {
+            Rows::Mapped { map, start } => writer.write_all(&map[start + HEADER_LEN..])?,
+            Rows::Owned(values) => {
+                for x in values {
+                    writer.write_all(&x.to_le_bytes())?;
+                }
+            }
+            Rows::Quantized { codes, scales } => {
+                for scale in scales {
+                    writer.write_all(&scale.to_le_bytes())?;
+                }
+                let codes: Vec<u8> = codes.iter().map(|&c| c as u8).collect();
+                writer.write_all(&codes)?;
+            }
+        }
+        writer
+            .finish()
+            .and_then(|file| file.into_inner().map_err(|e| e.into_error()))
+            .and_then(|file| file.sync_all())
+            .wrap_err_with(|| format!("Failed to write embeddings {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .wrap_err_with(|| format!("Failed to replace embeddings {}", path.display()))
+    }
+
+    /// Builds a matrix from separate vectors, which must share one length.
+    pub fn from_rows<'a>(rows: impl IntoIterator<Item = &'a [f32]>) -> Result<Self> {
+        let mut matrix = Self::default();
+        for row in rows {
+            matrix.push(row)?;
+        }
+        Ok(matrix)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// Length of each row; 0 while the matrix is empty.
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.data, Rows::Quantized { .. })
+    }
+
+    /// Row `i`, scaled back to `f32` if the matrix is quantized.
+    pub fn row(&self, i: usize) -> Cow<'_, [f32]> {
+        let span = i * self.dim..(i + 1) * self.dim;
+        match &self.data {
+            Rows::Quantized { codes, scales } => Cow::Owned(
+                codes[span]
+                    .iter()
+                    .map(|&c| f32::from(c) * scales[i])
+                    .collect(),
+            ),
+            _ => Cow::Borrowed(&self.values()[span]),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'_, [f32]>> {
+        (0..self.rows).map(|i| self.row(i))
+    }
+
+    /// Converts the rows to int8 codes with a scale per row. The lost
+    /// precision can't be recovered; later rows are quantized as they come.
+    pub fn quantize(&mut self) {
+        if self.is_quantized() {
+            return;
+        }
+        self.norms.take();
+        let mut codes = Vec::with_capacity(self.rows * self.dim);
+        let mut scales = Vec::with_capacity(self.rows);
+        for i in 0..self.rows {
+            scales.push(quantize_into(&self.row(i), &mut codes));
+        }
+        self.data = Rows::Quantized { codes, scales };
+    }
+
+    /// Scores `query` against rows by `metric`, one at a time and from any
+    /// thread. With `unit_rows` the rows are taken to be unit length, so
+    /// cosine similarity is a dot product with the normalized query.
+    /// Quantized rows are scored on int8 codes, which is only precise enough
+    /// to shortlist candidates for rescoring with `Scorer::exact`.
+    pub fn scorer(&self, query: &[f32], metric: Metric, unit_rows: bool) -> Scorer<'_> {
+        let codes = match &self.data {
+            Rows::Quantized { .. } => {
+                let mut codes = Vec::with_capacity(query.len());
+                let scale = quantize_into(query, &mut codes);
+                let norm = code_dot(&codes, &codes) as f32;
+                Some(QueryCodes { codes, scale, norm })
+            }
+            _ => None,
+        };
+        let unit = unit_rows && metric == Metric::Cosine && codes.is_none();
+        let mut query = query.to_vec();
+        if unit {
+            normalize(&mut query);
+        }
+        let norm = dot(&query, &query).sqrt();
+        Scorer {
+            matrix: self,
+            metric,
+            query,
+            norm,
+            unit,
+            codes,
+        }
+    }
+
+    fn norms(&self) -> &[f32] {
+        self.norms
+            .get_or_init(|| self.iter().map(|row| dot(&row, &row).sqrt()).collect())
+    }
+
+    /// Appends a row. The first row fixes the dimension of an empty matrix.
+    pub fn push(&mut self, row: &[f32]) -> Result<()> {
+        if self.rows == 0 {
+            self.dim = row.len();
+        }
+        self.check_dim(row)?;
+        self.norms.take();
+        match &mut self.data {
+            Rows::Quantized { codes, scales } => scales.push(quantize_into(row, codes)),
+            _ => self.owned().extend_from_slice(row),
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn set(&mut self, i: usize, row: &[f32]) -> Result<()> {
+        self.check_dim(row)?;
+        self.norms.take();
+        let dim = self.dim;
+        match &mut self.data {
+            Rows::Quantized { codes, scales } => {
+                let mut row_codes = Vec::with_capacity(dim);
+                scales[i] = quantize_into(row, &mut row_codes);
+                codes[i * dim..(i + 1) * dim].copy_from_slice(&row_codes);
+            }
+            _ => self.owned()[i * dim..(i + 1) * dim].copy_from_slice(row),
+        }
+        Ok(())
+    }
+
+    /// Keeps the rows whose flag in `keep` is set.
+    pub fn retain(&mut self, keep: &[bool]) {
+        let dim = self.dim;
+        let kept: Vec<usize> = (0..self.rows).filter(|&i| keep[i]).collect();
+        self.norms.take();
+        match &mut self.data {
+            Rows::Quantized { codes, scales } => {
+                compact(codes, dim, &kept);
+                compact(scales, 1, &kept);
+            }
+            _ => compact(self.owned(), dim, &kept),
+        }
+        self.rows = kept.len();
+    }
+
+    /// Drops every row, keeping the encoding.
+    pub fn clear(&mut self) {
+        let quantized = self.is_quantized();
+        *self = Self::default();
+        if quantized {
+            self.quantize();
+        }
+    }
+
+    fn check_dim(&self, row: &[f32]) -> Result<()> {
+        if row.len() != self.dim {
+            bail!(
+                "embedding has {} dimensions, but the store holds {}",
+                row.len(),
+                self.dim
+            );
+        }
+        Ok(())
+    }
+
+    /// The full-precision rows, in memory or mapped.
+    fn values(&self) -> &[f32] {
+        match &self.data {
+            Rows::Owned(values) => values,
+            Rows::Mapped { map, start } => {
+                // SAFETY: every bit pattern is a valid `f32`, and `align_to`
+                // only yields the correctly aligned middle part.
+                let (_, values, _) = unsafe { map[start + HEADER_LEN..].align_to::<f32>() };
+                &values[..self.rows * self.dim]
+            }
+            Rows::Quantized { .. } => unreachable!("quantized rows have no f32 values"),
+        }
+    }
+
+    /// The full-precision rows as an owned vector, copying them out of the
+    /// mapping first.
+    fn owned(&mut self) -> &mut Vec<f32> {
+        if let Rows::Mapped { .. } = &self.data {
+            self.data = Rows::Owned(self.values().to_vec());
+        }
+        match &mut self.data {
+            Rows::Owned(values) => values,
+            _ => unreachable!("rows are full precision and were copied out of the mapping"),
+        }
+    }
+}
+
+/// A query prepared by `EmbeddingMatrix::scorer`.
+pub struct Scorer<'a> {
+    matrix: &'a EmbeddingMatrix,
+    metric: Metric,
+    /// The query, normalized if `unit`.
+    query: Vec<f32>,
+    norm: f32,
+    /// Rows and query are unit length and scored by dot product alone.
+    unit: bool,
+    /// Set for quantized matrices, whose rows are scored on their codes.
+    codes: Option<QueryCodes>,
+}
+
+/// The query's int8 codes, their scale and their squared length.
+struct QueryCodes {
+    codes: Vec<i8>,
+    scale: f32,
+    norm: f32,
+}
+
+impl Scorer<'_> {
+    /// The score of row `i`, approximated from int8 codes if the matrix is
+    /// quantized.
+    pub fn score(&self, i: usize) -> f32 {
+        let Some(query) = &self.codes else {
+            return self.exact(i);
+        };
+        let matrix = self.matrix;
+        let Rows::Quantized { codes, scales } = &matrix.data else {
+            unreachable!("query codes are only made for quantized matrices")
+        };
+        let row = &codes[i * matrix.dim..(i + 1) * matrix.dim];
+        let (product, row_norm) = (
+            code_dot(&query.codes, row) as f32,
+            code_dot(row, row) as f32,
+        );
+        match self.metric {
+            Metric::Cosine => {
+                let norms = (query.norm * row_norm).sqrt();
+                if norms > 0.0 { product / norms } else { 0.0 }
+            }
+            Metric::Dot => product * query.scale * scales[i],
+            Metric::Euclidean => {
+                let (a, b) = (query.scale, scales[i]);
+                euclidean(query.norm * a * a + row_norm * b * b - 2.0 * product * a * b)
+            }
+        }
+    }
+
+    /// The score of row `i` at full precision, against the rows scaled back
+    /// from their codes if the matrix is quantized.
+    pub fn exact(&self, i: usize) -> f32 {
+        let product = dot(&self.query, &self.matrix.row(i));
+        if self.unit {
+            return product;
+        }
+        match self.metric {
+            Metric::Cosine => {
+                let norms = self.norm * self.matrix.norms()[i];
+                if norms > 0.0 { product / norms } else { 0.0 }
+            }
+            Metric::Dot => product,
+            Metric::Euclidean => {
+                let row_norm = self.matrix.norms()[i];
+                euclidean(self.norm * self.norm + row_norm * row_norm - 2.0 * product)
+            }
+        }
+    }
+
+    /// Whether `score` gives int8 approximations to be rescored with `exact`.
+    pub fn is_approximate(&self) -> bool {
+        self.codes.is_some()
+    }
+}
+
+/// Euclidean similarity from a squared distance, which rounding may have
+/// pushed below zero.
+fn euclidean(squared_distance: f32) -> f32 {
+    1.0 / (1.0 + squared_distance.max(0.0).sqrt())
+}
+
+/// Appends the int8 codes of `row` to `codes` and returns the scale that
+/// maps them back, so the largest component becomes ±127.
+fn quantize_into(row: &[f32], codes: &mut Vec<i8>) -> f32 {
+    let max = row.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+    let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+    codes.extend(row.iter().map(|x| (x / scale).round() as i8));
+    scale
+}
+
+fn code_dot(a: &[i8], b: &[i8]) -> i32 {
+    a.iter()
+        .zip(b)
+        .map(|(&x, &y)| i32::from(x) * i32::from(y))
+        .sum()
+}
+
+/// Moves the `width`-long rows listed in `kept` to the front and drops the rest.
+fn compact<T: Copy>(values: &mut Vec<T>, width: usize, kept: &[usize]) {
+    for (to, &from) in kept.iter().enumerate() {
+        values.copy_within(from * width..(from + 1) * width, to * width);
+    }
+    values.truncate(kept.len() * width);
+}
+
+/// The dimension, the encoding and the row bytes of an embedding file.
+fn parse_header<'a>(data: &'a [u8], path: &Path) -> Result<(usize, u32, &'a [u8])> {
+    let Some(body) = data.strip_prefix(EMBEDDINGS_MAGIC) else {
+        bail!("{} is not an embedding file", path.display());
+    };
+    if body.len() < HEADER_LEN - EMBEDDINGS_MAGIC.len() {
+        bail!("Embedding file {} is truncated", path.display());
+    }
+    let dim = u32::from_le_bytes([body[0], body[1], body[2], body[3]]) as usize;
+    let encoding = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+    let rows = &body[HEADER_LEN - EMBEDDINGS_MAGIC.len()..];
+    let row_len = match encoding {
+        ENCODING_F32 => 4 * dim,
+        ENCODING_INT8 => 4 + dim,
+        _ => bail!(
+            "Embedding file {} has unknown encoding {}",
+            path.display(),
+            encoding
+        ),
+    };
+    if dim == 0 && !rows.is_empty() || dim > 0 && !rows.len().is_multiple_of(row_len) {
+        bail!("Embedding file {} is truncated", path.display());
+    }
+    Ok((dim, encoding, rows))
+}
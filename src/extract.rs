@@ -0,0 +1,480 @@
+use log::debug;
+use quick_xml::events::Event;
+use std::io::{Cursor, Read};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::Path;
+use thiserror::Error;
+
+/// Every PDF starts with this header, whatever its extension.
+const PDF_MAGIC: &[u8] = b"%PDF-";
+/// DOCX files are zip archives.
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/// How much of a file `is_binary` and `mime_type` look at.
+pub const SNIFF_LEN: usize = 8000;
+/// Leading bytes of common binary formats and their MIME types.
+const MAGIC_TYPES: &[(&[u8], &str)] = &[
+    (PDF_MAGIC, "application/pdf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"MZ", "application/x-msdownload"),
+    (b"\x00asm", "application/wasm"),
+    (b"SQLite format 3\x00", "application/vnd.sqlite3"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+];
+/// Elements whose content is never visible text.
+const HTML_SKIPPED: &[&str] = &["script", "style", "noscript", "template"];
+/// Elements that start a new line of text.
+const HTML_BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+#[derive(Error, Debug)]
+pub enum ExtractError {
+    #[error("failed to extract text from PDF: {0}")]
+    Pdf(String),
+    #[error("failed to extract text from DOCX: {0}")]
+    Docx(String),
+    #[error("content is not UTF-8 text")]
+    NotText,
+}
+
+/// How a file's content is turned into text, detected from its header and extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Pdf,
+    Docx,
+    Html,
+    Markdown,
+    Text,
+}
+
+impl Format {
+    pub fn detect(path: &Path, content: &[u8]) -> Self {
+        if content.starts_with(PDF_MAGIC) {
+            return Self::Pdf;
+        }
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("docx") if content.starts_with(ZIP_MAGIC) => Self::Docx,
+            Some("html" | "htm" | "xhtml") => Self::Html,
+            Some("md" | "markdown") => Self::Markdown,
+            _ if looks_like_html(content) => Self::Html,
+            _ => Self::Text,
+        }
+    }
+}
+
+/// MIME type of a file from its first bytes, falling back to its extension
+/// and then to `text/plain` or `application/octet-stream`. Only the formats
+/// ingestion commonly meets are told apart.
+pub fn mime_type(path: &Path, head: &[u8]) -> &'static str {
+    if let Some((_, mime)) = MAGIC_TYPES
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+    {
+        return mime;
+    }
+    if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        return "image/webp";
+    }
+    if head.get(4..8) == Some(b"ftyp") {
+        return "video/mp4";
+    }
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("docx") if head.starts_with(ZIP_MAGIC) => {
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        }
+        _ if head.starts_with(ZIP_MAGIC) => "application/zip",
+        Some("html" | "htm" | "xhtml") => "text/html",
+        Some("md" | "markdown") => "text/markdown",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("csv") => "text/csv",
+        Some("svg") => "image/svg+xml",
+        _ if looks_like_html(head) => "text/html",
+        _ if is_binary(head) => "application/octet-stream",
+        _ => "text/plain",
+    }
+}
+
+/// Whether content looks binary rather than text: like git, a NUL byte among
+/// the first `SNIFF_LEN` bytes decides it.
+pub fn is_binary(head: &[u8]) -> bool {
+    head[..head.len().min(SNIFF_LEN)].contains(&0)
+}
+
+/// A heading and where its section starts in the extracted text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// Titles of the enclosing headings and this one, e.g. `Setup > Linux`.
+    pub title: String,
+    pub start_byte: usize,
+}
+
+/// Clean text from a file, with the sections its headings divide it into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extracted {
+    pub text: String,
+    /// Ordered by `start_byte`.
+    pub sections: Vec<Section>,
+}
+
+impl Extracted {
+    fn plain(text: String) -> Self {
+        Self {
+            text,
+            sections: Vec::new(),
+        }
+    }
+
+    /// Title of the section containing byte `offset` of the text, if any.
+    pub fn section_at(&self, offset: usize) -> Option<&str> {
+        let after = self.sections.partition_point(|s| s.start_byte <= offset);
+        after
+            .checked_sub(1)
+            .map(|i| self.sections[i].title.as_str())
+    }
+}
+
+/// Returns the text to embed for `path` with `content`: markup is stripped,
+/// PDF and DOCX text is extracted, and anything else must already be UTF-8.
+pub fn extract(path: &Path, content: Vec<u8>) -> Result<Extracted, ExtractError> {
+    let format = Format::detect(path, &content);
+    debug!("Extracting {} as {:?}", path.display(), format);
+    match format {
+        Format::Pdf => pdf_text(&content).map(Extracted::plain),
+        Format::Docx => docx_text(&content),
+        Format::Html => Ok(html_text(&utf8(content)?)),
+        Format::Markdown => Ok(markdown_sections(utf8(content)?)),
+        Format::Text => utf8(content).map(Extracted::plain),
+    }
+}
+
+fn utf8(content: Vec<u8>) -> Result<String, ExtractError> {
+    String::from_utf8(content).map_err(|_| ExtractError::NotText)
+}
+
+fn looks_like_html(content: &[u8]) -> bool {
+    let head = &content[..content.len().min(256)];
+    let head = String::from_utf8_lossy(head)
+        .trim_start()
+        .to_ascii_lowercase();
+    head.starts_with("<!doctype html") || head.starts_with("<html")
+}
+
+fn pdf_text(content: &[u8]) -> Result<String, ExtractError> {
+    // The parser panics on some malformed files instead of returning an error.
+    let text = catch_unwind(AssertUnwindSafe(|| {
+        pdf_extract::extract_text_from_mem(content)
+    }))
+    .map_err(|_| ExtractError::Pdf("parser panicked".to_string()))?
+    .map_err(|e| ExtractError::Pdf(e.to_string()))?;
+    debug!(
+        "Extracted {} bytes of text from a {} byte PDF",
+        text.len(),
+        content.len()
+    );
+    Ok(text)
+}
+
+/// The headings enclosing the current position, from which section titles are built.
+#[derive(Default)]
+struct Outline {
+    open: Vec<(usize, String)>,
+    sections: Vec<Section>,
+}
+
+impl Outline {
+    fn heading(&mut self, level: usize, title: &str, start_byte: usize) {
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+        if title.is_empty() {
+            return;
+        }
+        self.open.retain(|(open_level, _)| *open_level < level);
+        self.open.push((level, title));
+        let path: Vec<&str> = self.open.iter().map(|(_, t)| t.as_str()).collect();
+        self.sections.push(Section {
+            title: path.join(" > "),
+            start_byte,
+        });
+    }
+}
+
+/// Markdown is embedded as written; only its ATX headings (`# Title`) are
+/// picked up as sections, outside fenced code blocks.
+fn markdown_sections(text: String) -> Extracted {
+    let mut outline = Outline::default();
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        match fence {
+            Some(marker) if trimmed.starts_with(marker) => fence = None,
+            Some(_) => {}
+            None if trimmed.starts_with("```") => fence = Some("```"),
+            None if trimmed.starts_with("~~~") => fence = Some("~~~"),
+            None => {
+                let level = trimmed.bytes().take_while(|&b| b == b'#').count();
+                let rest = &trimmed[level..];
+                if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with(' ')) {
+                    outline.heading(level, rest.trim().trim_end_matches('#'), offset);
+                }
+            }
+        }
+        offset += line.len();
+    }
+    Extracted {
+        text,
+        sections: outline.sections,
+    }
+}
+
+/// Strips tags, comments, scripts and styles, decodes entities and collapses
+/// whitespace the way a browser would, keeping block elements on their own lines.
+fn html_text(html: &str) -> Extracted {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut outline = Outline::default();
+    let mut heading: Option<(usize, usize)> = None;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        let Some(lt) = rest.find('<') else {
+            push_html_text(&mut text, rest);
+            break;
+        };
+        push_html_text(&mut text, &rest[..lt]);
+        rest = &rest[lt..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(gt) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[1..gt];
+        rest = &rest[gt + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        if !closing && HTML_SKIPPED.contains(&name.as_str()) {
+            let end_tag = format!("</{}", name);
+            rest = rest
+                .to_ascii_lowercase()
+                .find(&end_tag)
+                .map_or("", |end| &rest[end..]);
+            continue;
+        }
+        if HTML_BLOCKS.contains(&name.as_str()) {
+            new_line(&mut text);
+        }
+        let level = match name.as_bytes() {
+            [b'h', digit @ b'1'..=b'6'] => Some(usize::from(digit - b'0')),
+            _ => None,
+        };
+        match (level, closing) {
+            (Some(level), false) => heading = Some((level, text.len())),
+            (Some(_), true) => {
+                if let Some((level, start)) = heading.take() {
+                    outline.heading(level, &text[start..], start);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Nothing is pushed before the first word, so only the end needs trimming.
+    text.truncate(text.trim_end().len());
+    Extracted {
+        text,
+        sections: outline.sections,
+    }
+}
+
+fn push_html_text(text: &mut String, raw: &str) {
+    let decoded = decode_entities(raw);
+    let space = |text: &mut String| {
+        if !text.is_empty() && !text.ends_with([' ', '\n']) {
+            text.push(' ');
+        }
+    };
+    if decoded.starts_with(|c: char| c.is_ascii_whitespace()) {
+        space(text);
+    }
+    for (i, word) in decoded.split_ascii_whitespace().enumerate() {
+        if i > 0 {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    if decoded.ends_with(|c: char| c.is_ascii_whitespace()) {
+        space(text);
+    }
+}
+
+fn new_line(text: &mut String) {
+    while text.ends_with(' ') {
+        text.pop();
+    }
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Decodes named HTML entities and numeric character references.
+fn decode_entities(raw: &str) -> String {
+    let mut decoded = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 32)
+            .map(|end| &rest[1..end + 1]);
+        match (entity, entity.and_then(resolve_entity)) {
+            (Some(entity), Some(resolved)) => {
+                decoded.push_str(&resolved);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Resolves an entity name like `eacute` or `#x41`, without the `&` and `;`.
+fn resolve_entity(entity: &str) -> Option<String> {
+    let Some(number) = entity.strip_prefix('#') else {
+        return quick_xml::escape::resolve_html5_entity(entity).map(String::from);
+    };
+    let code = match number.strip_prefix(['x', 'X']) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => number.parse().ok(),
+    };
+    code.and_then(char::from_u32).map(String::from)
+}
+
+/// Reads the paragraphs of `word/document.xml`, taking `HeadingN` and
+/// `Title` paragraph styles as section headings.
+fn docx_text(content: &[u8]) -> Result<Extracted, ExtractError> {
+    let docx_error = |e: &dyn std::fmt::Display| ExtractError::Docx(e.to_string());
+    let mut archive = zip::ZipArchive::new(Cursor::new(content)).map_err(|e| docx_error(&e))?;
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")
+        .map_err(|e| docx_error(&e))?
+        .read_to_string(&mut xml)
+        .map_err(|e| docx_error(&e))?;
+
+    let mut reader = quick_xml::Reader::from_str(&xml);
+    let mut text = String::new();
+    let mut outline = Outline::default();
+    let mut paragraph_start = 0;
+    let mut heading_level = None;
+    let mut in_text = false;
+    loop {
+        match reader.read_event().map_err(|e| docx_error(&e))? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                "p" => {
+                    paragraph_start = text.len();
+                    heading_level = None;
+                }
+                "pStyle" => {
+                    let style = e
+                        .try_get_attribute("w:val")
+                        .ok()
+                        .flatten()
+                        .map(|a| a.value.into_owned())
+                        .unwrap_or_default();
+                    heading_level = match style.strip_prefix("Heading") {
+                        Some(level) => level.parse().ok(),
+                        None => (style == "Title").then_some(1),
+                    };
+                }
+                "t" => in_text = true,
+                "tab" => text.push('\t'),
+                "br" | "cr" => text.push('\n'),
+                _ => {}
+            },
+            Event::End(e) => match e.local_name().as_ref() {
+                "t" => in_text = false,
+                "p" => {
+                    if let Some(level) = heading_level.take() {
+                        let title = text[paragraph_start..].to_string();
+                        outline.heading(level, &title, paragraph_start);
+                    }
+                    text.push('\n');
+                }
+                _ => {}
+            },
+            Event::Text(t) if in_text => text.push_str(&t.into_inner()),
+            Event::GeneralRef(r) if in_text => {
+                text.extend(resolve_entity(&r.into_inner()));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(Extracted {
+        text,
+        sections: outline.sections,
+    })
+}
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+use crate::vector_store::cosine_similarity;
+
+const DEFAULT_M: usize = 16;
+const DEFAULT_EF_CONSTRUCTION: usize = 100;
+const DEFAULT_EF_SEARCH: usize = 128;
+
+/// Hierarchical Navigable Small World graph over vectors addressed by index.
+/// The index stores only the graph; vectors are looked up through a closure so
+/// the caller stays in charge of how embeddings are held.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HnswIndex {
+    m: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    entry_point: Option<usize>,
+    /// `neighbors[node][level]` lists the node's links on that level.
+    neighbors: Vec<Vec<Vec<usize>>>,
+    rng_state: u64,
+}
+
+impl Default for HnswIndex {
+    fn default() -> Self {
+        Self {
+            m: DEFAULT_M,
+            ef_construction: DEFAULT_EF_CONSTRUCTION,
+            ef_search: DEFAULT_EF_SEARCH,
+            entry_point: None,
+            neighbors: Vec::new(),
+            rng_state: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+}
+
+/// Candidate ordered by distance, ties broken by node so results are deterministic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then_with(|| self.node.cmp(&other.node))
+    }
+}
+
+impl HnswIndex {
+    /// Builds an index over `len` vectors.
+    pub fn build<V: AsRef<[f32]>>(len: usize, vector: impl Fn(usize) -> V) -> Self {
+        let mut index = Self::default();
+        for node in 0..len {
+            index.insert(node, &vector);
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.neighbors.is_empty()
+    }
+
+    /// Adds the next node; `node` must equal the current `len()`.
+    pub fn insert<V: AsRef<[f32]>>(&mut self, node: usize, vector: &impl Fn(usize) -> V) {
+        debug_assert_eq!(node, self.neighbors.len());
+        let level = self.random_level();
+        self.neighbors.push(vec![Vec::new(); level + 1]);
+
+        let Some(mut entry) = self.entry_point else {
+            self.entry_point = Some(node);
+            return;
+        };
+        let query = vector(node);
+        let query = query.as_ref();
+        let top_level = self.neighbors[entry].len() - 1;
+
+        for lc in (level + 1..=top_level).rev() {
+            entry = self.search_layer(query, &[entry], 1, lc, vector)[0].node;
+        }
+
+        let mut entries = vec![entry];
+        for lc in (0..=level.min(top_level)).rev() {
+            let found = self.search_layer(query, &entries, self.ef_construction, lc, vector);
+            let max_links = self.max_links(lc);
+            let selected: Vec<usize> = found.iter().take(self.m).map(|c| c.node).collect();
+
+            for &neighbor in &selected {
+                let links = &mut self.neighbors[neighbor][lc];
+                links.push(node);
+                if links.len() > max_links {
+                    let base = vector(neighbor);
+                    let mut ranked: Vec<Candidate> = links
+                        .iter()
+                        .map(|&n| Candidate {
+                            distance: distance(base.as_ref(), vector(n).as_ref()),
+                            node: n,
+                        })
+                        .collect();
+                    ranked.sort();
+                    *links = ranked.into_iter().take(max_links).map(|c| c.node).collect();
+                }
+            }
+            self.neighbors[node][lc] = selected;
+            entries = found.into_iter().map(|c| c.node).collect();
+        }
+
+        if level > top_level {
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// Approximate `k` nearest nodes to `query`, closest first, with their cosine similarity.
+    pub fn search<V: AsRef<[f32]>>(
+        &self,
+        query: &[f32],
+        k: usize,
+        vector: impl Fn(usize) -> V,
+    ) -> Vec<(usize, f32)> {
+        let Some(mut entry) = self.entry_point else {
+            return Vec::new();
+        };
+        let top_level = self.neighbors[entry].len() - 1;
+        for lc in (1..=top_level).rev() {
+            entry = self.search_layer(query, &[entry], 1, lc, &vector)[0].node;
+        }
+
+        self.search_layer(query, &[entry], self.ef_search.max(k), 0, &vector)
+            .into_iter()
+            .take(k)
+            .map(|c| (c.node, 1.0 - c.distance))
+            .collect()
+    }
+
+    /// Best-first search of one layer, returning up to `ef` candidates closest first.
+    fn search_layer<V: AsRef<[f32]>>(
+        &self,
+        query: &[f32],
+        entries: &[usize],
+        ef: usize,
+        level: usize,
+        vector: &impl Fn(usize) -> V,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().copied().collect();
+        let mut candidates = BinaryHeap::new();
+        let mut results = BinaryHeap::new();
+        for &node in entries {
+            let candidate = Candidate {
+                distance: distance(query, vector(node).as_ref()),
+                node,
+            };
+            candidates.push(Reverse(candidate));
+            results.push(candidate);
+        }
+
+        while let Some(Reverse(current)) = candidates.pop() {
+            if results
+                .peek()
+                .is_some_and(|worst| results.len() >= ef && current.distance > worst.distance)
+            {
+                break;
+            }
+            for &neighbor in self.neighbors[current.node]
+                .get(level)
+                .into_iter()
+                .flatten()
+            {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let candidate = Candidate {
+                    distance: distance(query, vector(neighbor).as_ref()),
+                    node: neighbor,
+                };
+                if results.len() < ef || results.peek().is_some_and(|worst| candidate < *worst) {
+                    candidates.push(Reverse(candidate));
+                    results.push(candidate);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    fn max_links(&self, level: usize) -> usize {
+        if level == 0 { self.m * 2 } else { self.m }
+    }
+
+    /// Draws a level from the exponential distribution with mL = 1/ln(M), using
+    /// a fixed-seed xorshift so rebuilding the same entries yields the same graph.
+    fn random_level(&mut self) -> usize {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+
+        let uniform = ((x >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level = -uniform.ln() / (self.m as f64).ln();
+        level as usize
+    }
+}
+
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    1.0 - cosine_similarity(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random vectors.
+    fn vectors(count: usize, dims: usize) -> Vec<Vec<f32>> {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        (0..count)
+            .map(|_| {
+                (0..dims)
+                    .map(|_| {
+                        state = state
+                            .wrapping_mul(6_364_136_223_846_793_005)
+                            .wrapping_add(1_442_695_040_888_963_407);
+                        (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn search_finds_the_exact_nearest_neighbours() {
+        let points = vectors(500, 8);
+        let index = HnswIndex::build(points.len(), |i| points[i].as_slice());
+        assert_eq!(index.len(), points.len());
+
+        for query in vectors(20, 8) {
+            let mut exact: Vec<(usize, f32)> = points
+                .iter()
+                .enumerate()
+                .map(|(i, point)| (i, cosine_similarity(&query, point)))
+                .collect();
+            exact.sort_by(|a, b| b.1.total_cmp(&a.1));
+            let found = index.search(&query, 5, |i| points[i].as_slice());
+            let found: Vec<usize> = found.into_iter().map(|(i, _)| i).collect();
+            let expected: Vec<usize> = exact.iter().take(5).map(|&(i, _)| i).collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn a_point_is_its_own_nearest_neighbour() {
+        let points = vectors(300, 4);
+        let index = HnswIndex::build(points.len(), |i| points[i].as_slice());
+        for (i, point) in points.iter().enumerate() {
+            let found = index.search(point, 1, |i| points[i].as_slice());
+            assert_eq!(found[0].0, i);
+            assert!((found[0].1 - 1.0).abs() < 1e-5);
+        }
+    }
+}
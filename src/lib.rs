@@ -0,0 +1,31 @@
+pub mod archive;
+pub mod backend;
+pub mod bert;
+pub mod bm25;
+pub mod checkpoint;
+pub mod chunk;
+pub mod compress;
+pub mod config;
+pub mod crypt;
+pub mod digest;
+pub mod embeddings;
+pub mod extract;
+pub mod hnsw;
+pub mod lock;
+pub mod mcp;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod patch;
+pub mod pipeline;
+pub mod process;
+pub mod progress;
+pub mod project;
+pub mod rerank;
+pub mod server;
+pub mod shutdown;
+pub mod snippet;
+pub mod storage;
+pub mod sync;
+pub mod vector_store;
+pub mod wal;
+pub mod watch;
@@ -0,0 +1,79 @@
+use eyre::{Context, Result};
+use log::debug;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Lock file in the memory directory, holding the PID of its owner.
+pub const LOCK_FILE: &str = ".lock";
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("{} is in use by another ouroboros process{}", .dir.display(), match .pid {
+        Some(pid) => format!(" (pid {pid})"),
+        None => String::new(),
+    })]
+    Held { dir: PathBuf, pid: Option<u32> },
+}
+
+/// Exclusive advisory lock on a memory directory, held by whatever changes
+/// it so that two processes don't clobber each other's histories and vector
+/// stores. Released when dropped; the OS releases it if the process dies, so
+/// a lock is never left stale.
+#[derive(Debug)]
+pub struct MemoryLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl MemoryLock {
+    /// Takes the lock on `memory_dir`, creating the directory if needed.
+    /// Fails at once with `LockError::Held` if another process holds it.
+    pub fn acquire(memory_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(memory_dir)
+            .wrap_err_with(|| format!("Failed to create {}", memory_dir.display()))?;
+        let path = memory_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut owner = String::new();
+                let pid = file
+                    .read_to_string(&mut owner)
+                    .ok()
+                    .and_then(|_| owner.trim().parse().ok());
+                return Err(LockError::Held {
+                    dir: memory_dir.to_path_buf(),
+                    pid,
+                }
+                .into());
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).wrap_err_with(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        debug!("Locked {}", path.display());
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        // Leave the file for the next owner; removing it would race with one
+        // that already opened it.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+        debug!("Unlocked {}", self.path.display());
+    }
+}
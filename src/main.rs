@@ -1,10 +1,353 @@
-mod process;
-mod storage;
-
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use eyre::Result;
-use log::{error, info, warn};
-use process::Processor;
-use storage::FileStorage;
+use log::{debug, error, info, warn};
+use ouroboros::backend::BackendKind;
+use ouroboros::bert::Pooling;
+use ouroboros::config::Config;
+use ouroboros::digest::{DeviceChoice, DigestPatterns, Digester, DigesterConfig, LongInput};
+use ouroboros::lock::{LOCK_FILE, MemoryLock};
+use ouroboros::mcp::McpServer;
+use ouroboros::pipeline::{self, Orchestrator};
+use ouroboros::process::{ProcessMode, ProcessSummary, Processor, RetentionPolicy};
+use ouroboros::project::Project;
+use ouroboros::rerank::{DEFAULT_RERANK_MODEL, Reranker, RerankerConfig};
+use ouroboros::snippet::{self, DEFAULT_SNIPPET_CHARS};
+use ouroboros::storage::FileStorage;
+use ouroboros::vector_store::{
+    DEFAULT_RERANK_TOP_N, DEFAULT_SEARCH_LIMIT, Label, Labels, MergeStrategy, Metric, SearchFilter,
+    SearchOptions, VectorEntry, VectorStore,
+};
+use ouroboros::{archive, crypt, server, shutdown, sync, watch};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(
+    name = "ouroboros",
+    version,
+    about = "Parallel versioned storage with semantic memory"
+)]
+struct Cli {
+    /// Config file to read instead of ./ouroboros.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Directory holding intermediate memory and the vector store
+    /// [default: ./memory if it exists, else the platform data directory]
+    #[arg(long, global = true)]
+    memory_dir: Option<PathBuf>,
+    /// Device for the embedding model: auto, cpu, cuda[:N] or metal[:N]
+    #[arg(long, global = true)]
+    device: Option<DeviceChoice>,
+    /// Where embeddings are computed: local, openai, ollama or onnx
+    #[arg(long, global = true)]
+    backend: Option<BackendKind>,
+    /// Base URL of the remote backend's API [default: OpenAI's, or Ollama on localhost]
+    #[arg(long, global = true)]
+    backend_url: Option<String>,
+    /// Hugging Face repo of the embedding model, or its name on a remote backend
+    #[arg(long, global = true)]
+    model: Option<String>,
+    /// Revision (branch, tag or commit) of the embedding model
+    #[arg(long, global = true)]
+    model_revision: Option<String>,
+    /// Load the embedding model from this local directory instead of the hub
+    #[arg(long, global = true)]
+    model_path: Option<PathBuf>,
+    /// Pooling of token embeddings: mean or cls (default: the model's own setting)
+    #[arg(long, global = true)]
+    pooling: Option<Pooling>,
+    /// L2-normalize embeddings so searches can score by dot product, as if
+    /// `normalize = true` were in the config
+    #[arg(long, global = true)]
+    normalize: bool,
+    /// Encrypt memory with the 32-byte key in this file (raw or hex)
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+    /// Vector store collection to digest into and search, e.g. code or notes
+    #[arg(long, global = true)]
+    collection: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a .ouroboros/ project with its own config, memory and ignore file
+    Init {
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Version files into intermediate memory (reads ingest.txt when no paths are given)
+    Ingest {
+        paths: Vec<PathBuf>,
+        /// Only report which files changed, without hashing or storing anything
+        #[arg(long, conflicts_with = "dry_run")]
+        scan: bool,
+        /// Compute diffs for changed files but don't store them
+        #[arg(long)]
+        dry_run: bool,
+        /// Also embed the new versions into the vector store
+        #[arg(long, conflicts_with_all = ["scan", "dry_run"])]
+        digest: bool,
+        /// Tag the embedded files with a name or key=value, replacing their earlier tags
+        #[arg(long, requires = "digest")]
+        tag: Vec<Label>,
+        #[command(flatten)]
+        filters: PathFilters,
+    },
+    /// Embed versioned files into the vector store
+    Digest {
+        /// Embed these files and directories as they are on disk instead of
+        /// what intermediate memory tracks
+        paths: Vec<PathBuf>,
+        /// Only embed files whose original path matches one of these globs
+        #[arg(long)]
+        include: Vec<String>,
+        /// Never embed files whose original path matches one of these globs
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Tag the embedded files with a name or key=value, replacing their earlier tags
+        #[arg(long)]
+        tag: Vec<Label>,
+    },
+    /// Search the vector store with a natural-language query
+    Search {
+        query: String,
+        #[arg(short, long, default_value_t = DEFAULT_SEARCH_LIMIT)]
+        limit: usize,
+        /// Skip this many results first, to page through them with --limit
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Only return chunks of files whose path matches one of these globs
+        #[arg(long)]
+        path: Vec<String>,
+        /// Only return chunks digested on or after this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_time)]
+        since: Option<u64>,
+        /// Only return chunks digested before this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_time)]
+        until: Option<u64>,
+        /// Search what the memory held at this time, including since superseded versions
+        #[arg(long, value_parser = parse_time, conflicts_with = "all_versions")]
+        as_of: Option<u64>,
+        /// Search superseded versions kept with keep_archived along with current ones
+        #[arg(long)]
+        all_versions: bool,
+        /// Only return chunks with this tag or key=value; repeat to require several
+        #[arg(long)]
+        tag: Vec<Label>,
+        /// Drop results scoring below this, judged after reranking if that is on
+        #[arg(long)]
+        min_score: Option<f32>,
+        /// Share of the score from keyword matches, 0 for pure semantic search (default: from config)
+        #[arg(long)]
+        keyword_weight: Option<f32>,
+        /// Rescore the top hits with a cross-encoder for better precision
+        #[arg(long)]
+        rerank: bool,
+        /// Hits handed to the cross-encoder
+        #[arg(long, default_value_t = DEFAULT_RERANK_TOP_N)]
+        rerank_top_n: usize,
+        /// Hugging Face repo or local directory of the cross-encoder
+        #[arg(long, default_value = DEFAULT_RERANK_MODEL)]
+        rerank_model: String,
+        /// Diversify results: 1 ranks by relevance alone, lower values favour variety (e.g. 0.5)
+        #[arg(long)]
+        mmr_lambda: Option<f32>,
+        /// Score by cosine, dot or euclidean similarity (default: the store's metric)
+        #[arg(long)]
+        metric: Option<Metric>,
+        /// List each matching file once, with its matching chunks under it
+        #[arg(long)]
+        by_file: bool,
+        /// Highlight query terms in snippets: auto (when printing to a terminal), always or never
+        #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+        color: ColorChoice,
+    },
+    /// List the stored versions of a file
+    History { file: PathBuf },
+    /// Reconstruct a stored version of a file (printed to stdout unless --output is given)
+    Restore {
+        file: PathBuf,
+        version: u32,
+        /// Write the version here, with its recorded permissions and mtime
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Show the changes between two stored versions of a file
+    Diff {
+        file: PathBuf,
+        from: u32,
+        to: u32,
+        /// Color added and removed lines: auto (when printing to a terminal), always or never
+        #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+        color: ColorChoice,
+    },
+    /// Report tracked files that changed since they were last ingested
+    Status {
+        /// List unchanged files too
+        #[arg(long)]
+        all: bool,
+    },
+    /// Erase files from intermediate memory and every vector store collection
+    Forget {
+        /// Files, or directories whose tracked files all go
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Only list what would be erased
+        #[arg(long)]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Prune old versions according to the retention policy and drop unreferenced blobs
+    Gc {
+        /// Keep the newest N versions of each file (overrides keep_versions)
+        #[arg(long)]
+        keep: Option<usize>,
+        /// Keep versions younger than N days (overrides keep_days)
+        #[arg(long)]
+        keep_days: Option<u64>,
+    },
+    /// List the vector store collections and their sizes
+    Collections,
+    /// Check intermediate memory and the vector stores for corruption and leftovers
+    Verify {
+        /// Remove orphaned directories and vector entries of files that are gone
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Report what memory holds and where its disk space goes
+    Stats {
+        /// How many of the largest files to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Store embeddings as int8 with a scale per vector, about 4x smaller (irreversible)
+    Quantize,
+    /// Embed every entry again with the configured model, swapping the new
+    /// embeddings in only once all are done
+    ReEmbed,
+    /// Encrypt memory written before a key was configured; until then it is
+    /// refused
+    Encrypt,
+    /// Write every vector entry to a JSON Lines file, or all of memory to an archive
+    Export {
+        #[arg(required_unless_present = "output")]
+        path: Option<PathBuf>,
+        /// Bundle intermediate memory and every collection into this .tar.zst instead
+        #[arg(long, conflicts_with = "path")]
+        output: Option<PathBuf>,
+    },
+    /// Merge vector entries, or a whole memory archive, written by export
+    Import {
+        path: PathBuf,
+        /// What to do with entries and files already stored: skip, overwrite or replace (drop all first)
+        #[arg(long, default_value = "skip")]
+        merge: MergeStrategy,
+    },
+    /// Copy memory to or from the S3 bucket in sync_url, transferring only what changed
+    Sync {
+        #[command(subcommand)]
+        direction: SyncDirection,
+    },
+    /// Keep versioning files as they change (reads ingest.txt when no paths are given)
+    Watch {
+        paths: Vec<PathBuf>,
+        /// Also embed each new version into the vector store
+        #[arg(long)]
+        digest: bool,
+        /// Quiet period before a burst of changes to a file is processed
+        #[arg(long, default_value_t = 500)]
+        debounce_ms: u64,
+        #[command(flatten)]
+        filters: PathFilters,
+    },
+    /// Serve memory to LLM clients as a Model Context Protocol server on stdio
+    Mcp,
+    /// Serve ingest, digest, search and history over HTTP
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:7878")]
+        addr: SocketAddr,
+    },
+}
+
+/// Globs scoping which files under the given paths are versioned.
+#[derive(Args, Debug)]
+struct PathFilters {
+    /// Only version files matching one of these globs
+    #[arg(long)]
+    include: Vec<String>,
+    /// Never version files matching one of these globs
+    #[arg(long)]
+    exclude: Vec<String>,
+}
+
+impl Command {
+    /// Whether the command changes intermediate memory or a vector store.
+    fn mutates_memory(&self) -> bool {
+        match self {
+            Command::Ingest { scan, dry_run, .. } => !scan && !dry_run,
+            Command::Forget { dry_run, .. } => !dry_run,
+            Command::Verify { repair } => *repair,
+            Command::Digest { .. }
+            | Command::Gc { .. }
+            | Command::Quantize
+            | Command::ReEmbed
+            | Command::Encrypt
+            | Command::Import { .. } => true,
+            // Pushes and archives hold the lock too, for a consistent snapshot.
+            Command::Sync { .. }
+            | Command::Export {
+                output: Some(_), ..
+            } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum SyncDirection {
+    /// Make the bucket mirror local memory
+    Push {
+        /// Only report what would be uploaded
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Download what the bucket has and local memory doesn't
+    Pull {
+        /// Also delete local files the bucket doesn't have
+        #[arg(long)]
+        delete: bool,
+        /// Only report what would be downloaded
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                use std::io::IsTerminal;
+                std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -36,27 +379,1047 @@ async fn main() -> Result<()> {
         })
         .init();
 
-    info!("Starting Parallel Versioned Storage...");
+    let cli = Cli::parse();
+    shutdown::install();
+    if let Err(e) = run(cli).await {
+        error!("Fatal error: {:?}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-    let mut storage = FileStorage::new();
+async fn run(cli: Cli) -> Result<()> {
+    // Command-line flags win over the environment, which wins over the config file.
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(memory_dir) = cli.memory_dir {
+        config.memory_dir = memory_dir;
+    }
+    if let Some(device) = cli.device {
+        config.device = device;
+    }
+    if let Some(backend) = cli.backend {
+        config.backend = backend;
+    }
+    if let Some(backend_url) = cli.backend_url {
+        config.backend_url = Some(backend_url);
+    }
+    if let Some(model) = cli.model {
+        config.model = model;
+    }
+    if let Some(revision) = cli.model_revision {
+        config.revision = Some(revision);
+    }
+    if let Some(model_path) = cli.model_path {
+        config.model_path = Some(model_path);
+    }
+    if let Some(pooling) = cli.pooling {
+        config.pooling = Some(pooling);
+    }
+    if cli.normalize {
+        config.normalize = true;
+    }
+    if let Some(key_file) = cli.key_file {
+        config.key_file = Some(key_file);
+    }
+    if let Some(collection) = cli.collection {
+        config.collection = collection;
+    }
+    debug!("Memory directory is {}", config.memory_dir.display());
+    if let Some(key) = config.encryption_key()? {
+        crypt::install(key);
+        debug!("Encryption at rest enabled");
+    }
+    let digester_config = config.digester();
 
-    if let Ok(content) = tokio::fs::read_to_string("ingest.txt").await {
-        for line in content.lines() {
-            let path = line.trim();
-            if !path.is_empty() && !path.starts_with('#') {
-                storage.add(path).await;
+    // Held until the command is done; the servers lock per request instead.
+    let _lock = match cli.command.mutates_memory() {
+        true => Some(MemoryLock::acquire(&config.memory_dir)?),
+        false => None,
+    };
+    match cli.command {
+        Command::Ingest {
+            paths,
+            scan,
+            dry_run,
+            digest,
+            tag,
+            filters,
+        } => {
+            let mode = if scan {
+                ProcessMode::ScanOnly
+            } else if dry_run {
+                ProcessMode::DryRun
+            } else {
+                ProcessMode::Full
+            };
+            let digester_config = digest.then_some(digester_config);
+            ingest(&config, paths, &filters, mode, digester_config, labels(tag)).await
+        }
+        Command::Digest {
+            paths,
+            include,
+            exclude,
+            tag,
+        } => {
+            let labels = labels(tag);
+            digest(&config, digester_config, paths, &include, &exclude, labels).await
+        }
+        Command::Search {
+            query,
+            limit,
+            offset,
+            path,
+            since,
+            until,
+            as_of,
+            all_versions,
+            tag,
+            min_score,
+            keyword_weight,
+            rerank,
+            rerank_top_n,
+            rerank_model,
+            mmr_lambda,
+            metric,
+            by_file,
+            color,
+        } => {
+            let path: Vec<_> = path.iter().map(String::as_str).collect();
+            let options = SearchOptions {
+                filter: SearchFilter::new()
+                    .with_paths(&path)?
+                    .digested_between(since, until)
+                    .as_of(as_of)
+                    .with_all_versions(all_versions)
+                    .with_labels(tag.into_iter().collect()),
+                min_score,
+                offset,
+                limit,
+                keyword_weight: keyword_weight.unwrap_or(config.keyword_weight),
+                rerank,
+                rerank_top_n,
+                mmr_lambda,
+                metric,
+            };
+            let reranker_config = rerank.then(|| RerankerConfig {
+                device: config.device,
+                model: rerank_model,
+                ..Default::default()
+            });
+            search(
+                &config,
+                digester_config,
+                reranker_config,
+                &query,
+                &options,
+                by_file,
+                color.enabled(),
+            )
+        }
+        Command::History { file } => history(&config, &file).await,
+        Command::Restore {
+            file,
+            version,
+            output,
+        } => restore(&config, &file, version, output.as_deref()).await,
+        Command::Diff {
+            file,
+            from,
+            to,
+            color,
+        } => diff(&config, &file, from, to, color).await,
+        Command::Status { all } => status(&config, all).await,
+        Command::Forget {
+            paths,
+            dry_run,
+            yes,
+        } => forget(&config, &paths, dry_run, yes).await,
+        Command::Gc { keep, keep_days } => {
+            let mut policy = config.retention();
+            if keep.is_some() {
+                policy.keep_last = keep;
             }
+            if keep_days.is_some() {
+                policy.max_age_days = keep_days;
+            }
+            gc(&config, &policy).await
+        }
+        Command::Init { path } => init(&path),
+        Command::Collections => collections(&config),
+        Command::Stats { top } => stats(&config, top).await,
+        Command::Verify { repair } => verify(&config, repair).await,
+        Command::Quantize => quantize(&config),
+        Command::ReEmbed => re_embed(&config, digester_config).await,
+        Command::Encrypt => encrypt(&config),
+        Command::Export { path, output } => match output {
+            Some(output) => export_archive(&config, &output),
+            None => export(&config, &path.expect("clap requires a path or --output")),
+        },
+        Command::Import { path, merge } if archive::is_archive(&path) => {
+            import_archive(&config, &path, merge).await
+        }
+        Command::Import { path, merge } => import(&config, &path, merge),
+        Command::Sync { direction } => sync(&config, direction),
+        Command::Watch {
+            paths,
+            digest,
+            debounce_ms,
+            filters,
+        } => {
+            let digester_config = digest.then_some(digester_config);
+            watch(
+                &config,
+                paths,
+                &filters,
+                digester_config,
+                Duration::from_millis(debounce_ms),
+            )
+            .await
+        }
+        Command::Mcp => {
+            let digester = Digester::with_config(digester_config)?
+                .with_chunking(config.chunking())
+                .with_archived(config.keep_archived);
+            McpServer::new(config, digester)?.serve_stdio().await
+        }
+        Command::Serve { addr } => {
+            let digester = Digester::with_config(digester_config)?
+                .with_chunking(config.chunking())
+                .with_archived(config.keep_archived);
+            server::serve(config, digester, addr).await
+        }
+    }
+}
+
+/// Parses an RFC 3339 timestamp, or a date meaning local midnight, into
+/// milliseconds since the Unix epoch.
+fn parse_time(s: &str) -> Result<u64, String> {
+    let time = match chrono::DateTime::parse_from_rfc3339(s) {
+        Ok(time) => time.timestamp_millis(),
+        Err(_) => chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| {
+                date.and_time(chrono::NaiveTime::MIN)
+                    .and_local_timezone(chrono::Local)
+                    .earliest()
+            })
+            .ok_or_else(|| format!("expected YYYY-MM-DD or an RFC 3339 timestamp, got {s:?}"))?
+            .timestamp_millis(),
+    };
+    u64::try_from(time).map_err(|_| format!("{s} is before 1970"))
+}
+
+/// The paths given on the command line, or those listed in ingest.txt.
+async fn ingest_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    if !paths.is_empty() {
+        return paths;
+    }
+    match tokio::fs::read_to_string("ingest.txt").await {
+        Ok(content) => content
+            .lines()
+            .map(str::trim)
+            .filter(|path| !path.is_empty() && !path.starts_with('#'))
+            .map(PathBuf::from)
+            .collect(),
+        Err(_) => {
+            warn!("No paths given and ingest.txt not found or unreadable, nothing to ingest");
+            Vec::new()
         }
+    }
+}
+
+fn file_storage(config: &Config, filters: &PathFilters) -> Result<FileStorage> {
+    config
+        .file_storage()?
+        .with_filters(&filters.include, &filters.exclude)
+}
+
+async fn ingest(
+    config: &Config,
+    paths: Vec<PathBuf>,
+    filters: &PathFilters,
+    mode: ProcessMode,
+    digester_config: Option<DigesterConfig>,
+    labels: Option<Labels>,
+) -> Result<()> {
+    info!("Starting Parallel Versioned Storage...");
+    let digester = match digester_config {
+        Some(digester_config) => Some(
+            Digester::with_config(digester_config)?
+                .with_chunking(config.chunking())
+                .with_archived(config.keep_archived)
+                .with_labels(labels),
+        ),
+        None => None,
+    };
+    let mut orchestrator = Orchestrator::new(config)
+        .with_filters(&filters.include, &filters.exclude)
+        .with_mode(mode);
+    if let Some(digester) = &digester {
+        orchestrator = orchestrator.with_digester(digester, DigestPatterns::default());
+    }
+
+    let mut store = VectorStore::load(config.vector_store_path()?)?;
+    let summary = orchestrator
+        .run(ingest_paths(paths).await, &mut store)
+        .await?;
+    print_summary(&summary.process);
+    match summary.digest {
+        Some(_) => info!("Vector store holds {} entries", store.len()),
+        None => log_invalidated(summary.invalidated),
+    }
+    Ok(())
+}
+
+/// Drops the vector entries of files that just got a new version from every
+/// collection but `except`, so search never returns their old content. The
+/// next digest re-embeds them.
+fn invalidate_modified(config: &Config, modified: &[PathBuf], except: Option<&str>) -> Result<()> {
+    log_invalidated(pipeline::invalidate_modified(config, modified, except)?);
+    Ok(())
+}
+
+fn log_invalidated(removed: usize) {
+    if removed > 0 {
+        info!(
+            "Invalidated {} vector entries of modified files, run `ouroboros digest` to re-embed them",
+            removed
+        );
+    }
+}
+
+/// The labels of `--tag` flags, `None` when there were none so files keep theirs.
+fn labels(tags: Vec<Label>) -> Option<Labels> {
+    (!tags.is_empty()).then(|| tags.into_iter().collect())
+}
+
+async fn digest(
+    config: &Config,
+    digester_config: DigesterConfig,
+    paths: Vec<PathBuf>,
+    include: &[String],
+    exclude: &[String],
+    labels: Option<Labels>,
+) -> Result<()> {
+    let mut vector_store = VectorStore::load(config.vector_store_path()?)?;
+    let digester = Digester::with_config(digester_config)?
+        .with_chunking(config.chunking())
+        .with_archived(config.keep_archived)
+        .with_labels(labels);
+    if paths.is_empty() {
+        let include: Vec<_> = include.iter().map(String::as_str).collect();
+        let exclude: Vec<_> = exclude.iter().map(String::as_str).collect();
+        let patterns = DigestPatterns::new(&include, &exclude)?;
+        digester.digest_all(
+            &config.memory_dir,
+            &mut vector_store,
+            &patterns,
+            &shutdown::token(),
+        )?;
     } else {
-        warn!("ingest.txt not found or unreadable, skipping initial ingestion");
+        let mut storage = config.file_storage()?.with_filters(include, exclude)?;
+        for path in paths {
+            storage.add(path).await;
+        }
+        digester.digest_storage(&storage, &mut vector_store, &shutdown::token())?;
     }
 
-    info!("Collected {} unique files", storage.len());
+    info!("Vector store holds {} entries", vector_store.len());
+    Ok(())
+}
+
+fn search(
+    config: &Config,
+    digester_config: DigesterConfig,
+    reranker_config: Option<RerankerConfig>,
+    query: &str,
+    options: &SearchOptions,
+    by_file: bool,
+    color: bool,
+) -> Result<()> {
+    let vector_store = VectorStore::load(config.vector_store_path()?)?;
+    if vector_store.is_empty() {
+        println!("Vector store is empty, run `ouroboros digest` first.");
+        return Ok(());
+    }
 
-    if let Err(e) = Processor::process_all(storage.paths()).await {
-        error!("Fatal error during processing: {:?}", e);
-        std::process::exit(1);
+    let mut digester = Digester::with_config(digester_config)?;
+    if let Some(reranker_config) = reranker_config {
+        digester = digester.with_reranker(Reranker::with_config(reranker_config)?);
+    }
+    if by_file {
+        for file in vector_store.search_files(&digester, query, options)? {
+            let chunks = file.chunks.len();
+            println!(
+                "{:.4}  {}  {} matching chunk{}",
+                file.score,
+                source(file.best()),
+                chunks,
+                if chunks == 1 { "" } else { "s" }
+            );
+            for (entry, score) in &file.chunks {
+                println!(
+                    "        {:.4} {}  {}",
+                    score,
+                    location(entry),
+                    snippet(entry, query, color)
+                );
+            }
+        }
+        return Ok(());
+    }
+    for (entry, score) in vector_store.search_text(&digester, query, options)? {
+        println!(
+            "{:.4}  {}{}  {}",
+            score,
+            source(entry),
+            location(entry),
+            snippet(entry, query, color)
+        );
+    }
+    Ok(())
+}
+
+/// The passage of a hit best matching `query`, its terms in bold with `color`.
+fn snippet(entry: &VectorEntry, query: &str, color: bool) -> String {
+    let snippet = snippet::extract(entry.text(), query, DEFAULT_SNIPPET_CHARS);
+    match color {
+        true => snippet.marked("\x1b[1m", "\x1b[0m"),
+        false => snippet.text,
+    }
+}
+
+/// The file a search hit comes from.
+fn source(entry: &VectorEntry) -> String {
+    match &entry.source_path {
+        Some(path) => path.display().to_string(),
+        None => entry.file_name.clone(),
+    }
+}
+
+/// Where in its file a search hit is: lines, version, section and symbol.
+fn location(entry: &VectorEntry) -> String {
+    let mut location = String::new();
+    if entry.span.start_line > 0 {
+        location += &format!(":{}-{}", entry.span.start_line, entry.span.end_line);
+    }
+    match (entry.version, entry.stored_on()) {
+        (Some(version), Some(date)) => location += &format!(" (v{version}, stored {date})"),
+        (Some(version), None) => location += &format!(" (v{version})"),
+        _ => {}
+    }
+    if let Some(section) = &entry.section {
+        location += &format!(" > {section}");
+    }
+    if let Some(symbol) = &entry.symbol {
+        location += &format!(" ({symbol})");
+    }
+    for tag in &entry.tags {
+        location += &format!(" #{tag}");
+    }
+    for (key, value) in &entry.metadata {
+        location += &format!(" #{key}={value}");
+    }
+    if entry.is_archived() {
+        location += " [archived]";
+    }
+    location
+}
+
+async fn watch(
+    config: &Config,
+    paths: Vec<PathBuf>,
+    filters: &PathFilters,
+    digester_config: Option<DigesterConfig>,
+    debounce: Duration,
+) -> Result<()> {
+    let paths = ingest_paths(paths).await;
+    let storage = file_storage(config, filters)?;
+    let digester = match digester_config {
+        Some(digester_config) => Some(
+            Digester::with_config(digester_config)?
+                .with_chunking(config.chunking())
+                .with_archived(config.keep_archived),
+        ),
+        None => None,
+    };
+
+    let processor_config = config.processor();
+    let cancel = shutdown::token();
+    watch::watch(
+        &paths,
+        &storage,
+        &processor_config,
+        debounce,
+        &cancel,
+        |summary| {
+            let Some(digester) = &digester else {
+                return invalidate_modified(config, &summary.modified, None);
+            };
+            invalidate_modified(config, &summary.modified, Some(&config.collection))?;
+            // Other commands may have changed the store since the last batch,
+            // while the lock was free.
+            let mut store = VectorStore::load(config.vector_store_path()?)?;
+            let changed: HashSet<&Path> = summary
+                .new
+                .iter()
+                .chain(&summary.modified)
+                .map(PathBuf::as_path)
+                .collect();
+            for tracked in Processor::tracked_files(&config.memory_dir)? {
+                if !changed.contains(tracked.original_path.as_path()) {
+                    continue;
+                }
+                if let Err(e) = digester.digest_tracked(&tracked, &mut store) {
+                    warn!(
+                        "Failed to digest {}: {:?}",
+                        tracked.original_path.display(),
+                        e
+                    );
+                }
+            }
+            Ok(())
+        },
+    )
+    .await
+}
+
+async fn history(config: &Config, file: &Path) -> Result<()> {
+    let history = Processor::history(&config.memory_dir, file).await?;
+    let embedded = VectorStore::load(config.vector_store_path()?)?
+        .embedded_versions(Path::new(&history.original_path));
+    println!("{} ({})", history.original_path, history.alias);
+    for version in &history.versions {
+        println!(
+            "  v{:<4} {}  {:>10} bytes  {}{}{}",
+            version.version,
+            version.processed_at,
+            version.size,
+            &version.hash[..12.min(version.hash.len())],
+            version
+                .diff_file
+                .as_deref()
+                .map(|d| format!("  {d}"))
+                .unwrap_or_default(),
+            embedded
+                .get(&version.version)
+                .map(|chunks| format!("  [{chunks} chunks embedded]"))
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+async fn restore(config: &Config, file: &Path, version: u32, output: Option<&Path>) -> Result<()> {
+    match output {
+        Some(dest) => Processor::restore_to(&config.memory_dir, file, version, dest).await,
+        None => {
+            use std::io::Write;
+            let content = Processor::restore(&config.memory_dir, file, version).await?;
+            std::io::stdout().write_all(&content)?;
+            Ok(())
+        }
+    }
+}
+
+async fn diff(config: &Config, file: &Path, from: u32, to: u32, color: ColorChoice) -> Result<()> {
+    let diff = Processor::diff(&config.memory_dir, file, from, to).await?;
+    if diff.is_empty() {
+        println!("v{from} and v{to} are identical");
+        return Ok(());
+    }
+    let color = color.enabled();
+    for line in diff.lines() {
+        let style = match line.as_bytes().first() {
+            _ if !color => None,
+            _ if line.starts_with("+++") || line.starts_with("---") => Some("1"),
+            Some(b'+') => Some("32"),
+            Some(b'-') => Some("31"),
+            Some(b'@') => Some("36"),
+            _ => None,
+        };
+        match style {
+            Some(style) => println!("\x1b[{style}m{line}\x1b[0m"),
+            None => println!("{line}"),
+        }
+    }
+    Ok(())
+}
+
+async fn forget(config: &Config, paths: &[PathBuf], dry_run: bool, yes: bool) -> Result<()> {
+    let mut files = Vec::new();
+    for path in paths {
+        let tracked = Processor::tracked_under(&config.memory_dir, path).await?;
+        if tracked.is_empty() {
+            warn!("{} is not tracked", path.display());
+        }
+        files.extend(tracked);
+    }
+    files.sort_by(|a, b| a.alias.cmp(&b.alias));
+    files.dedup_by(|a, b| a.alias == b.alias);
+    if files.is_empty() {
+        println!("Nothing to forget.");
+        return Ok(());
+    }
+
+    let sources: Vec<PathBuf> = files.iter().map(|f| f.original_path.clone()).collect();
+    let source_set: HashSet<&Path> = sources.iter().map(PathBuf::as_path).collect();
+    let mut entries = 0;
+    for name in config.collections()? {
+        let store = VectorStore::load(config.collection_path(&name)?)?;
+        entries += store
+            .iter()
+            .filter(|e| {
+                e.source_path
+                    .as_deref()
+                    .is_some_and(|p| source_set.contains(p))
+            })
+            .count();
+    }
+    for file in &files {
+        println!(
+            "{} (latest v{})",
+            file.original_path.display(),
+            file.version
+        );
+    }
+    println!(
+        "{} files and {} vector entries {} erased",
+        files.len(),
+        entries,
+        if dry_run { "would be" } else { "will be" }
+    );
+    if dry_run {
+        return Ok(());
+    }
+    if !yes && !confirm("Erase them for good?")? {
+        println!("Nothing forgotten.");
+        return Ok(());
+    }
+
+    let blobs = Processor::forget(&config.memory_dir, &files).await?;
+    let mut removed = 0;
+    for name in config.collections()? {
+        removed += VectorStore::load(config.collection_path(&name)?)?.delete_by_paths(&sources)?;
+    }
+    println!(
+        "Forgot {} files ({} blobs) and {} vector entries",
+        files.len(),
+        blobs,
+        removed
+    );
+    Ok(())
+}
+
+/// Asks `question` on the terminal. Refuses when stdin isn't one, so scripts
+/// have to pass `--yes`.
+fn confirm(question: &str) -> Result<bool> {
+    use std::io::{BufRead, IsTerminal, Write};
+    if !std::io::stdin().is_terminal() {
+        eyre::bail!("Not asking for confirmation without a terminal, pass --yes");
+    }
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+async fn status(config: &Config, all: bool) -> Result<()> {
+    let tracked = Processor::tracked_files(&config.memory_dir)?;
+    let mut paths = std::collections::BTreeSet::new();
+    let mut missing = 0;
+    for file in tracked {
+        if file.original_path.exists() {
+            paths.insert(file.original_path);
+        } else {
+            println!("missing:   {}", file.original_path.display());
+            missing += 1;
+        }
+    }
+
+    let summary = Processor::process_all(
+        &paths,
+        ProcessMode::ScanOnly,
+        &config.processor(),
+        &shutdown::token(),
+    )
+    .await?;
+    for path in &summary.modified {
+        println!("modified:  {}", path.display());
+    }
+    for path in &summary.busy {
+        println!("busy:      {}", path.display());
+    }
+    for skipped in &summary.skipped {
+        println!("skipped:   {} ({})", skipped.path.display(), skipped.reason);
+    }
+    if all {
+        let changed: HashSet<&Path> = summary
+            .modified
+            .iter()
+            .chain(&summary.new)
+            .chain(&summary.busy)
+            .map(PathBuf::as_path)
+            .chain(summary.skipped.iter().map(|s| s.path.as_path()))
+            .collect();
+        for path in paths.iter().filter(|p| !changed.contains(p.as_path())) {
+            println!("unchanged: {}", path.display());
+        }
+    }
+    println!(
+        "{} modified, {} missing, {} unchanged, {} busy, {} skipped",
+        summary.modified.len(),
+        missing,
+        summary.unchanged,
+        summary.busy.len(),
+        summary.skipped.len()
+    );
+    Ok(())
+}
+
+async fn gc(config: &Config, policy: &RetentionPolicy) -> Result<()> {
+    if policy.is_unbounded() {
+        println!("No retention policy set, only removing unreferenced blobs.");
+    }
+    let summary = Processor::gc(&config.memory_dir, policy).await?;
+    println!(
+        "Removed {} versions and {} diffs from {} files, and {} unreferenced blobs.",
+        summary.versions, summary.diffs, summary.files, summary.blobs
+    );
+    Ok(())
+}
+
+async fn verify(config: &Config, repair: bool) -> Result<()> {
+    let report = Processor::verify(&config.memory_dir).await?;
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+
+    // Entries of files intermediate memory no longer tracks, or that were
+    // digested straight from disk and are gone from there.
+    let tracked: HashSet<String> = Processor::tracked_files(&config.memory_dir)?
+        .into_iter()
+        .map(|file| file.alias)
+        .collect();
+    let dangling = |entry: &VectorEntry| match (&entry.alias, &entry.source_path) {
+        (Some(alias), _) => !tracked.contains(alias),
+        (None, Some(path)) => !path.exists(),
+        (None, None) => false,
+    };
+    let mut dangling_entries = 0;
+    for name in config.collections()? {
+        let mut store = VectorStore::load(config.collection_path(&name)?)?;
+        let count = store.iter().filter(|entry| dangling(entry)).count();
+        if count == 0 {
+            continue;
+        }
+        println!("collection {name}: {count} vector entries of missing files");
+        dangling_entries += count;
+        if repair {
+            store.delete_where(dangling)?;
+        }
+    }
+
+    println!(
+        "{} files checked, {} versions intact, {} unrestorable by design",
+        report.files, report.versions, report.unrestorable
+    );
+    let mut remaining = report.problems.len() + dangling_entries;
+    if repair {
+        let orphans = Processor::remove_orphans(&report.problems).await?;
+        remaining -= orphans + dangling_entries;
+        println!(
+            "Repaired: removed {orphans} orphaned directories and {dangling_entries} vector entries"
+        );
+    }
+    if remaining > 0 {
+        eyre::bail!("{remaining} problems found");
+    }
+    println!("No problems found");
+    Ok(())
+}
+
+async fn stats(config: &Config, top: usize) -> Result<()> {
+    use indicatif::HumanBytes;
+    let stats = Processor::stats(&config.memory_dir).await?;
+    println!(
+        "{} tracked files, {} versions in {}",
+        stats.files,
+        stats.versions,
+        config.memory_dir.display()
+    );
+
+    let mut vector_bytes = 0;
+    let mut collections = Vec::new();
+    for name in config.collections()? {
+        let store = VectorStore::load(config.collection_path(&name)?)?;
+        vector_bytes += store.disk_usage();
+        let model = store.model_id();
+        collections.push((
+            name,
+            store.len(),
+            store.dimension(),
+            store.is_quantized(),
+            store.metric(),
+            model,
+        ));
+    }
+    let total = stats.content_bytes + stats.diff_bytes + stats.history_bytes + stats.other_bytes;
+    println!("{:>10}  total", HumanBytes(total).to_string());
+    for (label, bytes) in [
+        ("latest versions", stats.content_bytes),
+        ("diffs", stats.diff_bytes),
+        ("histories", stats.history_bytes),
+        ("vectors", vector_bytes),
+        ("other", stats.other_bytes.saturating_sub(vector_bytes)),
+    ] {
+        println!("{:>10}    {}", HumanBytes(bytes).to_string(), label);
+    }
+
+    for (name, entries, dimension, quantized, metric, model) in &collections {
+        println!(
+            "collection {}: {} vectors of {} dimensions{}, scored by {}{}",
+            name,
+            entries,
+            dimension,
+            if *quantized { ", int8" } else { "" },
+            metric,
+            model
+                .as_ref()
+                .map(|m| format!(" from {m}"))
+                .unwrap_or_default()
+        );
     }
+    if top > 0 && !stats.largest.is_empty() {
+        println!("largest:");
+        for (path, bytes) in stats.largest.iter().take(top) {
+            println!("{:>10}  {}", HumanBytes(*bytes).to_string(), path.display());
+        }
+    }
+    Ok(())
+}
 
+fn init(path: &Path) -> Result<()> {
+    let project = Project::init(path)?;
+    println!("Initialized project in {}", project.dir().display());
+    println!("  config  {}", project.config_path().display());
+    println!("  memory  {}", project.memory_dir().display());
+    println!("  ignore  {}", project.ignore_path().display());
+    println!(
+        "Commands run anywhere below {} now use this memory.",
+        project.root().display()
+    );
     Ok(())
 }
+
+fn collections(config: &Config) -> Result<()> {
+    let names = config.collections()?;
+    if names.is_empty() {
+        println!("No collections yet, run `ouroboros digest` first.");
+    }
+    for name in names {
+        let store = VectorStore::load(config.collection_path(&name)?)?;
+        let marker = if name == config.collection { "*" } else { " " };
+        println!("{} {:<20} {} entries", marker, name, store.len());
+    }
+    Ok(())
+}
+
+fn quantize(config: &Config) -> Result<()> {
+    let mut store = VectorStore::load(config.vector_store_path()?)?;
+    if store.is_quantized() {
+        println!("The vector store is already quantized.");
+        return Ok(());
+    }
+    store.quantize()?;
+    println!("Quantized {} embeddings to int8.", store.len());
+    Ok(())
+}
+
+fn encrypt(config: &Config) -> Result<()> {
+    if !crypt::is_enabled() {
+        eyre::bail!("No encryption key configured, set key_file or OUROBOROS_PASSPHRASE");
+    }
+    crypt::accept_plaintext();
+    // Logged changes are sealed record by record, so fold them into their
+    // stores first; saving seals the stores as well.
+    for name in config.collections()? {
+        VectorStore::load(config.collection_path(&name)?)?.save()?;
+    }
+    let skip = |path: &Path| {
+        let name = path.file_name().unwrap_or_default();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        name == LOCK_FILE
+            || name == crypt::SALT_FILE
+            || matches!(&*extension, "wal" | "tmp" | "db" | "db-wal" | "db-shm")
+    };
+    let sealed = crypt::seal_files(&config.memory_dir, &skip)?;
+    println!("Encrypted {sealed} files.");
+    Ok(())
+}
+
+async fn re_embed(config: &Config, digester_config: DigesterConfig) -> Result<()> {
+    let path = config.vector_store_path()?;
+    let store = VectorStore::load(&path)?;
+    if store.is_empty() {
+        println!("Vector store is empty, nothing to re-embed.");
+        return Ok(());
+    }
+    let digester = Digester::with_config(digester_config)?;
+    let from = store
+        .model_id()
+        .unwrap_or_else(|| "an unrecorded model".to_string());
+    info!("Re-embedding {} entries from {}", store.len(), from);
+
+    // Built next to the store and swapped in only when complete, so an
+    // interrupted or failed run leaves the old embeddings searchable.
+    let staging = VectorStore::staging_path(&path);
+    VectorStore::remove_files(&staging)?;
+    let mut target = VectorStore::load(&staging)?;
+    let result = digester
+        .re_embed(&config.memory_dir, &store, &mut target, &shutdown::token())
+        .await;
+    let summary = match result {
+        Ok(summary) if !summary.interrupted => summary,
+        other => {
+            VectorStore::remove_files(&staging)?;
+            other?;
+            println!("Interrupted; the vector store is unchanged.");
+            return Ok(());
+        }
+    };
+    if store.is_quantized() {
+        target.quantize()?;
+    }
+    target.save_as(&path)?;
+    VectorStore::remove_files(&staging)?;
+
+    println!(
+        "Re-embedded {} entries with {} (was {}).",
+        summary.entries,
+        digester.header(),
+        from
+    );
+    if summary.oversized > 0 {
+        warn!(
+            "{} chunks are longer than the {} tokens the new model reads and were {}",
+            summary.oversized,
+            digester.token_budget(),
+            match digester.long_input() {
+                LongInput::Window => "embedded as averaged windows",
+                LongInput::Truncate => "embedded from their start only",
+            }
+        );
+    }
+    Ok(())
+}
+
+fn export(config: &Config, path: &Path) -> Result<()> {
+    let store = VectorStore::load(config.vector_store_path()?)?;
+    let count = store.export_jsonl(path)?;
+    println!("Exported {} entries to {}.", count, path.display());
+    Ok(())
+}
+
+fn export_archive(config: &Config, output: &Path) -> Result<()> {
+    let summary = archive::export(&config.memory_dir, output, config.compression_level)?;
+    println!(
+        "Archived {} files ({}) to {}.",
+        summary.files,
+        indicatif::HumanBytes(summary.bytes),
+        output.display()
+    );
+    Ok(())
+}
+
+async fn import_archive(config: &Config, path: &Path, merge: MergeStrategy) -> Result<()> {
+    let summary = archive::import(&config.memory_dir, path, merge).await?;
+    println!(
+        "Imported {} new files, replaced {}, kept {} local; vector entries: {} new, {} replaced, {} skipped.",
+        summary.added,
+        summary.replaced,
+        summary.kept,
+        summary.entries.added,
+        summary.entries.replaced,
+        summary.entries.skipped
+    );
+    Ok(())
+}
+
+fn sync(config: &Config, direction: SyncDirection) -> Result<()> {
+    use indicatif::HumanBytes;
+
+    let remote = config.remote()?;
+    let (summary, dry_run, verb, side) = match direction {
+        SyncDirection::Push { dry_run } => {
+            let summary = sync::push(&config.memory_dir, &remote, dry_run)?;
+            (summary, dry_run, ["Uploaded", "Would upload"], "the bucket")
+        }
+        SyncDirection::Pull { delete, dry_run } => {
+            let summary = sync::pull(&config.memory_dir, &remote, delete, dry_run)?;
+            (
+                summary,
+                dry_run,
+                ["Downloaded", "Would download"],
+                "local memory",
+            )
+        }
+    };
+    println!(
+        "{} {} files ({}), {} unchanged, {} {} {}.",
+        verb[dry_run as usize],
+        summary.transferred,
+        HumanBytes(summary.bytes),
+        summary.unchanged,
+        summary.deleted,
+        if dry_run {
+            "to remove from"
+        } else {
+            "removed from"
+        },
+        side
+    );
+    Ok(())
+}
+
+fn import(config: &Config, path: &Path, merge: MergeStrategy) -> Result<()> {
+    let mut store = VectorStore::load(config.vector_store_path()?)?;
+    let summary = store.import_jsonl(path, merge)?;
+    println!(
+        "Imported {} new entries, replaced {}, skipped {}; the store holds {}.",
+        summary.added,
+        summary.replaced,
+        summary.skipped,
+        store.len()
+    );
+    Ok(())
+}
+
+fn print_summary(summary: &ProcessSummary) {
+    match summary.mode {
+        ProcessMode::Full => {}
+        ProcessMode::ScanOnly => {
+            for path in &summary.new {
+                println!("new:       {}", path.display());
+            }
+            for path in &summary.modified {
+                println!("modified:  {}", path.display());
+            }
+        }
+        ProcessMode::DryRun => {
+            for preview in &summary.previews {
+                println!("{} -> v{}", preview.path.display(), preview.version);
+                if let Some(diff) = &preview.diff {
+                    println!("{diff}");
+                }
+            }
+        }
+    }
+    for path in &summary.busy {
+        println!("busy:      {}", path.display());
+    }
+    for skipped in &summary.skipped {
+        println!("skipped:   {} ({})", skipped.path.display(), skipped.reason);
+    }
+}
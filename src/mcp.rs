@@ -0,0 +1,423 @@
+use eyre::{Context, Result};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::config::Config;
+use crate::digest::{DigestPatterns, Digester};
+use crate::lock::MemoryLock;
+use crate::pipeline::Orchestrator;
+use crate::process::Processor;
+use crate::vector_store::{DEFAULT_SEARCH_LIMIT, SearchFilter, SearchOptions, VectorStore};
+
+/// Newest Model Context Protocol revision this server speaks.
+const PROTOCOL_VERSION: &str = "2025-06-18";
+
+// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Model Context Protocol server over stdio: one JSON-RPC message per line
+/// on stdin, replies on stdout, logs on stderr. Offers the memory to LLM
+/// clients through the `memory_search`, `memory_ingest` and `memory_history`
+/// tools.
+pub struct McpServer {
+    config: Config,
+    digester: Digester,
+    store: VectorStore,
+}
+
+#[derive(Deserialize)]
+struct SearchArgs {
+    query: String,
+    limit: Option<usize>,
+    #[serde(default)]
+    path: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct IngestArgs {
+    paths: Vec<PathBuf>,
+    /// Embed the new versions right away so they are searchable.
+    #[serde(default = "default_true")]
+    digest: bool,
+}
+
+#[derive(Deserialize)]
+struct HistoryArgs {
+    file: PathBuf,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl McpServer {
+    pub fn new(config: Config, digester: Digester) -> Result<Self> {
+        let store = VectorStore::load(config.vector_store_path()?)?;
+        Ok(Self {
+            config,
+            digester,
+            store,
+        })
+    }
+
+    /// Answers requests until stdin closes.
+    pub async fn serve_stdio(mut self) -> Result<()> {
+        info!("MCP server listening on stdio");
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let mut stdout = tokio::io::stdout();
+        while let Some(line) = lines.next_line().await.wrap_err("Failed to read stdin")? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some(reply) = self.handle_line(&line).await else {
+                continue;
+            };
+            let mut data = serde_json::to_vec(&reply)?;
+            data.push(b'\n');
+            stdout.write_all(&data).await?;
+            stdout.flush().await?;
+        }
+        debug!("stdin closed, stopping MCP server");
+        Ok(())
+    }
+
+    /// The reply to one message, or `None` for notifications.
+    async fn handle_line(&mut self, line: &str) -> Option<Value> {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(e) => return Some(error_reply(Value::Null, PARSE_ERROR, &e.to_string())),
+        };
+        let method = message.get("method").and_then(Value::as_str).unwrap_or("");
+        // Notifications carry no id and get no reply.
+        let id = message.get("id").cloned()?;
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        debug!("MCP request {}", method);
+        let result = match method {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tool_definitions() })),
+            "tools/call" => self.call_tool(params).await,
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {method}"))),
+        };
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error_reply(id, code, &message),
+        })
+    }
+
+    /// Runs a tool. Tool failures are reported in the result, as MCP asks,
+    /// so the model can see them; only malformed calls are protocol errors.
+    async fn call_tool(&mut self, params: Value) -> Result<Value, (i64, String)> {
+        let name = params.get("name").and_then(Value::as_str).unwrap_or("");
+        let arguments = params.get("arguments").cloned().unwrap_or(json!({}));
+        let outcome = match name {
+            "memory_search" => self.search(parse_args(arguments)?).await,
+            "memory_ingest" => self.ingest(parse_args(arguments)?).await,
+            "memory_history" => self.history(parse_args(arguments)?).await,
+            _ => return Err((INVALID_PARAMS, format!("unknown tool {name}"))),
+        };
+        let (text, is_error) = match outcome {
+            Ok(text) => (text, false),
+            Err(e) => {
+                warn!("Tool {} failed: {:?}", name, e);
+                (format!("{e:#}"), true)
+            }
+        };
+        Ok(json!({
+            "content": [{ "type": "text", "text": text }],
+            "isError": is_error,
+        }))
+    }
+
+    async fn search(&self, args: SearchArgs) -> Result<String> {
+        let path: Vec<_> = args.path.iter().map(String::as_str).collect();
+        let options = SearchOptions {
+            filter: SearchFilter::new().with_paths(&path)?,
+            keyword_weight: self.config.keyword_weight,
+            limit: args.limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+            ..Default::default()
+        };
+        let hits = self
+            .store
+            .search_text(&self.digester, &args.query, &options)?;
+        if hits.is_empty() {
+            return Ok("No matching memories.".to_string());
+        }
+
+        let mut text = String::new();
+        for (rank, (entry, score)) in hits.iter().enumerate() {
+            let source = match &entry.source_path {
+                Some(path) => path.display().to_string(),
+                None => entry.file_name.clone(),
+            };
+            write!(text, "{}. {} (score {:.3})", rank + 1, source, score)?;
+            if entry.span.start_line > 0 {
+                write!(
+                    text,
+                    ", lines {}-{}",
+                    entry.span.start_line, entry.span.end_line
+                )?;
+            }
+            if let Some(version) = entry.version {
+                write!(text, ", v{version}")?;
+            }
+            if let Some(date) = entry.stored_on() {
+                write!(text, ", stored {date}")?;
+            }
+            if let Some(section) = &entry.section {
+                write!(text, ", section {section}")?;
+            }
+            if let Some(symbol) = &entry.symbol {
+                write!(text, ", in {symbol}")?;
+            }
+            let content = VectorStore::fetch_content(&self.config.memory_dir, entry).await?;
+            writeln!(text, "\n{}\n", content.trim())?;
+        }
+        Ok(text)
+    }
+
+    async fn ingest(&mut self, args: IngestArgs) -> Result<String> {
+        let mut orchestrator = Orchestrator::new(&self.config);
+        if args.digest {
+            orchestrator = orchestrator.with_digester(&self.digester, DigestPatterns::default());
+        }
+        let _lock = MemoryLock::acquire(&self.config.memory_dir)?;
+        let pipeline = orchestrator.run(args.paths, &mut self.store).await?;
+        let summary = &pipeline.process;
+
+        let mut text = format!(
+            "{} new, {} modified, {} unchanged files.",
+            summary.new.len(),
+            summary.modified.len(),
+            summary.unchanged
+        );
+        for path in &summary.busy {
+            write!(text, "\nBusy, not versioned: {}", path.display())?;
+        }
+        for skipped in &summary.skipped {
+            write!(
+                text,
+                "\nSkipped: {} ({})",
+                skipped.path.display(),
+                skipped.reason
+            )?;
+        }
+        if let Some(digested) = &pipeline.digest {
+            write!(text, "\nEmbedded {} chunks.", digested.chunks)?;
+        }
+        Ok(text)
+    }
+
+    async fn history(&self, args: HistoryArgs) -> Result<String> {
+        let history = Processor::history(&self.config.memory_dir, &args.file).await?;
+        let embedded = self
+            .store
+            .embedded_versions(Path::new(&history.original_path));
+        let mut text = format!("{} ({})\n", history.original_path, history.alias);
+        for version in &history.versions {
+            write!(
+                text,
+                "v{}  {}  {} bytes",
+                version.version, version.processed_at, version.size
+            )?;
+            if let Some(chunks) = embedded.get(&version.version) {
+                write!(text, ", {chunks} chunks searchable")?;
+            }
+            writeln!(text)?;
+        }
+        Ok(text)
+    }
+}
+
+fn parse_args<T: for<'de> Deserialize<'de>>(arguments: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(arguments).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn error_reply(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+fn tool_definitions() -> Value {
+    json!([
+        {
+            "name": "memory_search",
+            "description": "Search long-term memory for passages relevant to a natural-language query.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "What to look for" },
+                    "limit": { "type": "integer", "description": "Maximum results (default 5)" },
+                    "path": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                        "description": "Only search files matching one of these globs",
+                    },
+                },
+                "required": ["query"],
+            },
+        },
+        {
+            "name": "memory_ingest",
+            "description": "Store new versions of files or directories in memory and make them searchable.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "paths": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                        "description": "Files or directories to remember",
+                    },
+                    "digest": {
+                        "type": "boolean",
+                        "description": "Embed the new versions right away (default true)",
+                    },
+                },
+                "required": ["paths"],
+            },
+        },
+        {
+            "name": "memory_history",
+            "description": "List the stored versions of a file.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "file": { "type": "string", "description": "Path of the file" },
+                },
+                "required": ["file"],
+            },
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::tests::ConstantEmbedder;
+
+    /// A server over an empty memory in `dir` that embeds without a model.
+    fn server(dir: &Path) -> McpServer {
+        let config = Config {
+            memory_dir: dir.join("memory"),
+            ..Default::default()
+        };
+        McpServer::new(config, Digester::from_embedder(Box::new(ConstantEmbedder))).unwrap()
+    }
+
+    /// The text a tool call answers with, and whether it is an error.
+    async fn call(server: &mut McpServer, tool: &str, arguments: Value) -> (String, bool) {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "tools/call",
+            "params": { "name": tool, "arguments": arguments },
+        });
+        let reply = server.handle_line(&request.to_string()).await.unwrap();
+        let result = &reply["result"];
+        (
+            result["content"][0]["text"].as_str().unwrap().to_string(),
+            result["isError"].as_bool().unwrap(),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ingested_files_can_be_searched_and_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "The cache is flushed every night.").unwrap();
+        let mut server = server(dir.path());
+
+        let init = json!({ "jsonrpc": "2.0", "id": 0, "method": "initialize" });
+        let reply = server.handle_line(&init.to_string()).await.unwrap();
+        assert_eq!(reply["result"]["protocolVersion"], PROTOCOL_VERSION);
+        let notification = json!({ "jsonrpc": "2.0", "method": "notifications/initialized" });
+        assert!(
+            server
+                .handle_line(&notification.to_string())
+                .await
+                .is_none()
+        );
+
+        let (text, is_error) = call(&mut server, "memory_ingest", json!({ "paths": [file] })).await;
+        assert!(!is_error, "{text}");
+        assert!(text.starts_with("1 new, 0 modified"), "{text}");
+        assert!(text.contains("Embedded 1 chunks."), "{text}");
+
+        let (text, is_error) =
+            call(&mut server, "memory_search", json!({ "query": "cache" })).await;
+        assert!(!is_error, "{text}");
+        assert!(text.contains("flushed every night"), "{text}");
+
+        let (text, is_error) = call(&mut server, "memory_history", json!({ "file": file })).await;
+        assert!(!is_error, "{text}");
+        assert!(
+            text.contains("v1") && text.contains("1 chunks searchable"),
+            "{text}"
+        );
+
+        // A failing tool is reported in its result, not as a protocol error.
+        let missing = dir.path().join("missing.txt");
+        let (_, is_error) = call(&mut server, "memory_history", json!({ "file": missing })).await;
+        assert!(is_error);
+    }
+
+    #[test]
+    fn tool_schemas_match_their_arguments() {
+        let tools = tool_definitions();
+        let tools = tools.as_array().unwrap();
+        let names: Vec<_> = tools
+            .iter()
+            .map(|tool| tool["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["memory_search", "memory_ingest", "memory_history"]);
+
+        // A call with just the required arguments must parse.
+        let minimal = |tool: &Value| -> Value {
+            let schema = &tool["inputSchema"];
+            let mut args = serde_json::Map::new();
+            for field in schema["required"].as_array().unwrap() {
+                let field = field.as_str().unwrap();
+                let value = match schema["properties"][field]["type"].as_str().unwrap() {
+                    "array" => json!(["notes"]),
+                    _ => json!("notes"),
+                };
+                args.insert(field.to_string(), value);
+            }
+            Value::Object(args)
+        };
+        parse_args::<SearchArgs>(minimal(&tools[0])).unwrap();
+        let ingest = parse_args::<IngestArgs>(minimal(&tools[1])).unwrap();
+        assert!(ingest.digest);
+        parse_args::<HistoryArgs>(minimal(&tools[2])).unwrap();
+    }
+
+    #[test]
+    fn malformed_arguments_are_invalid_params() {
+        let Err((code, _)) = parse_args::<SearchArgs>(json!({ "limit": 3 })) else {
+            panic!("a search without a query must be rejected");
+        };
+        assert_eq!(code, INVALID_PARAMS);
+
+        let reply = error_reply(json!(7), code, "missing field `query`");
+        assert_eq!(reply["id"], 7);
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+        assert_eq!(reply["jsonrpc"], "2.0");
+    }
+}
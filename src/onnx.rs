@@ -0,0 +1,325 @@
+use eyre::{Context, Result, eyre};
+use log::{debug, info};
+use ort::session::Session;
+use ort::value::Tensor;
+use std::sync::Mutex;
+use tokenizers::{Encoding, PostProcessor, Tokenizer};
+
+use crate::backend::Embedder;
+use crate::bert::{ModelFiles, Pooling, detect_pooling, resolve_max_tokens, unpadded};
+use crate::digest::{DigestError, DigesterConfig};
+
+/// Where sentence-transformers repos keep their ONNX export, and where plain
+/// exports put it.
+const MODEL_FILES: [&str; 2] = ["onnx/model.onnx", "model.onnx"];
+
+/// A BERT-style model exported to ONNX, from the hub or a local directory, run
+/// on the CPU with ONNX Runtime.
+pub struct OnnxBackend {
+    model_id: String,
+    revision: Option<String>,
+    /// Runs take `&mut Session`; the lock lets batches be embedded through `&self`.
+    session: Mutex<Session>,
+    tokenizer: Tokenizer,
+    /// `tokenizer` without truncation or padding, so token counts and offsets
+    /// cover the whole text however long it is.
+    counter: Tokenizer,
+    dimension: usize,
+    max_tokens: usize,
+    pooling: Pooling,
+    /// Whether the exported graph takes `token_type_ids`; many exports drop it.
+    token_type_ids: bool,
+}
+
+impl OnnxBackend {
+    /// Loads `config.model` at `config.revision`.
+    pub fn load(config: &DigesterConfig) -> Result<Self> {
+        match &config.revision {
+            Some(revision) => info!("Loading ONNX model {}@{}...", config.model, revision),
+            None => info!("Loading ONNX model {}...", config.model),
+        }
+        let repo = ModelFiles::open(
+            &config.model,
+            config.revision.as_deref(),
+            config.model_path.as_deref(),
+        )?;
+        let (raw_config, tokenizer) = repo.load_tokenizer()?;
+        let max_tokens = resolve_max_tokens(&raw_config, &tokenizer);
+        let dimension = raw_config
+            .get("hidden_size")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| eyre!("Model config has no hidden_size"))?
+            as usize;
+        let pooling = match config.pooling {
+            Some(pooling) => pooling,
+            None => detect_pooling(&repo),
+        };
+        let counter = unpadded(&tokenizer)?;
+
+        let model_path = match repo.get(MODEL_FILES[0]) {
+            Ok(path) => path,
+            Err(e) => {
+                debug!("No {} ({}), trying {}", MODEL_FILES[0], e, MODEL_FILES[1]);
+                repo.get(MODEL_FILES[1])?
+            }
+        };
+        let session = Session::builder()
+            .and_then(|mut builder| builder.commit_from_file(&model_path))
+            .wrap_err_with(|| format!("Failed to load ONNX model {}", model_path.display()))?;
+        let token_type_ids = session
+            .inputs()
+            .iter()
+            .any(|input| input.name() == "token_type_ids");
+
+        debug!(
+            "ONNX model loaded ({} dimensions, {:?} pooling)",
+            dimension, pooling
+        );
+        Ok(Self {
+            model_id: config.model.clone(),
+            revision: config.revision.clone(),
+            session: Mutex::new(session),
+            tokenizer,
+            counter,
+            dimension,
+            max_tokens,
+            pooling,
+            token_type_ids,
+        })
+    }
+}
+
+impl Embedder for OnnxBackend {
+    /// Embeds `texts` in a single run, padding them to the longest input.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(|e| DigestError::Encode(e.to_string()))?;
+        let batch = Batch::new(&encodings);
+        let shape = vec![batch.size as i64, batch.len as i64];
+        let mut inputs = ort::inputs! {
+            "input_ids" => Tensor::from_array((shape.clone(), batch.ids))?,
+            "attention_mask" => Tensor::from_array((shape.clone(), batch.mask.clone()))?,
+        };
+        if self.token_type_ids {
+            let types = vec![0i64; batch.size * batch.len];
+            inputs.push((
+                "token_type_ids".into(),
+                Tensor::from_array((shape, types))?.into(),
+            ));
+        }
+
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let outputs = session.run(inputs)?;
+        let output = match outputs.get("last_hidden_state") {
+            Some(output) => output,
+            None => &outputs[0],
+        };
+        let (output_shape, values) = output.try_extract_tensor::<f32>()?;
+        let dims: Vec<usize> = output_shape.iter().map(|&d| d as usize).collect();
+        pool(values, &dims, &batch.mask, batch.len, self.pooling)
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    fn pooling(&self) -> Option<Pooling> {
+        Some(self.pooling)
+    }
+
+    /// From the model's `hidden_size`.
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        match self.counter.encode(text, false) {
+            Ok(encoding) => encoding.get_ids().len(),
+            // A token per byte overestimates, so chunks measured this way still fit.
+            Err(_) => text.len(),
+        }
+    }
+
+    fn special_tokens(&self) -> usize {
+        self.tokenizer
+            .get_post_processor()
+            .map_or(0, |processor| processor.added_tokens(false))
+    }
+
+    fn token_offsets(&self, text: &str) -> Result<Option<Vec<(usize, usize)>>> {
+        let encoding = self
+            .counter
+            .encode(text, false)
+            .map_err(|e| DigestError::Encode(e.to_string()))?;
+        Ok(Some(encoding.get_offsets().to_vec()))
+    }
+}
+
+/// The inputs of a run: `size` encodings of `len` tokens each, row by row.
+struct Batch {
+    size: usize,
+    len: usize,
+    ids: Vec<i64>,
+    mask: Vec<i64>,
+}
+
+impl Batch {
+    /// Flattens `encodings`, which the tokenizer has padded to one length.
+    fn new(encodings: &[Encoding]) -> Self {
+        let len = encodings.first().map_or(0, |encoding| encoding.len());
+        let mut ids = Vec::with_capacity(encodings.len() * len);
+        let mut mask = Vec::with_capacity(encodings.len() * len);
+        for encoding in encodings {
+            ids.extend(encoding.get_ids().iter().map(|&id| id as i64));
+            mask.extend(encoding.get_attention_mask().iter().map(|&m| m as i64));
+        }
+        Self {
+            size: encodings.len(),
+            len,
+            ids,
+            mask,
+        }
+    }
+}
+
+/// Reduces the output of a run over `mask.len() / len` inputs of `len` tokens
+/// to one embedding per input.
+fn pool(
+    values: &[f32],
+    dims: &[usize],
+    mask: &[i64],
+    len: usize,
+    pooling: Pooling,
+) -> Result<Vec<Vec<f32>>> {
+    let batch = mask.len().checked_div(len).unwrap_or(0);
+    let pooled = match dims[..] {
+        // Some exports pool inside the graph.
+        [b, h] if b == batch => values.chunks_exact(h).map(<[f32]>::to_vec).collect(),
+        [b, l, h] if b == batch && l == len => values
+            .chunks_exact(l * h)
+            .zip(mask.chunks_exact(l))
+            .map(|(tokens, mask)| match pooling {
+                // Average only over real tokens so padding doesn't dilute shorter inputs.
+                Pooling::Mean => {
+                    let mut summed = vec![0.0; h];
+                    for (token, _) in tokens.chunks_exact(h).zip(mask).filter(|(_, m)| **m != 0) {
+                        summed.iter_mut().zip(token).for_each(|(s, x)| *s += x);
+                    }
+                    let count = mask.iter().filter(|&&m| m != 0).count().max(1) as f32;
+                    summed.iter_mut().for_each(|s| *s /= count);
+                    summed
+                }
+                // Padding is on the right, so position 0 is always `[CLS]`.
+                Pooling::Cls => tokens[..h].to_vec(),
+            })
+            .collect(),
+        _ => {
+            return Err(eyre!(
+                "ONNX model returned output of shape {dims:?} for a batch of {batch}x{len} tokens"
+            ));
+        }
+    };
+    Ok(pooled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::{PaddingParams, PaddingStrategy, TruncationParams};
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = ["[PAD]", "[UNK]", "one", "two", "three"]
+            .into_iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer
+            .with_pre_tokenizer(Some(Whitespace {}))
+            .with_padding(Some(PaddingParams {
+                strategy: PaddingStrategy::BatchLongest,
+                ..Default::default()
+            }));
+        tokenizer
+    }
+
+    #[test]
+    fn batches_are_padded_to_their_longest_input() {
+        let encodings = tokenizer()
+            .encode_batch(vec!["one two three", "two"], true)
+            .unwrap();
+        let batch = Batch::new(&encodings);
+        assert_eq!((batch.size, batch.len), (2, 3));
+        assert_eq!(batch.ids, [2, 3, 4, 3, 0, 0]);
+        assert_eq!(batch.mask, [1, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn tokens_are_counted_without_truncation_or_padding() {
+        let mut tokenizer = tokenizer();
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: 2,
+                ..Default::default()
+            }))
+            .unwrap()
+            .with_padding(Some(PaddingParams {
+                strategy: PaddingStrategy::Fixed(8),
+                ..Default::default()
+            }));
+        let text = "one two three";
+        assert_eq!(tokenizer.encode(text, false).unwrap().len(), 8);
+        let counter = unpadded(&tokenizer).unwrap();
+        let encoding = counter.encode(text, false).unwrap();
+        assert_eq!(encoding.get_offsets(), [(0, 3), (4, 7), (8, 13)]);
+    }
+
+    #[test]
+    fn token_outputs_are_pooled_over_real_tokens() {
+        // Two inputs of two tokens with two dimensions; the second is one token and padding.
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 100.0, 100.0];
+        let mask = [1, 1, 1, 0];
+        let mean = pool(&values, &[2, 2, 2], &mask, 2, Pooling::Mean).unwrap();
+        assert_eq!(mean, [vec![2.0, 3.0], vec![5.0, 6.0]]);
+        let cls = pool(&values, &[2, 2, 2], &mask, 2, Pooling::Cls).unwrap();
+        assert_eq!(cls, [vec![1.0, 2.0], vec![5.0, 6.0]]);
+    }
+
+    #[test]
+    fn pooled_outputs_are_taken_as_they_are() {
+        let pooled = pool(
+            &[1.0, 2.0, 3.0, 4.0],
+            &[2, 2],
+            &[1, 1, 1, 0],
+            2,
+            Pooling::Mean,
+        )
+        .unwrap();
+        assert_eq!(pooled, [vec![1.0, 2.0], vec![3.0, 4.0]]);
+    }
+
+    #[test]
+    fn outputs_of_another_shape_are_rejected() {
+        let values = [0.0; 12];
+        for dims in [&[3, 2, 2][..], &[2, 3, 2], &[12]] {
+            assert!(pool(&values, dims, &[1, 1, 1, 1], 2, Pooling::Mean).is_err());
+        }
+    }
+}
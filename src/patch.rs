@@ -0,0 +1,106 @@
+use thiserror::Error;
+
+const NO_NEWLINE_MARKER: char = '\\';
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    #[error("malformed hunk header: {0}")]
+    Header(String),
+    #[error("unexpected line in hunk: {0}")]
+    Line(String),
+    #[error("hunk {0} does not match the text it is applied to")]
+    Mismatch(String),
+}
+
+/// One `@@ -a,b +c,d @@` section of a unified diff.
+struct Hunk<'a> {
+    header: &'a str,
+    new_start: usize,
+    new_len: usize,
+    /// Tag (`' '`, `'-'` or `'+'`) and text of each line, newline included.
+    lines: Vec<(char, &'a str)>,
+}
+
+/// Undoes `diff`, a unified diff from some old text to `new`, returning the
+/// old text. Every context and added line must match `new` exactly.
+pub fn revert(new: &str, diff: &str) -> Result<String, PatchError> {
+    let lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let mut old = String::with_capacity(new.len());
+    let mut pos = 0;
+
+    for hunk in parse(diff)? {
+        // An empty new range starts at the line *before* it.
+        let start = if hunk.new_len == 0 {
+            hunk.new_start
+        } else {
+            hunk.new_start.saturating_sub(1)
+        };
+        if start < pos || start > lines.len() {
+            return Err(PatchError::Mismatch(hunk.header.to_string()));
+        }
+        old.extend(lines[pos..start].iter().copied());
+        pos = start;
+
+        for (tag, text) in hunk.lines {
+            match tag {
+                '-' => old.push_str(text),
+                _ => {
+                    if lines.get(pos) != Some(&text) {
+                        return Err(PatchError::Mismatch(hunk.header.to_string()));
+                    }
+                    pos += 1;
+                    if tag == ' ' {
+                        old.push_str(text);
+                    }
+                }
+            }
+        }
+    }
+    old.extend(lines[pos..].iter().copied());
+    Ok(old)
+}
+
+fn parse(diff: &str) -> Result<Vec<Hunk<'_>>, PatchError> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    for line in diff.split_inclusive('\n') {
+        if line.starts_with("@@") {
+            let (new_start, new_len) = parse_header(line)?;
+            hunks.push(Hunk {
+                header: line.trim_end(),
+                new_start,
+                new_len,
+                lines: Vec::new(),
+            });
+            continue;
+        }
+        let Some(hunk) = hunks.last_mut() else {
+            // `---`/`+++` file headers before the first hunk
+            continue;
+        };
+        let mut chars = line.chars();
+        match chars.next() {
+            Some(tag @ (' ' | '-' | '+')) => hunk.lines.push((tag, chars.as_str())),
+            Some(NO_NEWLINE_MARKER) => {
+                if let Some((_, text)) = hunk.lines.last_mut() {
+                    *text = text.strip_suffix('\n').unwrap_or(text);
+                }
+            }
+            _ => return Err(PatchError::Line(line.trim_end().to_string())),
+        }
+    }
+    Ok(hunks)
+}
+
+/// Parses the new-side range of `@@ -a,b +c,d @@`; a missing length means 1.
+fn parse_header(line: &str) -> Result<(usize, usize), PatchError> {
+    let malformed = || PatchError::Header(line.trim_end().to_string());
+    let range = line
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix('+'))
+        .ok_or_else(malformed)?;
+    let (start, len) = match range.split_once(',') {
+        Some((start, len)) => (start, len.parse().map_err(|_| malformed())?),
+        None => (range, 1),
+    };
+    Ok((start.parse().map_err(|_| malformed())?, len))
+}
@@ -0,0 +1,146 @@
+use eyre::Result;
+use log::info;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::digest::{DigestPatterns, DigestSummary, Digester};
+use crate::process::{ProcessMode, ProcessSummary, Processor};
+use crate::shutdown::{self, CancellationToken};
+use crate::vector_store::VectorStore;
+
+/// Runs the whole pipeline over a set of paths in one call: collects files
+/// with `FileStorage`, versions them with the `Processor`, drops the vector
+/// entries of modified files, and, given a digester, embeds the new versions.
+pub struct Orchestrator<'a> {
+    config: &'a Config,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    mode: ProcessMode,
+    digestion: Option<(&'a Digester, DigestPatterns)>,
+    cancel: CancellationToken,
+}
+
+/// What each stage of `Orchestrator::run` did.
+#[derive(Debug)]
+pub struct PipelineSummary {
+    /// Files collected from the given paths.
+    pub collected: usize,
+    pub process: ProcessSummary,
+    /// Vector entries dropped because their file got a new version.
+    pub invalidated: usize,
+    /// `None` unless a digester was given and files were versioned.
+    pub digest: Option<DigestSummary>,
+}
+
+impl<'a> Orchestrator<'a> {
+    /// A pipeline that versions files in `ProcessMode::Full` without embedding them.
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            mode: ProcessMode::Full,
+            digestion: None,
+            cancel: shutdown::token(),
+        }
+    }
+
+    /// Include/exclude globs applied while collecting files.
+    pub fn with_filters(mut self, include: &[String], exclude: &[String]) -> Self {
+        self.include = include.to_vec();
+        self.exclude = exclude.to_vec();
+        self
+    }
+
+    /// Scanning and dry runs stop after the Processor; nothing is invalidated or embedded.
+    pub fn with_mode(mut self, mode: ProcessMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Embeds the versioned files `patterns` select with `digester`.
+    pub fn with_digester(mut self, digester: &'a Digester, patterns: DigestPatterns) -> Self {
+        self.digestion = Some((digester, patterns));
+        self
+    }
+
+    /// Stops the run at the next file once `cancel` fires, instead of on Ctrl-C.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Runs every stage over `paths`, writing vector entries to `store`, which
+    /// should be the configured collection. Other collections only have the
+    /// entries of modified files dropped.
+    pub async fn run(
+        &self,
+        paths: Vec<PathBuf>,
+        store: &mut VectorStore,
+    ) -> Result<PipelineSummary> {
+        info!("[1/3] Collecting files from {} paths", paths.len());
+        let mut storage = self
+            .config
+            .file_storage()?
+            .with_filters(&self.include, &self.exclude)?;
+        for path in paths {
+            storage.add(path).await;
+        }
+        let collected = storage.len();
+
+        info!("[2/3] Versioning {} files", collected);
+        let process = Processor::process_all(
+            storage.paths(),
+            self.mode,
+            &self.config.processor(),
+            &self.cancel,
+        )
+        .await?;
+        let mut summary = PipelineSummary {
+            collected,
+            process,
+            invalidated: 0,
+            digest: None,
+        };
+        if self.mode != ProcessMode::Full {
+            return Ok(summary);
+        }
+        let modified = &summary.process.modified;
+        summary.invalidated = store.supersede_paths(modified, self.config.keep_archived)?
+            + invalidate_modified(self.config, modified, Some(&self.config.collection))?;
+
+        let Some((digester, patterns)) = &self.digestion else {
+            return Ok(summary);
+        };
+        // Files versioned earlier but never digested are picked up as well.
+        info!("[3/3] Embedding new versions");
+        // Embedding is CPU-bound; keep it off the threads driving other tasks.
+        let digest = tokio::task::block_in_place(|| {
+            digester.digest_all(&self.config.memory_dir, store, patterns, &self.cancel)
+        })?;
+        summary.digest = Some(digest);
+        Ok(summary)
+    }
+}
+
+/// Retires the vector entries of `modified` files in every collection on disk
+/// but `except`, archiving them if `keep_archived` is set, and returns how
+/// many there were.
+pub fn invalidate_modified(
+    config: &Config,
+    modified: &[PathBuf],
+    except: Option<&str>,
+) -> Result<usize> {
+    let mut removed = 0;
+    if modified.is_empty() {
+        return Ok(removed);
+    }
+    for name in config.collections()? {
+        if except == Some(name.as_str()) {
+            continue;
+        }
+        removed += VectorStore::load(config.collection_path(&name)?)?
+            .supersede_paths(modified, config.keep_archived)?;
+    }
+    Ok(removed)
+}
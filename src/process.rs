@@ -1,5 +1,4 @@
 use eyre::{Context, Result};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log::{debug, error, info, trace, warn};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -8,10 +7,25 @@ use std::collections::BTreeSet;
 use std::fs;
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, UNIX_EPOCH};
 use thiserror::Error;
 
-const CHUNK_SIZE: usize = 8 * 1024 * 1024; // 8MB
+use crate::checkpoint::{Checkpoint, FileStamp};
+use crate::compress;
+use crate::crypt;
+use crate::extract::{self, Format};
+use crate::patch;
+use crate::progress::{IndicatifProgress, ProgressEvent, ProgressSink};
+use crate::shutdown::CancellationToken;
+
+/// Files smaller than this are read whole; larger ones are streamed in chunks of it.
+pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024; // 8MB
+pub const DEFAULT_MEMORY_DIR: &str = "memory";
+pub const DEFAULT_CONCURRENCY: usize = 16;
+/// Content-addressed store of file contents shared by every alias.
+pub const OBJECTS_DIR: &str = "objects";
+/// Extension of binary deltas, as opposed to `.diff` for unified diffs.
+const BINARY_DELTA_EXT: &str = "bdiff";
 
 #[derive(Error, Debug)]
 pub enum ProcessError {
@@ -21,33 +35,409 @@ pub enum ProcessError {
     File(PathBuf),
     #[error("failed to write metadata: {0}")]
     Metadata(PathBuf),
+    #[error("version v{1} is not recorded for {0}")]
+    UnknownVersion(String, u32),
+    #[error("diff file listed in history but missing on disk: {0}")]
+    MissingDiff(PathBuf),
+    #[error("no versions recorded for {0}")]
+    NoVersions(String),
+    #[error("file is locked or busy: {0}")]
+    Busy(PathBuf),
+    #[error("v{1} of {0} can't be reconstructed: a later version was stored without a diff")]
+    Unrecoverable(String, u32),
+    #[error("reconstructed v{1} of {0} does not match its recorded hash")]
+    Corrupt(String, u32),
+    #[error("v{1} of {0} is not text and can't be diffed")]
+    Binary(String, u32),
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct FileHistory {
+    pub versions: Vec<FileVersion>,
+    pub alias: String,
+    pub original_path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileVersion {
+    pub version: u32,
+    pub hash: String,
+    pub size: u64,
+    pub mtime_ns: u128,
+    pub processed_at: String,
+    pub diff_file: Option<String>,
+    /// Unix permission bits at process time; `None` on other platforms.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// SHA-256 of the exact bytes, naming the blob in `objects/`. `None` for
+    /// versions stored before blobs, whose content is the alias' own `latest`.
+    #[serde(default)]
+    pub blob: Option<String>,
+}
+
+/// How far `process_all` takes each file through the pipeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProcessMode {
+    #[default]
+    Full,
+    /// Stop after the size+mtime comparison and leave `memory/` untouched.
+    ScanOnly,
+    /// Hash and diff changed files, but report the planned versions in
+    /// `ProcessSummary::previews` instead of writing anything to `memory/`.
+    DryRun,
+}
+
+/// Per-file result of a pipeline run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// No history existed for the file.
+    New,
+    Modified,
+    Unchanged,
+    /// Skipped because another process held the file locked or busy.
+    Busy,
+    /// Not processed because an interrupt arrived first.
+    Interrupted,
+    /// Left alone because of a `ProcessorConfig` limit.
+    Skipped(SkipReason),
+}
+
+/// Why a file was not versioned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Larger than `ProcessorConfig::max_file_size`.
+    TooLarge { size: u64, limit: u64 },
+    /// Binary content, with `ProcessorConfig::skip_binary` set.
+    Binary,
+    /// Of a MIME type `ProcessorConfig::skip_mime` lists.
+    Mime(&'static str),
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { size, limit } => {
+                write!(f, "{size} bytes exceeds the limit of {limit} bytes")
+            }
+            Self::Binary => write!(f, "binary content"),
+            Self::Mime(mime) => write!(f, "MIME type {mime} is skipped"),
+        }
+    }
+}
+
+/// A file `process_all` skipped, and why.
+#[derive(Debug, Clone)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+/// Aggregate result of `process_all`. In `ScanOnly` mode `new` and `modified`
+/// list the files a full run would (re)process.
+#[derive(Debug, Default)]
+pub struct ProcessSummary {
+    pub mode: ProcessMode,
+    pub new: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub unchanged: usize,
+    pub busy: Vec<PathBuf>,
+    /// Files left unprocessed because the run was interrupted.
+    pub interrupted: usize,
+    pub skipped: Vec<SkippedFile>,
+    /// Versions a `DryRun` would have stored.
+    pub previews: Vec<VersionPreview>,
+}
+
+/// A version `ProcessMode::DryRun` would store for a changed file.
+#[derive(Debug, Clone)]
+pub struct VersionPreview {
+    pub path: PathBuf,
+    pub version: u32,
+    /// Unified diff against the current `latest`, when both are text.
+    pub diff: Option<String>,
+}
+
+/// How a version is rebuilt from the one after it.
+enum Delta {
+    /// Unified diff from the old text to the new one.
+    Text(String),
+    /// bsdiff patch that turns the new content back into the old one.
+    Binary(Vec<u8>),
+}
+
+impl ProcessSummary {
+    pub fn changed(&self) -> usize {
+        self.new.len() + self.modified.len()
+    }
+
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupted > 0
+    }
+
+    fn record(&mut self, path: PathBuf, status: FileStatus) {
+        match status {
+            FileStatus::New => self.new.push(path),
+            FileStatus::Modified => self.modified.push(path),
+            FileStatus::Unchanged => self.unchanged += 1,
+            FileStatus::Busy => self.busy.push(path),
+            FileStatus::Interrupted => self.interrupted += 1,
+            FileStatus::Skipped(reason) => self.skipped.push(SkippedFile { path, reason }),
+        }
+    }
+}
+
+/// A file tracked in intermediate memory, as recorded in its `history.json`.
+#[derive(Debug, Clone)]
+pub struct TrackedFile {
+    pub alias: String,
+    pub original_path: PathBuf,
+    pub latest: PathBuf,
+    pub version: u32,
+    /// When `version` was stored, in RFC 3339.
+    pub processed_at: String,
+}
+
+/// Which versions `Processor::gc` keeps. A version survives if either rule
+/// keeps it; the latest version is always kept. With no rules set nothing is pruned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep the newest N versions of each file.
+    pub keep_last: Option<usize>,
+    /// Keep versions processed within this many days.
+    pub max_age_days: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_unbounded(&self) -> bool {
+        self.keep_last.is_none() && self.max_age_days.is_none()
+    }
+
+    /// Index of the oldest version in `versions` to keep. Everything after it
+    /// is kept too: older versions are only reachable through newer diffs, so
+    /// pruning can only drop the oldest ones.
+    fn first_kept(&self, versions: &[FileVersion], now: chrono::DateTime<chrono::Local>) -> usize {
+        if self.is_unbounded() || versions.is_empty() {
+            return 0;
+        }
+        let by_count = self
+            .keep_last
+            .map_or(versions.len(), |n| versions.len().saturating_sub(n.max(1)));
+        let by_age = self.max_age_days.map_or(versions.len(), |days| {
+            let cutoff = now - chrono::Duration::days(days as i64);
+            versions
+                .iter()
+                .position(|v| {
+                    chrono::DateTime::parse_from_rfc3339(&v.processed_at)
+                        .is_ok_and(|processed| processed >= cutoff)
+                })
+                .unwrap_or(versions.len())
+        });
+        by_count.min(by_age).min(versions.len() - 1)
+    }
+}
+
+/// What `Processor::gc` removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcSummary {
+    /// Files that had at least one version pruned.
+    pub files: usize,
+    pub versions: usize,
+    pub diffs: usize,
+    /// Blobs no file's newest version refers to anymore.
+    pub blobs: usize,
+}
+
+/// Something `Processor::verify` found wrong with intermediate memory.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityProblem {
+    #[error("{0}: history.json is unreadable: {1}")]
+    UnreadableHistory(PathBuf, String),
+    #[error("{0}: not a tracked file's directory")]
+    Orphan(PathBuf),
+    #[error("[{0}] the newest content is missing or unreadable: {1}")]
+    MissingContent(String, String),
+    #[error("[{0}] v{1} does not match its recorded hash")]
+    Corrupt(String, u32),
+    #[error("[{0}] the diff of v{1} can't be applied: {2}")]
+    BrokenDiff(String, u32, String),
+}
+
+/// What `Processor::verify` checked and found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub files: usize,
+    /// Versions whose content was rebuilt and matched its hash.
+    pub versions: usize,
+    /// Versions that can't be rebuilt by design, because a later version
+    /// was stored without a diff.
+    pub unrestorable: usize,
+    pub problems: Vec<IntegrityProblem>,
+}
+
+/// Disk usage of intermediate memory, from `Processor::stats`. Sizes are in
+/// bytes as stored, i.e. compressed and encrypted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub files: usize,
+    pub versions: usize,
+    /// Blobs and `latest` copies holding the newest content of each file.
+    pub content_bytes: u64,
+    pub diff_bytes: u64,
+    /// `history.json` files.
+    pub history_bytes: u64,
+    /// Everything else in the memory directory, vector stores included.
+    pub other_bytes: u64,
+    /// Tracked files by the space their versions take, largest first.
+    pub largest: Vec<(PathBuf, u64)>,
+}
+
+/// Where `Processor` keeps intermediate memory, how many files it works on
+/// at once and how much of each it reads at a time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessorConfig {
+    pub memory_dir: PathBuf,
+    /// Files hashed, diffed and stored concurrently.
+    pub max_concurrency: usize,
+    /// Files smaller than this many bytes are read into memory at once and
+    /// diffed; larger ones are streamed through a buffer of this size.
+    pub chunk_size: usize,
+    /// Files larger than this many bytes are skipped; `None` takes any size.
+    pub max_file_size: Option<u64>,
+    /// Skips binary files other than documents `extract` reads, like PDFs.
+    pub skip_binary: bool,
+    /// Skips files whose sniffed MIME type matches one of these, either
+    /// exactly or by a `type/*` wildcard.
+    pub skip_mime: Vec<String>,
+    /// zstd level for stored `latest` copies and diffs; `None` stores them as is.
+    pub compression: Option<i32>,
+}
+
+impl Default for ProcessorConfig {
+    fn default() -> Self {
+        Self {
+            memory_dir: PathBuf::from(DEFAULT_MEMORY_DIR),
+            max_concurrency: DEFAULT_CONCURRENCY,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_file_size: None,
+            skip_binary: false,
+            skip_mime: Vec::new(),
+            compression: Some(compress::DEFAULT_LEVEL),
+        }
+    }
+}
+
+impl ProcessorConfig {
+    /// Defaults, keeping intermediate memory in `memory_dir`.
+    pub fn new(memory_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            memory_dir: memory_dir.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn with_max_file_size(mut self, max_file_size: Option<u64>) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn with_skip_binary(mut self, skip_binary: bool) -> Self {
+        self.skip_binary = skip_binary;
+        self
+    }
+
+    pub fn with_skip_mime(mut self, skip_mime: Vec<String>) -> Self {
+        self.skip_mime = skip_mime;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Option<i32>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Whether a file's content has to be sniffed for the skip rules.
+    fn sniffs(&self) -> bool {
+        self.skip_binary || !self.skip_mime.is_empty()
+    }
+
+    /// Which skip rule, if any, a file starting with `head` falls under.
+    fn skip_reason(&self, path: &Path, head: &[u8]) -> Option<SkipReason> {
+        let mime = extract::mime_type(path, head);
+        let skipped = self
+            .skip_mime
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => mime.split('/').next() == Some(kind),
+                None => pattern.eq_ignore_ascii_case(mime),
+            });
+        if skipped {
+            return Some(SkipReason::Mime(mime));
+        }
+        let readable = matches!(Format::detect(path, head), Format::Pdf | Format::Docx);
+        (self.skip_binary && !readable && extract::is_binary(head)).then_some(SkipReason::Binary)
+    }
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct FileHistory {
-    versions: Vec<FileVersion>,
-    alias: String,
-    original_path: String,
+/// Opens the files being versioned. Every read of a source file goes through
+/// one, which lets tests count how often a file is read.
+trait SourceReader: Send + Sync {
+    fn open(&self, path: &Path) -> std::io::Result<fs::File>;
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct FileVersion {
-    version: u32,
-    hash: String,
-    size: u64,
-    mtime_ns: u128,
-    processed_at: String,
-    diff_file: Option<String>,
+/// Reads source files from disk.
+struct DiskReader;
+
+impl SourceReader for DiskReader {
+    fn open(&self, path: &Path) -> std::io::Result<fs::File> {
+        fs::File::open(path)
+    }
 }
 
 pub struct Processor;
 
 impl Processor {
-    pub async fn process_all(paths: &BTreeSet<PathBuf>) -> Result<()> {
-        let memory_dir = PathBuf::from("memory");
-        if !memory_dir.exists() {
-            fs::create_dir_all(&memory_dir).context("Failed to create memory directory")?;
-        }
+    /// Versions `paths`, drawing progress bars for the files it streams. Once
+    /// `cancel` fires, files not yet started are counted as interrupted.
+    pub async fn process_all(
+        paths: &BTreeSet<PathBuf>,
+        mode: ProcessMode,
+        config: &ProcessorConfig,
+        cancel: &CancellationToken,
+    ) -> Result<ProcessSummary> {
+        let progress = IndicatifProgress::new(config.chunk_size as u64);
+        Self::process_all_with_progress(paths, mode, config, std::sync::Arc::new(progress), cancel)
+            .await
+    }
+
+    /// Like `process_all`, reporting progress to `progress` instead.
+    pub async fn process_all_with_progress(
+        paths: &BTreeSet<PathBuf>,
+        mode: ProcessMode,
+        config: &ProcessorConfig,
+        progress: std::sync::Arc<dyn ProgressSink>,
+        cancel: &CancellationToken,
+    ) -> Result<ProcessSummary> {
+        let memory_dir = &config.memory_dir;
+        let checkpoint = if mode == ProcessMode::Full {
+            if !memory_dir.exists() {
+                fs::create_dir_all(memory_dir).context("Failed to create memory directory")?;
+            }
+            Self::remove_stray_temp_files(memory_dir);
+            Checkpoint::start(memory_dir, paths)?
+        } else {
+            Checkpoint::disabled()
+        };
+        let checkpoint = std::sync::Arc::new(checkpoint);
 
         let paths_vec: Vec<_> = paths.iter().cloned().collect();
         info!(
@@ -56,47 +446,161 @@ impl Processor {
         );
 
         let mut handles = Vec::new();
-        let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(16)); // Limit concurrent I/O to 16
-        let multi = std::sync::Arc::new(MultiProgress::new());
+        let semaphore =
+            std::sync::Arc::new(tokio::sync::Semaphore::new(config.max_concurrency.max(1)));
+        let shared_config = std::sync::Arc::new(config.clone());
+        let source: std::sync::Arc<dyn SourceReader> = std::sync::Arc::new(DiskReader);
+        let mut summary = ProcessSummary {
+            mode,
+            ..Default::default()
+        };
 
         for path in paths_vec {
-            let memory_dir = memory_dir.clone();
+            if cancel.is_cancelled() {
+                summary.interrupted += 1;
+                progress.report(ProgressEvent::Skipped {
+                    path,
+                    status: FileStatus::Interrupted,
+                });
+                continue;
+            }
+            let config = shared_config.clone();
             let semaphore = semaphore.clone();
-            let multi = multi.clone();
+            let progress = progress.clone();
+            let cancel = cancel.clone();
+            let checkpoint = checkpoint.clone();
+            let source = source.clone();
             handles.push(tokio::spawn(async move {
-                Self::pipeline_file(path, memory_dir, semaphore, multi).await
+                let result = Self::pipeline_file(
+                    path.clone(),
+                    config,
+                    mode,
+                    semaphore,
+                    progress.as_ref(),
+                    &cancel,
+                    &checkpoint,
+                    source,
+                )
+                .await;
+                let status = match result {
+                    Err(e)
+                        if matches!(
+                            e.downcast_ref::<ProcessError>(),
+                            Some(ProcessError::Busy(_))
+                        ) =>
+                    {
+                        warn!("Skipping {}: {}", path.display(), e);
+                        (FileStatus::Busy, None)
+                    }
+                    Err(e) => {
+                        progress.report(ProgressEvent::Failed {
+                            path,
+                            error: format!("{e:#}"),
+                        });
+                        return Err(e);
+                    }
+                    Ok(status) => status,
+                };
+                let stored = mode == ProcessMode::Full
+                    && matches!(status.0, FileStatus::New | FileStatus::Modified);
+                if !stored {
+                    progress.report(ProgressEvent::Skipped {
+                        path: path.clone(),
+                        status: status.0,
+                    });
+                }
+                Ok::<_, eyre::Report>((path, status))
             }));
         }
 
         for handle in handles {
-            if let Err(e) = handle.await.wrap_err("Task panicked")? {
-                error!("A processing task failed: {:?}", e);
-                return Err(e);
+            match handle
+                .await
+                .wrap_err("Task panicked")
+                .and_then(|result| result)
+            {
+                Ok((path, (status, preview))) => {
+                    summary.previews.extend(preview);
+                    summary.record(path, status);
+                }
+                Err(e) => {
+                    error!("A processing task failed: {:?}", e);
+                    if let Err(e) = checkpoint.flush() {
+                        warn!("Failed to write checkpoint: {:?}", e);
+                    }
+                    return Err(e);
+                }
             }
         }
+        if let Err(e) = checkpoint.finish(!summary.was_interrupted()) {
+            warn!("Failed to update checkpoint: {:?}", e);
+        }
 
-        info!("Finished all processing tasks.");
-        Ok(())
+        info!(
+            "Finished all processing tasks: {} new, {} modified, {} unchanged, {} busy, {} skipped.",
+            summary.new.len(),
+            summary.modified.len(),
+            summary.unchanged,
+            summary.busy.len(),
+            summary.skipped.len()
+        );
+        if summary.was_interrupted() {
+            warn!(
+                "Interrupted after {} files, {} left unprocessed.",
+                summary.changed() + summary.unchanged + summary.busy.len() + summary.skipped.len(),
+                summary.interrupted
+            );
+        }
+        Ok(summary)
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn pipeline_file(
         path: PathBuf,
-        memory_dir: PathBuf,
+        config: std::sync::Arc<ProcessorConfig>,
+        mode: ProcessMode,
         semaphore: std::sync::Arc<tokio::sync::Semaphore>,
-        multi: std::sync::Arc<MultiProgress>,
-    ) -> Result<()> {
+        progress: &dyn ProgressSink,
+        cancel: &CancellationToken,
+        checkpoint: &Checkpoint,
+        source: std::sync::Arc<dyn SourceReader>,
+    ) -> Result<(FileStatus, Option<VersionPreview>)> {
+        let memory_dir = &config.memory_dir;
+        let compression = config.compression;
         let path_ref = &path;
         let metadata = tokio::fs::metadata(path_ref).await.map_err(|e| {
             error!("Failed to get metadata for {}: {}", path_ref.display(), e);
             ProcessError::File(path_ref.to_path_buf())
         })?;
         let current_size = metadata.len();
+        if let Some(limit) = config.max_file_size.filter(|&limit| current_size > limit) {
+            debug!(
+                "[{}] Skipping file of {} bytes.",
+                path.display(),
+                current_size
+            );
+            let reason = SkipReason::TooLarge {
+                size: current_size,
+                limit,
+            };
+            return Ok((FileStatus::Skipped(reason), None));
+        }
+        let current_mode = Self::file_mode(&metadata);
         let current_mtime = metadata
             .modified()
             .map_err(|_| ProcessError::File(path.to_path_buf()))?
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
             .as_nanos();
+        let stamp = FileStamp {
+            size: current_size,
+            mtime_ns: current_mtime,
+        };
+        if checkpoint.is_done(&path, stamp) {
+            trace!("[{}] Done before the last run stopped.", path.display());
+            checkpoint.complete(&path, stamp);
+            return Ok((FileStatus::Unchanged, None));
+        }
 
         let path_alias = Self::calculate_path_alias(&path);
         let target_dir = memory_dir.join(&path_alias);
@@ -105,30 +609,17 @@ impl Processor {
             .map(|n| n.to_string_lossy())
             .unwrap_or_default();
 
-        if !target_dir.exists() {
-            tokio::fs::create_dir_all(&target_dir).await.map_err(|e| {
+        let history_path = target_dir.join("history.json");
+        let mut history = if history_path.exists() {
+            let data = Self::read_sealed(&history_path).await.map_err(|e| {
                 error!(
-                    "Failed to create target dir {}: {}",
-                    target_dir.display(),
+                    "Failed to read history file {}: {}",
+                    history_path.display(),
                     e
                 );
-                ProcessError::CreateDir(target_dir.clone())
+                ProcessError::Metadata(history_path.clone())
             })?;
-        }
-
-        let history_path = target_dir.join("history.json");
-        let mut history = if history_path.exists() {
-            let data = tokio::fs::read_to_string(&history_path)
-                .await
-                .map_err(|e| {
-                    error!(
-                        "Failed to read history file {}: {}",
-                        history_path.display(),
-                        e
-                    );
-                    ProcessError::Metadata(history_path.clone())
-                })?;
-            serde_json::from_str(&data).unwrap_or_else(|_| {
+            serde_json::from_slice(&data).unwrap_or_else(|_| {
                 warn!(
                     "Failed to parse history.json for {}. Recreating.",
                     path.display()
@@ -153,14 +644,65 @@ impl Processor {
             .is_some_and(|l| l.size == current_size && l.mtime_ns == current_mtime)
         {
             trace!("[{}] Skipping unchanged file (metadata).", file_basename);
-            return Ok(());
+            checkpoint.complete(&path, stamp);
+            return Ok((FileStatus::Unchanged, None));
+        }
+
+        if config.sniffs() {
+            let head = Self::read_head(&source, &path, extract::SNIFF_LEN).await?;
+            if let Some(reason) = config.skip_reason(&path, &head) {
+                debug!("[{}] Skipping: {}.", file_basename, reason);
+                return Ok((FileStatus::Skipped(reason), None));
+            }
+        }
+
+        let status = if history.versions.is_empty() {
+            FileStatus::New
+        } else {
+            FileStatus::Modified
+        };
+        if mode == ProcessMode::ScanOnly {
+            debug!("[{}] Would process ({:?}).", file_basename, status);
+            return Ok((status, None));
         }
 
         let _permit = semaphore
             .acquire()
             .await
             .wrap_err("Failed to acquire semaphore")?;
-        let current_hash = Self::process_file_stream(&path, multi).await?;
+        if cancel.is_cancelled() {
+            return Ok((FileStatus::Interrupted, None));
+        }
+
+        progress.report(ProgressEvent::FileStarted {
+            path: path.clone(),
+            size: current_size,
+        });
+        // Small files are read once and the buffer reused for hashing, diffing and
+        // writing `latest`; larger ones are streamed.
+        let content = if current_size < config.chunk_size as u64 {
+            Some(Self::read_source(&source, &path).await?)
+        } else {
+            None
+        };
+        let (current_hash, blob) = match &content {
+            Some(bytes) => {
+                let mut hasher = Sha256::new();
+                Self::update_hash(&mut hasher, bytes);
+                progress.report(ProgressEvent::BytesHashed {
+                    path: path.clone(),
+                    bytes: bytes.len() as u64,
+                });
+                (
+                    format!("{:x}", hasher.finalize()),
+                    format!("{:x}", Sha256::digest(bytes)),
+                )
+            }
+            None => {
+                Self::process_file_stream(&path, config.chunk_size, progress, source.clone())
+                    .await?
+            }
+        };
 
         // Deep change detection
         if history
@@ -169,151 +711,1010 @@ impl Processor {
             .is_some_and(|l| l.hash == current_hash)
         {
             trace!("[{}] Skipping unchanged file (content).", file_basename);
-            return Ok(());
+            checkpoint.complete(&path, stamp);
+            return Ok((FileStatus::Unchanged, None));
         }
 
-        // Diff and Storage Stage
-        let latest_file_path = target_dir.join("latest");
-        let mut diff_filename = None;
+        // Diff Stage
+        let latest_file_path = Self::latest_path(memory_dir, &history);
+        // Numbered from the last recorded version, since `gc` may have dropped earlier ones.
+        let next_version = history.versions.last().map_or(1, |l| l.version + 1);
+        let delta = match content.as_deref().filter(|_| latest_file_path.exists()) {
+            Some(bytes) => Self::compute_delta(bytes, &latest_file_path, &file_basename).await,
+            None => None,
+        };
 
-        if latest_file_path.exists() && current_size < CHUNK_SIZE as u64 {
-            if let Ok(source_content) = tokio::fs::read_to_string(&path).await {
-                if let Ok(old_content) = tokio::fs::read_to_string(&latest_file_path).await {
-                    let next_v = history.versions.len() + 1;
-                    let diff_name = format!("v{}.diff", next_v);
-                    let diff_path = target_dir.join(&diff_name);
-
-                    let text_diff = TextDiff::from_lines(&old_content, &source_content);
-                    let diff_text = UnifiedDiff::from_text_diff(&text_diff)
-                        .header(file_basename.as_ref(), file_basename.as_ref())
-                        .to_string();
-
-                    if !diff_text.is_empty() {
-                        tokio::fs::write(&diff_path, diff_text).await.map_err(|e| {
-                            error!("Failed to write diff file {}: {}", diff_path.display(), e);
-                            ProcessError::File(diff_path)
-                        })?;
-                        diff_filename = Some(diff_name);
-                    }
-                } else {
-                    debug!(
-                        "Could not read old content from {} for diffing.",
-                        latest_file_path.display()
-                    );
-                }
-            } else {
-                debug!(
-                    "Could not read source content from {} for diffing.",
-                    path.display()
-                );
+        if mode == ProcessMode::DryRun {
+            debug!("[{}] Would store v{}.", file_basename, next_version);
+            let preview = VersionPreview {
+                path: path.clone(),
+                version: next_version,
+                diff: match delta {
+                    Some(Delta::Text(diff)) => Some(diff),
+                    _ => None,
+                },
+            };
+            return Ok((status, Some(preview)));
+        }
+
+        // Storage Stage: once started, runs to completion so an interrupt never
+        // leaves a half-recorded version behind.
+        if cancel.is_cancelled() {
+            return Ok((FileStatus::Interrupted, None));
+        }
+        let objects_dir = memory_dir.join(OBJECTS_DIR);
+        for dir in [&target_dir, &objects_dir] {
+            if !dir.exists() {
+                tokio::fs::create_dir_all(dir).await.map_err(|e| {
+                    error!("Failed to create target dir {}: {}", dir.display(), e);
+                    ProcessError::CreateDir(dir.to_path_buf())
+                })?;
             }
         }
 
-        // Finalize: Update latest and record version
+        let mut diff_filename = None;
+        if let Some(delta) = delta {
+            let (diff_name, bytes) = match &delta {
+                Delta::Text(diff) => (format!("v{}.diff", next_version), diff.as_bytes()),
+                Delta::Binary(patch) => (
+                    format!("v{}.{}", next_version, BINARY_DELTA_EXT),
+                    patch.as_slice(),
+                ),
+            };
+            let diff_path = target_dir.join(&diff_name);
+            let written = match compress::encode(bytes, compression) {
+                Ok(encoded) => tokio::fs::write(&diff_path, encoded).await,
+                Err(e) => Err(e),
+            };
+            written.map_err(|e| {
+                error!("Failed to write diff file {}: {}", diff_path.display(), e);
+                ProcessError::File(diff_path)
+            })?;
+            diff_filename = Some(diff_name);
+        }
+
+        // Finalize: store the content as a blob, unless an identical file already did.
+        let blob_path = objects_dir.join(&blob);
+        if blob_path.exists() {
+            trace!("[{}] Content already stored as {}.", file_basename, blob);
+        } else {
+            Self::write_blob(
+                &path,
+                content.as_deref(),
+                &target_dir,
+                &blob_path,
+                compression,
+                source,
+            )
+            .await?;
+        }
+
+        history.versions.push(FileVersion {
+            version: next_version,
+            hash: current_hash,
+            size: current_size,
+            mtime_ns: current_mtime,
+            processed_at: chrono::Local::now().to_rfc3339(),
+            diff_file: diff_filename,
+            mode: current_mode,
+            blob: Some(blob),
+        });
+
+        Self::write_history(&history_path, &history).await?;
+        // Content from before blobs lives on in the diffs and the new blob.
+        let legacy_latest = target_dir.join("latest");
+        if legacy_latest.exists()
+            && let Err(e) = tokio::fs::remove_file(&legacy_latest).await
+        {
+            warn!("Failed to remove {}: {}", legacy_latest.display(), e);
+        }
+
+        info!("[{}] Version v{} stored.", file_basename, next_version);
+        checkpoint.complete(&path, stamp);
+        progress.report(ProgressEvent::VersionStored {
+            path,
+            version: next_version,
+        });
+        Ok((status, None))
+    }
+
+    /// Writes the blob through a `latest.tmp` in `target_dir`, so concurrent
+    /// writers of the same content each rename a complete copy into place.
+    async fn write_blob(
+        path: &Path,
+        content: Option<&[u8]>,
+        target_dir: &Path,
+        blob_path: &Path,
+        compression: Option<i32>,
+        source: std::sync::Arc<dyn SourceReader>,
+    ) -> Result<()> {
         let temp_latest = target_dir.join("latest.tmp");
-        tokio::fs::copy(&path, &temp_latest).await.map_err(|e| {
+        let written = match content {
+            Some(bytes) => match compress::encode(bytes, compression) {
+                Ok(encoded) => tokio::fs::write(&temp_latest, encoded).await,
+                Err(e) => Err(e),
+            },
+            None => {
+                let (src, dest) = (path.to_path_buf(), temp_latest.clone());
+                tokio::task::spawn_blocking(move || {
+                    compress::copy_encoded(source.open(&src)?, &dest, compression)
+                })
+                .await
+                .wrap_err("Compression task panicked")?
+            }
+        };
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temp_latest).await;
+            if Self::is_busy(&e) {
+                return Err(ProcessError::Busy(path.to_path_buf()).into());
+            }
             error!(
                 "Failed to copy {} to {}: {}",
                 path.display(),
                 temp_latest.display(),
                 e
             );
-            ProcessError::File(temp_latest.clone())
-        })?;
-        tokio::fs::rename(&temp_latest, &latest_file_path)
+            return Err(ProcessError::File(temp_latest).into());
+        }
+        tokio::fs::rename(&temp_latest, blob_path)
             .await
             .map_err(|e| {
                 error!(
                     "Failed to rename {} to {}: {}",
                     temp_latest.display(),
-                    latest_file_path.display(),
+                    blob_path.display(),
                     e
                 );
-                ProcessError::File(latest_file_path)
+                ProcessError::File(blob_path.to_path_buf())
             })?;
+        Ok(())
+    }
 
-        let next_version = history.versions.len() as u32 + 1;
-        history.versions.push(FileVersion {
-            version: next_version,
-            hash: current_hash,
-            size: current_size,
-            mtime_ns: current_mtime,
-            processed_at: chrono::Local::now().to_rfc3339(),
-            diff_file: diff_filename,
-        });
+    /// Returns the stored unified diff for `version` of the file tracked under `alias`,
+    /// or `None` if that version was stored without one (first version, large or
+    /// binary file).
+    pub async fn diff_for(memory_dir: &Path, alias: &str, version: u32) -> Result<Option<String>> {
+        let target_dir = memory_dir.join(alias);
+        let history = Self::read_history(&target_dir).await?;
 
-        let history_json =
-            serde_json::to_string_pretty(&history).wrap_err("Failed to serialize history")?;
-        tokio::fs::write(&history_path, history_json)
+        let entry = history
+            .versions
+            .iter()
+            .find(|v| v.version == version)
+            .ok_or_else(|| ProcessError::UnknownVersion(alias.to_string(), version))?;
+        let Some(diff_name) = entry
+            .diff_file
+            .as_ref()
+            .filter(|name| !Self::is_binary_delta(name))
+        else {
+            return Ok(None);
+        };
+
+        Self::read_diff(&target_dir.join(diff_name)).await.map(Some)
+    }
+
+    /// Loads the recorded history of `path`, resolving it to its alias the same
+    /// way `process_all` does.
+    pub async fn history(memory_dir: &Path, path: &Path) -> Result<FileHistory> {
+        Self::read_history(&Self::target_dir(memory_dir, path).await).await
+    }
+
+    /// Reconstructs the content `path` had at `version` by reverting the stored
+    /// diffs of every later version, newest first, starting from `latest`.
+    /// Fails if a later version was stored without a diff (large files).
+    pub async fn restore(memory_dir: &Path, path: &Path, version: u32) -> Result<Vec<u8>> {
+        let target_dir = Self::target_dir(memory_dir, path).await;
+        let history = Self::read_history(&target_dir).await?;
+        Self::reconstruct(memory_dir, &history, version).await
+    }
+
+    /// A unified diff from version `from` of `path` to version `to`, empty
+    /// when they are the same. Both versions must be text.
+    pub async fn diff(memory_dir: &Path, path: &Path, from: u32, to: u32) -> Result<String> {
+        let target_dir = Self::target_dir(memory_dir, path).await;
+        let history = Self::read_history(&target_dir).await?;
+        let old = Self::reconstruct(memory_dir, &history, from).await?;
+        let new = Self::reconstruct(memory_dir, &history, to).await?;
+        let text = |content: Vec<u8>, version: u32| {
+            String::from_utf8(content)
+                .map_err(|_| ProcessError::Binary(history.original_path.clone(), version))
+        };
+        let (old, new) = (text(old, from)?, text(new, to)?);
+        let text_diff = TextDiff::from_lines(&old, &new);
+        Ok(UnifiedDiff::from_text_diff(&text_diff)
+            .header(
+                &format!("{} v{}", history.original_path, from),
+                &format!("{} v{}", history.original_path, to),
+            )
+            .to_string())
+    }
+
+    /// Writes `version` of `path` to `dest`, with the permissions and mtime
+    /// recorded for that version.
+    pub async fn restore_to(
+        memory_dir: &Path,
+        path: &Path,
+        version: u32,
+        dest: &Path,
+    ) -> Result<()> {
+        let target_dir = Self::target_dir(memory_dir, path).await;
+        let history = Self::read_history(&target_dir).await?;
+        let content = Self::reconstruct(memory_dir, &history, version).await?;
+        tokio::fs::write(dest, content).await.map_err(|e| {
+            error!("Failed to write {}: {}", dest.display(), e);
+            ProcessError::File(dest.to_path_buf())
+        })?;
+        if let Some(recorded) = history.versions.iter().find(|v| v.version == version) {
+            Self::apply_version_metadata(dest, recorded).map_err(|e| {
+                error!("Failed to restore metadata on {}: {}", dest.display(), e);
+                ProcessError::File(dest.to_path_buf())
+            })?;
+        }
+
+        info!(
+            "[{}] Restored v{} to {}.",
+            history.alias,
+            version,
+            dest.display()
+        );
+        Ok(())
+    }
+
+    async fn reconstruct(
+        memory_dir: &Path,
+        history: &FileHistory,
+        version: u32,
+    ) -> Result<Vec<u8>> {
+        let alias = &history.alias;
+        let recorded = history
+            .versions
+            .iter()
+            .find(|v| v.version == version)
+            .ok_or_else(|| ProcessError::UnknownVersion(alias.clone(), version))?;
+
+        // Blobs of older versions stay around until `gc`, sparing the diff chain.
+        if let Some(blob) = &recorded.blob {
+            let blob_path = memory_dir.join(OBJECTS_DIR).join(blob);
+            match tokio::fs::read(&blob_path).await {
+                Ok(data) => {
+                    return compress::decode(data)
+                        .map_err(|e| Self::read_error(&blob_path, e).into());
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Self::read_error(&blob_path, e).into()),
+            }
+        }
+        let target_dir = memory_dir.join(alias);
+        let latest_file_path = Self::latest_path(memory_dir, history);
+        let latest = match tokio::fs::read(&latest_file_path).await {
+            Ok(data) => compress::decode(data),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| Self::read_error(&latest_file_path, e))?;
+        let later: Vec<_> = history
+            .versions
+            .iter()
+            .filter(|v| v.version > version)
+            .collect();
+        if later.is_empty() {
+            return Ok(latest);
+        }
+
+        let mut content = latest;
+        for newer in later.into_iter().rev() {
+            content = Self::revert(&target_dir, alias, newer, content, version).await?;
+        }
+
+        if Self::content_hash(&content) != recorded.hash {
+            error!(
+                "[{}] Reconstructed v{} fails its hash check.",
+                alias, version
+            );
+            return Err(ProcessError::Corrupt(alias.clone(), version).into());
+        }
+        debug!("[{}] Reconstructed v{}.", alias, version);
+        Ok(content)
+    }
+
+    /// Turns `content`, the content of `newer`, into that of the version
+    /// before it by undoing `newer`'s diff. `version` is the one being
+    /// rebuilt, for errors.
+    async fn revert(
+        target_dir: &Path,
+        alias: &str,
+        newer: &FileVersion,
+        content: Vec<u8>,
+        version: u32,
+    ) -> Result<Vec<u8>> {
+        let Some(diff_name) = &newer.diff_file else {
+            return Err(ProcessError::Unrecoverable(alias.to_string(), version).into());
+        };
+        let diff_path = target_dir.join(diff_name);
+        let reverted = if Self::is_binary_delta(diff_name) {
+            let patch = Self::read_stored_diff(&diff_path).await?;
+            let mut old = Vec::new();
+            bsdiff::patch(&content, &mut patch.as_slice(), &mut old).map(|()| old)
+        } else {
+            let text = String::from_utf8(content)
+                .map_err(|_| ProcessError::Unrecoverable(alias.to_string(), version))?;
+            let diff = Self::read_diff(&diff_path).await?;
+            patch::revert(&text, &diff)
+                .map(String::into_bytes)
+                .map_err(std::io::Error::other)
+        };
+        reverted.wrap_err_with(|| format!("Failed to revert {}", diff_path.display()))
+    }
+
+    /// The hash versions are recorded under, which ignores `\r`.
+    fn content_hash(content: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        Self::update_hash(&mut hasher, content);
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Lists every alias under `memory_dir` that has a stored `latest` copy.
+    pub fn tracked_files(memory_dir: &Path) -> Result<Vec<TrackedFile>> {
+        let mut tracked = Vec::new();
+        if !memory_dir.exists() {
+            return Ok(tracked);
+        }
+
+        for entry in fs::read_dir(memory_dir).wrap_err("Failed to read memory directory")? {
+            let target_dir = entry.wrap_err("Failed to read memory directory")?.path();
+            let history_path = target_dir.join("history.json");
+            if !history_path.exists() {
+                continue;
+            }
+
+            let history: FileHistory = match crypt::read(&history_path)
+                .map_err(eyre::Report::from)
+                .and_then(|data| Ok(serde_json::from_slice(&data)?))
+            {
+                Ok(history) => history,
+                Err(e) => {
+                    warn!("Skipping unreadable {}: {}", history_path.display(), e);
+                    continue;
+                }
+            };
+            let latest = Self::latest_path(memory_dir, &history);
+            let Some(last) = history.versions.last().filter(|_| latest.exists()) else {
+                continue;
+            };
+            tracked.push(TrackedFile {
+                version: last.version,
+                processed_at: last.processed_at.clone(),
+                alias: history.alias,
+                original_path: PathBuf::from(history.original_path),
+                latest,
+            });
+        }
+
+        tracked.sort_by(|a, b| a.alias.cmp(&b.alias));
+        Ok(tracked)
+    }
+
+    /// Tallies what intermediate memory under `memory_dir` holds and where its
+    /// space goes. A blob shared by several files counts towards each of them
+    /// in `largest`, but only once in `content_bytes`.
+    pub async fn stats(memory_dir: &Path) -> Result<MemoryStats> {
+        let mut stats = MemoryStats::default();
+        if !memory_dir.exists() {
+            return Ok(stats);
+        }
+        let objects_dir = memory_dir.join(OBJECTS_DIR);
+        for entry in fs::read_dir(memory_dir).wrap_err("Failed to read memory directory")? {
+            let path = entry.wrap_err("Failed to read memory directory")?.path();
+            if path == objects_dir {
+                stats.content_bytes += disk_usage(&path);
+                continue;
+            }
+            if !path.join("history.json").exists() {
+                stats.other_bytes += disk_usage(&path);
+                continue;
+            }
+
+            let history = Self::read_history(&path).await?;
+            let mut file_bytes = 0;
+            for file in fs::read_dir(&path).wrap_err("Failed to read memory directory")? {
+                let file = file.wrap_err("Failed to read memory directory")?;
+                let size = disk_usage(&file.path());
+                let name = file.file_name();
+                if name == "history.json" {
+                    stats.history_bytes += size;
+                } else if name == "latest" {
+                    stats.content_bytes += size;
+                } else {
+                    stats.diff_bytes += size;
+                }
+                file_bytes += size;
+            }
+            if let Some(blob) = history.versions.last().and_then(|v| v.blob.as_ref()) {
+                file_bytes += disk_usage(&objects_dir.join(blob));
+            }
+            stats.files += 1;
+            stats.versions += history.versions.len();
+            stats
+                .largest
+                .push((PathBuf::from(history.original_path), file_bytes));
+        }
+        stats
+            .largest
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(stats)
+    }
+
+    /// Checks every tracked file: its newest content must hash to what the
+    /// history records, and each diff must turn a version into the one
+    /// before it, again matching its hash. Directories that don't belong to
+    /// a tracked file are reported as orphans.
+    pub async fn verify(memory_dir: &Path) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        if !memory_dir.exists() {
+            return Ok(report);
+        }
+        let known = [OBJECTS_DIR, crate::config::COLLECTIONS_DIR];
+        for entry in fs::read_dir(memory_dir).wrap_err("Failed to read memory directory")? {
+            let target_dir = entry.wrap_err("Failed to read memory directory")?.path();
+            let is_known = target_dir
+                .file_name()
+                .is_some_and(|name| known.iter().any(|k| name == *k));
+            if !target_dir.is_dir() || is_known {
+                continue;
+            }
+            if !target_dir.join("history.json").exists() {
+                report.problems.push(IntegrityProblem::Orphan(target_dir));
+                continue;
+            }
+            let history = match Self::read_history(&target_dir).await {
+                Ok(history) => history,
+                Err(e) => {
+                    report.problems.push(IntegrityProblem::UnreadableHistory(
+                        target_dir,
+                        format!("{e:#}"),
+                    ));
+                    continue;
+                }
+            };
+            report.files += 1;
+            Self::verify_history(memory_dir, &target_dir, &history, &mut report).await;
+        }
+        info!(
+            "Verified {} files: {} versions intact, {} unrestorable by design, {} problems.",
+            report.files,
+            report.versions,
+            report.unrestorable,
+            report.problems.len()
+        );
+        Ok(report)
+    }
+
+    /// Walks `history` from its newest version back, reverting one diff at
+    /// a time, and stops at the first version that fails.
+    async fn verify_history(
+        memory_dir: &Path,
+        target_dir: &Path,
+        history: &FileHistory,
+        report: &mut VerifyReport,
+    ) {
+        let alias = &history.alias;
+        let Some(newest) = history.versions.last() else {
+            return;
+        };
+        let latest_path = Self::latest_path(memory_dir, history);
+        let mut content = match tokio::fs::read(&latest_path)
+            .await
+            .and_then(compress::decode)
+        {
+            Ok(content) => content,
+            Err(e) => {
+                report.problems.push(IntegrityProblem::MissingContent(
+                    alias.clone(),
+                    e.to_string(),
+                ));
+                return;
+            }
+        };
+        if Self::content_hash(&content) != newest.hash {
+            report
+                .problems
+                .push(IntegrityProblem::Corrupt(alias.clone(), newest.version));
+            return;
+        }
+        report.versions += 1;
+
+        for pair in history.versions.windows(2).rev() {
+            let (older, newer) = (&pair[0], &pair[1]);
+            if newer.diff_file.is_none() {
+                report.unrestorable += history
+                    .versions
+                    .iter()
+                    .filter(|v| v.version <= older.version)
+                    .count();
+                return;
+            }
+            content = match Self::revert(target_dir, alias, newer, content, older.version).await {
+                Ok(content) => content,
+                Err(e) => {
+                    report.problems.push(IntegrityProblem::BrokenDiff(
+                        alias.clone(),
+                        newer.version,
+                        format!("{e:#}"),
+                    ));
+                    return;
+                }
+            };
+            if Self::content_hash(&content) != older.hash {
+                report
+                    .problems
+                    .push(IntegrityProblem::Corrupt(alias.clone(), older.version));
+                return;
+            }
+            report.versions += 1;
+        }
+    }
+
+    /// Deletes the orphaned directories among `problems`, returning how many.
+    pub async fn remove_orphans(problems: &[IntegrityProblem]) -> Result<usize> {
+        let mut removed = 0;
+        for problem in problems {
+            if let IntegrityProblem::Orphan(dir) = problem {
+                tokio::fs::remove_dir_all(dir)
+                    .await
+                    .wrap_err_with(|| format!("Failed to remove {}", dir.display()))?;
+                info!("Removed orphaned {}", dir.display());
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// The tracked files at `path`, or anywhere under it if it's a directory.
+    /// `path` need not exist anymore.
+    pub async fn tracked_under(memory_dir: &Path, path: &Path) -> Result<Vec<TrackedFile>> {
+        let path = match tokio::fs::canonicalize(path).await {
+            Ok(canonical) => canonical,
+            Err(_) => std::path::absolute(path)?,
+        };
+        let mut tracked = Self::tracked_files(memory_dir)?;
+        tracked.retain(|file| file.original_path.starts_with(&path));
+        Ok(tracked)
+    }
+
+    /// Erases `files` from intermediate memory: their directories with every
+    /// version and diff, then the blobs no remaining file refers to. Returns
+    /// how many blobs went.
+    pub async fn forget(memory_dir: &Path, files: &[TrackedFile]) -> Result<usize> {
+        for file in files {
+            let target_dir = memory_dir.join(&file.alias);
+            tokio::fs::remove_dir_all(&target_dir)
+                .await
+                .wrap_err_with(|| format!("Failed to remove {}", target_dir.display()))?;
+            info!("[{}] Forgot {}.", file.alias, file.original_path.display());
+        }
+        Self::sweep_objects(memory_dir).await
+    }
+
+    /// Drops the versions `policy` doesn't keep from every tracked file,
+    /// rewriting `history.json` and deleting the diffs only they needed, then
+    /// deletes the blobs no file's newest version refers to.
+    pub async fn gc(memory_dir: &Path, policy: &RetentionPolicy) -> Result<GcSummary> {
+        let mut summary = GcSummary::default();
+        let now = chrono::Local::now();
+        for tracked in Self::tracked_files(memory_dir)? {
+            let target_dir = memory_dir.join(&tracked.alias);
+            let mut history = Self::read_history(&target_dir).await?;
+            let first_kept = policy.first_kept(&history.versions, now);
+            if first_kept == 0 {
+                continue;
+            }
+
+            // The oldest kept version's own diff leads back to a pruned version.
+            let pruned: Vec<_> = history.versions.drain(..first_kept).collect();
+            let oldest = &mut history.versions[0];
+            let obsolete_diffs = pruned
+                .iter()
+                .filter_map(|v| v.diff_file.clone())
+                .chain(oldest.diff_file.take());
+            // Record the pruning before deleting anything, so an interrupted gc
+            // leaves stray diffs rather than a history pointing at missing ones.
+            Self::write_history(&target_dir.join("history.json"), &history).await?;
+
+            for diff_name in obsolete_diffs {
+                let diff_path = target_dir.join(diff_name);
+                match tokio::fs::remove_file(&diff_path).await {
+                    Ok(()) => summary.diffs += 1,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => warn!("Failed to remove {}: {}", diff_path.display(), e),
+                }
+            }
+            debug!(
+                "[{}] Pruned {} versions, oldest kept is now v{}.",
+                tracked.alias,
+                pruned.len(),
+                history.versions[0].version
+            );
+            summary.files += 1;
+            summary.versions += pruned.len();
+        }
+
+        summary.blobs = Self::sweep_objects(memory_dir).await?;
+
+        info!(
+            "Garbage collection removed {} versions ({} diffs) from {} files and {} blobs.",
+            summary.versions, summary.diffs, summary.files, summary.blobs
+        );
+        Ok(summary)
+    }
+
+    /// Deletes every blob not referenced by a newest version. Older versions
+    /// can still be rebuilt from their diffs. Any history that can't be read
+    /// aborts the sweep, since its blobs can't be told apart from garbage.
+    pub async fn sweep_objects(memory_dir: &Path) -> Result<usize> {
+        let objects_dir = memory_dir.join(OBJECTS_DIR);
+        if !objects_dir.exists() {
+            return Ok(0);
+        }
+
+        let mut referenced = std::collections::HashSet::new();
+        for entry in fs::read_dir(memory_dir).wrap_err("Failed to read memory directory")? {
+            let target_dir = entry.wrap_err("Failed to read memory directory")?.path();
+            if !target_dir.join("history.json").exists() {
+                continue;
+            }
+            let history = Self::read_history(&target_dir).await?;
+            if let Some(blob) = history.versions.last().and_then(|v| v.blob.clone()) {
+                referenced.insert(blob);
+            }
+        }
+
+        let mut removed = 0;
+        for entry in fs::read_dir(&objects_dir).wrap_err("Failed to read objects directory")? {
+            let blob_path = entry.wrap_err("Failed to read objects directory")?.path();
+            let is_referenced = blob_path
+                .file_name()
+                .is_some_and(|name| referenced.contains(name.to_string_lossy().as_ref()));
+            if is_referenced {
+                continue;
+            }
+            match tokio::fs::remove_file(&blob_path).await {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove {}: {}", blob_path.display(), e),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Writes the latest stored version of `alias` to `dest`, carrying over the
+    /// permissions and mtime recorded when it was processed. Returns the version number.
+    pub async fn checkout(memory_dir: &Path, alias: &str, dest: &Path) -> Result<u32> {
+        let target_dir = memory_dir.join(alias);
+        let history = Self::read_history(&target_dir).await?;
+        let latest = history
+            .versions
+            .last()
+            .ok_or_else(|| ProcessError::NoVersions(alias.to_string()))?;
+
+        let latest_file_path = Self::latest_path(memory_dir, &history);
+        let (src, dest_buf) = (latest_file_path.clone(), dest.to_path_buf());
+        tokio::task::spawn_blocking(move || compress::copy_decoded(&src, &dest_buf))
             .await
+            .wrap_err("Decompression task panicked")?
             .map_err(|e| {
                 error!(
-                    "Failed to write history file {}: {}",
-                    history_path.display(),
+                    "Failed to copy {} to {}: {}",
+                    latest_file_path.display(),
+                    dest.display(),
                     e
                 );
-                ProcessError::Metadata(history_path)
+                ProcessError::File(dest.to_path_buf())
             })?;
+        Self::apply_version_metadata(dest, latest).map_err(|e| {
+            error!("Failed to restore metadata on {}: {}", dest.display(), e);
+            ProcessError::File(dest.to_path_buf())
+        })?;
 
-        info!("[{}] Version v{} stored.", file_basename, next_version);
+        info!(
+            "[{}] Checked out v{} to {}.",
+            alias,
+            latest.version,
+            dest.display()
+        );
+        Ok(latest.version)
+    }
+
+    /// Where the content of the newest version is stored: its blob, or the
+    /// alias' `latest` copy for histories from before blobs.
+    fn latest_path(memory_dir: &Path, history: &FileHistory) -> PathBuf {
+        match history.versions.last().and_then(|v| v.blob.as_ref()) {
+            Some(blob) => memory_dir.join(OBJECTS_DIR).join(blob),
+            None => memory_dir.join(&history.alias).join("latest"),
+        }
+    }
+
+    /// Directory holding the history of `path`, resolved the same way `process_all` does.
+    async fn target_dir(memory_dir: &Path, path: &Path) -> PathBuf {
+        let canonical = tokio::fs::canonicalize(path)
+            .await
+            .unwrap_or_else(|_| path.to_path_buf());
+        memory_dir.join(Self::calculate_path_alias(&canonical))
+    }
+
+    fn is_binary_delta(diff_name: &str) -> bool {
+        Path::new(diff_name)
+            .extension()
+            .is_some_and(|ext| ext == BINARY_DELTA_EXT)
+    }
+
+    async fn read_diff(diff_path: &Path) -> Result<String> {
+        let data = Self::read_stored_diff(diff_path).await?;
+        String::from_utf8(data).wrap_err_with(|| format!("{} is not UTF-8", diff_path.display()))
+    }
+
+    async fn read_stored_diff(diff_path: &Path) -> Result<Vec<u8>> {
+        let data = match tokio::fs::read(diff_path).await {
+            Ok(data) => compress::decode(data),
+            Err(e) => Err(e),
+        };
+        match data {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(ProcessError::MissingDiff(diff_path.to_path_buf()).into())
+            }
+            Err(e) => {
+                error!("Failed to read diff file {}: {}", diff_path.display(), e);
+                Err(ProcessError::File(diff_path.to_path_buf()).into())
+            }
+        }
+    }
+
+    async fn write_history(history_path: &Path, history: &FileHistory) -> Result<()> {
+        let history_json =
+            serde_json::to_string_pretty(history).wrap_err("Failed to serialize history")?;
+        let written = match crypt::seal(history_json.as_bytes()) {
+            Ok(sealed) => tokio::fs::write(history_path, sealed).await,
+            Err(e) => Err(e),
+        };
+        written.map_err(|e| {
+            error!(
+                "Failed to write history file {}: {}",
+                history_path.display(),
+                e
+            );
+            ProcessError::Metadata(history_path.to_path_buf())
+        })?;
         Ok(())
     }
 
-    async fn process_file_stream(
+    /// Up to the first `len` bytes of `path`, read through `source`.
+    async fn read_head(
+        source: &std::sync::Arc<dyn SourceReader>,
         path: &Path,
-        multi: std::sync::Arc<MultiProgress>,
-    ) -> Result<String> {
-        let metadata = fs::metadata(path).map_err(|_| ProcessError::File(path.to_path_buf()))?;
-        let file_size = metadata.len();
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let (source, path_buf) = (source.clone(), path.to_path_buf());
+        let read = tokio::task::spawn_blocking(move || -> std::io::Result<Vec<u8>> {
+            let mut head = Vec::with_capacity(len);
+            source
+                .open(&path_buf)?
+                .take(len as u64)
+                .read_to_end(&mut head)?;
+            Ok(head)
+        })
+        .await
+        .wrap_err("Read task panicked")?;
+        Ok(read.map_err(|e| Self::read_error(path, e))?)
+    }
 
-        let file_basename = path
-            .file_name()
-            .map(|n| n.to_string_lossy().into_owned())
-            .unwrap_or_default();
+    /// Reads a file, decrypting it if it was sealed.
+    async fn read_sealed(path: &Path) -> std::io::Result<Vec<u8>> {
+        crypt::open(tokio::fs::read(path).await?)
+    }
+
+    async fn read_history(target_dir: &Path) -> Result<FileHistory> {
+        let history_path = target_dir.join("history.json");
+        let data = Self::read_sealed(&history_path).await.map_err(|e| {
+            error!(
+                "Failed to read history file {}: {}",
+                history_path.display(),
+                e
+            );
+            ProcessError::Metadata(history_path.clone())
+        })?;
+        serde_json::from_slice(&data)
+            .wrap_err_with(|| format!("Failed to parse {}", history_path.display()))
+    }
+
+    fn apply_version_metadata(dest: &Path, version: &FileVersion) -> std::io::Result<()> {
+        let mtime = UNIX_EPOCH + Duration::from_nanos(version.mtime_ns as u64);
+        fs::File::options()
+            .write(true)
+            .open(dest)?
+            .set_modified(mtime)?;
+
+        // Applied last so a read-only mode doesn't block setting the mtime.
+        #[cfg(unix)]
+        if let Some(mode) = version.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dest, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    /// Removes `latest.tmp` files left behind by a previous run that was killed
+    /// between copying and renaming.
+    fn remove_stray_temp_files(memory_dir: &Path) {
+        let Ok(entries) = fs::read_dir(memory_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let temp_latest = entry.path().join("latest.tmp");
+            if temp_latest.exists() {
+                match fs::remove_file(&temp_latest) {
+                    Ok(()) => debug!("Removed stray {}", temp_latest.display()),
+                    Err(e) => warn!("Failed to remove {}: {}", temp_latest.display(), e),
+                }
+            }
+        }
+    }
+
+    /// Transient lock/busy conditions, e.g. a log being written or a file held
+    /// open exclusively on Windows. Permission errors are not considered busy.
+    fn is_busy(e: &std::io::Error) -> bool {
+        use std::io::ErrorKind;
+        if matches!(
+            e.kind(),
+            ErrorKind::WouldBlock | ErrorKind::ResourceBusy | ErrorKind::ExecutableFileBusy
+        ) {
+            return true;
+        }
+        // ERROR_SHARING_VIOLATION / ERROR_LOCK_VIOLATION
+        cfg!(windows) && matches!(e.raw_os_error(), Some(32 | 33))
+    }
 
-        let pb = multi.add(ProgressBar::new(file_size));
-        pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%) {msg}")?
-            .progress_chars("#>-"));
-        pb.set_message(file_basename.clone());
+    fn read_error(path: &Path, e: std::io::Error) -> ProcessError {
+        if Self::is_busy(&e) {
+            ProcessError::Busy(path.to_path_buf())
+        } else {
+            error!("Failed to read {}: {}", path.display(), e);
+            ProcessError::File(path.to_path_buf())
+        }
+    }
+
+    #[cfg(unix)]
+    fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode())
+    }
+
+    #[cfg(not(unix))]
+    fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+        None
+    }
+
+    /// Reads all of `path` through `source`.
+    async fn read_source(
+        source: &std::sync::Arc<dyn SourceReader>,
+        path: &Path,
+    ) -> Result<Vec<u8>> {
+        let (source, path_buf) = (source.clone(), path.to_path_buf());
+        let read = tokio::task::spawn_blocking(move || -> std::io::Result<Vec<u8>> {
+            let mut bytes = Vec::new();
+            source.open(&path_buf)?.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        })
+        .await
+        .wrap_err("Read task panicked")?;
+        Ok(read.map_err(|e| Self::read_error(path, e))?)
+    }
 
+    async fn process_file_stream(
+        path: &Path,
+        chunk_size: usize,
+        progress: &dyn ProgressSink,
+        source: std::sync::Arc<dyn SourceReader>,
+    ) -> Result<(String, String)> {
+        // Hashing runs on a blocking thread; its progress is relayed from here.
+        let (sent, mut hashed) = tokio::sync::mpsc::unbounded_channel();
         let path_buf = path.to_path_buf();
-        let pb_inner = pb.clone();
-        let hash = tokio::task::spawn_blocking(move || -> Result<String> {
-            let mut f =
-                fs::File::open(&path_buf).map_err(|_| ProcessError::File(path_buf.clone()))?;
+        let hashing = tokio::task::spawn_blocking(move || -> Result<(String, String)> {
+            let mut f = source
+                .open(&path_buf)
+                .map_err(|e| Self::read_error(&path_buf, e))?;
 
             let mut hasher = Sha256::new();
-            let mut buffer = vec![0u8; CHUNK_SIZE];
+            let mut blob_hasher = Sha256::new();
+            let mut buffer = vec![0u8; chunk_size.max(1)];
 
             loop {
                 let n = f
                     .read(&mut buffer)
-                    .map_err(|_| ProcessError::File(path_buf.clone()))?;
+                    .map_err(|e| Self::read_error(&path_buf, e))?;
                 if n == 0 {
                     break;
                 }
 
-                let chunk = &buffer[..n];
-                // Efficient \r filtering: find segments between \r and update hasher with slices
-                let mut start = 0;
-                while let Some(pos) = chunk[start..].iter().position(|&b| b == b'\r') {
-                    let actual_pos = start + pos;
-                    hasher.update(&chunk[start..actual_pos]);
-                    start = actual_pos + 1;
-                }
-                hasher.update(&chunk[start..]);
+                Self::update_hash(&mut hasher, &buffer[..n]);
+                blob_hasher.update(&buffer[..n]);
 
-                pb_inner.inc(n as u64);
+                let _ = sent.send(n as u64);
             }
 
-            Ok(format!("{:x}", hasher.finalize()))
+            Ok((
+                format!("{:x}", hasher.finalize()),
+                format!("{:x}", blob_hasher.finalize()),
+            ))
+        });
+        while let Some(bytes) = hashed.recv().await {
+            progress.report(ProgressEvent::BytesHashed {
+                path: path.to_path_buf(),
+                bytes,
+            });
+        }
+        hashing.await.wrap_err("Hashing task panicked")?
+    }
+
+    /// Diffs `source` against the stored latest content: a unified diff when
+    /// both are UTF-8, a binary delta otherwise.
+    async fn compute_delta(
+        source: &[u8],
+        latest_file_path: &Path,
+        file_basename: &str,
+    ) -> Option<Delta> {
+        let old_content = match tokio::fs::read(latest_file_path).await {
+            Ok(data) => compress::decode(data).ok(),
+            Err(_) => None,
+        };
+        let Some(old_content) = old_content else {
+            debug!(
+                "Could not read old content from {} for diffing.",
+                latest_file_path.display()
+            );
+            return None;
+        };
+
+        if let (Ok(source_text), Ok(old_text)) = (
+            std::str::from_utf8(source),
+            std::str::from_utf8(&old_content),
+        ) {
+            let text_diff = TextDiff::from_lines(old_text, source_text);
+            let diff_text = UnifiedDiff::from_text_diff(&text_diff)
+                .header(file_basename, file_basename)
+                .to_string();
+            return (!diff_text.is_empty()).then_some(Delta::Text(diff_text));
+        }
+
+        debug!(
+            "[{}] Binary content, computing a binary delta.",
+            file_basename
+        );
+        let source = source.to_vec();
+        let patch = tokio::task::spawn_blocking(move || {
+            let mut patch = Vec::new();
+            bsdiff::diff(&source, &old_content, &mut patch).map(|()| patch)
         })
-        .await
-        .wrap_err("Hashing task panicked")??;
+        .await;
+        match patch {
+            Ok(Ok(patch)) => Some(Delta::Binary(patch)),
+            Ok(Err(e)) => {
+                warn!("[{}] Failed to compute binary delta: {}", file_basename, e);
+                None
+            }
+            Err(e) => {
+                warn!("[{}] Binary delta task panicked: {}", file_basename, e);
+                None
+            }
+        }
+    }
 
-        pb.finish_with_message(format!("{} [DONE]", file_basename));
-        Ok(hash)
+    fn update_hash(hasher: &mut Sha256, chunk: &[u8]) {
+        // Efficient \r filtering: find segments between \r and update hasher with slices
+        let mut start = 0;
+        while let Some(pos) = chunk[start..].iter().position(|&b| b == b'\r') {
+            let actual_pos = start + pos;
+            hasher.update(&chunk[start..actual_pos]);
+            start = actual_pos + 1;
+        }
+        hasher.update(&chunk[start..]);
     }
 
     fn calculate_path_alias(path: &Path) -> String {
@@ -340,3 +1741,279 @@ impl Processor {
             .to_lowercase()
     }
 }
+
+/// Bytes taken by `path`, recursively for directories; 0 if it can't be read.
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| disk_usage(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Reads from disk, counting how often each file is opened.
+    #[derive(Default)]
+    struct CountingReader {
+        opens: Mutex<BTreeMap<PathBuf, usize>>,
+    }
+
+    impl CountingReader {
+        /// Reads of `path` since the last call.
+        fn take(&self, path: &Path) -> usize {
+            self.opens.lock().unwrap().remove(path).unwrap_or(0)
+        }
+    }
+
+    impl SourceReader for CountingReader {
+        fn open(&self, path: &Path) -> std::io::Result<fs::File> {
+            *self
+                .opens
+                .lock()
+                .unwrap()
+                .entry(path.to_path_buf())
+                .or_default() += 1;
+            fs::File::open(path)
+        }
+    }
+
+    /// Small enough that the streaming path is cheap to exercise.
+    const TEST_CHUNK_SIZE: usize = 4096;
+
+    async fn process(path: &Path, memory_dir: &Path, reader: &Arc<CountingReader>) -> FileStatus {
+        let config = ProcessorConfig::new(memory_dir).with_chunk_size(TEST_CHUNK_SIZE);
+        process_with(path, config, reader).await
+    }
+
+    async fn process_with(
+        path: &Path,
+        config: ProcessorConfig,
+        reader: &Arc<CountingReader>,
+    ) -> FileStatus {
+        Processor::pipeline_file(
+            path.to_path_buf(),
+            Arc::new(config),
+            ProcessMode::Full,
+            Arc::new(tokio::sync::Semaphore::new(1)),
+            &NoProgress,
+            &CancellationToken::new(),
+            &Checkpoint::disabled(),
+            reader.clone(),
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    #[tokio::test]
+    async fn small_files_are_read_once_per_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory_dir = dir.path().join("memory");
+        let path = dir.path().join("small.txt");
+        let reader = Arc::new(CountingReader::default());
+
+        fs::write(&path, "first version\n").unwrap();
+        assert_eq!(process(&path, &memory_dir, &reader).await, FileStatus::New);
+        assert_eq!(reader.take(&path), 1);
+
+        // Hashed, diffed against the stored version and stored from one read.
+        fs::write(&path, "second version, a little longer\n").unwrap();
+        assert_eq!(
+            process(&path, &memory_dir, &reader).await,
+            FileStatus::Modified
+        );
+        assert_eq!(reader.take(&path), 1);
+    }
+
+    #[tokio::test]
+    async fn large_files_are_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory_dir = dir.path().join("memory");
+        let path = dir.path().join("large.txt");
+        let reader = Arc::new(CountingReader::default());
+
+        fs::write(&path, vec![b'x'; TEST_CHUNK_SIZE]).unwrap();
+        assert_eq!(process(&path, &memory_dir, &reader).await, FileStatus::New);
+        // Once to hash, once to store.
+        assert_eq!(reader.take(&path), 2);
+    }
+
+    #[tokio::test]
+    async fn binary_files_are_skipped_after_reading_their_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ProcessorConfig::new(dir.path().join("memory"))
+            .with_chunk_size(TEST_CHUNK_SIZE)
+            .with_skip_binary(true);
+        let reader = Arc::new(CountingReader::default());
+
+        let binary = dir.path().join("data.bin");
+        fs::write(&binary, [b'a', 0, b'b', 0]).unwrap();
+        assert_eq!(
+            process_with(&binary, config.clone(), &reader).await,
+            FileStatus::Skipped(SkipReason::Binary)
+        );
+        assert_eq!(reader.take(&binary), 1);
+
+        let text = dir.path().join("notes.txt");
+        fs::write(&text, "plain text\n").unwrap();
+        assert_eq!(process_with(&text, config, &reader).await, FileStatus::New);
+        // Sniffed, then read in full.
+        assert_eq!(reader.take(&text), 2);
+    }
+
+    /// Versions `path` into `memory_dir` as `content`.
+    async fn store_version(path: &Path, memory_dir: &Path, content: impl AsRef<[u8]>) {
+        fs::write(path, content).unwrap();
+        let config = ProcessorConfig {
+            memory_dir: memory_dir.to_path_buf(),
+            ..Default::default()
+        };
+        let paths = BTreeSet::from([path.to_path_buf()]);
+        let summary = Processor::process_all(
+            &paths,
+            ProcessMode::Full,
+            &config,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.changed(), 1);
+    }
+
+    #[tokio::test]
+    async fn earlier_versions_are_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory_dir = dir.path().join("memory");
+        let path = dir.path().canonicalize().unwrap().join("notes.txt");
+        let versions = [
+            "one\ntwo\nthree\n",
+            "one\n2\nthree\nfour\n",
+            "zero\none\n2\nthree\nfour, and no newline",
+        ];
+        for content in versions {
+            store_version(&path, &memory_dir, content).await;
+        }
+
+        for (version, content) in (1..).zip(versions) {
+            let restored = Processor::restore(&memory_dir, &path, version)
+                .await
+                .unwrap();
+            assert_eq!(String::from_utf8(restored).unwrap(), content);
+        }
+
+        let dest = dir.path().join("restored.txt");
+        Processor::restore_to(&memory_dir, &path, 1, &dest)
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), versions[0]);
+    }
+
+    #[tokio::test]
+    async fn zstd_files_stored_uncompressed_are_restored_as_they_were() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory_dir = dir.path().join("memory");
+        // What `compression_level = 0` configures.
+        let config = ProcessorConfig::new(&memory_dir)
+            .with_chunk_size(TEST_CHUNK_SIZE)
+            .with_compression(None);
+        let reader = Arc::new(CountingReader::default());
+        // Incompressible, so the zstd file stays past the chunk size.
+        let mut state = 0x9e37_79b9_u32;
+        let noise: Vec<u8> = (0..2 * TEST_CHUNK_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        // One read whole, one streamed.
+        for (name, content) in [("small.zst", &b"small"[..]), ("large.zst", &noise)] {
+            let path = dir.path().canonicalize().unwrap().join(name);
+            let zstd_file = zstd::encode_all(content, 1).unwrap();
+            assert_eq!(zstd_file.len() < TEST_CHUNK_SIZE, name == "small.zst");
+            fs::write(&path, &zstd_file).unwrap();
+            assert_eq!(
+                process_with(&path, config.clone(), &reader).await,
+                FileStatus::New
+            );
+
+            let restored = Processor::restore(&memory_dir, &path, 1).await.unwrap();
+            assert_eq!(restored, zstd_file);
+            let dest = dir.path().join("restored");
+            Processor::restore_to(&memory_dir, &path, 1, &dest)
+                .await
+                .unwrap();
+            assert_eq!(fs::read(&dest).unwrap(), zstd_file);
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_files_share_a_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory_dir = dir.path().join("memory");
+        let root = dir.path().canonicalize().unwrap();
+        let (a, b) = (root.join("a.txt"), root.join("b.txt"));
+        store_version(&a, &memory_dir, "same\n").await;
+        store_version(&b, &memory_dir, "same\n").await;
+        store_version(&a, &memory_dir, "changed\n").await;
+
+        let blobs = fs::read_dir(memory_dir.join(OBJECTS_DIR)).unwrap().count();
+        assert_eq!(blobs, 2);
+        for (path, version, content) in [(&a, 1, "same\n"), (&b, 1, "same\n"), (&a, 2, "changed\n")]
+        {
+            let restored = Processor::restore(&memory_dir, path, version)
+                .await
+                .unwrap();
+            assert_eq!(restored, content.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_versions_are_rebuilt_from_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory_dir = dir.path().join("memory");
+        let path = dir.path().canonicalize().unwrap().join("image.bin");
+        let versions: Vec<Vec<u8>> = (0u8..3)
+            .map(|v| {
+                (0..4096u32)
+                    .map(|i| (i % 253) as u8 ^ v)
+                    .chain([0xff, v])
+                    .collect()
+            })
+            .collect();
+        for content in &versions {
+            store_version(&path, &memory_dir, content).await;
+        }
+
+        // Leave only the newest blob so older versions must go through the deltas.
+        let newest = format!("{:x}", Sha256::digest(&versions[2]));
+        for blob in fs::read_dir(memory_dir.join(OBJECTS_DIR)).unwrap() {
+            let blob = blob.unwrap();
+            if blob.file_name() != newest.as_str() {
+                fs::remove_file(blob.path()).unwrap();
+            }
+        }
+        for (version, content) in (1..).zip(&versions) {
+            let restored = Processor::restore(&memory_dir, &path, version)
+                .await
+                .unwrap();
+            assert_eq!(&restored, content);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::process::FileStatus;
+
+/// What the `Processor` is doing with a file. Every file handed to it ends
+/// with exactly one `VersionStored`, `Skipped` or `Failed`; files ruled out
+/// before being read get no `FileStarted`.
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    /// Reading of a file of `size` bytes began.
+    FileStarted {
+        path: PathBuf,
+        size: u64,
+    },
+    /// Another `bytes` of the file were hashed.
+    BytesHashed {
+        path: PathBuf,
+        bytes: u64,
+    },
+    VersionStored {
+        path: PathBuf,
+        version: u32,
+    },
+    /// Done without storing a version; scans and dry runs report what they
+    /// would have done as `New` or `Modified`.
+    Skipped {
+        path: PathBuf,
+        status: FileStatus,
+    },
+    Failed {
+        path: PathBuf,
+        error: String,
+    },
+}
+
+/// Receives the `Processor`'s progress, from any of its tasks.
+pub trait ProgressSink: Send + Sync {
+    fn report(&self, event: ProgressEvent);
+}
+
+/// Discards all progress.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn report(&self, _event: ProgressEvent) {}
+}
+
+/// Forwards events to a channel; they are dropped once the receiver is gone.
+impl ProgressSink for tokio::sync::mpsc::UnboundedSender<ProgressEvent> {
+    fn report(&self, event: ProgressEvent) {
+        let _ = self.send(event);
+    }
+}
+
+/// Terminal progress bars for files of at least `min_size` bytes.
+pub struct IndicatifProgress {
+    multi: MultiProgress,
+    min_size: u64,
+    bars: Mutex<HashMap<PathBuf, ProgressBar>>,
+}
+
+impl IndicatifProgress {
+    pub fn new(min_size: u64) -> Self {
+        Self {
+            multi: MultiProgress::new(),
+            min_size,
+            bars: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn start(&self, path: PathBuf, size: u64) {
+        let bar = self.multi.add(ProgressBar::new(size));
+        if let Ok(style) = ProgressStyle::default_bar().template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%) {msg}",
+        ) {
+            bar.set_style(style.progress_chars("#>-"));
+        }
+        bar.set_message(basename(&path));
+        self.bars.lock().unwrap().insert(path, bar);
+    }
+
+    fn take(&self, path: &Path) -> Option<ProgressBar> {
+        self.bars.lock().unwrap().remove(path)
+    }
+}
+
+impl ProgressSink for IndicatifProgress {
+    fn report(&self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::FileStarted { path, size } if size >= self.min_size => {
+                self.start(path, size)
+            }
+            ProgressEvent::FileStarted { .. } => {}
+            ProgressEvent::BytesHashed { path, bytes } => {
+                if let Some(bar) = self.bars.lock().unwrap().get(&path) {
+                    bar.inc(bytes);
+                }
+            }
+            ProgressEvent::VersionStored { path, .. } | ProgressEvent::Skipped { path, .. } => {
+                if let Some(bar) = self.take(&path) {
+                    bar.finish_with_message(format!("{} [DONE]", basename(&path)));
+                }
+            }
+            ProgressEvent::Failed { path, .. } => {
+                if let Some(bar) = self.take(&path) {
+                    bar.abandon_with_message(format!("{} [FAILED]", basename(&path)));
+                }
+            }
+        }
+    }
+}
+
+fn basename(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,114 @@
+use eyre::{Context, Result};
+use log::{info, trace};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::config::CONFIG_FILE;
+use crate::process::DEFAULT_MEMORY_DIR;
+
+/// Directory marking the root of a project with its own memory.
+pub const PROJECT_DIR: &str = ".ouroboros";
+/// Project-wide ignore rules inside `PROJECT_DIR`, in gitignore syntax and
+/// relative to the project root.
+pub const IGNORE_FILE: &str = "ignore";
+
+const CONFIG_TEMPLATE: &str = r#"# Settings for this project's memory; see `ouroboros --help`.
+# Relative paths are relative to this directory.
+memory_dir = "memory"
+
+# chunk_strategy = "fixed"
+# chunk_size = 1000
+# chunk_overlap = 200
+# keep_versions = 20
+# keep_days = 90
+# keyword_weight = 0.3
+# model = "sentence-transformers/all-MiniLM-L6-v2"
+"#;
+
+const IGNORE_TEMPLATE: &str = r#"# Paths never ingested, in gitignore syntax and relative to the project root.
+# .gitignore and .memignore files in the project are honoured as well.
+/.ouroboros/
+"#;
+
+/// Keeps the memory itself out of version control; the config is meant to be shared.
+const GITIGNORE_TEMPLATE: &str = "/memory/\n";
+
+#[derive(Error, Debug)]
+pub enum ProjectError {
+    #[error("{} is already an ouroboros project", .0.display())]
+    Exists(PathBuf),
+}
+
+/// A directory holding a `.ouroboros/` with the config, memory and ignore
+/// rules of everything below it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Project {
+    root: PathBuf,
+}
+
+impl Project {
+    /// The nearest project containing `start`, searching its parents as git does.
+    pub fn discover(start: &Path) -> Option<Self> {
+        let start = std::path::absolute(start).ok()?;
+        let root = start
+            .ancestors()
+            .find(|dir| dir.join(PROJECT_DIR).is_dir())?;
+        trace!("Found project at {}", root.display());
+        Some(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// The project containing the working directory, if any.
+    pub fn current() -> Option<Self> {
+        Self::discover(Path::new("."))
+    }
+
+    /// Creates `.ouroboros/` in `root` with a config, an empty memory and an
+    /// ignore file. Fails if `root` already has one; projects may still nest.
+    pub fn init(root: &Path) -> Result<Self> {
+        let root = std::path::absolute(root)
+            .wrap_err_with(|| format!("Failed to resolve {}", root.display()))?;
+        let project = Self { root };
+        let dir = project.dir();
+        if dir.exists() {
+            return Err(ProjectError::Exists(project.root).into());
+        }
+
+        std::fs::create_dir_all(project.memory_dir())
+            .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        for (name, contents) in [
+            (CONFIG_FILE, CONFIG_TEMPLATE),
+            (IGNORE_FILE, IGNORE_TEMPLATE),
+            (".gitignore", GITIGNORE_TEMPLATE),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, contents)
+                .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        }
+        info!("Initialized project at {}", project.root.display());
+        Ok(project)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The project's `.ouroboros/` directory.
+    pub fn dir(&self) -> PathBuf {
+        self.root.join(PROJECT_DIR)
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.dir().join(CONFIG_FILE)
+    }
+
+    /// Where memory lives unless the project config says otherwise.
+    pub fn memory_dir(&self) -> PathBuf {
+        self.dir().join(DEFAULT_MEMORY_DIR)
+    }
+
+    pub fn ignore_path(&self) -> PathBuf {
+        self.dir().join(IGNORE_FILE)
+    }
+}
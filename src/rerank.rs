@@ -0,0 +1,159 @@
+use candle_core::{Device, Tensor};
+use candle_nn::{Linear, Module, VarBuilder, linear};
+use candle_transformers::models::bert::BertModel;
+use eyre::{Context, Result};
+use log::{debug, info, trace};
+use tokenizers::{Tokenizer, TruncationParams, TruncationStrategy};
+
+use crate::bert::{BertFiles, ModelFiles};
+use crate::digest::{DeviceChoice, DigestError};
+use crate::vector_store::VectorEntry;
+
+pub const DEFAULT_RERANK_MODEL: &str = "cross-encoder/ms-marco-MiniLM-L-6-v2";
+/// Query/passage pairs scored per forward pass.
+const RERANK_BATCH_SIZE: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct RerankerConfig {
+    pub device: DeviceChoice,
+    /// Hugging Face repo of a BERT cross-encoder with a single-logit
+    /// classification head, or a local directory holding one.
+    pub model: String,
+    pub revision: Option<String>,
+    pub model_path: Option<std::path::PathBuf>,
+}
+
+impl Default for RerankerConfig {
+    fn default() -> Self {
+        Self {
+            device: DeviceChoice::default(),
+            model: DEFAULT_RERANK_MODEL.to_string(),
+            revision: None,
+            model_path: None,
+        }
+    }
+}
+
+/// Cross-encoder that reads a query and a passage together and scores how
+/// well the passage answers it. Far slower than comparing embeddings, so it
+/// only rescores the top hits of a search.
+pub struct Reranker {
+    model: BertModel,
+    /// BERT's pooler over `[CLS]`; absent from some distilled cross-encoders.
+    pooler: Option<Linear>,
+    classifier: Linear,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl Reranker {
+    pub fn new() -> Result<Self> {
+        Self::with_config(RerankerConfig::default())
+    }
+
+    pub fn with_config(config: RerankerConfig) -> Result<Self> {
+        let device = config.device.resolve()?;
+        info!("Loading reranking model {}...", config.model);
+        let repo = ModelFiles::open(
+            &config.model,
+            config.revision.as_deref(),
+            config.model_path.as_deref(),
+        )?;
+        let BertFiles {
+            config,
+            mut tokenizer,
+            max_tokens,
+            weights,
+        } = repo.load_bert(&device)?;
+        // Passages are cut, never the query, so long chunks still fit the model.
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: max_tokens,
+                strategy: TruncationStrategy::OnlySecond,
+                ..Default::default()
+            }))
+            .map_err(|e| DigestError::Tokenizer(e.to_string()))?;
+
+        let model =
+            BertModel::load(weights.clone(), &config).wrap_err("Failed to load BERT weights")?;
+        let pooler = Self::load_pooler(&weights, config.hidden_size);
+        let classifier = linear(config.hidden_size, 1, weights.pp("classifier"))
+            .wrap_err("Failed to load the cross-encoder's classification head")?;
+        debug!(
+            "Reranking model loaded on {:?} (pooler: {})",
+            device,
+            pooler.is_some()
+        );
+        Ok(Self {
+            model,
+            pooler,
+            classifier,
+            tokenizer,
+            device,
+        })
+    }
+
+    fn load_pooler(weights: &VarBuilder, hidden_size: usize) -> Option<Linear> {
+        ["bert.pooler.dense", "pooler.dense"]
+            .into_iter()
+            .find_map(|prefix| linear(hidden_size, hidden_size, weights.pp(prefix)).ok())
+    }
+
+    /// Relevance of each passage to `query`, from 0 to 1.
+    pub fn score(&self, query: &str, passages: &[&str]) -> Result<Vec<f32>> {
+        let mut scores = Vec::with_capacity(passages.len());
+        for batch in passages.chunks(RERANK_BATCH_SIZE) {
+            scores.extend(self.score_batch(query, batch)?);
+        }
+        Ok(scores)
+    }
+
+    fn score_batch(&self, query: &str, passages: &[&str]) -> Result<Vec<f32>> {
+        trace!("Reranking batch of {} passages", passages.len());
+        let pairs: Vec<(&str, &str)> = passages.iter().map(|passage| (query, *passage)).collect();
+        let encodings = self
+            .tokenizer
+            .encode_batch(pairs, true)
+            .map_err(|e| DigestError::Encode(e.to_string()))?;
+
+        let mut ids = Vec::with_capacity(encodings.len());
+        let mut types = Vec::with_capacity(encodings.len());
+        let mut masks = Vec::with_capacity(encodings.len());
+        for encoding in &encodings {
+            ids.push(Tensor::new(encoding.get_ids(), &self.device)?);
+            types.push(Tensor::new(encoding.get_type_ids(), &self.device)?);
+            masks.push(Tensor::new(encoding.get_attention_mask(), &self.device)?);
+        }
+        let input_ids = Tensor::stack(&ids, 0)?;
+        let token_type_ids = Tensor::stack(&types, 0)?;
+        let attention_mask = Tensor::stack(&masks, 0)?;
+
+        let output = self
+            .model
+            .forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+        let mut cls = output.narrow(1, 0, 1)?.squeeze(1)?;
+        if let Some(pooler) = &self.pooler {
+            cls = pooler.forward(&cls)?.tanh()?;
+        }
+        let logits = self.classifier.forward(&cls)?.squeeze(1)?;
+        let scores = candle_nn::ops::sigmoid(&logits)?.to_vec1::<f32>()?;
+        Ok(scores)
+    }
+
+    /// Rescores `hits` against `query` and returns them best first.
+    pub fn rerank<'a>(
+        &self,
+        query: &str,
+        hits: Vec<(&'a VectorEntry, f32)>,
+    ) -> Result<Vec<(&'a VectorEntry, f32)>> {
+        let passages: Vec<&str> = hits.iter().map(|(entry, _)| entry.text()).collect();
+        let scores = self.score(query, &passages)?;
+        let mut reranked: Vec<_> = hits
+            .into_iter()
+            .zip(scores)
+            .map(|((entry, _), score)| (entry, score))
+            .collect();
+        reranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.id.cmp(&b.0.id)));
+        Ok(reranked)
+    }
+}
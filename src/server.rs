@@ -0,0 +1,419 @@
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use eyre::{Context, Result};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::chunk::ChunkSpan;
+use crate::config::Config;
+use crate::digest::{DigestPatterns, DigestSummary, Digester};
+use crate::lock::{LockError, MemoryLock};
+use crate::pipeline::Orchestrator;
+use crate::process::{FileHistory, Processor};
+use crate::shutdown;
+use crate::snippet::{self, DEFAULT_SNIPPET_CHARS, Snippet};
+use crate::vector_store::{
+    DEFAULT_SEARCH_LIMIT, Label, Labels, Metric, SearchFilter, SearchOptions, VectorEntry,
+    VectorStore,
+};
+
+/// Everything the handlers share. The store lock also serializes ingestion
+/// and digestion, so at most one request writes to memory at a time.
+struct AppState {
+    config: Config,
+    digester: Digester,
+    store: RwLock<VectorStore>,
+}
+
+/// Any failure, reported as a 500 with the error chain as JSON, or a 409 when
+/// another process holds the memory lock.
+struct ApiError(eyre::Report);
+
+impl From<eyre::Report> for ApiError {
+    fn from(e: eyre::Report) -> Self {
+        Self(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        error!("Request failed: {:?}", self.0);
+        let body = Json(ErrorBody {
+            error: format!("{:#}", self.0),
+        });
+        let status = match self.0.downcast_ref::<LockError>() {
+            Some(_) => StatusCode::CONFLICT,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, body).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+/// Serves the memory over HTTP on `addr` until the process is stopped:
+///
+/// - `POST /ingest` `{"paths": [...], "include": [...], "exclude": [...]}`
+/// - `POST /digest` `{"include": [...], "exclude": [...]}`
+/// - `POST /search` `{"query": "...", "limit": 5, "offset": 0, "path": [...], ...}`
+/// - `GET /history?file=<path>`
+pub async fn serve(config: Config, digester: Digester, addr: SocketAddr) -> Result<()> {
+    let app = router(config, digester)?;
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .wrap_err_with(|| format!("Failed to listen on {addr}"))?;
+    info!("Serving memory on http://{}", addr);
+    axum::serve(listener, app).await.wrap_err("Server failed")
+}
+
+/// The routes of the API, over the memory and collection `config` names.
+fn router(config: Config, digester: Digester) -> Result<Router> {
+    let store = VectorStore::load(config.vector_store_path()?)?;
+    let state = Arc::new(AppState {
+        config,
+        digester,
+        store: RwLock::new(store),
+    });
+    Ok(Router::new()
+        .route("/ingest", post(ingest))
+        .route("/digest", post(digest))
+        .route("/search", post(search))
+        .route("/history", get(history))
+        .with_state(state))
+}
+
+#[derive(Deserialize)]
+struct IngestRequest {
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct IngestResponse {
+    new: Vec<PathBuf>,
+    modified: Vec<PathBuf>,
+    unchanged: usize,
+    busy: Vec<PathBuf>,
+    /// Files left out by a size limit.
+    skipped: Vec<PathBuf>,
+    /// Vector entries dropped because their file got a new version.
+    invalidated: usize,
+}
+
+async fn ingest(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<IngestRequest>,
+) -> ApiResult<IngestResponse> {
+    let orchestrator =
+        Orchestrator::new(&state.config).with_filters(&request.include, &request.exclude);
+    let mut store = state.store.write().await;
+    let _lock = MemoryLock::acquire(&state.config.memory_dir)?;
+    let summary = orchestrator.run(request.paths, &mut store).await?;
+    Ok(Json(IngestResponse {
+        new: summary.process.new,
+        modified: summary.process.modified,
+        unchanged: summary.process.unchanged,
+        busy: summary.process.busy,
+        skipped: summary
+            .process
+            .skipped
+            .into_iter()
+            .map(|s| s.path)
+            .collect(),
+        invalidated: summary.invalidated,
+    }))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DigestRequest {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DigestResponse {
+    stored: usize,
+    chunks: usize,
+    current: usize,
+    skipped: usize,
+    excluded: usize,
+    removed: usize,
+    entries: usize,
+}
+
+async fn digest(
+    State(state): State<Arc<AppState>>,
+    request: Option<Json<DigestRequest>>,
+) -> ApiResult<DigestResponse> {
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let include: Vec<_> = request.include.iter().map(String::as_str).collect();
+    let exclude: Vec<_> = request.exclude.iter().map(String::as_str).collect();
+    let patterns = DigestPatterns::new(&include, &exclude)?;
+
+    let mut store = state.store.write().await;
+    let _lock = MemoryLock::acquire(&state.config.memory_dir)?;
+    // Embedding is CPU-bound; keep it off the threads serving other requests.
+    let summary: DigestSummary = tokio::task::block_in_place(|| {
+        state.digester.digest_all(
+            &state.config.memory_dir,
+            &mut store,
+            &patterns,
+            &shutdown::token(),
+        )
+    })?;
+    Ok(Json(DigestResponse {
+        stored: summary.stored,
+        chunks: summary.chunks,
+        current: summary.current,
+        skipped: summary.skipped,
+        excluded: summary.excluded,
+        removed: summary.removed,
+        entries: store.len(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    query: String,
+    limit: Option<usize>,
+    /// Hits skipped before the first one returned, for paging.
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    path: Vec<String>,
+    /// Bounds on the digest time, in milliseconds since the Unix epoch.
+    since: Option<u64>,
+    until: Option<u64>,
+    /// Search what the memory held at this time, in milliseconds since the Unix epoch.
+    as_of: Option<u64>,
+    #[serde(default)]
+    all_versions: bool,
+    /// Tags (`name`) and metadata values (`key=value`) hits must all have.
+    #[serde(default)]
+    tags: Vec<String>,
+    min_score: Option<f32>,
+    keyword_weight: Option<f32>,
+    mmr_lambda: Option<f32>,
+    metric: Option<Metric>,
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    id: String,
+    score: f32,
+    file_name: String,
+    source_path: Option<PathBuf>,
+    alias: Option<String>,
+    version: Option<u32>,
+    stored_at: Option<String>,
+    archived: bool,
+    span: ChunkSpan,
+    section: Option<String>,
+    symbol: Option<String>,
+    tags: Vec<String>,
+    metadata: BTreeMap<String, String>,
+    preview: String,
+    /// The passage best matching the query, with where its terms are.
+    snippet: Snippet,
+    content: String,
+}
+
+impl SearchHit {
+    fn new(entry: &VectorEntry, score: f32, query: &str) -> Self {
+        Self {
+            id: entry.id.clone(),
+            score,
+            file_name: entry.file_name.clone(),
+            source_path: entry.source_path.clone(),
+            alias: entry.alias.clone(),
+            version: entry.version,
+            stored_at: entry.stored_at.clone(),
+            archived: entry.is_archived(),
+            span: entry.span,
+            section: entry.section.clone(),
+            symbol: entry.symbol.clone(),
+            tags: entry.tags.clone(),
+            metadata: entry.metadata.clone(),
+            preview: entry.content_preview.clone(),
+            snippet: snippet::extract(entry.text(), query, DEFAULT_SNIPPET_CHARS),
+            content: entry.text().to_string(),
+        }
+    }
+}
+
+async fn search(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SearchRequest>,
+) -> ApiResult<Vec<SearchHit>> {
+    let path: Vec<_> = request.path.iter().map(String::as_str).collect();
+    let labels = request
+        .tags
+        .iter()
+        .map(|tag| tag.parse::<Label>())
+        .collect::<Result<Labels, _>>()
+        .map_err(eyre::Report::from)?;
+    let options = SearchOptions {
+        filter: SearchFilter::new()
+            .with_paths(&path)?
+            .digested_between(request.since, request.until)
+            .as_of(request.as_of)
+            .with_all_versions(request.all_versions)
+            .with_labels(labels),
+        min_score: request.min_score,
+        offset: request.offset,
+        limit: request.limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+        keyword_weight: request
+            .keyword_weight
+            .unwrap_or(state.config.keyword_weight),
+        mmr_lambda: request.mmr_lambda,
+        metric: request.metric,
+        ..Default::default()
+    };
+
+    let store = state.store.read().await;
+    let hits = tokio::task::block_in_place(|| {
+        store.search_text(&state.digester, &request.query, &options)
+    })?;
+    Ok(Json(
+        hits.into_iter()
+            .map(|(entry, score)| SearchHit::new(entry, score, &request.query))
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    file: PathBuf,
+}
+
+async fn history(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HistoryQuery>,
+) -> ApiResult<FileHistory> {
+    let history = Processor::history(&state.config.memory_dir, &query.file).await?;
+    Ok(Json(history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::tests::ConstantEmbedder;
+
+    /// Serves an empty memory in `dir`, embedding without a model, on a
+    /// local port for as long as the runtime lives.
+    fn start(runtime: &tokio::runtime::Runtime, dir: &std::path::Path) -> String {
+        let config = Config {
+            memory_dir: dir.join("memory"),
+            ..Default::default()
+        };
+        let app = router(config, Digester::from_embedder(Box::new(ConstantEmbedder))).unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        runtime.spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn post(url: &str, body: serde_json::Value) -> serde_json::Value {
+        ureq::post(url)
+            .send_json(body)
+            .unwrap()
+            .into_json()
+            .unwrap()
+    }
+
+    #[test]
+    fn ingested_files_can_be_digested_searched_and_listed() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let url = start(&runtime, dir.path());
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "The cache is flushed every night.").unwrap();
+
+        let ingested = post(
+            &format!("{url}/ingest"),
+            serde_json::json!({ "paths": [file] }),
+        );
+        assert_eq!(ingested["new"].as_array().unwrap().len(), 1);
+        assert_eq!(ingested["unchanged"], 0);
+
+        let digested = post(&format!("{url}/digest"), serde_json::json!({}));
+        assert_eq!(
+            (digested["stored"].as_u64(), digested["entries"].as_u64()),
+            (Some(1), Some(1))
+        );
+
+        let hits = post(
+            &format!("{url}/search"),
+            serde_json::json!({ "query": "cache" }),
+        );
+        let hits = hits.as_array().unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0]["content"], "The cache is flushed every night.");
+        assert_eq!(hits[0]["version"], 1);
+
+        let history: serde_json::Value = ureq::get(&format!("{url}/history"))
+            .query("file", &file.to_string_lossy())
+            .call()
+            .unwrap()
+            .into_json()
+            .unwrap();
+        assert_eq!(history["versions"].as_array().unwrap().len(), 1);
+
+        // Failures come back as a JSON error, not a dropped connection.
+        let missing = dir.path().join("missing.txt");
+        let Err(ureq::Error::Status(status, response)) = ureq::get(&format!("{url}/history"))
+            .query("file", &missing.to_string_lossy())
+            .call()
+        else {
+            panic!("the history of an untracked file must fail");
+        };
+        assert_eq!(status, 500);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn errors_are_reported_as_json() {
+        let response =
+            ApiError(eyre::eyre!("disk full").wrap_err("Failed to ingest")).into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "Failed to ingest: disk full");
+    }
+
+    #[test]
+    fn requests_fill_in_optional_fields() {
+        let ingest: IngestRequest = serde_json::from_str(r#"{"paths": ["notes"]}"#).unwrap();
+        assert_eq!(ingest.paths, [PathBuf::from("notes")]);
+        assert!(ingest.include.is_empty() && ingest.exclude.is_empty());
+
+        let search: SearchRequest = serde_json::from_str(r#"{"query": "cache"}"#).unwrap();
+        assert_eq!(search.query, "cache");
+        assert!(search.limit.is_none() && search.path.is_empty() && search.min_score.is_none());
+
+        let digest: DigestRequest = serde_json::from_str("{}").unwrap();
+        assert!(digest.include.is_empty());
+    }
+}
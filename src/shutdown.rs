@@ -0,0 +1,54 @@
+use log::{error, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+
+static INTERRUPT: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+/// Asks a running `Processor` or `Digester` to stop picking up new files.
+/// Files in flight are finished and their history and vectors written, so a
+/// cancelled run leaves memory consistent. Clones share one flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Spawns the Ctrl-C listener. The first interrupt cancels `token()`, asking
+/// running stages to stop picking up new files and finish what's in flight;
+/// a second one exits. Must be called from within a tokio runtime.
+pub fn install() {
+    tokio::spawn(async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            return;
+        }
+        warn!("Interrupt received, finishing in-flight files. Press Ctrl-C again to abort.");
+        INTERRUPT.cancel();
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            error!("Second interrupt received, aborting.");
+            std::process::exit(130);
+        }
+    });
+}
+
+/// The token cancelled by Ctrl-C.
+pub fn token() -> CancellationToken {
+    INTERRUPT.clone()
+}
+
+/// Whether an interrupt has been received.
+pub fn requested() -> bool {
+    INTERRUPT.is_cancelled()
+}
@@ -0,0 +1,224 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::bm25::{query_terms, tokenize};
+
+/// Characters of a chunk shown around its best match by default.
+pub const DEFAULT_SNIPPET_CHARS: usize = 200;
+/// Marks text left out before or after a snippet.
+const ELLIPSIS: &str = "…";
+
+/// The part of a chunk that best matches a query, with the query's terms
+/// located in it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Snippet {
+    /// The passage on a single line, with `…` where the chunk goes on.
+    pub text: String,
+    /// Byte ranges of `text` holding a query term, in order.
+    pub matches: Vec<Range<usize>>,
+}
+
+impl Snippet {
+    /// `text` with every match wrapped in `open` and `close`, e.g. ANSI bold
+    /// or `**` for Markdown.
+    pub fn marked(&self, open: &str, close: &str) -> String {
+        let mut marked = String::with_capacity(self.text.len());
+        let mut last = 0;
+        for range in &self.matches {
+            marked.push_str(&self.text[last..range.start]);
+            marked.push_str(open);
+            marked.push_str(&self.text[range.clone()]);
+            marked.push_str(close);
+            last = range.end;
+        }
+        marked.push_str(&self.text[last..]);
+        marked
+    }
+}
+
+/// The run of whole sentences or lines of `text`, at most `max_chars` long
+/// and opening on a match, holding the most distinct terms of `query`, then
+/// the most occurrences, then coming first. A single sentence too long to
+/// fit is cut around its first match. Without any match this is the start
+/// of `text`.
+pub fn extract(text: &str, query: &str, max_chars: usize) -> Snippet {
+    let terms: HashSet<String> = query_terms(query).into_iter().collect();
+    let sentences = sentences(text);
+    if sentences.is_empty() {
+        return Snippet::default();
+    }
+    let found: Vec<Vec<String>> = sentences
+        .iter()
+        .map(|span| {
+            tokenize(&text[span.clone()])
+                .filter(|term| terms.contains(term))
+                .collect()
+        })
+        .collect();
+
+    // Windows open on a match, so the snippet doesn't spend its length on
+    // the lead-up; with no match anywhere the only one opens the text.
+    let mut best: Option<(Range<usize>, (usize, usize))> = None;
+    for start in 0..sentences.len() {
+        if found[start].is_empty() && (start > 0 || found.iter().any(|f| !f.is_empty())) {
+            continue;
+        }
+        let mut end = start + 1;
+        while end < sentences.len()
+            && text[sentences[start].start..sentences[end].end]
+                .chars()
+                .count()
+                <= max_chars
+        {
+            end += 1;
+        }
+        let window = &found[start..end];
+        let distinct: HashSet<&String> = window.iter().flatten().collect();
+        let score = (distinct.len(), window.iter().map(Vec::len).sum::<usize>());
+        if best.as_ref().is_none_or(|(_, best)| score > *best) {
+            best = Some((start..end, score));
+        }
+    }
+
+    let (window, _) = best.expect("there is a sentence");
+    let mut span = sentences[window.start].start..sentences[window.end - 1].end;
+    if text[span.clone()].chars().count() > max_chars {
+        span = cut(text, span, &terms, max_chars);
+    }
+    let mut snippet = String::new();
+    if !text[..span.start].trim().is_empty() {
+        snippet.push_str(ELLIPSIS);
+    }
+    snippet.push_str(
+        &text[span.clone()]
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+    );
+    if !text[span.end..].trim().is_empty() {
+        snippet.push_str(ELLIPSIS);
+    }
+    let matches = term_ranges(&snippet)
+        .filter(|range| terms.contains(&snippet[range.clone()].to_lowercase()))
+        .collect();
+    Snippet {
+        text: snippet,
+        matches,
+    }
+}
+
+/// Byte spans of the sentences and lines of `text`, trimmed and non-empty.
+fn sentences(text: &str) -> Vec<Range<usize>> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let ends = c == '\n'
+            || matches!(c, '.' | '!' | '?')
+                && chars.peek().is_some_and(|(_, next)| next.is_whitespace());
+        if ends {
+            spans.push(start..i + c.len_utf8());
+            start = i + c.len_utf8();
+        }
+    }
+    spans.push(start..text.len());
+    spans
+        .into_iter()
+        .filter_map(|span| trimmed(text, span))
+        .collect()
+}
+
+/// `span` without the whitespace at either end, `None` if nothing is left.
+fn trimmed(text: &str, span: Range<usize>) -> Option<Range<usize>> {
+    let part = &text[span.clone()];
+    let start = span.start + (part.len() - part.trim_start().len());
+    let end = span.end - (part.len() - part.trim_end().len());
+    (start < end).then_some(start..end)
+}
+
+/// A `max_chars` long part of `span` starting a little before its first
+/// match, cut at whitespace where there is any.
+fn cut(text: &str, span: Range<usize>, terms: &HashSet<String>, max_chars: usize) -> Range<usize> {
+    let part = &text[span.clone()];
+    let first = term_ranges(part)
+        .find(|range| terms.contains(&part[range.clone()].to_lowercase()))
+        .map_or(0, |range| range.start);
+    // Keep about a quarter of the snippet as context before the match.
+    let lead = part[..first]
+        .char_indices()
+        .rev()
+        .nth(max_chars / 4)
+        .map_or(0, |(i, _)| i);
+    let mut start = match part[lead..first].find(char::is_whitespace) {
+        Some(space) if lead > 0 => lead + space,
+        _ => lead,
+    };
+    start += part[start..].len() - part[start..].trim_start().len();
+    let rest = &part[start..];
+    let mut end = rest
+        .char_indices()
+        .nth(max_chars)
+        .map_or(rest.len(), |(i, _)| i);
+    if end < rest.len()
+        && let Some(space) = rest[..end].rfind(char::is_whitespace)
+        && space > 0
+    {
+        end = space;
+    }
+    span.start + start..span.start + start + end
+}
+
+/// Byte spans of the alphanumeric runs of `text`, the terms `tokenize` yields.
+fn term_ranges(text: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut start = None;
+    text.char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+        .filter_map(move |(i, c)| match (c.is_alphanumeric(), start) {
+            (true, None) => {
+                start = Some(i);
+                None
+            }
+            (false, Some(from)) => {
+                start = None;
+                Some(from..i)
+            }
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_sentences_with_the_most_terms_are_picked() {
+        let text = "The cache is warm. Nothing else here.\nEviction drops the oldest cache entry. Then it stops.";
+        let snippet = extract(text, "cache eviction", 60);
+        assert_eq!(
+            snippet.text,
+            "…Eviction drops the oldest cache entry. Then it stops."
+        );
+        assert_eq!(
+            snippet.marked("[", "]"),
+            "…[Eviction] drops the oldest [cache] entry. Then it stops."
+        );
+    }
+
+    #[test]
+    fn long_sentences_are_cut_around_their_first_match() {
+        let text = format!("{} needle {}", "é ".repeat(100), "ü ".repeat(100));
+        let snippet = extract(&text, "needle", 40);
+        assert!(snippet.text.starts_with('…') && snippet.text.ends_with('…'));
+        assert!(snippet.text.chars().count() <= 42);
+        assert_eq!(snippet.matches.len(), 1);
+        assert_eq!(&snippet.text[snippet.matches[0].clone()], "needle");
+    }
+
+    #[test]
+    fn without_a_match_the_text_opens_the_snippet() {
+        let snippet = extract("First line.\nSecond line.", "absent", 200);
+        assert_eq!(snippet.text, "First line. Second line.");
+        assert!(snippet.matches.is_empty());
+    }
+}
@@ -1,11 +1,56 @@
+use eyre::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use log::{debug, trace, warn};
-use std::collections::BTreeSet;
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use tokio::fs as tfs;
 
-#[derive(Default, Debug)]
+/// Per-directory ignore files, in gitignore syntax. Later ones override
+/// earlier ones, so `.memignore` can re-include what `.gitignore` excludes.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".memignore"];
+
+#[derive(Debug)]
 pub struct FileStorage {
     paths: BTreeSet<PathBuf>,
+    /// Whether symlinks met while walking are followed. Paths given to `add`
+    /// are always resolved.
+    follow_symlinks: bool,
+    /// Directories walked and hardlinked files added, so symlink cycles end
+    /// and each file is only added once.
+    visited: HashSet<FileId>,
+    ignore: Option<GlobSet>,
+    /// Only files matching one of these are added, when set.
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    /// Parsed ignore files by directory, `None` where there are none.
+    ignore_files: HashMap<PathBuf, Option<Gitignore>>,
+    /// Rules from a project's ignore file, consulted when no per-directory
+    /// ignore file has an opinion.
+    project_ignore: Option<Gitignore>,
+}
+
+/// Identifies a file across its links: device and inode on Unix, the
+/// canonical path elsewhere.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+impl Default for FileStorage {
+    fn default() -> Self {
+        Self {
+            paths: BTreeSet::new(),
+            follow_symlinks: true,
+            visited: HashSet::new(),
+            ignore: None,
+            include: None,
+            exclude: None,
+            ignore_files: HashMap::new(),
+            project_ignore: None,
+        }
+    }
 }
 
 impl FileStorage {
@@ -14,28 +59,103 @@ impl FileStorage {
         Self::default()
     }
 
+    /// Skips files and whole directories matching any of `patterns` (e.g.
+    /// `**/target`, `*.log`), matched against each path as it's walked.
+    pub fn with_ignore(patterns: &[String]) -> Result<Self> {
+        Ok(Self {
+            ignore: glob_set(patterns, "ignore")?,
+            ..Self::new()
+        })
+    }
+
+    /// Scopes what gets added: only files matching an `include` glob (any file
+    /// when there are none), and nothing matching an `exclude` glob, e.g.
+    /// `**/*.rs` and `**/tests/**`. Directories are still walked unless
+    /// excluded, so includes can pick files at any depth.
+    pub fn with_filters(mut self, include: &[String], exclude: &[String]) -> Result<Self> {
+        self.include = glob_set(include, "include")?;
+        self.exclude = glob_set(exclude, "exclude")?;
+        Ok(self)
+    }
+
+    /// Whether to follow symlinks found inside added directories (the
+    /// default). Cycles are detected either way.
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Also skips what the gitignore-style `file` excludes, its patterns
+    /// relative to `root`. A missing file is the same as an empty one.
+    pub fn with_ignore_file(mut self, root: &Path, file: &Path) -> Self {
+        if !file.is_file() {
+            return self;
+        }
+        let mut builder = GitignoreBuilder::new(root);
+        if let Some(e) = builder.add(file) {
+            warn!("Failed to read {}: {}", file.display(), e);
+        }
+        self.project_ignore = builder
+            .build()
+            .inspect(|_| debug!("Loaded ignore rules from {}", file.display()))
+            .inspect_err(|e| warn!("Invalid ignore rules in {}: {}", file.display(), e))
+            .ok();
+        self
+    }
+
+    /// An empty storage with the same ignore rules.
+    pub fn empty_like(&self) -> Self {
+        Self {
+            paths: BTreeSet::new(),
+            follow_symlinks: self.follow_symlinks,
+            visited: HashSet::new(),
+            ignore: self.ignore.clone(),
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            ignore_files: self.ignore_files.clone(),
+            project_ignore: self.project_ignore.clone(),
+        }
+    }
+
     pub async fn add(&mut self, path: impl Into<PathBuf>) -> &mut Self {
-        self.add_recursive(path.into()).await;
+        self.add_recursive(path.into(), true).await;
         self
     }
 
-    async fn add_recursive(&mut self, path: PathBuf) {
-        if !path.exists() {
+    async fn add_recursive(&mut self, path: PathBuf, top_level: bool) {
+        let is_symlink = tfs::symlink_metadata(&path)
+            .await
+            .is_ok_and(|m| m.file_type().is_symlink());
+        if is_symlink && !top_level && !self.follow_symlinks {
+            trace!("Not following symlink {}", path.display());
+            return;
+        }
+        let Ok(metadata) = tfs::metadata(&path).await else {
             warn!("Path does not exist: {}", path.display());
             return;
+        };
+        let is_dir = metadata.is_dir();
+        if self.is_ignored(&path, is_dir) {
+            trace!("Ignoring {}", path.display());
+            return;
         }
 
-        if tfs::metadata(&path)
-            .await
-            .map(|m| m.is_dir())
-            .unwrap_or(false)
-        {
+        if is_dir {
+            let id = file_id(&path, &metadata).await;
+            if !self.visited.insert(id) {
+                debug!("Skipping {}, already walked", path.display());
+                return;
+            }
             if let Ok(mut entries) = tfs::read_dir(&path).await {
                 while let Ok(Some(entry)) = entries.next_entry().await {
-                    Box::pin(self.add_recursive(entry.path())).await;
+                    Box::pin(self.add_recursive(entry.path(), false)).await;
                 }
             }
         } else {
+            if has_other_links(&metadata) && !self.visited.insert(file_id(&path, &metadata).await) {
+                debug!("Skipping {}, a hardlink of an added file", path.display());
+                return;
+            }
             let target_path = match tfs::canonicalize(&path).await {
                 Ok(canonical) => canonical,
                 Err(_) => path,
@@ -45,11 +165,123 @@ impl FileStorage {
         }
     }
 
+    /// Whether `path` is excluded by the configured globs or filters, is a
+    /// `.git` directory, or is ignored by a `.gitignore`/`.memignore` in one
+    /// of its parent directories, up to the root of its git repository, or
+    /// by the project ignore file.
+    fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let matches = |set: &Option<GlobSet>| set.as_ref().is_some_and(|set| set.is_match(path));
+        if matches(&self.ignore) || matches(&self.exclude) {
+            return true;
+        }
+        if !is_dir && self.include.as_ref().is_some_and(|set| !set.is_match(path)) {
+            return true;
+        }
+        if is_dir && path.file_name().is_some_and(|name| name == ".git") {
+            return true;
+        }
+
+        let Ok(path) = std::path::absolute(path) else {
+            return false;
+        };
+        // The nearest ignore file with an opinion wins, as in git.
+        for dir in path.ancestors().skip(1) {
+            let matched = self
+                .ignore_files(dir)
+                .map(|rules| rules.matched_path_or_any_parents(&path, is_dir));
+            match matched {
+                Some(Match::Ignore(_)) => return true,
+                Some(Match::Whitelist(_)) => return false,
+                _ => {}
+            }
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+        match &self.project_ignore {
+            Some(rules) if path.starts_with(rules.path()) => {
+                rules.matched_path_or_any_parents(&path, is_dir).is_ignore()
+            }
+            _ => false,
+        }
+    }
+
+    /// The rules of the ignore files in `dir`, parsed on first use.
+    fn ignore_files(&mut self, dir: &Path) -> Option<&Gitignore> {
+        self.ignore_files
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                let mut builder = GitignoreBuilder::new(dir);
+                let mut found = false;
+                for name in IGNORE_FILES {
+                    let file = dir.join(name);
+                    if !file.is_file() {
+                        continue;
+                    }
+                    found = true;
+                    if let Some(e) = builder.add(&file) {
+                        warn!("Failed to read {}: {}", file.display(), e);
+                    }
+                }
+                if !found {
+                    return None;
+                }
+                builder
+                    .build()
+                    .inspect(|_| debug!("Loaded ignore rules from {}", dir.display()))
+                    .inspect_err(|e| warn!("Invalid ignore rules in {}: {}", dir.display(), e))
+                    .ok()
+            })
+            .as_ref()
+    }
+
     pub fn len(&self) -> usize {
         self.paths.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
     pub fn paths(&self) -> &BTreeSet<PathBuf> {
         &self.paths
     }
 }
+
+#[cfg(unix)]
+async fn file_id(_path: &Path, metadata: &std::fs::Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+async fn file_id(path: &Path, _metadata: &std::fs::Metadata) -> FileId {
+    tfs::canonicalize(path)
+        .await
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether the file has hardlinks besides `metadata`'s path.
+#[cfg(unix)]
+fn has_other_links(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn has_other_links(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Compiles `patterns`, `None` when there are none. `kind` names them in errors.
+fn glob_set(patterns: &[String], kind: &str) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder
+            .add(Glob::new(pattern).wrap_err_with(|| format!("Invalid {kind} pattern {pattern}"))?);
+    }
+    Ok(Some(builder.build()?))
+}
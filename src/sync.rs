@@ -0,0 +1,678 @@
+use chrono::Utc;
+use eyre::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+use crate::checkpoint::MANIFEST_FILE as RUN_MANIFEST;
+use crate::lock::LOCK_FILE;
+
+/// Object under the remote prefix listing every synced file and its hash.
+pub const REMOTE_MANIFEST: &str = "manifest.json";
+/// Prefix of the content-addressed objects holding the files' bytes.
+const BLOBS_DIR: &str = "blobs";
+const DEFAULT_REGION: &str = "us-east-1";
+/// SHA-256 of an empty payload, for signing requests without a body.
+const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("invalid sync URL {0:?}, expected s3://bucket[/prefix]")]
+    InvalidUrl(String),
+    #[error("no credentials for {0}, set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY")]
+    MissingCredentials(String),
+    #[error("{method} {key} failed with HTTP {status}: {body}")]
+    Http {
+        method: &'static str,
+        key: String,
+        status: u16,
+        body: String,
+    },
+    #[error("nothing has been pushed to {0} yet")]
+    Empty(String),
+    #[error("downloaded {0} does not match its hash")]
+    Corrupt(String),
+    #[error("refusing to write {0:?} outside the memory directory")]
+    UnsafePath(String),
+}
+
+/// Every synced file of a memory directory, by its `/`-separated path
+/// relative to the directory.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SyncManifest {
+    pub files: BTreeMap<String, SyncedFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncedFile {
+    /// SHA-256 of the contents, which is also the name of its blob.
+    pub hash: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    /// Files uploaded or downloaded, or that would be in a dry run.
+    pub transferred: usize,
+    pub bytes: u64,
+    pub unchanged: usize,
+    /// Files dropped from the other side because this one doesn't have them.
+    pub deleted: usize,
+}
+
+/// An S3 bucket, or a prefix in one, that memory directories are synced
+/// with. Any S3-compatible store works through a custom endpoint.
+///
+/// The bucket holds one blob per distinct file content, named by its hash,
+/// and a manifest mapping paths to hashes. Only blobs the bucket doesn't
+/// have yet are uploaded, and since the manifest is written last an
+/// interrupted push leaves the previous snapshot intact.
+pub struct Remote {
+    url: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    /// Addressed path-style when set (MinIO, R2, ...), otherwise AWS itself,
+    /// virtual-hosted.
+    endpoint: Option<String>,
+    credentials: Credentials,
+    agent: ureq::Agent,
+}
+
+enum Body<'a> {
+    Empty,
+    Bytes(&'a [u8]),
+    File(File, u64),
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Remote {
+    /// The remote at `url` (`s3://bucket/prefix`), with credentials from the
+    /// usual `AWS_*` environment variables. Without `region` it comes from
+    /// `AWS_REGION`, defaulting to us-east-1.
+    pub fn new(url: &str, endpoint: Option<&str>, region: Option<&str>) -> Result<Self> {
+        let path = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| SyncError::InvalidUrl(url.to_string()))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(SyncError::InvalidUrl(url.to_string()).into());
+        }
+
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let (Some(access_key), Some(secret_key)) =
+            (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY"))
+        else {
+            return Err(SyncError::MissingCredentials(url.to_string()).into());
+        };
+        let region = region
+            .map(String::from)
+            .or_else(|| var("AWS_REGION"))
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        Ok(Self {
+            url: url.to_string(),
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            region,
+            endpoint: endpoint.map(|e| e.trim_end_matches('/').to_string()),
+            credentials: Credentials {
+                access_key,
+                secret_key,
+                session_token: var("AWS_SESSION_TOKEN"),
+            },
+            agent: ureq::AgentBuilder::new().build(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The manifest of the last push, `None` if there was none.
+    pub fn manifest(&self) -> Result<Option<SyncManifest>> {
+        let Some(response) = self.get(REMOTE_MANIFEST)? else {
+            return Ok(None);
+        };
+        let manifest = serde_json::from_reader(response.into_reader())
+            .wrap_err_with(|| format!("Failed to parse the manifest in {}", self.url))?;
+        Ok(Some(manifest))
+    }
+
+    fn put_manifest(&self, manifest: &SyncManifest) -> Result<()> {
+        let data = serde_json::to_vec(manifest)?;
+        let hash = format!("{:x}", Sha256::digest(&data));
+        self.send("PUT", REMOTE_MANIFEST, &hash, Body::Bytes(&data))?;
+        Ok(())
+    }
+
+    fn put_blob(&self, hash: &str, path: &Path, size: u64) -> Result<()> {
+        let key = blob_key(hash);
+        let file =
+            File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        self.send("PUT", &key, hash, Body::File(file, size))?;
+        Ok(())
+    }
+
+    /// Streams the blob `hash` into `target`, replacing it only once the
+    /// whole blob arrived and matched its hash.
+    fn get_blob(&self, hash: &str, target: &Path) -> Result<u64> {
+        let key = blob_key(hash);
+        let response = self
+            .get(&key)?
+            .ok_or_else(|| SyncError::Corrupt(key.clone()))?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let temp = target.with_extension("sync.tmp");
+        let mut file =
+            File::create(&temp).wrap_err_with(|| format!("Failed to create {}", temp.display()))?;
+        let mut reader = response.into_reader();
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = reader
+                .read(&mut buffer)
+                .wrap_err_with(|| format!("Failed to download {key}"))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            file.write_all(&buffer[..read])?;
+            size += read as u64;
+        }
+        file.sync_all()?;
+        if format!("{:x}", hasher.finalize()) != hash {
+            let _ = fs::remove_file(&temp);
+            return Err(SyncError::Corrupt(key).into());
+        }
+        fs::rename(&temp, target)
+            .wrap_err_with(|| format!("Failed to replace {}", target.display()))?;
+        Ok(size)
+    }
+
+    fn delete_blob(&self, hash: &str) -> Result<()> {
+        self.send("DELETE", &blob_key(hash), EMPTY_HASH, Body::Empty)?;
+        Ok(())
+    }
+
+    /// `None` when the object doesn't exist.
+    fn get(&self, key: &str) -> Result<Option<ureq::Response>> {
+        match self.send("GET", key, EMPTY_HASH, Body::Empty) {
+            Ok(response) => Ok(Some(response)),
+            Err(e) if matches!(e.downcast_ref(), Some(SyncError::Http { status: 404, .. })) => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends a signed `method` request for `key` whose `body` hashes to
+    /// `payload_hash`.
+    fn send(
+        &self,
+        method: &'static str,
+        key: &str,
+        payload_hash: &str,
+        body: Body,
+    ) -> Result<ureq::Response> {
+        let key = match self.prefix.is_empty() {
+            true => key.to_string(),
+            false => format!("{}/{}", self.prefix, key),
+        };
+        let (url, host, path) = match &self.endpoint {
+            Some(endpoint) => {
+                let path = format!("/{}/{}", self.bucket, uri_encode(&key));
+                let host = endpoint
+                    .split_once("://")
+                    .map_or(endpoint.as_str(), |(_, rest)| rest);
+                (format!("{endpoint}{path}"), host.to_string(), path)
+            }
+            None => {
+                let host = format!("{}.s3.{}.amazonaws.com", self.bucket, self.region);
+                let path = format!("/{}", uri_encode(&key));
+                (format!("https://{host}{path}"), host, path)
+            }
+        };
+        debug!("[{}] {} {}", self.url, method, key);
+
+        let mut request = self.agent.request(method, &url);
+        for (name, value) in self.sign(method, &host, &path, payload_hash) {
+            request = request.set(name, &value);
+        }
+        let sent = match body {
+            Body::Empty => request.call(),
+            Body::Bytes(data) => request.send_bytes(data),
+            // A known length keeps ureq from chunking the body, which S3 rejects.
+            Body::File(file, size) => request.set("Content-Length", &size.to_string()).send(file),
+        };
+        match sent {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => Err(SyncError::Http {
+                method,
+                key,
+                status,
+                body: error_body(response),
+            }
+            .into()),
+            Err(e) => Err(e).wrap_err_with(|| format!("{method} {key} failed")),
+        }
+    }
+
+    /// AWS Signature Version 4 headers for a request without a query string.
+    fn sign(
+        &self,
+        method: &str,
+        host: &str,
+        path: &str,
+        payload_hash: &str,
+    ) -> Vec<(&'static str, String)> {
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+        // Sorted by name, as the canonical request wants them.
+        let mut headers = vec![
+            ("host", host.to_string()),
+            ("x-amz-content-sha256", payload_hash.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request =
+            format!("{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{:x}",
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let secret = format!("AWS4{}", self.credentials.secret_key);
+        let mut signing_key = hmac_sha256(secret.as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = hmac_sha256(&signing_key, string_to_sign.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+
+        headers.retain(|(name, _)| *name != "host");
+        headers.push((
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                self.credentials.access_key
+            ),
+        ));
+        headers
+    }
+}
+
+/// Makes the remote mirror `memory_dir`: uploads the blobs it lacks, then
+/// the new manifest, then drops the blobs nothing refers to anymore.
+pub fn push(memory_dir: &Path, remote: &Remote, dry_run: bool) -> Result<SyncSummary> {
+    let local = local_manifest(memory_dir)?;
+    let previous = remote.manifest()?.unwrap_or_default();
+    let mut uploaded: HashSet<&str> = previous.files.values().map(|f| f.hash.as_str()).collect();
+
+    let mut summary = SyncSummary::default();
+    for (name, file) in &local.files {
+        if previous.files.get(name) == Some(file) {
+            summary.unchanged += 1;
+            continue;
+        }
+        summary.transferred += 1;
+        if !uploaded.insert(&file.hash) {
+            debug!("[{}] Already in {}", name, remote.url());
+            continue;
+        }
+        summary.bytes += file.size;
+        if !dry_run {
+            info!("[{}] Uploading {} bytes", name, file.size);
+            remote.put_blob(&file.hash, &local_path(memory_dir, name)?, file.size)?;
+        }
+    }
+    summary.deleted = previous
+        .files
+        .keys()
+        .filter(|name| !local.files.contains_key(*name))
+        .count();
+    if dry_run {
+        return Ok(summary);
+    }
+
+    remote.put_manifest(&local)?;
+    let current: HashSet<&str> = local.files.values().map(|f| f.hash.as_str()).collect();
+    let stale: HashSet<&str> = previous
+        .files
+        .values()
+        .map(|f| f.hash.as_str())
+        .filter(|hash| !current.contains(hash))
+        .collect();
+    for hash in stale {
+        if let Err(e) = remote.delete_blob(hash) {
+            warn!("Failed to delete unused blob {}: {:#}", hash, e);
+        }
+    }
+    Ok(summary)
+}
+
+/// Downloads whatever differs from the remote's last push into
+/// `memory_dir`. Local files the push didn't have are kept unless `delete`.
+pub fn pull(
+    memory_dir: &Path,
+    remote: &Remote,
+    delete: bool,
+    dry_run: bool,
+) -> Result<SyncSummary> {
+    let Some(manifest) = remote.manifest()? else {
+        return Err(SyncError::Empty(remote.url().to_string()).into());
+    };
+    let local = local_manifest(memory_dir)?;
+
+    let mut summary = SyncSummary::default();
+    for (name, file) in &manifest.files {
+        if local.files.get(name) == Some(file) {
+            summary.unchanged += 1;
+            continue;
+        }
+        let target = local_path(memory_dir, name)?;
+        summary.transferred += 1;
+        summary.bytes += file.size;
+        if !dry_run {
+            info!("[{}] Downloading {} bytes", name, file.size);
+            remote.get_blob(&file.hash, &target)?;
+        }
+    }
+    if delete {
+        for name in local
+            .files
+            .keys()
+            .filter(|name| !manifest.files.contains_key(*name))
+        {
+            summary.deleted += 1;
+            if !dry_run {
+                let path = local_path(memory_dir, name)?;
+                info!("[{}] Removing, not in {}", name, remote.url());
+                fs::remove_file(&path)
+                    .wrap_err_with(|| format!("Failed to remove {}", path.display()))?;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Hashes every file of `memory_dir` worth syncing.
+pub fn local_manifest(memory_dir: &Path) -> Result<SyncManifest> {
+    let mut manifest = SyncManifest::default();
+    for (name, path) in memory_files(memory_dir)? {
+        let mut file =
+            File::open(&path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher)
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        manifest.files.insert(
+            name,
+            SyncedFile {
+                hash: format!("{:x}", hasher.finalize()),
+                size,
+            },
+        );
+    }
+    Ok(manifest)
+}
+
+/// Every file of `memory_dir` that makes up the memory, by its `/`-separated
+/// relative path, leaving out the lock, run checkpoints, backups and
+/// temporary files.
+pub fn memory_files(memory_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    if memory_dir.is_dir() {
+        collect(memory_dir, memory_dir, &mut files)?;
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn collect(memory_dir: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    for entry in fs::read_dir(dir).wrap_err_with(|| format!("Failed to list {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect(memory_dir, &path, files)?;
+            continue;
+        }
+        let skipped = path
+            .extension()
+            .is_some_and(|ext| ext == "tmp" || ext == "bak")
+            || [LOCK_FILE, RUN_MANIFEST]
+                .map(|name| memory_dir.join(name))
+                .contains(&path);
+        if skipped {
+            continue;
+        }
+        let Some(name) = path
+            .strip_prefix(memory_dir)?
+            .iter()
+            .map(|part| part.to_str())
+            .collect::<Option<Vec<_>>>()
+        else {
+            warn!("Leaving out {}, its name is not UTF-8", path.display());
+            continue;
+        };
+        files.push((name.join("/"), path));
+    }
+    Ok(())
+}
+
+/// Where the file `name` from a manifest or archive lives under
+/// `memory_dir`, refusing names that would escape it.
+pub(crate) fn local_path(memory_dir: &Path, name: &str) -> Result<PathBuf> {
+    let relative = Path::new(name);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(SyncError::UnsafePath(name.to_string()).into());
+    }
+    Ok(memory_dir.join(relative))
+}
+
+/// The start of an error response's body, enough to tell what went wrong.
+pub(crate) fn error_body(response: ureq::Response) -> String {
+    let mut body = response.into_string().unwrap_or_default();
+    body.truncate(body.floor_char_boundary(500));
+    body.trim().to_string()
+}
+
+fn blob_key(hash: &str) -> String {
+    format!("{BLOBS_DIR}/{hash}")
+}
+
+/// Percent-encodes `key` as SigV4 canonical URIs want, keeping `/`.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{Method, StatusCode, Uri};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Enough of S3 for push and pull: PUT, GET and DELETE of whole objects.
+    async fn object(
+        State(objects): State<Objects>,
+        method: Method,
+        uri: Uri,
+        body: Bytes,
+    ) -> (StatusCode, Vec<u8>) {
+        let key = uri.path().to_string();
+        let mut objects = objects.lock().unwrap();
+        match method {
+            Method::PUT => {
+                objects.insert(key, body.to_vec());
+                (StatusCode::OK, Vec::new())
+            }
+            Method::GET => match objects.get(&key) {
+                Some(data) => (StatusCode::OK, data.clone()),
+                None => (StatusCode::NOT_FOUND, b"NoSuchKey".to_vec()),
+            },
+            Method::DELETE => {
+                objects.remove(&key);
+                (StatusCode::NO_CONTENT, Vec::new())
+            }
+            _ => (StatusCode::METHOD_NOT_ALLOWED, Vec::new()),
+        }
+    }
+
+    /// Serves a fake bucket on a local port for as long as the runtime lives.
+    fn fake_s3(runtime: &tokio::runtime::Runtime) -> (Remote, Objects) {
+        let objects = Objects::default();
+        let app = axum::Router::new()
+            .fallback(object)
+            .with_state(objects.clone());
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        runtime.spawn(async move { axum::serve(listener, app).await });
+        let remote = Remote {
+            url: "s3://bucket/memory".to_string(),
+            bucket: "bucket".to_string(),
+            prefix: "memory".to_string(),
+            region: DEFAULT_REGION.to_string(),
+            endpoint: Some(endpoint),
+            credentials: Credentials {
+                access_key: "test".to_string(),
+                secret_key: "test".to_string(),
+                session_token: None,
+            },
+            agent: ureq::AgentBuilder::new().build(),
+        };
+        (remote, objects)
+    }
+
+    fn write(dir: &Path, name: &str, data: &str) {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn pushed_memory_is_pulled_back() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (remote, objects) = fake_s3(&runtime);
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        write(a.path(), "notes_txt/history.json", "{}");
+        write(a.path(), "objects/one", "same");
+        write(a.path(), "objects/two", "same");
+        write(a.path(), "vectors.bin.bak", "backup");
+
+        let pushed = push(a.path(), &remote, false).unwrap();
+        assert_eq!((pushed.transferred, pushed.bytes), (3, 6));
+        // Two files with the same content share a blob: two blobs and the manifest.
+        assert_eq!(objects.lock().unwrap().len(), 3);
+
+        write(b.path(), "stale", "only here");
+        let pulled = pull(b.path(), &remote, true, false).unwrap();
+        assert_eq!((pulled.transferred, pulled.deleted), (3, 1));
+        let names = |dir: &Path| -> Vec<String> {
+            local_manifest(dir).unwrap().files.into_keys().collect()
+        };
+        assert_eq!(names(b.path()), names(a.path()));
+        assert_eq!(
+            fs::read_to_string(b.path().join("objects/two")).unwrap(),
+            "same"
+        );
+
+        // Blobs only the old manifest used are dropped after the next push.
+        write(a.path(), "objects/one", "changed");
+        write(a.path(), "objects/two", "changed");
+        push(a.path(), &remote, false).unwrap();
+        assert_eq!(objects.lock().unwrap().len(), 3);
+        let again = pull(b.path(), &remote, false, false).unwrap();
+        assert_eq!((again.transferred, again.unchanged), (2, 1));
+    }
+
+    #[test]
+    fn manifest_names_stay_inside_the_memory_dir() {
+        let dir = Path::new("/memory");
+        assert_eq!(
+            local_path(dir, "a/b.json").unwrap(),
+            Path::new("/memory/a/b.json")
+        );
+        for name in ["../escape", "/etc/passwd", "a/../../b", "./a"] {
+            assert!(local_path(dir, name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let mac = hmac_sha256(&[0x0b; 20], b"Hi There");
+        let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(
+            hex,
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+    }
+
+    #[test]
+    fn error_bodies_are_cut_at_a_char_boundary() {
+        let body = "é".repeat(400);
+        let response = ureq::Response::new(500, "Internal Server Error", &body).unwrap();
+        let cut = error_body(response);
+        assert_eq!(cut.len(), 500);
+        assert!(body.starts_with(&cut));
+    }
+}
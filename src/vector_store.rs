@@ -0,0 +1,3118 @@
+use eyre::{Context, Result, bail};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, info, trace, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::bert::Pooling;
+use crate::bm25::Bm25Index;
+use crate::chunk::ChunkSpan;
+use crate::crypt;
+use crate::digest::{DigestError, Digester};
+use crate::embeddings::{EmbeddingMatrix, Scorer};
+use crate::extract::extract;
+use crate::hnsw::HnswIndex;
+use crate::process::Processor;
+use crate::wal::Wal;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// Leads every binary store file; anything else is read as legacy JSON.
+/// Stores under `STORE_MAGIC` keep their embeddings in a separate file,
+/// older ones under `INLINE_STORE_MAGIC` inline with each entry.
+const STORE_MAGIC: &[u8] = b"OUROVEC2";
+const INLINE_STORE_MAGIC: &[u8] = b"OUROVEC1";
+/// How far a squared norm may stray from 1.0 for the vector to count as normalized.
+const UNIT_TOLERANCE: f32 = 1e-4;
+/// Below this many entries a linear scan is fast enough and exact, so no index is kept.
+const INDEX_MIN_ENTRIES: usize = 2048;
+/// Extra index candidates fetched per search so filtered-out entries don't starve results.
+const INDEX_OVERSAMPLE: usize = 32;
+/// Keyword matches count for this share of a search score by default.
+pub const DEFAULT_KEYWORD_WEIGHT: f32 = 0.3;
+/// Hits `search_text` returns per page unless asked for another number.
+pub const DEFAULT_SEARCH_LIMIT: usize = 5;
+/// Hits handed to the reranker when reranking is on.
+pub const DEFAULT_RERANK_TOP_N: usize = 20;
+/// Candidates gathered per requested result when diversifying with MMR.
+const MMR_CANDIDATES_PER_RESULT: usize = 4;
+/// Chunk hits gathered per requested file when grouping hits by file.
+const CHUNKS_PER_FILE_RESULT: usize = 8;
+/// Candidates shortlisted per requested result from int8 scores before rescoring.
+const RESCORE_CANDIDATES_PER_RESULT: usize = 4;
+/// Entries scored per rayon task when scanning without an index, so small
+/// stores are scored on one thread.
+const SCAN_CHUNK: usize = 4096;
+/// Partial sums `dot` keeps, enough to fill a 256-bit register.
+const DOT_LANES: usize = 8;
+/// Neighbours examined per entry by `near_duplicates` once an index is available.
+const DUPLICATE_NEIGHBORS: usize = 32;
+/// The write-ahead log is compacted into the store once it holds this many
+/// records, or more if a quarter of the store's entries is more.
+const WAL_COMPACT_RECORDS: usize = 1024;
+/// Version of the store contents this build writes into `StoreHeader`.
+/// Stores of a newer version are refused rather than misread.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum VectorStoreError {
+    #[error("refusing to store zero-magnitude embedding for entry {0}")]
+    ZeroVector(String),
+    #[error(
+        "store was embedded with {store}, but {requested} was used; \
+         run `ouroboros re-embed` to embed it again with the new model"
+    )]
+    ModelMismatch { store: String, requested: String },
+    #[error(
+        "store {path} has schema version {found}, but this build only reads up to {SCHEMA_VERSION}"
+    )]
+    SchemaVersion { path: PathBuf, found: u32 },
+    #[error("entry {id} has {found} dimensions, but the store holds {expected}")]
+    DimensionMismatch {
+        id: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("no entry with id {0}")]
+    UnknownEntry(String),
+    #[error("line {line} of {path} is not a vector entry: {reason}")]
+    BadExportLine {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    #[error("unknown merge strategy {0:?}, expected skip, overwrite or replace")]
+    MergeStrategy(String),
+    #[error("unknown metric {0:?}, expected cosine, dot or euclidean")]
+    Metric(String),
+    #[error("invalid tag {0:?}, expected a name or key=value")]
+    Label(String),
+    #[error("unknown store format {0:?}, expected files or sqlite")]
+    StoreFormat(String),
+    #[error("{0} is an SQLite store, which needs a build with --features sqlite")]
+    NoSqlite(PathBuf),
+    #[error("SQLite stores aren't encrypted; use store_format = \"files\" with a key")]
+    SqliteEncryption,
+}
+
+/// How vector stores are kept on disk, told apart by their extension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StoreFormat {
+    /// The store's own files: entries in `.bin`, embeddings in `.emb` and
+    /// changes since the last save in `.wal`.
+    #[default]
+    Files,
+    /// A single SQLite database, `.db`, with the `sqlite` feature.
+    Sqlite,
+}
+
+impl StoreFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Files => "bin",
+            Self::Sqlite => "db",
+        }
+    }
+
+    /// The format of the store at `path`.
+    pub fn of(path: &Path) -> Self {
+        if path
+            .extension()
+            .is_some_and(|ext| ext == Self::Sqlite.extension())
+        {
+            Self::Sqlite
+        } else {
+            Self::Files
+        }
+    }
+}
+
+impl std::str::FromStr for StoreFormat {
+    type Err = VectorStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "files" => Ok(Self::Files),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(VectorStoreError::StoreFormat(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for StoreFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Files => write!(f, "files"),
+            Self::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+/// What `import_jsonl` does with imported entries whose id is already stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keep the stored entry.
+    #[default]
+    Skip,
+    /// Replace the stored entry with the imported one.
+    Overwrite,
+    /// Drop every stored entry first, leaving exactly the imported ones.
+    Replace,
+}
+
+impl std::str::FromStr for MergeStrategy {
+    type Err = VectorStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "replace" => Ok(Self::Replace),
+            _ => Err(VectorStoreError::MergeStrategy(s.to_string())),
+        }
+    }
+}
+
+/// How a search scores entries against the query; higher is always closer.
+/// The three agree on the ranking of normalized embeddings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// Cosine similarity, from -1 to 1; blind to embedding length.
+    #[default]
+    Cosine,
+    /// Raw dot product, for models trained to put relevance in the length
+    /// of their embeddings.
+    Dot,
+    /// `1 / (1 + d)` for the Euclidean distance `d`, from 0 to 1.
+    Euclidean,
+}
+
+impl std::str::FromStr for Metric {
+    type Err = VectorStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cosine" => Ok(Self::Cosine),
+            "dot" => Ok(Self::Dot),
+            "euclidean" | "l2" => Ok(Self::Euclidean),
+            _ => Err(VectorStoreError::Metric(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Cosine => "cosine",
+            Self::Dot => "dot",
+            Self::Euclidean => "euclidean",
+        })
+    }
+}
+
+/// Counts of what `import_jsonl` did with each line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub replaced: usize,
+    pub skipped: usize,
+}
+
+/// First line of an export, naming the model the embeddings came from.
+/// Exports made before store headers only carry `model_id`.
+#[derive(Serialize, Deserialize)]
+struct ExportHeader {
+    ouroboros_export: u32,
+    #[serde(default)]
+    model_id: Option<String>,
+    #[serde(default)]
+    header: Option<StoreHeader>,
+}
+
+/// What produced a store's embeddings, recorded on first insert. Embeddings
+/// that differ in any of these can't be scored against each other.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoreHeader {
+    pub schema_version: u32,
+    pub model: String,
+    pub revision: Option<String>,
+    pub dimension: usize,
+    /// Whether the embedder scales embeddings to unit length.
+    pub normalized: bool,
+    /// How the model's token embeddings were pooled; `None` for remote
+    /// backends and headers recorded before pooling was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pooling: Option<Pooling>,
+}
+
+impl StoreHeader {
+    /// The model as stores written before headers recorded it: `model@revision`.
+    pub fn model_id(&self) -> String {
+        match &self.revision {
+            Some(revision) => format!("{}@{}", self.model, revision),
+            None => self.model.clone(),
+        }
+    }
+
+    /// Whether embeddings made under `other` can share a store with these.
+    /// Pooling only has to match when both record it.
+    pub fn is_compatible(&self, other: &StoreHeader) -> bool {
+        self.model == other.model
+            && self.revision == other.revision
+            && self.dimension == other.dimension
+            && self.normalized == other.normalized
+            && (self.pooling.is_none() || other.pooling.is_none() || self.pooling == other.pooling)
+    }
+}
+
+impl std::fmt::Display for StoreHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} dimensions", self.model_id(), self.dimension)?;
+        if self.normalized {
+            write!(f, ", normalized")?;
+        }
+        if let Some(pooling) = self.pooling {
+            write!(f, ", {pooling} pooling")?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VectorEntry {
+    pub id: String,
+    /// Name of the source file. Stores written before paths were recorded
+    /// call this `file_hash`.
+    #[serde(alias = "file_hash")]
+    pub file_name: String,
+    /// Canonical path of the source file, `None` in older stores.
+    #[serde(default)]
+    pub source_path: Option<PathBuf>,
+    /// Alias and version of the file in intermediate memory, when the entry
+    /// was digested from there.
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// When `version` was stored in intermediate memory, in RFC 3339.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_at: Option<String>,
+    /// When the chunk was embedded, in milliseconds since the Unix epoch; 0 if unknown.
+    #[serde(default)]
+    pub digested_at: u64,
+    /// When a newer version of the file superseded the chunk, in milliseconds
+    /// since the Unix epoch. Archived entries are only searched as of an
+    /// earlier time or across all versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived: Option<u64>,
+    pub content_preview: String,
+    /// Full text of the chunk, empty in older stores; see `VectorStore::fetch_content`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub content: String,
+    /// SHA-256 of `content`, empty in older stores.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub content_hash: String,
+    /// The vector to store when adding an entry. Stored entries keep theirs
+    /// in the store's embedding file and leave this empty; read it back with
+    /// `VectorStore::embedding`.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "embedding_format"
+    )]
+    pub embedding: Vec<f32>,
+    /// Position of the embedded chunk in its source file.
+    #[serde(default)]
+    pub span: ChunkSpan,
+    /// Heading path of the section the chunk starts in, e.g. `Setup > Linux`.
+    #[serde(default)]
+    pub section: Option<String>,
+    /// Function, type or other definition the chunk holds, for source code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    /// Names and `key=value` pairs given the file when it was digested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub last_accessed: AccessTime,
+}
+
+/// When an entry was last added or returned by a search, in milliseconds since
+/// the Unix epoch. Atomic so searches can record accesses through `&self`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(transparent)]
+pub struct AccessTime(AtomicU64);
+
+impl VectorEntry {
+    pub fn is_archived(&self) -> bool {
+        self.archived.is_some()
+    }
+
+    /// Whether the chunk was in the store at `time`, in milliseconds since the Unix epoch.
+    pub fn known_at(&self, time: u64) -> bool {
+        self.digested_at <= time && self.archived.is_none_or(|archived| archived > time)
+    }
+
+    /// The date `version` was stored, e.g. `2024-03-02`.
+    pub fn stored_on(&self) -> Option<&str> {
+        self.stored_at.as_deref().and_then(|at| at.get(..10))
+    }
+
+    /// The chunk's text, or its preview for entries stored without it.
+    pub fn text(&self) -> &str {
+        if self.content.is_empty() {
+            &self.content_preview
+        } else {
+            &self.content
+        }
+    }
+}
+
+impl AccessTime {
+    pub fn get(&self) -> u64 {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    fn touch(&self) {
+        self.0.store(now_millis(), AtomicOrdering::Relaxed);
+    }
+}
+
+impl Clone for AccessTime {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct VectorStore {
+    entries: Vec<VectorEntry>,
+    /// What produced the stored embeddings, recorded on first insert.
+    #[serde(default)]
+    header: Option<StoreHeader>,
+    /// The model of stores written before `header`, upgraded to a header by
+    /// the next digest with that model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model_id: Option<String>,
+    /// Whether every stored embedding has unit length, so a dot product can
+    /// stand in for cosine similarity. Stores written before this was tracked
+    /// read as `false`.
+    #[serde(default)]
+    normalized: bool,
+    /// How searches score entries unless they ask for another metric.
+    #[serde(default)]
+    metric: Metric,
+    /// Labels last given to each source path, which the entries of its
+    /// later versions inherit.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    file_labels: BTreeMap<PathBuf, Labels>,
+    /// Changes with every save; only a log naming it is replayed on load.
+    #[serde(default)]
+    generation: u64,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    max_entries: Option<usize>,
+    /// Approximate nearest-neighbour graph over `entries`, kept once the store is large.
+    #[serde(skip)]
+    index: Option<HnswIndex>,
+    /// Whether `index` was rebuilt since it was written, which `flush` then
+    /// does so the next load needn't rebuild it too.
+    #[serde(skip)]
+    index_rebuilt: bool,
+    /// BM25 index over each entry's text, rebuilt whenever the store is read.
+    #[serde(skip)]
+    keywords: Bm25Index,
+    /// Embedding of each entry, by position; memory-mapped from disk until changed.
+    #[serde(skip)]
+    vectors: EmbeddingMatrix,
+    /// Inside `batch`, changes only mark the store dirty instead of saving it.
+    #[serde(skip)]
+    deferred: bool,
+    /// Set by changes the log can't describe, which wait for a full save.
+    #[serde(skip)]
+    dirty: bool,
+    /// Keeps the store on disk, `None` until it has been saved or read.
+    #[serde(skip)]
+    backend: Option<Box<dyn StoreBackend>>,
+}
+
+/// Keeps a `VectorStore` between runs. The store works in memory and hands
+/// its backend every change as it is made, or the whole store when a change
+/// can't be described on its own.
+pub trait StoreBackend: Send + Sync + std::fmt::Debug {
+    /// Writes all of `store`, replacing what was kept before.
+    fn save(&mut self, store: &VectorStore) -> Result<()>;
+
+    /// Keeps `change`, just made to `store`.
+    fn record(&mut self, store: &VectorStore, change: &StoreChange) -> Result<()>;
+
+    /// Makes the recorded changes durable. Returns whether they should now
+    /// be compacted by saving the whole store.
+    fn sync(&mut self, store: &VectorStore) -> Result<bool>;
+}
+
+/// A change to a store's entries its backend can keep without a full save.
+/// Positions are those of the entries when the change was made.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StoreChange {
+    /// The entry, embedding included, was appended.
+    Add(VectorEntry),
+    /// The entry replaced the one with the same id.
+    Upsert(VectorEntry),
+    Delete(Vec<usize>),
+    Archive {
+        positions: Vec<usize>,
+        at: u64,
+    },
+}
+
+/// The store's own files, with changes since the last save appended to a
+/// write-ahead log that is compacted by the next save.
+#[derive(Debug)]
+struct FileBackend {
+    wal: Wal,
+}
+
+impl StoreBackend for FileBackend {
+    /// Writes the store in the binary format: a magic header followed by the
+    /// entries as CBOR, with their embeddings in a flat file next to it.
+    /// Each file is replaced atomically, and the previous pair is kept as
+    /// `.bak` files that `load` falls back to. The log is started over.
+    fn save(&mut self, store: &VectorStore) -> Result<()> {
+        let path = &store.path;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let vectors_path = VectorStore::vectors_path(path);
+        for path in [path, &vectors_path] {
+            rotate_backup(path)
+                .wrap_err_with(|| format!("Failed to back up {}", path.display()))?;
+        }
+        store.vectors.save(&vectors_path)?;
+        let mut data = STORE_MAGIC.to_vec();
+        ciborium::into_writer(store, &mut data).wrap_err("Failed to serialize vector store")?;
+        write_atomic(path, &crypt::seal(&data)?)
+            .wrap_err_with(|| format!("Failed to write vector store {}", path.display()))?;
+        self.wal = Wal::start(store.wal_path(), store.generation)?;
+        Ok(())
+    }
+
+    fn record(&mut self, _store: &VectorStore, change: &StoreChange) -> Result<()> {
+        self.wal.append(change)
+    }
+
+    fn sync(&mut self, store: &VectorStore) -> Result<bool> {
+        if self.wal.records() >= WAL_COMPACT_RECORDS.max(store.len() / 4) {
+            debug!(
+                "Compacting {} logged changes into the vector store",
+                self.wal.records()
+            );
+            return Ok(true);
+        }
+        self.wal.sync()?;
+        Ok(false)
+    }
+}
+
+/// A tag, `name`, or a metadata field, `key=value`, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Label {
+    Tag(String),
+    Field(String, String),
+}
+
+impl std::str::FromStr for Label {
+    type Err = VectorStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(Self::Field(
+                key.trim().to_string(),
+                value.trim().to_string(),
+            )),
+            None if !s.trim().is_empty() => Ok(Self::Tag(s.trim().to_string())),
+            _ => Err(VectorStoreError::Label(s.to_string())),
+        }
+    }
+}
+
+/// Tags and metadata, given to entries when digesting and required of them
+/// when searching.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels {
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Labels {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.metadata.is_empty()
+    }
+
+    /// Whether `entry` has every tag and metadata value of these.
+    pub fn matched_by(&self, entry: &VectorEntry) -> bool {
+        self.tags.iter().all(|tag| entry.tags.contains(tag))
+            && self
+                .metadata
+                .iter()
+                .all(|(key, value)| entry.metadata.get(key) == Some(value))
+    }
+}
+
+impl FromIterator<Label> for Labels {
+    fn from_iter<I: IntoIterator<Item = Label>>(labels: I) -> Self {
+        let mut collected = Self::default();
+        for label in labels {
+            match label {
+                Label::Tag(tag) if !collected.tags.contains(&tag) => collected.tags.push(tag),
+                Label::Tag(_) => {}
+                Label::Field(key, value) => {
+                    collected.metadata.insert(key, value);
+                }
+            }
+        }
+        collected
+    }
+}
+
+/// Restricts which entries a search returns. The default lets all through.
+#[derive(Debug, Default, Clone)]
+pub struct SearchFilter {
+    /// Globs, one of which the source path (or the file name of entries
+    /// without one) must match.
+    paths: Option<GlobSet>,
+    /// Bounds on `digested_at`, in milliseconds since the Unix epoch; the
+    /// lower one is inclusive, the upper one exclusive.
+    digested_after: Option<u64>,
+    digested_before: Option<u64>,
+    /// Search what the store held at this time instead of its current entries.
+    as_of: Option<u64>,
+    /// Search archived entries along with current ones.
+    all_versions: bool,
+    /// Tags and metadata values entries must all have.
+    labels: Labels,
+}
+
+impl SearchFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only entries whose source path matches one of `patterns`, e.g. `src/**/*.rs`.
+    pub fn with_paths(mut self, patterns: &[&str]) -> Result<Self> {
+        if patterns.is_empty() {
+            self.paths = None;
+            return Ok(self);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(
+                Glob::new(pattern).wrap_err_with(|| format!("Invalid path pattern {pattern}"))?,
+            );
+        }
+        self.paths = Some(builder.build()?);
+        Ok(self)
+    }
+
+    /// Only entries digested within `after..before`; either bound may be open.
+    pub fn digested_between(mut self, after: Option<u64>, before: Option<u64>) -> Self {
+        self.digested_after = after;
+        self.digested_before = before;
+        self
+    }
+
+    /// Only entries the store held at `time`, archived or not, in
+    /// milliseconds since the Unix epoch; current entries when `None`.
+    pub fn as_of(mut self, time: Option<u64>) -> Self {
+        self.as_of = time;
+        self
+    }
+
+    /// Also search entries of superseded versions.
+    pub fn with_all_versions(mut self, all_versions: bool) -> Self {
+        self.all_versions = all_versions;
+        self
+    }
+
+    /// Only entries with every tag and metadata value of `labels`.
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Whether `entry` may be scored at all.
+    fn accepts(&self, entry: &VectorEntry) -> bool {
+        let path_matches = self
+            .paths
+            .as_ref()
+            .is_none_or(|set| match &entry.source_path {
+                Some(path) => set.is_match(path),
+                None => set.is_match(&entry.file_name),
+            });
+        let version_matches = match self.as_of {
+            Some(time) => entry.known_at(time),
+            None => self.all_versions || !entry.is_archived(),
+        };
+        path_matches
+            && version_matches
+            && self.labels.matched_by(entry)
+            && self.digested_after.is_none_or(|t| entry.digested_at >= t)
+            && self.digested_before.is_none_or(|t| entry.digested_at < t)
+    }
+}
+
+/// How `search_text` finds, orders and pages hits.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub filter: SearchFilter,
+    /// Drops hits whose final score, after any reranking, is below this.
+    pub min_score: Option<f32>,
+    /// Hits skipped before the page starts, and hits on it at most.
+    pub offset: usize,
+    pub limit: usize,
+    /// Share of the score from keyword matches, as in `search_hybrid`.
+    pub keyword_weight: f32,
+    /// Rescore the best `rerank_top_n` hits with the digester's cross-encoder.
+    pub rerank: bool,
+    pub rerank_top_n: usize,
+    /// Diversify hits with Maximal Marginal Relevance: 1 ranks by relevance
+    /// alone, lower values increasingly favour hits unlike those already
+    /// picked. Off when `None`.
+    pub mmr_lambda: Option<f32>,
+    /// Overrides the digester's and the store's metric.
+    pub metric: Option<Metric>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            filter: SearchFilter::default(),
+            min_score: None,
+            offset: 0,
+            limit: DEFAULT_SEARCH_LIMIT,
+            keyword_weight: DEFAULT_KEYWORD_WEIGHT,
+            rerank: false,
+            rerank_top_n: DEFAULT_RERANK_TOP_N,
+            mmr_lambda: None,
+            metric: None,
+        }
+    }
+}
+
+/// The chunks of one file among the hits of `search_files`.
+#[derive(Debug, Clone)]
+pub struct FileHit<'a> {
+    /// The best score of any of its chunks.
+    pub score: f32,
+    /// Its matching chunks, best first.
+    pub chunks: Vec<(&'a VectorEntry, f32)>,
+}
+
+impl<'a> FileHit<'a> {
+    /// The best matching chunk, which names the file.
+    pub fn best(&self) -> &'a VectorEntry {
+        self.chunks[0].0
+    }
+}
+
+/// On-disk form of the index, tagged with the entries it was built over.
+#[derive(Serialize, Deserialize)]
+struct PersistedIndex<'a> {
+    fingerprint: String,
+    index: Cow<'a, HnswIndex>,
+}
+
+impl VectorStore {
+    /// Loads the store at `path`. If it doesn't exist but a legacy JSON store
+    /// sits next to it (`vectors.json` for `vectors.bin`), that one is migrated.
+    /// A store that is missing or unreadable while its backup from the
+    /// previous save is intact is loaded from the backup.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if StoreFormat::of(&path) == StoreFormat::Sqlite {
+            let mut store = Self::open_sqlite(path)?;
+            store.load_index();
+            debug!("Loaded {} vector entries", store.entries.len());
+            return Ok(store);
+        }
+        let backup = backup_path(&path);
+        if !path.exists() && backup.exists() {
+            warn!(
+                "Vector store {} is missing, loading its backup",
+                path.display()
+            );
+            return Self::load_backup(path);
+        }
+        if !path.exists() {
+            let legacy = path.with_extension("json");
+            if legacy != path && legacy.exists() {
+                return Self::migrate(legacy, path);
+            }
+            debug!("No vector store at {}, starting empty", path.display());
+            return Ok(Self {
+                path,
+                ..Default::default()
+            });
+        }
+
+        let mut store = match Self::read(&path) {
+            Ok(store) => store,
+            // A newer store isn't damaged; its backup would be older still.
+            Err(e)
+                if backup.exists()
+                    && !matches!(
+                        e.downcast_ref::<VectorStoreError>(),
+                        Some(VectorStoreError::SchemaVersion { .. })
+                    ) =>
+            {
+                warn!("{:?}", e);
+                warn!("Loading the backup of {} instead", path.display());
+                return Self::load_backup(path);
+            }
+            Err(e) => return Err(e),
+        };
+        store.path = path;
+        store.replay_wal()?;
+        store.load_index();
+        debug!("Loaded {} vector entries", store.entries.len());
+        Ok(store)
+    }
+
+    /// Applies the changes logged since the store was saved and opens the
+    /// log for more. The vector index is left to `load_index`.
+    fn replay_wal(&mut self) -> Result<()> {
+        let (wal, records) = Wal::open::<StoreChange>(self.wal_path(), self.generation)?;
+        if !records.is_empty() {
+            debug!("Replaying {} logged vector store changes", records.len());
+        }
+        let start = self.entries.len();
+        let mut reindex = false;
+        for record in records {
+            let applied = match record {
+                StoreChange::Add(entry) => self.push_entry(entry).is_ok(),
+                StoreChange::Upsert(entry) => {
+                    reindex = true;
+                    match self.position(&entry.id) {
+                        Some(i) => self.replace_entry(i, entry).is_ok(),
+                        None => self.push_entry(entry).is_ok(),
+                    }
+                }
+                StoreChange::Delete(positions) => {
+                    reindex = true;
+                    let mut keep = vec![true; self.entries.len()];
+                    let fits = positions.iter().all(|&i| i < keep.len());
+                    if fits {
+                        for i in positions {
+                            keep[i] = false;
+                        }
+                        self.retain_entries(&keep);
+                    }
+                    fits
+                }
+                StoreChange::Archive { positions, at } => {
+                    let fits = positions.iter().all(|&i| i < self.entries.len());
+                    if fits {
+                        for i in positions {
+                            self.entries[i].archived = Some(at);
+                        }
+                    }
+                    fits
+                }
+            };
+            if !applied {
+                warn!(
+                    "Write-ahead log {} doesn't fit the store, ignoring the rest of it",
+                    self.wal_path().display()
+                );
+                // The next change saves the store in full, starting a new log.
+                self.dirty = true;
+                break;
+            }
+        }
+        if reindex {
+            self.rescan();
+        } else {
+            for i in start..self.entries.len() {
+                self.keywords.insert(i, keyword_text(&self.entries[i]));
+            }
+        }
+        self.backend = Some(Box::new(FileBackend { wal }));
+        Ok(())
+    }
+
+    /// Reads the SQLite store at `path`, creating it if there is none.
+    #[cfg(feature = "sqlite")]
+    fn open_sqlite(path: PathBuf) -> Result<Self> {
+        sqlite::load(path)
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn open_sqlite(path: PathBuf) -> Result<Self> {
+        Err(VectorStoreError::NoSqlite(path).into())
+    }
+
+    /// A backend for the store at `path`, to save the store with.
+    fn open_backend(&self) -> Result<Box<dyn StoreBackend>> {
+        match StoreFormat::of(&self.path) {
+            StoreFormat::Files => Ok(Box::new(FileBackend {
+                wal: Wal::start(self.wal_path(), self.generation)?,
+            })),
+            #[cfg(feature = "sqlite")]
+            StoreFormat::Sqlite => Ok(Box::new(sqlite::SqliteBackend::open(&self.path)?)),
+            #[cfg(not(feature = "sqlite"))]
+            StoreFormat::Sqlite => Err(VectorStoreError::NoSqlite(self.path.clone()).into()),
+        }
+    }
+
+    /// Loads the backup of the store at `path` and copies it back over the
+    /// damaged files, so the next save doesn't rotate them into the backup.
+    fn load_backup(path: PathBuf) -> Result<Self> {
+        let backup = backup_path(&path);
+        let vectors_path = Self::vectors_path(&path);
+        let mut store = Self::read_with(&backup, &backup_path(&vectors_path))
+            .wrap_err_with(|| format!("Backup {} is unreadable too", backup.display()))?;
+        for target in [&path, &vectors_path] {
+            let backup = backup_path(target);
+            if backup.exists() {
+                std::fs::read(&backup)
+                    .and_then(|data| write_atomic(target, &data))
+                    .wrap_err_with(|| format!("Failed to restore {}", target.display()))?;
+            }
+        }
+        store.path = path;
+        store.rebuild_index();
+        Ok(store)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        Self::read_with(path, &Self::vectors_path(path))
+    }
+
+    /// Reads any format, telling them apart by the binary header. Embeddings
+    /// of older stores are moved out of their entries into the matrix.
+    fn read_with(path: &Path, vectors_path: &Path) -> Result<Self> {
+        let data = crypt::read(path)
+            .wrap_err_with(|| format!("Failed to read vector store {}", path.display()))?;
+        let parse_error = || format!("Failed to parse vector store {}", path.display());
+        let mut store: Self = if let Some(body) = data.strip_prefix(STORE_MAGIC) {
+            let mut store: Self = ciborium::from_reader(body).wrap_err_with(parse_error)?;
+            if let Some(header) = &store.header
+                && header.schema_version > SCHEMA_VERSION
+            {
+                return Err(VectorStoreError::SchemaVersion {
+                    path: path.to_path_buf(),
+                    found: header.schema_version,
+                }
+                .into());
+            }
+            if !store.entries.is_empty() || vectors_path.exists() {
+                store.vectors = EmbeddingMatrix::open(vectors_path)?;
+            }
+            if store.vectors.len() != store.entries.len() {
+                bail!(
+                    "Embeddings {} hold {} vectors, but {} lists {} entries",
+                    vectors_path.display(),
+                    store.vectors.len(),
+                    path.display(),
+                    store.entries.len()
+                );
+            }
+            store
+        } else {
+            let mut store: Self = match data.strip_prefix(INLINE_STORE_MAGIC) {
+                Some(body) => ciborium::from_reader(body).wrap_err_with(parse_error)?,
+                None => serde_json::from_slice(&data).wrap_err_with(parse_error)?,
+            };
+            store.vectors =
+                EmbeddingMatrix::from_rows(store.entries.iter().map(|e| e.embedding.as_slice()))
+                    .wrap_err_with(parse_error)?;
+            for entry in &mut store.entries {
+                entry.embedding = Vec::new();
+            }
+            store
+        };
+        store.rebuild_keywords();
+        Ok(store)
+    }
+
+    fn migrate(legacy: PathBuf, path: PathBuf) -> Result<Self> {
+        info!(
+            "Migrating vector store {} to {}",
+            legacy.display(),
+            path.display()
+        );
+        let mut store = Self::read(&legacy)?;
+        store.path = path;
+        store.rebuild_index();
+        store.save()?;
+        std::fs::remove_file(&legacy).wrap_err_with(|| {
+            format!(
+                "Failed to remove migrated vector store {}",
+                legacy.display()
+            )
+        })?;
+        debug!("Migrated {} vector entries", store.entries.len());
+        Ok(store)
+    }
+
+    /// Writes the whole store through its backend, by default in the
+    /// binary format with the previous save kept as a backup, and persists
+    /// the index next to it. Changes kept since the last save are part of
+    /// this one, so the write-ahead log is started over.
+    pub fn save(&mut self) -> Result<()> {
+        // Never reused, even by a store restored from its backup, so no
+        // earlier log ever passes for this save's.
+        self.generation = (self.generation + 1).max(now_millis());
+        let mut backend = match self.backend.take() {
+            Some(backend) => backend,
+            None => self.open_backend()?,
+        };
+        let saved = backend.save(self);
+        self.backend = Some(backend);
+        saved?;
+        self.dirty = false;
+        self.save_index()
+    }
+
+    /// Saves the store as the one at `path`, in the format its extension
+    /// names, replacing it the way `save` does, so a store in the files
+    /// format that was there is kept as the backup.
+    pub fn save_as(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        self.path = path.into();
+        self.backend = None;
+        self.save()
+    }
+
+    /// Every file the store at `path` may be kept in.
+    fn files(path: &Path) -> Vec<PathBuf> {
+        let mut files = vec![path.to_path_buf(), path.with_extension("hnsw")];
+        match StoreFormat::of(path) {
+            StoreFormat::Files => {
+                let vectors_path = Self::vectors_path(path);
+                files.extend([
+                    backup_path(path),
+                    backup_path(&vectors_path),
+                    vectors_path,
+                    path.with_extension("wal"),
+                ]);
+            }
+            StoreFormat::Sqlite => {
+                // SQLite's own write-ahead log and its index.
+                for suffix in ["-wal", "-shm"] {
+                    let mut file = path.as_os_str().to_owned();
+                    file.push(suffix);
+                    files.push(file.into());
+                }
+            }
+        }
+        files
+    }
+
+    /// Where a replacement for the store at `path` is built before being
+    /// swapped in, e.g. `vectors.reembed.tmp`. The dot keeps it apart from
+    /// every collection's files.
+    pub fn staging_path(path: &Path) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!("{stem}.reembed.tmp"))
+    }
+
+    /// Deletes the store at `path` along with its embeddings, index, log and backups.
+    pub fn remove_files(path: &Path) -> Result<()> {
+        for file in Self::files(path) {
+            match std::fs::remove_file(&file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).wrap_err_with(|| format!("Failed to remove {}", file.display()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` with saving deferred, then writes the store once if `f`
+    /// changed it, even if `f` failed part way. Nested batches write when the
+    /// outermost one ends.
+    pub fn batch<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R>) -> Result<R> {
+        let outer = std::mem::replace(&mut self.deferred, true);
+        let result = f(self);
+        if outer {
+            return result;
+        }
+        self.deferred = false;
+        let flushed = self.flush();
+        let result = result?;
+        flushed?;
+        Ok(result)
+    }
+
+    /// Adds all of `entries` with a single save, returning how many there were.
+    pub fn add_batch(&mut self, entries: impl IntoIterator<Item = VectorEntry>) -> Result<usize> {
+        self.batch(|store| {
+            let mut added = 0;
+            for entry in entries {
+                store.add(entry)?;
+                added += 1;
+            }
+            Ok(added)
+        })
+    }
+
+    /// Writes changes deferred by a batch, if there are any, and syncs the
+    /// ones logged, compacting the log into the store once it is long.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.dirty {
+            return self.save();
+        }
+        let Some(mut backend) = self.backend.take() else {
+            return Ok(());
+        };
+        let synced = backend.sync(self);
+        self.backend = Some(backend);
+        if synced? {
+            return self.save();
+        }
+        if self.index_rebuilt {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Saves after a change, or leaves that to the end of the batch.
+    fn changed(&mut self) -> Result<()> {
+        if self.deferred {
+            self.dirty = true;
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Whether a change can be handed to the backend rather than saving the
+    /// store: not before the store was first saved, nor after a change
+    /// that can't be described on its own.
+    fn logging(&self) -> bool {
+        self.backend.is_some() && !self.dirty
+    }
+
+    /// Hands `change` to the backend, or saves the store as `changed` does
+    /// when there is no change to hand over or the backend fails to keep it.
+    fn logged(&mut self, change: Option<StoreChange>) -> Result<()> {
+        let Some(change) = change else {
+            return self.changed();
+        };
+        let Some(mut backend) = self.backend.take() else {
+            return self.changed();
+        };
+        let recorded = backend.record(self, &change);
+        self.backend = Some(backend);
+        if let Err(e) = recorded {
+            warn!("{:?}", e);
+            warn!("Saving the vector store in full instead");
+            self.dirty = true;
+            return self.changed();
+        }
+        if self.deferred {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    /// Where the changes since the last save of the store are logged.
+    fn wal_path(&self) -> PathBuf {
+        self.path.with_extension("wal")
+    }
+
+    /// Where the embeddings of the store at `path` live.
+    fn vectors_path(path: &Path) -> PathBuf {
+        path.with_extension("emb")
+    }
+
+    /// Where the index is persisted, next to the store itself.
+    fn index_path(&self) -> PathBuf {
+        self.path.with_extension("hnsw")
+    }
+
+    /// Identifies the exact list of the first `len` entries an index was
+    /// built over.
+    fn fingerprint(&self, len: usize) -> String {
+        let mut hasher = Sha256::new();
+        for entry in &self.entries[..len] {
+            hasher.update(entry.id.as_bytes());
+            hasher.update([0]);
+        }
+        format!("{}-{:x}", len, hasher.finalize())
+    }
+
+    /// Reuses the persisted index when it was built over the loaded entries,
+    /// or over the first of them with the rest added since, which are then
+    /// inserted. Rebuilds it otherwise.
+    fn load_index(&mut self) {
+        if self.entries.len() < INDEX_MIN_ENTRIES {
+            return;
+        }
+        let index_path = self.index_path();
+        let persisted = crypt::read(&index_path)
+            .ok()
+            .and_then(|data| ciborium::from_reader::<PersistedIndex, _>(data.as_slice()).ok());
+        let indexed = persisted.as_ref().and_then(|persisted| {
+            let (len, _) = persisted.fingerprint.split_once('-')?;
+            let len = len.parse().ok().filter(|&len| len <= self.entries.len())?;
+            (persisted.fingerprint == self.fingerprint(len)).then_some(len)
+        });
+        match (persisted, indexed) {
+            (Some(persisted), Some(len)) => {
+                debug!("Loaded vector index {}", index_path.display());
+                let mut index = persisted.index.into_owned();
+                let vectors = &self.vectors;
+                for i in len..self.entries.len() {
+                    index.insert(i, &|i| vectors.row(i));
+                }
+                self.index = Some(index);
+            }
+            _ => {
+                debug!("Vector index {} missing or stale", index_path.display());
+                self.rebuild_index();
+            }
+        }
+    }
+
+    fn save_index(&mut self) -> Result<()> {
+        self.index_rebuilt = false;
+        let index_path = self.index_path();
+        let Some(index) = &self.index else {
+            if index_path.exists() {
+                std::fs::remove_file(&index_path).wrap_err_with(|| {
+                    format!("Failed to remove vector index {}", index_path.display())
+                })?;
+            }
+            return Ok(());
+        };
+
+        let persisted = PersistedIndex {
+            fingerprint: self.fingerprint(self.entries.len()),
+            index: Cow::Borrowed(index),
+        };
+        let mut data = Vec::new();
+        ciborium::into_writer(&persisted, &mut data)
+            .wrap_err("Failed to serialize vector index")?;
+        write_atomic(&index_path, &crypt::seal(&data)?)
+            .wrap_err_with(|| format!("Failed to write vector index {}", index_path.display()))
+    }
+
+    fn rebuild_index(&mut self) {
+        if self.entries.len() < INDEX_MIN_ENTRIES {
+            self.index = None;
+            return;
+        }
+        debug!("Building vector index over {} entries", self.entries.len());
+        let vectors = &self.vectors;
+        self.index = Some(HnswIndex::build(vectors.len(), |i| vectors.row(i)));
+        self.index_rebuilt = true;
+    }
+
+    fn rebuild_keywords(&mut self) {
+        let entries = &self.entries;
+        self.keywords = Bm25Index::build(entries.len(), |i| keyword_text(&entries[i]));
+    }
+
+    pub fn add(&mut self, entry: VectorEntry) -> Result<()> {
+        if entry.embedding.iter().all(|&x| x == 0.0) {
+            return Err(VectorStoreError::ZeroVector(entry.id).into());
+        }
+        self.check_dimension(&entry)?;
+        trace!("Adding vector entry {}", entry.id);
+        let record = self.logging().then(|| StoreChange::Add(entry.clone()));
+        self.push_entry(entry)?;
+
+        if self.evict_over_capacity() {
+            self.rebuild_keywords();
+            self.rebuild_index();
+            return self.changed();
+        }
+        let last = self.entries.len() - 1;
+        self.keywords
+            .insert(last, keyword_text(&self.entries[last]));
+        if let Some(index) = &mut self.index {
+            let vectors = &self.vectors;
+            index.insert(last, &|i| vectors.row(i));
+        } else if self.entries.len() >= INDEX_MIN_ENTRIES {
+            self.rebuild_index();
+        }
+        self.logged(record)
+    }
+
+    /// Appends `entry` and its embedding, leaving the indexes to the caller.
+    fn push_entry(&mut self, mut entry: VectorEntry) -> Result<()> {
+        self.vectors.push(&std::mem::take(&mut entry.embedding))?;
+        // Judged on the stored row, which quantization may have moved off unit length.
+        let unit = is_unit(&self.vectors.row(self.vectors.len() - 1));
+        self.normalized = unit && (self.normalized || self.entries.is_empty());
+        entry.last_accessed.touch();
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the model id and then every entry, one JSON object per line,
+    /// encrypted if a key is installed. Returns the number of entries.
+    pub fn export_jsonl(&self, path: &Path) -> Result<usize> {
+        let file = File::create(path)
+            .wrap_err_with(|| format!("Failed to create export {}", path.display()))?;
+        let mut writer = crypt::writer(BufWriter::new(file))?;
+        let header = ExportHeader {
+            ouroboros_export: 1,
+            model_id: self.model_id(),
+            header: self.header.clone(),
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+        for (entry, embedding) in self.entries.iter().zip(self.vectors.iter()) {
+            let entry = VectorEntry {
+                embedding: embedding.into_owned(),
+                ..entry.clone()
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer
+            .finish()
+            .wrap_err_with(|| format!("Failed to write export {}", path.display()))?;
+        info!(
+            "Exported {} vector entries to {}",
+            self.entries.len(),
+            path.display()
+        );
+        Ok(self.entries.len())
+    }
+
+    /// Reads entries written by `export_jsonl`, resolving id conflicts by
+    /// `strategy`, and saves the store once at the end. Fails without changing
+    /// anything if the export was embedded with a different model or a line
+    /// doesn't parse.
+    pub fn import_jsonl(&mut self, path: &Path, strategy: MergeStrategy) -> Result<ImportSummary> {
+        let file = File::open(path)
+            .wrap_err_with(|| format!("Failed to open export {}", path.display()))?;
+        let reader = BufReader::new(crypt::reader_or_plaintext(BufReader::new(file))?);
+
+        let mut source = None;
+        let mut imported = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line =
+                line.wrap_err_with(|| format!("Failed to read export {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if index == 0
+                && let Ok(header) = serde_json::from_str::<ExportHeader>(&line)
+            {
+                source = Some(header);
+                continue;
+            }
+            let entry: VectorEntry =
+                serde_json::from_str(&line).map_err(|e| VectorStoreError::BadExportLine {
+                    path: path.to_path_buf(),
+                    line: index + 1,
+                    reason: e.to_string(),
+                })?;
+            if entry.embedding.iter().all(|&x| x == 0.0) {
+                return Err(VectorStoreError::ZeroVector(entry.id).into());
+            }
+            imported.push(entry);
+        }
+        let (header, model_id) = match source {
+            Some(source) => (source.header, source.model_id),
+            None => (None, None),
+        };
+        self.absorb(imported, header, model_id, strategy, path)
+    }
+
+    /// Merges the entries of the store at `path` that pass `keep`, resolving
+    /// id conflicts by `strategy` as `import_jsonl` does.
+    pub fn import_store(
+        &mut self,
+        path: &Path,
+        strategy: MergeStrategy,
+        keep: impl Fn(&VectorEntry) -> bool,
+    ) -> Result<ImportSummary> {
+        let other = match StoreFormat::of(path) {
+            StoreFormat::Files => {
+                let mut other = Self::read(path)?;
+                other.path = path.to_path_buf();
+                other.replay_wal()?;
+                other
+            }
+            StoreFormat::Sqlite => Self::open_sqlite(path.to_path_buf())?,
+        };
+        let imported = other
+            .entries
+            .iter()
+            .zip(other.vectors.iter())
+            .filter(|(entry, _)| keep(entry))
+            .map(|(entry, embedding)| VectorEntry {
+                embedding: embedding.into_owned(),
+                ..entry.clone()
+            })
+            .collect();
+        self.absorb(imported, other.header, other.model_id, strategy, path)
+    }
+
+    /// Adds `imported`, embeddings included, checking their dimensions and
+    /// model first, and saves the store once. `header` describes where they
+    /// came from, or just `model_id` for stores and exports older than headers.
+    fn absorb(
+        &mut self,
+        imported: Vec<VectorEntry>,
+        header: Option<StoreHeader>,
+        model_id: Option<String>,
+        strategy: MergeStrategy,
+        path: &Path,
+    ) -> Result<ImportSummary> {
+        let dim = match imported.first() {
+            Some(first) if strategy == MergeStrategy::Replace || self.is_empty() => {
+                first.embedding.len()
+            }
+            _ => self.vectors.dim(),
+        };
+        if let Some(entry) = imported.iter().find(|e| e.embedding.len() != dim) {
+            bail!(
+                "Entry {} in {} has {} dimensions, expected {}",
+                entry.id,
+                path.display(),
+                entry.embedding.len(),
+                dim
+            );
+        }
+
+        if strategy == MergeStrategy::Replace {
+            self.entries.clear();
+            self.vectors.clear();
+            self.header = None;
+            self.model_id = None;
+        }
+        match (&header, model_id) {
+            (Some(header), _) => self.claim_header(header)?,
+            (None, Some(model_id)) => self.claim_model_id(model_id)?,
+            (None, None) => {}
+        }
+
+        let mut summary = ImportSummary::default();
+        let mut positions: HashMap<String, usize> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.id.clone(), i))
+            .collect();
+        for mut entry in imported {
+            let embedding = std::mem::take(&mut entry.embedding);
+            match positions.get(&entry.id) {
+                Some(_) if strategy == MergeStrategy::Skip => summary.skipped += 1,
+                Some(&i) => {
+                    self.vectors.set(i, &embedding)?;
+                    self.entries[i] = entry;
+                    summary.replaced += 1;
+                }
+                None => {
+                    positions.insert(entry.id.clone(), self.entries.len());
+                    self.vectors.push(&embedding)?;
+                    self.entries.push(entry);
+                    summary.added += 1;
+                }
+            }
+        }
+
+        self.evict_over_capacity();
+        self.entries_changed();
+        self.changed()?;
+        info!(
+            "Imported {} vector entries from {} ({} replaced, {} skipped)",
+            summary.added,
+            path.display(),
+            summary.replaced,
+            summary.skipped
+        );
+        Ok(summary)
+    }
+
+    /// Adds `entry`, replacing any stored entry with the same id.
+    pub fn upsert(&mut self, entry: VectorEntry) -> Result<()> {
+        let Some(existing) = self.position(&entry.id) else {
+            return self.add(entry);
+        };
+        if entry.embedding.iter().all(|&x| x == 0.0) {
+            return Err(VectorStoreError::ZeroVector(entry.id).into());
+        }
+        self.check_dimension(&entry)?;
+        trace!("Replacing vector entry {}", entry.id);
+        let record = self.logging().then(|| StoreChange::Upsert(entry.clone()));
+        self.replace_entry(existing, entry)?;
+        self.entries_changed();
+        self.logged(record)
+    }
+
+    /// Puts `entry` and its embedding at `position`, leaving the indexes to
+    /// the caller.
+    fn replace_entry(&mut self, position: usize, mut entry: VectorEntry) -> Result<()> {
+        self.vectors
+            .set(position, &std::mem::take(&mut entry.embedding))?;
+        entry.last_accessed.touch();
+        self.entries[position] = entry;
+        Ok(())
+    }
+
+    /// Removes the entry `id`, returning whether it was stored.
+    pub fn delete(&mut self, id: &str) -> Result<bool> {
+        Ok(self.delete_where(|entry| entry.id == id)? > 0)
+    }
+
+    /// Removes every entry digested from `path`, returning how many there were.
+    pub fn delete_by_path(&mut self, path: &Path) -> Result<usize> {
+        self.delete_where(|entry| entry.source_path.as_deref() == Some(path))
+    }
+
+    /// Removes every entry digested from one of `paths` and forgets the
+    /// paths' labels, saving the store if either changed it. Returns how
+    /// many entries there were.
+    pub fn delete_by_paths(&mut self, paths: &[PathBuf]) -> Result<usize> {
+        let mut forgot = false;
+        for path in paths {
+            forgot |= self.file_labels.remove(path).is_some();
+        }
+        if forgot {
+            self.dirty = true;
+        }
+        let removed = self.delete_entries_of(paths)?;
+        // Removing entries saved the store already, labels and all.
+        if forgot && removed == 0 {
+            self.changed()?;
+        }
+        Ok(removed)
+    }
+
+    fn delete_entries_of(&mut self, paths: &[PathBuf]) -> Result<usize> {
+        let paths: HashSet<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        self.delete_where(|entry| {
+            entry
+                .source_path
+                .as_deref()
+                .is_some_and(|path| paths.contains(path))
+        })
+    }
+
+    /// Retires the current entries of `paths` once they got a new version:
+    /// archives them when `archive` is set, otherwise removes them along with
+    /// any archived ones. Their labels are kept for the new version.
+    /// Returns how many there were.
+    pub fn supersede_paths(&mut self, paths: &[PathBuf], archive: bool) -> Result<usize> {
+        if !archive {
+            return self.delete_entries_of(paths);
+        }
+        let paths: HashSet<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let now = now_millis();
+        let mut archived = Vec::new();
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            if !entry.is_archived()
+                && entry
+                    .source_path
+                    .as_deref()
+                    .is_some_and(|path| paths.contains(path))
+            {
+                entry.archived = Some(now);
+                archived.push(i);
+            }
+        }
+        let count = archived.len();
+        if count > 0 {
+            debug!("Archived {} vector entries", count);
+            let record = self.logging().then_some(StoreChange::Archive {
+                positions: archived,
+                at: now,
+            });
+            self.logged(record)?;
+        }
+        Ok(count)
+    }
+
+    /// Removes every entry `matches` accepts, returning how many there were.
+    pub fn delete_where(&mut self, matches: impl Fn(&VectorEntry) -> bool) -> Result<usize> {
+        let keep: Vec<bool> = self.entries.iter().map(|entry| !matches(entry)).collect();
+        let removed: Vec<usize> = (0..keep.len()).filter(|&i| !keep[i]).collect();
+        let count = removed.len();
+        if count > 0 {
+            debug!("Deleted {} vector entries", count);
+            self.retain_entries(&keep);
+            self.entries_changed();
+            let record = self.logging().then_some(StoreChange::Delete(removed));
+            self.logged(record)?;
+        }
+        Ok(count)
+    }
+
+    /// Whether the store holds entries embedded from `version` of `path`.
+    pub fn is_current(&self, path: &Path, version: u32) -> bool {
+        self.entries.iter().any(|e| {
+            e.source_path.as_deref() == Some(path) && e.version == Some(version) && !e.is_archived()
+        })
+    }
+
+    /// How many entries were embedded from each version of `path`.
+    pub fn embedded_versions(&self, path: &Path) -> BTreeMap<u32, usize> {
+        let mut versions = BTreeMap::new();
+        for entry in &self.entries {
+            if let Some(version) = entry.version
+                && entry.source_path.as_deref() == Some(path)
+            {
+                *versions.entry(version).or_default() += 1;
+            }
+        }
+        versions
+    }
+
+    /// Brings the normalized flag and the index up to date after entries were
+    /// replaced or removed.
+    fn entries_changed(&mut self) {
+        self.rescan();
+        self.rebuild_index();
+    }
+
+    /// Brings the normalized flag and the keyword index up to date.
+    fn rescan(&mut self) {
+        self.normalized = !self.vectors.is_empty() && self.vectors.iter().all(|v| is_unit(&v));
+        self.rebuild_keywords();
+    }
+
+    /// Caps the store at `max` entries; once exceeded, `add` evicts the least
+    /// recently accessed entries down to exactly `max`.
+    pub fn set_max_entries(&mut self, max: Option<usize>) {
+        self.max_entries = max;
+    }
+
+    /// Returns whether any entries were evicted.
+    fn evict_over_capacity(&mut self) -> bool {
+        let Some(max) = self.max_entries else {
+            return false;
+        };
+        let excess = self.entries.len().saturating_sub(max);
+        if excess == 0 {
+            return false;
+        }
+
+        let mut by_age: Vec<(u64, usize)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.last_accessed.get(), i))
+            .collect();
+        by_age.sort_unstable();
+        let mut keep = vec![true; self.entries.len()];
+        for (_, i) in by_age.into_iter().take(excess) {
+            keep[i] = false;
+        }
+        self.retain_entries(&keep);
+        debug!("Evicted {} least recently used vector entries", excess);
+        true
+    }
+
+    /// Keeps the entries, and their embeddings, whose flag in `keep` is set.
+    fn retain_entries(&mut self, keep: &[bool]) {
+        let mut flags = keep.iter();
+        self.entries
+            .retain(|_| *flags.next().expect("a flag per entry"));
+        self.vectors.retain(keep);
+    }
+
+    /// The `limit` entries most similar to `query` among those `filter` lets
+    /// through, scored by the store's metric.
+    pub fn search(
+        &self,
+        query: &[f32],
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Vec<(&VectorEntry, f32)> {
+        self.search_by(query, limit, filter, self.metric)
+    }
+
+    /// Like `search`, scoring by `metric`.
+    pub fn search_by(
+        &self,
+        query: &[f32],
+        limit: usize,
+        filter: &SearchFilter,
+        metric: Metric,
+    ) -> Vec<(&VectorEntry, f32)> {
+        self.rank(query, limit, metric, |entry| filter.accepts(entry))
+    }
+
+    /// Embeds `query` with `digester` and returns the best matching entries,
+    /// blending in keyword matches as `search_hybrid` does, then reranking and
+    /// diversifying them if `options` ask for it, and returns the page of
+    /// them `options` select. Scores by the metric of `options`, else the
+    /// digester's, else the store's. Fails if the store was embedded with a
+    /// different model, or reranking is asked for without a reranker.
+    pub fn search_text(
+        &self,
+        digester: &Digester,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<(&VectorEntry, f32)>> {
+        digester.check_compatible(self)?;
+        let query_embedding = digester.embed_query(query)?;
+        let metric = options.metric.or(digester.metric()).unwrap_or(self.metric);
+        // Every page is ranked from the top, so page n sees the hits before it.
+        let limit = options.offset + options.limit;
+        let mut pool = limit;
+        if options.rerank {
+            pool = pool.max(options.rerank_top_n);
+        }
+        if options.mmr_lambda.is_some() {
+            pool = pool.max(limit * MMR_CANDIDATES_PER_RESULT);
+        }
+
+        let mut hits = self.search_hybrid(
+            &query_embedding,
+            query,
+            pool,
+            &options.filter,
+            options.keyword_weight,
+            metric,
+        );
+        if options.rerank {
+            let reranker = digester.reranker().ok_or(DigestError::NoReranker)?;
+            hits = reranker.rerank(query, hits)?;
+        }
+        // Before paging, so dropped hits don't leave the page short.
+        if let Some(min_score) = options.min_score {
+            hits.retain(|(_, score)| *score >= min_score);
+        }
+        match options.mmr_lambda {
+            Some(lambda) => hits = self.mmr(hits, limit, lambda),
+            None => hits.truncate(limit),
+        }
+        hits.drain(..options.offset.min(hits.len()));
+        Ok(hits)
+    }
+
+    /// Like `search_text`, but collapses the hits of each file into one
+    /// `FileHit` scored by its best chunk, and pages through files rather
+    /// than chunks. Files are picked from the best `CHUNKS_PER_FILE_RESULT`
+    /// chunk hits per requested file, so a file whose chunks all rank below
+    /// that many others is missed.
+    pub fn search_files(
+        &self,
+        digester: &Digester,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<FileHit<'_>>> {
+        let wanted = options.offset + options.limit;
+        let chunk_options = SearchOptions {
+            offset: 0,
+            limit: wanted * CHUNKS_PER_FILE_RESULT,
+            ..options.clone()
+        };
+        let mut files: Vec<FileHit> = Vec::new();
+        let mut by_file: HashMap<&OsStr, usize> = HashMap::new();
+        for (entry, score) in self.search_text(digester, query, &chunk_options)? {
+            let file = *by_file.entry(file_key(entry)).or_insert_with(|| {
+                files.push(FileHit {
+                    score,
+                    chunks: Vec::new(),
+                });
+                files.len() - 1
+            });
+            files[file].score = files[file].score.max(score);
+            files[file].chunks.push((entry, score));
+        }
+        for file in &mut files {
+            file.chunks.sort_by(compare_hits);
+        }
+        // Stable, so files tied on score keep the order they were found in.
+        files.sort_by(|a, b| b.score.total_cmp(&a.score));
+        files.truncate(wanted);
+        files.drain(..options.offset.min(files.len()));
+        Ok(files)
+    }
+
+    /// Entries most similar to the stored entry `id`, excluding that entry itself.
+    pub fn similar_to(&self, id: &str, limit: usize) -> Result<Vec<(&VectorEntry, f32)>> {
+        let source = self
+            .position(id)
+            .ok_or_else(|| VectorStoreError::UnknownEntry(id.to_string()))?;
+        Ok(
+            self.rank(&self.vectors.row(source), limit, self.metric, |entry| {
+                entry.id != id && !entry.is_archived()
+            }),
+        )
+    }
+
+    fn rank(
+        &self,
+        query: &[f32],
+        limit: usize,
+        metric: Metric,
+        keep: impl Fn(&VectorEntry) -> bool + Sync,
+    ) -> Vec<(&VectorEntry, f32)> {
+        let scored: Vec<_> = self
+            .nearest(query, limit, metric, keep)
+            .into_iter()
+            .map(|(i, score)| (&self.entries[i], score))
+            .collect();
+        for (entry, _) in &scored {
+            entry.last_accessed.touch();
+        }
+        scored
+    }
+
+    /// Indexes and scores of the `limit` entries closest to `query`, best first.
+    /// The index is built on cosine similarity, so other metrics only use it
+    /// when normalized embeddings make them rank the same.
+    fn nearest(
+        &self,
+        query: &[f32],
+        limit: usize,
+        metric: Metric,
+        keep: impl Fn(&VectorEntry) -> bool + Sync,
+    ) -> Vec<(usize, f32)> {
+        if limit == 0 {
+            return Vec::new();
+        }
+        let scorer = self.vectors.scorer(query, metric, self.normalized);
+        if let Some(index) = &self.index
+            && (metric == Metric::Cosine || self.normalized)
+        {
+            let candidates = index.search(query, limit + INDEX_OVERSAMPLE, |i| self.vectors.row(i));
+            let mut heap = BinaryHeap::with_capacity(limit + 1);
+            for (i, score) in candidates {
+                if keep(&self.entries[i]) {
+                    let score = match metric {
+                        Metric::Cosine => score,
+                        _ => scorer.exact(i),
+                    };
+                    push_bounded(&mut heap, RankedHit(i, (&self.entries[i], score)), limit);
+                }
+            }
+            if heap.len() == limit {
+                return ranked(heap);
+            }
+            // A selective filter rejects most of the neighbourhood the index
+            // walks; only a full scan is sure to find every match.
+            debug!(
+                "Index found {} of {} hits passing the filter, scanning all entries",
+                heap.len(),
+                limit
+            );
+        }
+        self.scan(&scorer, limit, &keep)
+    }
+
+    /// Scores every entry passing `keep` with `scorer`, spread over all
+    /// cores, and returns the best `limit`. Each thread keeps a bounded heap
+    /// whose top is the worst hit kept so far, for O(n log limit) overall.
+    fn scan(
+        &self,
+        scorer: &Scorer<'_>,
+        limit: usize,
+        keep: &(impl Fn(&VectorEntry) -> bool + Sync),
+    ) -> Vec<(usize, f32)> {
+        let shortlist = if scorer.is_approximate() {
+            limit * RESCORE_CANDIDATES_PER_RESULT
+        } else {
+            limit
+        };
+        let heap = (0..self.entries.len())
+            .into_par_iter()
+            .with_min_len(SCAN_CHUNK)
+            .filter(|&i| keep(&self.entries[i]))
+            .fold(
+                || BinaryHeap::with_capacity(shortlist + 1),
+                |mut heap, i| {
+                    let hit = RankedHit(i, (&self.entries[i], scorer.score(i)));
+                    push_bounded(&mut heap, hit, shortlist);
+                    heap
+                },
+            )
+            .reduce(BinaryHeap::new, |mut heap, other| {
+                for hit in other {
+                    push_bounded(&mut heap, hit, shortlist);
+                }
+                heap
+            });
+
+        if !scorer.is_approximate() {
+            return ranked(heap);
+        }
+        // Int8 codes are only precise enough to shortlist; rescore against
+        // the full-precision query.
+        let mut hits = heap.into_vec();
+        for hit in &mut hits {
+            hit.1.1 = scorer.exact(hit.0);
+        }
+        hits.sort();
+        hits.truncate(limit);
+        hits.into_iter().map(|hit| (hit.0, hit.1.1)).collect()
+    }
+
+    /// Like `search`, but blends each hit's similarity with its BM25 keyword
+    /// score for `text`, so exact terms the embedding glosses over still rank.
+    /// `keyword_weight` runs from 0 (pure similarity) to 1 (pure keywords);
+    /// keyword scores are scaled so the best candidate gets 1.
+    pub fn search_hybrid(
+        &self,
+        query: &[f32],
+        text: &str,
+        limit: usize,
+        filter: &SearchFilter,
+        keyword_weight: f32,
+        metric: Metric,
+    ) -> Vec<(&VectorEntry, f32)> {
+        let keyword_weight = keyword_weight.clamp(0.0, 1.0);
+        if keyword_weight == 0.0 || limit == 0 {
+            return self.search_by(query, limit, filter, metric);
+        }
+
+        let pool = limit + INDEX_OVERSAMPLE;
+        let scorer = self.vectors.scorer(query, metric, self.normalized);
+        let mut candidates: Vec<usize> = self
+            .nearest(query, pool, metric, |entry| filter.accepts(entry))
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+        candidates.extend(
+            self.keywords
+                .search(text, pool, |i| filter.accepts(&self.entries[i]))
+                .into_iter()
+                .map(|(i, _)| i),
+        );
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let keyword_scores: Vec<f32> = candidates
+            .iter()
+            .map(|&i| self.keywords.score(text, i))
+            .collect();
+        let best_keyword = keyword_scores.iter().copied().fold(0.0, f32::max);
+        let mut hits: Vec<_> = candidates
+            .iter()
+            .zip(keyword_scores)
+            .map(|(&i, keyword_score)| {
+                let entry = &self.entries[i];
+                let similarity = scorer.exact(i);
+                let keyword = if best_keyword > 0.0 {
+                    keyword_score / best_keyword
+                } else {
+                    0.0
+                };
+                let score = (1.0 - keyword_weight) * similarity + keyword_weight * keyword;
+                (entry, score)
+            })
+            .collect();
+        hits.sort_by(compare_hits);
+        hits.truncate(limit);
+        for (entry, _) in &hits {
+            entry.last_accessed.touch();
+        }
+        hits
+    }
+
+    /// Pairs of distinct current entries whose cosine similarity is at least
+    /// `threshold`, each unordered pair reported once, most similar first.
+    /// Large stores only compare each entry against its nearest neighbours in
+    /// the index.
+    pub fn near_duplicates(&self, threshold: f32) -> Vec<(String, String, f32)> {
+        let mut pairs = Vec::new();
+        if let Some(index) = &self.index {
+            let embedding = |i: usize| self.vectors.row(i);
+            // Neighbourhoods aren't symmetric, so a pair may be found from
+            // either end or both; report it once, lower index first.
+            let mut seen = HashSet::new();
+            for (i, a) in self.entries.iter().enumerate() {
+                if a.is_archived() {
+                    continue;
+                }
+                for (j, score) in index.search(&embedding(i), DUPLICATE_NEIGHBORS, embedding) {
+                    let pair = (i.min(j), i.max(j));
+                    if i != j
+                        && score >= threshold
+                        && !self.entries[j].is_archived()
+                        && seen.insert(pair)
+                    {
+                        let (first, second) = (&self.entries[pair.0], &self.entries[pair.1]);
+                        pairs.push((first.id.clone(), second.id.clone(), score));
+                    }
+                }
+            }
+        } else {
+            for (i, a) in self.entries.iter().enumerate() {
+                for (j, b) in self.entries.iter().enumerate().skip(i + 1) {
+                    let (x, y) = (self.vectors.row(i), self.vectors.row(j));
+                    let score = if self.normalized {
+                        dot(&x, &y)
+                    } else {
+                        cosine_similarity(&x, &y)
+                    };
+                    if score >= threshold && !a.is_archived() && !b.is_archived() {
+                        pairs.push((a.id.clone(), b.id.clone(), score));
+                    }
+                }
+            }
+        }
+        pairs.sort_by(|a, b| b.2.total_cmp(&a.2));
+        pairs
+    }
+
+    /// The recorded model as `model@revision`, if any.
+    pub fn model_id(&self) -> Option<String> {
+        match &self.header {
+            Some(header) => Some(header.model_id()),
+            None => self.model_id.clone(),
+        }
+    }
+
+    pub fn header(&self) -> Option<&StoreHeader> {
+        self.header.as_ref()
+    }
+
+    /// Fails if the store holds embeddings made other than `header` says.
+    /// Stores older than headers are only checked for model and dimension,
+    /// and an empty store accepts anything.
+    pub fn check_header(&self, header: &StoreHeader) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let compatible = match (&self.header, &self.model_id) {
+            (Some(recorded), _) => recorded.is_compatible(header),
+            (None, Some(model_id)) => {
+                *model_id == header.model_id() && self.dimension() == header.dimension
+            }
+            (None, None) => self.dimension() == header.dimension,
+        };
+        if !compatible {
+            return Err(self.mismatch(header.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Like `check_header`, but records `header` if the store has none yet,
+    /// is empty or hasn't recorded its pooling.
+    pub fn claim_header(&mut self, header: &StoreHeader) -> Result<()> {
+        self.check_header(header)?;
+        let unpooled = self
+            .header
+            .as_ref()
+            .is_some_and(|recorded| recorded.pooling.is_none());
+        if self.header.is_none() || self.is_empty() || unpooled {
+            if self.header.as_ref() != Some(header) {
+                debug!("Recording embedding model {} in vector store", header);
+                self.dirty = true;
+            }
+            self.header = Some(header.clone());
+            self.model_id = None;
+        }
+        Ok(())
+    }
+
+    /// Checks and records a bare model id from an import older than headers.
+    fn claim_model_id(&mut self, model_id: String) -> Result<()> {
+        if self.is_empty() {
+            self.header = None;
+            self.model_id = Some(model_id);
+            return Ok(());
+        }
+        match self.model_id() {
+            Some(recorded) if recorded != model_id => Err(self.mismatch(model_id).into()),
+            Some(_) => Ok(()),
+            None => {
+                self.model_id = Some(model_id);
+                Ok(())
+            }
+        }
+    }
+
+    fn mismatch(&self, requested: String) -> VectorStoreError {
+        let store = match (&self.header, &self.model_id) {
+            (Some(header), _) => header.to_string(),
+            (None, Some(model_id)) => format!("{} ({} dimensions)", model_id, self.dimension()),
+            (None, None) => format!("an unrecorded model ({} dimensions)", self.dimension()),
+        };
+        VectorStoreError::ModelMismatch { store, requested }
+    }
+
+    /// Stores the embeddings as int8 with a scale per vector, about a quarter
+    /// of their size, and keeps quantizing new ones. Searches shortlist on the
+    /// int8 codes and rescore the shortlist against the full-precision query.
+    /// The lost precision can't be recovered.
+    pub fn quantize(&mut self) -> Result<()> {
+        if self.vectors.is_quantized() {
+            return Ok(());
+        }
+        info!("Quantizing {} embeddings to int8", self.vectors.len());
+        self.vectors.quantize();
+        self.entries_changed();
+        self.changed()
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        self.vectors.is_quantized()
+    }
+
+    /// Labels the entries of `path` get when it is digested.
+    pub fn file_labels(&self, path: &Path) -> Option<&Labels> {
+        self.file_labels.get(path)
+    }
+
+    /// Gives the entries of `path` digested from now on `labels`; saved with
+    /// the store's next change.
+    pub fn set_file_labels(&mut self, path: &Path, labels: Labels) {
+        if self.file_labels.get(path).unwrap_or(&Labels::default()) == &labels {
+            return;
+        }
+        if labels.is_empty() {
+            self.file_labels.remove(path);
+        } else {
+            self.file_labels.insert(path.to_path_buf(), labels);
+        }
+        self.dirty = true;
+    }
+
+    /// How searches score entries unless told otherwise.
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    /// Makes searches score by `metric` from now on; saved with the store's
+    /// next change.
+    pub fn set_metric(&mut self, metric: Metric) {
+        if metric != self.metric {
+            info!("Scoring vector store by {} from now on", metric);
+            self.metric = metric;
+            self.dirty = true;
+        }
+    }
+
+    /// True when all embeddings are unit length and searches score by dot product.
+    pub fn is_normalized(&self) -> bool {
+        self.normalized
+    }
+
+    /// Number of entries, archived ones included.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every entry in the order they were added, archived ones included.
+    pub fn iter(&self) -> impl Iterator<Item = &VectorEntry> {
+        self.entries.iter()
+    }
+
+    /// The entry `id`, if stored.
+    pub fn get(&self, id: &str) -> Option<&VectorEntry> {
+        self.position(id).map(|i| &self.entries[i])
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.position(id).is_some()
+    }
+
+    /// Fails unless `entry` is as long as the recorded header says, or as
+    /// the stored embeddings are for stores without one. An empty store
+    /// without a header takes any length.
+    fn check_dimension(&self, entry: &VectorEntry) -> Result<(), VectorStoreError> {
+        let expected = match &self.header {
+            Some(header) => header.dimension,
+            None if self.is_empty() => return Ok(()),
+            None => self.dimension(),
+        };
+        if entry.embedding.len() != expected {
+            return Err(VectorStoreError::DimensionMismatch {
+                id: entry.id.clone(),
+                expected,
+                found: entry.embedding.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.id == id)
+    }
+
+    /// Length of the stored embeddings; 0 while the store is empty.
+    pub fn dimension(&self) -> usize {
+        self.vectors.dim()
+    }
+
+    /// Bytes the store takes on disk: entries, embeddings, index, log and backups.
+    pub fn disk_usage(&self) -> u64 {
+        Self::files(&self.path)
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The full text of `entry`'s chunk. Entries stored with only a preview
+    /// are resolved against the version they were digested from in the
+    /// intermediate memory at `memory_dir`, falling back to the preview when
+    /// that version is gone.
+    pub async fn fetch_content(memory_dir: &Path, entry: &VectorEntry) -> Result<String> {
+        if !entry.content.is_empty() {
+            return Ok(entry.content.clone());
+        }
+        let (Some(path), Some(version)) = (&entry.source_path, entry.version) else {
+            return Ok(entry.content_preview.clone());
+        };
+        let data = match Processor::restore(memory_dir, path, version).await {
+            Ok(data) => data,
+            Err(e) => {
+                debug!(
+                    "[{}] Version {} not restorable, using preview: {:?}",
+                    entry.file_name, version, e
+                );
+                return Ok(entry.content_preview.clone());
+            }
+        };
+        let extracted = extract(path, data)
+            .wrap_err_with(|| format!("Failed to extract {} v{}", path.display(), version))?;
+        match extracted
+            .text
+            .get(entry.span.start_byte..entry.span.end_byte)
+        {
+            Some(text) => Ok(text.to_string()),
+            None => bail!(
+                "Chunk {} is outside {} v{}",
+                entry.id,
+                path.display(),
+                version
+            ),
+        }
+    }
+
+    /// The embedding of `entry`, which must be one of this store's entries,
+    /// such as a search hit.
+    pub fn embedding(&self, entry: &VectorEntry) -> Option<Cow<'_, [f32]>> {
+        let i = self.entries.element_offset(entry)?;
+        Some(self.vectors.row(i))
+    }
+
+    /// Picks `limit` of `hits` by Maximal Marginal Relevance: each pick maximizes
+    /// `lambda * score - (1 - lambda) * similarity to the closest earlier pick`,
+    /// so near-identical chunks don't crowd out the rest. Hits must be entries
+    /// of this store; they keep their own scores and are returned in pick order.
+    pub fn mmr<'a>(
+        &'a self,
+        mut hits: Vec<(&'a VectorEntry, f32)>,
+        limit: usize,
+        lambda: f32,
+    ) -> Vec<(&'a VectorEntry, f32)> {
+        let lambda = lambda.clamp(0.0, 1.0);
+        hits.sort_by(compare_hits);
+        let mut picked: Vec<(&VectorEntry, f32)> = Vec::with_capacity(limit.min(hits.len()));
+        // Highest similarity of each remaining hit to anything picked so far.
+        let mut redundancy = vec![f32::NEG_INFINITY; hits.len()];
+        let mut remaining: Vec<usize> = (0..hits.len()).collect();
+
+        while picked.len() < limit && !remaining.is_empty() {
+            let marginal = |i: usize| {
+                let penalty = if picked.is_empty() {
+                    0.0
+                } else {
+                    redundancy[i]
+                };
+                lambda * hits[i].1 - (1.0 - lambda) * penalty
+            };
+            let (slot, &best) = remaining
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| marginal(**a).total_cmp(&marginal(**b)).then(b.cmp(a)))
+                .expect("remaining is not empty");
+            remaining.remove(slot);
+            for &i in &remaining {
+                let similarity = match (self.embedding(hits[i].0), self.embedding(hits[best].0)) {
+                    (Some(a), Some(b)) => cosine_similarity(&a, &b),
+                    _ => 0.0,
+                };
+                redundancy[i] = redundancy[i].max(similarity);
+            }
+            picked.push(hits[best]);
+        }
+        picked
+    }
+}
+
+/// Orders search hits by descending score with NaN scores last, breaking ties
+/// by entry id so results are stable regardless of insertion order.
+fn compare_hits(a: &(&VectorEntry, f32), b: &(&VectorEntry, f32)) -> Ordering {
+    match (a.1.is_nan(), b.1.is_nan()) {
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        _ => b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal),
+    }
+    .then_with(|| a.0.id.cmp(&b.0.id))
+}
+
+/// Adds `hit` to `heap`, dropping the worst hit once it holds more than `limit`.
+fn push_bounded<'a>(heap: &mut BinaryHeap<RankedHit<'a>>, hit: RankedHit<'a>, limit: usize) {
+    heap.push(hit);
+    if heap.len() > limit {
+        heap.pop();
+    }
+}
+
+/// The hits of a heap filled by `push_bounded`, best first.
+fn ranked(heap: BinaryHeap<RankedHit<'_>>) -> Vec<(usize, f32)> {
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|hit| (hit.0, hit.1.1))
+        .collect()
+}
+
+/// Heap adapter ordering hits by `compare_hits`, so the greatest is the lowest ranked.
+/// Carries the entry's index alongside the hit.
+struct RankedHit<'a>(usize, (&'a VectorEntry, f32));
+
+impl PartialEq for RankedHit<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedHit<'_> {}
+
+impl PartialOrd for RankedHit<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RankedHit<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_hits(&self.1, &other.1)
+    }
+}
+
+/// What `search_files` groups hits by: the source path, or the file name of
+/// entries without one.
+fn file_key(entry: &VectorEntry) -> &OsStr {
+    match &entry.source_path {
+        Some(path) => path.as_os_str(),
+        None => OsStr::new(&entry.file_name),
+    }
+}
+
+/// The text an entry is found by in keyword search.
+fn keyword_text(entry: &VectorEntry) -> &str {
+    entry.text()
+}
+
+/// Replaces `path` with `data` so that a crash leaves either the old file or
+/// the new one, never a mix.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
+}
+
+/// Where the previous save of `path` is kept, e.g. `vectors.bin.bak`.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+/// Makes the backup of `path` a copy of it as it is now, by hardlinking
+/// where possible. Does nothing if `path` doesn't exist yet.
+fn rotate_backup(path: &Path) -> std::io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let backup = backup_path(path);
+    match std::fs::remove_file(&backup) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    std::fs::hard_link(path, &backup).or_else(|_| std::fs::copy(path, &backup).map(|_| ()))
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Dot product over the common prefix of `a` and `b`.
+/// Summed in `DOT_LANES` independent lanes, which the compiler turns into
+/// SIMD multiply-adds; a single running sum can't be reordered that way.
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let len = a.len().min(b.len());
+    let (a, b) = (
+        a[..len].chunks_exact(DOT_LANES),
+        b[..len].chunks_exact(DOT_LANES),
+    );
+    let tail: f32 = a
+        .remainder()
+        .iter()
+        .zip(b.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    let mut lanes = [0.0f32; DOT_LANES];
+    for (x, y) in a.zip(b) {
+        for lane in 0..DOT_LANES {
+            lanes[lane] += x[lane] * y[lane];
+        }
+    }
+    lanes.iter().sum::<f32>() + tail
+}
+
+/// Scales `v` to unit length in place. Zero vectors are left untouched.
+pub fn normalize(v: &mut [f32]) {
+    let norm = dot(v, v).sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Whether `v` has unit length, allowing for `f32` rounding.
+pub fn is_unit(v: &[f32]) -> bool {
+    (dot(v, v) - 1.0).abs() < UNIT_TOLERANCE
+}
+
+/// Cosine similarity of `a` and `b`. Returns 0.0 when either vector has zero
+/// magnitude instead of producing NaN.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norm_a = dot(a, a).sqrt();
+    let norm_b = dot(b, b).sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot(a, b) / (norm_a * norm_b)
+}
+
+/// Embeddings are written as a list of numbers in human-readable formats and
+/// as little-endian `f32` bytes otherwise. Either form is accepted when reading.
+mod embedding_format {
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(embedding: &[f32], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.collect_seq(embedding);
+        }
+        let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+        deserializer.deserialize_any(EmbeddingVisitor)
+    }
+
+    struct EmbeddingVisitor;
+
+    impl<'de> Visitor<'de> for EmbeddingVisitor {
+        type Value = Vec<f32>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a list of floats or a little-endian f32 byte block")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut embedding = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(x) = seq.next_element()? {
+                embedding.push(x);
+            }
+            Ok(embedding)
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            if !bytes.len().is_multiple_of(4) {
+                return Err(E::invalid_length(bytes.len(), &self));
+            }
+            Ok(bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, embedding: Vec<f32>) -> VectorEntry {
+        VectorEntry {
+            id: id.to_string(),
+            embedding,
+            ..Default::default()
+        }
+    }
+
+    fn store(dir: &tempfile::TempDir) -> VectorStore {
+        VectorStore::load(dir.path().join("vectors.bin")).unwrap()
+    }
+
+    fn header(model: &str, dimension: usize) -> StoreHeader {
+        StoreHeader {
+            schema_version: SCHEMA_VERSION,
+            model: model.to_string(),
+            revision: None,
+            dimension,
+            normalized: false,
+            pooling: None,
+        }
+    }
+
+    #[test]
+    fn add_rejects_a_zero_vector() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let error = store.add(entry("zero", vec![0.0; 3])).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VectorStoreError>(),
+            Some(VectorStoreError::ZeroVector(id)) if id == "zero"
+        ));
+        assert!(store.is_empty());
+        store.add(entry("unit", vec![1.0, 0.0, 0.0])).unwrap();
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn add_rejects_another_dimension() {
+        let dir = tempfile::tempdir().unwrap();
+        let mismatch = |store: &mut VectorStore, id: &str, embedding: Vec<f32>| {
+            let error = store.add(entry(id, embedding)).unwrap_err();
+            matches!(
+                error.downcast_ref::<VectorStoreError>(),
+                Some(VectorStoreError::DimensionMismatch {
+                    expected: 3,
+                    found: 2,
+                    ..
+                })
+            )
+        };
+
+        let mut claimed = store(&dir);
+        claimed.claim_header(&header("model-a", 3)).unwrap();
+        assert!(mismatch(&mut claimed, "a", vec![1.0, 0.0]));
+        assert!(claimed.is_empty());
+
+        let mut headerless = VectorStore::load(dir.path().join("other.bin")).unwrap();
+        headerless.add(entry("a", vec![1.0, 0.0, 0.0])).unwrap();
+        assert!(mismatch(&mut headerless, "b", vec![1.0, 0.0]));
+        assert_eq!(headerless.len(), 1);
+    }
+
+    #[test]
+    fn near_duplicates_reports_each_pair_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        for (id, embedding) in [
+            ("a", vec![1.0, 0.0, 0.0]),
+            ("b", vec![0.0, 1.0, 0.0]),
+            ("a-copy", vec![0.99, 0.01, 0.0]),
+            ("c", vec![0.0, 0.0, 1.0]),
+        ] {
+            store.add(entry(id, embedding)).unwrap();
+        }
+        let pairs = store.near_duplicates(0.95);
+        assert_eq!(pairs.len(), 1, "{pairs:?}");
+        let (first, second, score) = &pairs[0];
+        assert_eq!((first.as_str(), second.as_str()), ("a", "a-copy"));
+        assert!(*score > 0.99);
+    }
+
+    #[test]
+    fn indexed_near_duplicates_are_found_from_either_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let at = |angle: f32| vec![angle.cos(), angle.sin(), 0.0];
+        // "lone" is nearest to "near", but more than DUPLICATE_NEIGHBORS
+        // entries around "near" are nearer still, so only "lone" finds the pair.
+        let mut rows = vec![("near".to_string(), at(0.01))];
+        for i in 0..DUPLICATE_NEIGHBORS + 8 {
+            rows.push((format!("cluster{i}"), at(i as f32 * 1e-4)));
+        }
+        rows.push(("lone".to_string(), at(0.2)));
+        rows.push(("other".to_string(), vec![0.0, 0.0, 1.0]));
+        store.entries = rows.iter().map(|(id, _)| entry(id, Vec::new())).collect();
+        store.vectors = EmbeddingMatrix::from_rows(rows.iter().map(|(_, v)| v.as_slice())).unwrap();
+        store.entries_changed();
+        // Small stores aren't indexed on their own.
+        let index = HnswIndex::build(store.vectors.len(), |i| store.vectors.row(i));
+        store.index = Some(index);
+
+        let pairs = store.near_duplicates(0.975);
+        assert!(
+            pairs
+                .iter()
+                .any(|(a, b, _)| (a.as_str(), b.as_str()) == ("near", "lone")),
+            "{pairs:?}"
+        );
+        let unique: HashSet<_> = pairs.iter().map(|(a, b, _)| (a, b)).collect();
+        assert_eq!(unique.len(), pairs.len());
+        assert!(pairs.iter().all(|(a, b, _)| a != b && b != "other"));
+    }
+
+    #[test]
+    fn search_applies_the_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        for (id, path, digested_at, embedding) in [
+            ("lib", "src/lib.rs", 10, vec![1.0, 0.0]),
+            ("main", "src/main.rs", 20, vec![0.9, 0.1]),
+            ("readme", "README.md", 30, vec![1.0, 0.0]),
+            ("far", "src/far.rs", 40, vec![0.0, 1.0]),
+        ] {
+            store
+                .add(VectorEntry {
+                    source_path: Some(PathBuf::from(path)),
+                    digested_at,
+                    ..entry(id, embedding)
+                })
+                .unwrap();
+        }
+        let ids = |filter: SearchFilter| -> Vec<String> {
+            let hits = store.search(&[1.0, 0.0], 10, &filter);
+            hits.into_iter().map(|(e, _)| e.id.clone()).collect()
+        };
+
+        let rust = SearchFilter::new().with_paths(&["src/**/*.rs"]).unwrap();
+        assert_eq!(ids(rust), ["lib", "main", "far"]);
+        let recent = SearchFilter::new().digested_between(Some(20), Some(40));
+        assert_eq!(ids(recent), ["readme", "main"]);
+    }
+
+    /// Distinct directions, far enough apart that nearest neighbours are unambiguous.
+    fn spread(i: usize) -> Vec<f32> {
+        let angle = i as f32 * std::f32::consts::PI / INDEX_MIN_ENTRIES as f32;
+        let tilt = (i % 7) as f32;
+        vec![angle.cos(), angle.sin(), tilt, 7.0 - tilt]
+    }
+
+    #[test]
+    fn index_is_saved_and_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        store.entries = (0..INDEX_MIN_ENTRIES)
+            .map(|i| entry(&format!("e{i}"), Vec::new()))
+            .collect();
+        let rows: Vec<_> = (0..INDEX_MIN_ENTRIES).map(spread).collect();
+        store.vectors = EmbeddingMatrix::from_rows(rows.iter().map(Vec::as_slice)).unwrap();
+        store.entries_changed();
+        store.save().unwrap();
+        assert!(store.index_path().exists());
+
+        let loaded = VectorStore::load(store.path()).unwrap();
+        assert!(loaded.index.is_some());
+        let ids = |hits: Vec<(&VectorEntry, f32)>| {
+            hits.into_iter()
+                .map(|(entry, _)| entry.id.clone())
+                .collect::<Vec<_>>()
+        };
+        let query = spread(100);
+        assert_eq!(
+            ids(loaded.search(&query, 5, &SearchFilter::new())),
+            ids(store.search(&query, 5, &SearchFilter::new()))
+        );
+        assert_eq!(
+            ids(loaded.search(&query, 1, &SearchFilter::new())),
+            ["e100"]
+        );
+    }
+
+    #[test]
+    fn binary_store_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let embedding = vec![0.1, -2.5, f32::MIN_POSITIVE, 1e30];
+        store.add(entry("a", embedding.clone())).unwrap();
+        store.add(entry("b", vec![1.0, 0.0, 0.0, 0.0])).unwrap();
+
+        let data = std::fs::read(store.path()).unwrap();
+        assert!(crypt::unframe(&data).starts_with(STORE_MAGIC));
+        let loaded = VectorStore::load(store.path()).unwrap();
+        let ids: Vec<_> = loaded.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(loaded.embedding(&loaded.entries[0]).unwrap(), embedding);
+    }
+
+    #[test]
+    fn metric_is_saved_and_scores_searches() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        store.set_metric(Metric::Dot);
+        store.add(entry("near", vec![1.0, 0.0])).unwrap();
+        store.add(entry("long", vec![3.0, 1.0])).unwrap();
+
+        let loaded = VectorStore::load(store.path()).unwrap();
+        assert_eq!(loaded.metric(), Metric::Dot);
+        let ranked = |metric| {
+            let hits = loaded.search_by(&[1.0, 0.0], 2, &SearchFilter::default(), metric);
+            hits.iter()
+                .map(|(entry, _)| entry.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ranked(loaded.metric()), ["long", "near"]);
+        assert_eq!(ranked(Metric::Cosine), ["near", "long"]);
+        assert_eq!(ranked(Metric::Euclidean), ["near", "long"]);
+        let hits = loaded.search_by(&[1.0, 0.0], 1, &SearchFilter::default(), Metric::Euclidean);
+        assert_eq!(hits[0].1, 1.0);
+    }
+
+    #[test]
+    fn labels_are_saved_filter_searches_and_are_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let labels: Labels = ["draft", "project=foo"]
+            .iter()
+            .map(|label| label.parse::<Label>().unwrap())
+            .collect();
+        let (tagged, other) = (PathBuf::from("/notes/a.md"), PathBuf::from("/notes/b.md"));
+        store.set_file_labels(&tagged, labels.clone());
+        store
+            .add(VectorEntry {
+                source_path: Some(tagged.clone()),
+                tags: labels.tags.clone(),
+                metadata: labels.metadata.clone(),
+                ..entry("a", vec![1.0, 0.0])
+            })
+            .unwrap();
+        store
+            .add(VectorEntry {
+                source_path: Some(other.clone()),
+                ..entry("b", vec![1.0, 0.0])
+            })
+            .unwrap();
+
+        let mut loaded = VectorStore::load(store.path()).unwrap();
+        assert_eq!(loaded.file_labels(&tagged), Some(&labels));
+        let wanted = ["project=foo".parse::<Label>().unwrap()]
+            .into_iter()
+            .collect();
+        let hits = loaded.search(&[1.0, 0.0], 10, &SearchFilter::new().with_labels(wanted));
+        let ids: Vec<_> = hits.iter().map(|(entry, _)| entry.id.as_str()).collect();
+        assert_eq!(ids, ["a"]);
+
+        // Labels of a path without entries are forgotten on disk too.
+        loaded.delete_by_path(&tagged).unwrap();
+        assert_eq!(
+            loaded
+                .delete_by_paths(std::slice::from_ref(&tagged))
+                .unwrap(),
+            0
+        );
+        let reloaded = VectorStore::load(store.path()).unwrap();
+        assert_eq!(reloaded.file_labels(&tagged), None);
+        assert_eq!(reloaded.len(), 1);
+    }
+
+    #[test]
+    fn logged_changes_are_replayed_after_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        store.add(entry("a", vec![1.0, 0.0])).unwrap();
+        let saved = std::fs::read(store.path()).unwrap();
+        store.add(entry("b", vec![0.0, 1.0])).unwrap();
+        store.add(entry("c", vec![1.0, 1.0])).unwrap();
+        store.delete("b").unwrap();
+        store
+            .upsert(VectorEntry {
+                content: "replaced".to_string(),
+                ..entry("a", vec![2.0, 0.0])
+            })
+            .unwrap();
+        // Only the log was written since the first save; the process dies
+        // here, in the middle of appending one more record.
+        assert_eq!(std::fs::read(store.path()).unwrap(), saved);
+        let wal = store.wal_path();
+        drop(store);
+        let mut log = std::fs::OpenOptions::new().append(true).open(&wal).unwrap();
+        std::io::Write::write_all(&mut log, &[200, 0, 0, 0, 1, 2, 3]).unwrap();
+
+        let mut loaded = VectorStore::load(dir.path().join("vectors.bin")).unwrap();
+        let ids: Vec<_> = loaded.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(loaded.get("a").unwrap().content, "replaced");
+        assert_eq!(
+            loaded.embedding(loaded.get("a").unwrap()).unwrap(),
+            vec![2.0, 0.0]
+        );
+        assert_eq!(
+            loaded.search(&[0.0, 1.0], 1, &SearchFilter::default())[0]
+                .0
+                .id,
+            "c"
+        );
+
+        // The torn record is cut off, so later changes replay after the rest.
+        loaded.add(entry("d", vec![0.0, 1.0])).unwrap();
+        drop(loaded);
+        let reloaded = VectorStore::load(dir.path().join("vectors.bin")).unwrap();
+        let ids: Vec<_> = reloaded.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, ["a", "c", "d"]);
+    }
+
+    #[test]
+    fn a_log_from_an_earlier_save_is_not_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        store.add(entry("a", vec![1.0, 0.0])).unwrap();
+        store.add(entry("b", vec![0.0, 1.0])).unwrap();
+        let wal = store.wal_path();
+        let stale = std::fs::read(&wal).unwrap();
+        store.save().unwrap();
+        std::fs::write(&wal, stale).unwrap();
+
+        let loaded = VectorStore::load(dir.path().join("vectors.bin")).unwrap();
+        let ids: Vec<_> = loaded.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    #[test]
+    fn sqlite_stores_need_the_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = VectorStore::load(dir.path().join("vectors.db")).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VectorStoreError>(),
+            Some(VectorStoreError::NoSqlite(_))
+        ));
+    }
+
+    #[test]
+    fn json_store_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = VectorStore {
+            entries: vec![entry("a", vec![0.25, 0.5, 1.0])],
+            ..Default::default()
+        };
+        let json_path = dir.path().join("vectors.json");
+        std::fs::write(&json_path, serde_json::to_vec(&legacy).unwrap()).unwrap();
+
+        let store = store(&dir);
+        assert!(!json_path.exists());
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            store.embedding(&store.entries[0]).unwrap().as_ref(),
+            [0.25, 0.5, 1.0]
+        );
+        let reloaded = VectorStore::load(store.path()).unwrap();
+        assert_eq!(
+            reloaded.embedding(&reloaded.entries[0]).unwrap().as_ref(),
+            [0.25, 0.5, 1.0]
+        );
+    }
+
+    #[test]
+    fn exports_import_by_merge_strategy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut source = store(&dir);
+        source.claim_header(&header("model-a", 2)).unwrap();
+        source.add(entry("a", vec![1.0, 0.0])).unwrap();
+        source.add(entry("b", vec![0.0, 1.0])).unwrap();
+        let export = dir.path().join("export.jsonl");
+        assert_eq!(source.export_jsonl(&export).unwrap(), 2);
+
+        let mut target = VectorStore::load(dir.path().join("target.bin")).unwrap();
+        target.add(entry("a", vec![0.5, 0.5])).unwrap();
+        target.add(entry("c", vec![1.0, 1.0])).unwrap();
+        let skipped = target.import_jsonl(&export, MergeStrategy::Skip).unwrap();
+        assert_eq!(
+            skipped,
+            ImportSummary {
+                added: 1,
+                replaced: 0,
+                skipped: 1
+            }
+        );
+        let embedding = |store: &VectorStore, id: &str| {
+            let entry = store.entries.iter().find(|e| e.id == id).unwrap();
+            store.embedding(entry).unwrap().to_vec()
+        };
+        assert_ne!(embedding(&target, "a"), embedding(&source, "a"));
+
+        let overwritten = target
+            .import_jsonl(&export, MergeStrategy::Overwrite)
+            .unwrap();
+        assert_eq!(overwritten.replaced, 2);
+        assert_eq!(embedding(&target, "a"), embedding(&source, "a"));
+        assert_eq!(target.len(), 3);
+
+        target
+            .import_jsonl(&export, MergeStrategy::Replace)
+            .unwrap();
+        let reloaded = VectorStore::load(dir.path().join("target.bin")).unwrap();
+        let ids: Vec<_> = reloaded.entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(reloaded.header(), Some(&header("model-a", 2)));
+
+        let mut other = VectorStore::load(dir.path().join("other.bin")).unwrap();
+        other.claim_header(&header("model-b", 2)).unwrap();
+        other.add(entry("b", vec![1.0, 1.0])).unwrap();
+        assert!(other.import_jsonl(&export, MergeStrategy::Skip).is_err());
+        assert_eq!(other.len(), 1);
+    }
+
+    #[test]
+    fn header_is_saved_and_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let recorded = StoreHeader {
+            revision: Some("v2".to_string()),
+            normalized: true,
+            ..header("model-a", 3)
+        };
+        store.claim_header(&recorded).unwrap();
+        store.add(entry("a", vec![1.0, 0.0, 0.0])).unwrap();
+        store.save().unwrap();
+
+        let mut reloaded = VectorStore::load(dir.path().join("vectors.bin")).unwrap();
+        assert_eq!(reloaded.header(), Some(&recorded));
+        assert_eq!(reloaded.model_id().as_deref(), Some("model-a@v2"));
+        reloaded.check_header(&recorded).unwrap();
+        for other in [
+            StoreHeader {
+                normalized: false,
+                ..recorded.clone()
+            },
+            StoreHeader {
+                revision: None,
+                ..recorded.clone()
+            },
+            StoreHeader {
+                dimension: 4,
+                ..recorded.clone()
+            },
+        ] {
+            let error = reloaded.claim_header(&other).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<VectorStoreError>(),
+                Some(VectorStoreError::ModelMismatch { .. })
+            ));
+        }
+
+        // Pooling is recorded by the first digest that knows it, then checked.
+        let pooled = StoreHeader {
+            pooling: Some(Pooling::Cls),
+            ..recorded.clone()
+        };
+        reloaded.claim_header(&pooled).unwrap();
+        assert_eq!(reloaded.header(), Some(&pooled));
+        let mean = StoreHeader {
+            pooling: Some(Pooling::Mean),
+            ..recorded.clone()
+        };
+        let error = reloaded.claim_header(&mean).unwrap_err();
+        assert!(error.to_string().contains("cls pooling"), "{error}");
+        reloaded.save().unwrap();
+        let reloaded_pooled = VectorStore::load(dir.path().join("vectors.bin")).unwrap();
+        assert_eq!(reloaded_pooled.header(), Some(&pooled));
+
+        // A store written by a newer build is refused, not misread.
+        reloaded.header = Some(StoreHeader {
+            schema_version: SCHEMA_VERSION + 1,
+            ..recorded
+        });
+        reloaded.dirty = true;
+        reloaded.save().unwrap();
+        let error = VectorStore::load(dir.path().join("vectors.bin")).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VectorStoreError>(),
+            Some(VectorStoreError::SchemaVersion { found, .. }) if *found == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn embeddings_are_mapped_back_after_a_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        store.add(entry("a", vec![1.0, 0.0, 0.0])).unwrap();
+        store.add(entry("b", vec![0.0, 2.0, 0.0])).unwrap();
+        store.add(entry("c", vec![0.0, 0.0, 3.0])).unwrap();
+        assert!(store.delete("b").unwrap());
+        store.save().unwrap();
+
+        let loaded = VectorStore::load(store.path()).unwrap();
+        assert!(loaded.entries.iter().all(|e| e.embedding.is_empty()));
+        let rows: Vec<_> = loaded
+            .iter()
+            .map(|e| (e.id.as_str(), loaded.embedding(e).unwrap().into_owned()))
+            .collect();
+        assert_eq!(
+            rows,
+            [("a", vec![1.0, 0.0, 0.0]), ("c", vec![0.0, 0.0, 3.0])]
+        );
+    }
+
+    #[test]
+    fn quantized_store_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        // Distinct directions for `i` below 17, far enough apart to survive quantization.
+        let vector = |i: usize| -> Vec<f32> {
+            (0..8)
+                .map(|d| ((i * 7 + d * 13 + i * d) % 17) as f32 - 8.0)
+                .collect()
+        };
+        let embeddings: Vec<Vec<f32>> = (0..16).map(vector).collect();
+        for (i, embedding) in embeddings.iter().enumerate() {
+            store
+                .add(entry(&format!("e{i}"), embedding.clone()))
+                .unwrap();
+        }
+        store.quantize().unwrap();
+        store.add(entry("late", vector(16))).unwrap();
+        store.save().unwrap();
+
+        let loaded = VectorStore::load(store.path()).unwrap();
+        assert!(loaded.is_quantized());
+        for (entry, original) in loaded.iter().zip(&embeddings) {
+            let restored = loaded.embedding(entry).unwrap();
+            let error = restored
+                .iter()
+                .zip(original)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            // At most one int8 step of the largest component, 8.0 here.
+            assert!(error <= 8.0 / 127.0, "{}: off by {error}", entry.id);
+        }
+        for i in [3, 10] {
+            let hits = loaded.search(&vector(i), 1, &SearchFilter::new());
+            assert_eq!(hits[0].0.id, format!("e{i}"));
+        }
+        assert_eq!(
+            loaded.search(&vector(16), 1, &SearchFilter::new())[0].0.id,
+            "late"
+        );
+    }
+
+    #[tokio::test]
+    async fn content_is_fetched_from_the_digested_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory_dir = dir.path().join("memory");
+        let path = dir.path().canonicalize().unwrap().join("notes.txt");
+        let config = crate::process::ProcessorConfig::new(&memory_dir);
+        for content in ["alpha beta gamma\n", "changed entirely\n"] {
+            std::fs::write(&path, content).unwrap();
+            let paths = std::collections::BTreeSet::from([path.clone()]);
+            let cancel = crate::shutdown::CancellationToken::new();
+            Processor::process_all(&paths, crate::process::ProcessMode::Full, &config, &cancel)
+                .await
+                .unwrap();
+        }
+
+        let old = VectorEntry {
+            source_path: Some(path.clone()),
+            version: Some(1),
+            content_preview: "alpha…".to_string(),
+            span: ChunkSpan {
+                start_byte: 6,
+                end_byte: 10,
+                ..Default::default()
+            },
+            ..entry("old", vec![1.0])
+        };
+        let fetched = VectorStore::fetch_content(&memory_dir, &old).await.unwrap();
+        assert_eq!(fetched, "beta");
+
+        let gone = VectorEntry {
+            version: Some(9),
+            ..old.clone()
+        };
+        let fetched = VectorStore::fetch_content(&memory_dir, &gone)
+            .await
+            .unwrap();
+        assert_eq!(fetched, "alpha…");
+
+        let stored = VectorEntry {
+            content: "stored text".to_string(),
+            ..old
+        };
+        let fetched = VectorStore::fetch_content(&memory_dir, &stored)
+            .await
+            .unwrap();
+        assert_eq!(fetched, "stored text");
+    }
+
+    #[test]
+    fn a_corrupt_store_falls_back_to_its_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        // Each save keeps the previous one as the backup.
+        store.add(entry("a", vec![1.0, 0.0])).unwrap();
+        store.save().unwrap();
+        store.add(entry("b", vec![0.0, 1.0])).unwrap();
+        store.save().unwrap();
+        let ids = |store: &VectorStore| -> Vec<String> {
+            store.iter().map(|entry| entry.id.clone()).collect()
+        };
+
+        // A save torn halfway leaves the previous one to fall back to.
+        std::fs::write(store.path(), b"OUROVEC2 torn").unwrap();
+        let mut recovered = VectorStore::load(store.path()).unwrap();
+        assert_eq!(ids(&recovered), ["a"]);
+        assert_eq!(recovered.path(), store.path());
+        recovered.save().unwrap();
+        assert_eq!(ids(&VectorStore::load(store.path()).unwrap()), ["a"]);
+
+        std::fs::remove_file(store.path()).unwrap();
+        assert_eq!(ids(&VectorStore::load(store.path()).unwrap()), ["a"]);
+    }
+
+    #[test]
+    fn low_scores_are_dropped_before_the_page_is_cut() {
+        use crate::digest::tests::ConstantEmbedder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        // `near` ranks second but is so like `best` that diversifying would
+        // rather pick `far`, which is under the minimum score.
+        store.add(entry("best", vec![1.0, 0.0, 0.0])).unwrap();
+        store.add(entry("near", vec![0.99, 0.14, 0.0])).unwrap();
+        store.add(entry("far", vec![0.2, 0.0, 0.98])).unwrap();
+        let digester = Digester::from_embedder(Box::new(ConstantEmbedder));
+        let options = SearchOptions {
+            min_score: Some(0.5),
+            limit: 2,
+            keyword_weight: 0.0,
+            mmr_lambda: Some(0.3),
+            ..Default::default()
+        };
+        let hits = store.search_text(&digester, "query", &options).unwrap();
+        let ids: Vec<_> = hits.iter().map(|(entry, _)| entry.id.as_str()).collect();
+        assert_eq!(ids, ["best", "near"]);
+    }
+}
@@ -0,0 +1,345 @@
+use eyre::{Context, Result, bail};
+use log::debug;
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{Labels, StoreBackend, StoreChange, VectorEntry, VectorStore, VectorStoreError, crypt};
+use crate::embeddings::EmbeddingMatrix;
+
+/// Tables of a store, created on first open. `seq` keeps the entries in
+/// store order; the columns besides `entry` and `embedding` repeat fields of
+/// `entry` so they can be queried. `file_history` lists every version of
+/// every file the store has entries of.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    seq INTEGER PRIMARY KEY,
+    id TEXT NOT NULL,
+    source_path TEXT,
+    file_name TEXT NOT NULL,
+    alias TEXT,
+    version INTEGER,
+    stored_at TEXT,
+    digested_at INTEGER NOT NULL,
+    archived INTEGER,
+    content_hash TEXT NOT NULL,
+    entry TEXT NOT NULL,
+    embedding BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS entries_id ON entries (id);
+CREATE INDEX IF NOT EXISTS entries_source_path ON entries (source_path);
+CREATE TABLE IF NOT EXISTS file_labels (
+    path TEXT PRIMARY KEY,
+    tags TEXT NOT NULL,
+    metadata TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE VIEW IF NOT EXISTS file_history AS
+    SELECT source_path, file_name, alias, version, stored_at,
+           MIN(digested_at) AS digested_at, MAX(archived) AS archived,
+           COUNT(*) AS chunks
+    FROM entries
+    GROUP BY source_path, file_name, alias, version
+    ORDER BY source_path, version;
+";
+
+/// A store kept in an SQLite database, each change written in a transaction
+/// of its own.
+#[derive(Debug)]
+pub(super) struct SqliteBackend {
+    /// Only ever used through `&mut self`; the lock makes the backend `Sync`.
+    conn: Mutex<Connection>,
+    /// `seq` of each entry's row, by position.
+    rows: Vec<i64>,
+}
+
+impl SqliteBackend {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub(super) fn open(path: &Path) -> Result<Self> {
+        if crypt::is_enabled() {
+            return Err(VectorStoreError::SqliteEncryption.into());
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("Failed to open vector store {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)
+            .wrap_err_with(|| format!("Failed to create tables in {}", path.display()))?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            rows: Vec::new(),
+        })
+    }
+
+    fn conn(&mut self) -> &mut Connection {
+        self.conn.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StoreBackend for SqliteBackend {
+    fn save(&mut self, store: &VectorStore) -> Result<()> {
+        let tx = self.conn().transaction()?;
+        tx.execute_batch("DELETE FROM entries; DELETE FROM file_labels; DELETE FROM meta;")?;
+        let mut rows = Vec::with_capacity(store.entries.len());
+        for (i, entry) in store.entries.iter().enumerate() {
+            rows.push(insert_entry(&tx, entry, &store.vectors.row(i))?);
+        }
+        for (path, labels) in &store.file_labels {
+            tx.execute(
+                "INSERT INTO file_labels (path, tags, metadata) VALUES (?1, ?2, ?3)",
+                params![
+                    path.to_string_lossy(),
+                    serde_json::to_string(&labels.tags)?,
+                    serde_json::to_string(&labels.metadata)?
+                ],
+            )?;
+        }
+        set_meta(&tx, "header", &store.header)?;
+        set_meta(&tx, "model_id", &store.model_id)?;
+        set_meta(&tx, "normalized", &store.normalized)?;
+        set_meta(&tx, "metric", &store.metric)?;
+        set_meta(&tx, "quantized", &store.vectors.is_quantized())?;
+        tx.commit()
+            .wrap_err_with(|| format!("Failed to write vector store {}", store.path.display()))?;
+        self.rows = rows;
+        Ok(())
+    }
+
+    fn record(&mut self, store: &VectorStore, change: &StoreChange) -> Result<()> {
+        let mut rows = std::mem::take(&mut self.rows);
+        let tx = self.conn().transaction()?;
+        match change {
+            StoreChange::Add(entry) => {
+                let i = store.entries.len() - 1;
+                rows.push(insert_entry(&tx, entry, &store.vectors.row(i))?);
+            }
+            StoreChange::Upsert(entry) => {
+                let Some(i) = store.position(&entry.id) else {
+                    bail!("Upserted entry {} isn't in the store", entry.id);
+                };
+                if i < rows.len() {
+                    tx.execute("DELETE FROM entries WHERE seq = ?1", [rows[i]])?;
+                    // Takes the old row's place, keeping the entries in order.
+                    let seq = insert_entry(&tx, entry, &store.vectors.row(i))?;
+                    tx.execute("UPDATE entries SET seq = ?1 WHERE seq = ?2", [rows[i], seq])?;
+                } else {
+                    rows.push(insert_entry(&tx, entry, &store.vectors.row(i))?);
+                }
+            }
+            StoreChange::Delete(positions) => {
+                let mut keep = vec![true; rows.len()];
+                for &i in positions {
+                    tx.execute("DELETE FROM entries WHERE seq = ?1", [rows[i]])?;
+                    keep[i] = false;
+                }
+                let mut keep = keep.into_iter();
+                rows.retain(|_| keep.next().unwrap_or(true));
+            }
+            StoreChange::Archive { positions, at } => {
+                for &i in positions {
+                    tx.execute(
+                        "UPDATE entries SET archived = ?1, entry = json_set(entry, '$.archived', ?1)
+                         WHERE seq = ?2",
+                        params![*at as i64, rows[i]],
+                    )?;
+                }
+            }
+        }
+        tx.commit()?;
+        self.rows = rows;
+        Ok(())
+    }
+
+    /// Every change was committed as it was made, so there is nothing to sync
+    /// or compact.
+    fn sync(&mut self, _store: &VectorStore) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Reads the store kept in the database at `path`, empty if it was just
+/// created.
+pub(super) fn load(path: PathBuf) -> Result<VectorStore> {
+    let mut backend = SqliteBackend::open(&path)?;
+    let parse_error = || format!("Failed to parse vector store {}", path.display());
+    let mut store = VectorStore::default();
+    let conn = backend.conn();
+    let mut embeddings = Vec::new();
+    let mut rows = Vec::new();
+    {
+        let mut query = conn.prepare("SELECT seq, entry, embedding FROM entries ORDER BY seq")?;
+        let mut results = query.query([])?;
+        while let Some(row) = results.next()? {
+            rows.push(row.get::<_, i64>(0)?);
+            let entry: VectorEntry =
+                serde_json::from_str(&row.get::<_, String>(1)?).wrap_err_with(parse_error)?;
+            store.entries.push(entry);
+            embeddings.push(from_blob(&row.get::<_, Vec<u8>>(2)?));
+        }
+        let mut query = conn.prepare("SELECT path, tags, metadata FROM file_labels")?;
+        let mut results = query.query([])?;
+        while let Some(row) = results.next()? {
+            let labels = Labels {
+                tags: serde_json::from_str(&row.get::<_, String>(1)?).wrap_err_with(parse_error)?,
+                metadata: serde_json::from_str(&row.get::<_, String>(2)?)
+                    .wrap_err_with(parse_error)?,
+            };
+            store
+                .file_labels
+                .insert(PathBuf::from(row.get::<_, String>(0)?), labels);
+        }
+    }
+    store.header = get_meta(conn, "header")?.flatten();
+    store.model_id = get_meta(conn, "model_id")?.flatten();
+    store.normalized = get_meta(conn, "normalized")?.unwrap_or_default();
+    store.metric = get_meta(conn, "metric")?.unwrap_or_default();
+    store.vectors = EmbeddingMatrix::from_rows(embeddings.iter().map(Vec::as_slice))
+        .wrap_err_with(parse_error)?;
+    if get_meta(conn, "quantized")?.unwrap_or(false) {
+        store.vectors.quantize();
+    }
+    debug!("Read {} vector entries from {}", rows.len(), path.display());
+    store.path = path;
+    backend.rows = rows;
+    store.rebuild_keywords();
+    store.backend = Some(Box::new(backend));
+    Ok(store)
+}
+
+/// Inserts `entry` with `embedding` as the last row and returns its `seq`.
+fn insert_entry(tx: &Transaction, entry: &VectorEntry, embedding: &[f32]) -> Result<i64> {
+    let mut stored = entry.clone();
+    stored.embedding = Vec::new();
+    tx.execute(
+        "INSERT INTO entries (id, source_path, file_name, alias, version, stored_at,
+             digested_at, archived, content_hash, entry, embedding)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            entry.id,
+            entry.source_path.as_deref().map(Path::to_string_lossy),
+            entry.file_name,
+            entry.alias,
+            entry.version,
+            entry.stored_at,
+            entry.digested_at as i64,
+            entry.archived.map(|at| at as i64),
+            entry.content_hash,
+            serde_json::to_string(&stored)?,
+            to_blob(embedding),
+        ],
+    )?;
+    Ok(tx.last_insert_rowid())
+}
+
+fn set_meta(tx: &Transaction, key: &str, value: &impl Serialize) -> Result<()> {
+    tx.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2)",
+        params![key, serde_json::to_string(value)?],
+    )?;
+    Ok(())
+}
+
+fn get_meta<T: DeserializeOwned>(conn: &Connection, key: &str) -> Result<Option<T>> {
+    let value: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()
+        .wrap_err_with(|| format!("Failed to parse {key} of vector store"))
+}
+
+/// An embedding as little-endian `f32`s.
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().expect("4 bytes")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_store::{Label, Metric, SCHEMA_VERSION, SearchFilter, StoreHeader};
+
+    fn entry(id: &str, path: &str, embedding: Vec<f32>) -> VectorEntry {
+        VectorEntry {
+            id: id.to_string(),
+            source_path: Some(PathBuf::from(path)),
+            content: format!("text of {id}"),
+            embedding,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn store_round_trips_through_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors.db");
+        let header = StoreHeader {
+            schema_version: SCHEMA_VERSION,
+            model: "model-a".to_string(),
+            revision: None,
+            dimension: 2,
+            normalized: false,
+            pooling: None,
+        };
+        let labels: Labels = ["draft".parse::<Label>().unwrap()].into_iter().collect();
+        let mut store = VectorStore::load(&path).unwrap();
+        store.claim_header(&header).unwrap();
+        store.set_metric(Metric::Dot);
+        store.set_file_labels(Path::new("/notes/a.md"), labels.clone());
+        for (id, path, embedding) in [
+            ("a", "/notes/a.md", vec![1.0, 0.0]),
+            ("b", "/notes/b.md", vec![0.0, 1.0]),
+            ("c", "/notes/c.md", vec![1.0, 1.0]),
+        ] {
+            store.add(entry(id, path, embedding)).unwrap();
+        }
+        store.delete("b").unwrap();
+        store
+            .upsert(VectorEntry {
+                content: "replaced".to_string(),
+                ..entry("a", "/notes/a.md", vec![2.0, 0.0])
+            })
+            .unwrap();
+        store
+            .supersede_paths(&[PathBuf::from("/notes/c.md")], true)
+            .unwrap();
+        drop(store);
+
+        let loaded = VectorStore::load(&path).unwrap();
+        let ids: Vec<_> = loaded.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, ["a", "c"]);
+        let a = loaded.get("a").unwrap();
+        assert_eq!(a.content, "replaced");
+        assert_eq!(loaded.embedding(a).unwrap(), vec![2.0, 0.0]);
+        assert!(loaded.get("c").unwrap().is_archived());
+        assert_eq!(loaded.header(), Some(&header));
+        assert_eq!(loaded.metric(), Metric::Dot);
+        assert_eq!(loaded.file_labels(Path::new("/notes/a.md")), Some(&labels));
+        let hits = loaded.search(&[1.0, 0.0], 10, &SearchFilter::default());
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].0.id, "a");
+
+        let conn = Connection::open(&path).unwrap();
+        let versions: i64 = conn
+            .query_row("SELECT COUNT(*) FROM file_history", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(versions, 2);
+    }
+}
@@ -0,0 +1,229 @@
+use eyre::{Context, Result};
+use log::{debug, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::crypt;
+
+/// Leads every log, followed by the generation of the save it continues.
+const WAL_MAGIC: &[u8] = b"OUROWAL1";
+const HEADER_LEN: usize = WAL_MAGIC.len() + 8;
+
+/// Records appended to a file since the last full save of what they change,
+/// each as a little-endian `u32` length and its CBOR, sealed when a key is
+/// installed. A log only applies on top of the save whose generation it
+/// names, so one left behind by an older save is never replayed.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    generation: u64,
+    /// Opened on the first append; until then nothing is written.
+    file: Option<File>,
+    /// Bytes of the file holding intact records, where the next one goes.
+    len: u64,
+    records: usize,
+}
+
+impl Wal {
+    /// An empty log at `path` continuing the save `generation`, removing
+    /// whatever log was there. The file is created by the first append.
+    pub fn start(path: PathBuf, generation: u64) -> Result<Self> {
+        match std::fs::remove_file(&path) {
+            Ok(()) => debug!("Removed write-ahead log {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to remove {}", path.display()));
+            }
+        }
+        Ok(Self {
+            path,
+            generation,
+            file: None,
+            len: 0,
+            records: 0,
+        })
+    }
+
+    /// Reads the log at `path` if it continues the save `generation`, and
+    /// returns its records along with the log, to be appended to after the
+    /// last intact one. A record cut short by a crash ends the log. A
+    /// missing log or one of another generation has no records and is
+    /// replaced by the first append.
+    pub fn open<T: DeserializeOwned>(path: PathBuf, generation: u64) -> Result<(Self, Vec<T>)> {
+        let mut wal = Self {
+            path,
+            generation,
+            file: None,
+            len: 0,
+            records: 0,
+        };
+        let data = match std::fs::read(&wal.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((wal, Vec::new())),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to read {}", wal.path.display()));
+            }
+        };
+        let header = data
+            .strip_prefix(WAL_MAGIC)
+            .and_then(|rest| rest.get(..8))
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().expect("8 bytes")));
+        if header != Some(generation) {
+            debug!(
+                "Ignoring write-ahead log {} left by an earlier save",
+                wal.path.display()
+            );
+            return Ok((wal, Vec::new()));
+        }
+
+        let mut records = Vec::new();
+        let mut offset = HEADER_LEN;
+        while offset < data.len() {
+            match read_record(&data[offset..]) {
+                Some((record, len)) => {
+                    records.push(record);
+                    offset += len;
+                }
+                None => {
+                    warn!(
+                        "Dropping {} bytes of an incomplete record at the end of {}",
+                        data.len() - offset,
+                        wal.path.display()
+                    );
+                    break;
+                }
+            }
+        }
+        wal.len = offset as u64;
+        wal.records = records.len();
+        Ok((wal, records))
+    }
+
+    /// Appends `record` with a single write, so a crash loses at most the
+    /// record being written. `sync` makes it survive a power loss too.
+    pub fn append<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        let mut body = Vec::new();
+        ciborium::into_writer(record, &mut body).wrap_err("Failed to serialize log record")?;
+        let body = crypt::seal(&body)?;
+        let mut frame = Vec::with_capacity(body.len() + 4);
+        frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&body);
+
+        let file = self.file()?;
+        file.write_all(&frame)
+            .wrap_err_with(|| format!("Failed to append to {}", self.path.display()))?;
+        self.len += frame.len() as u64;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Flushes appended records to disk.
+    pub fn sync(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_data()
+                .wrap_err_with(|| format!("Failed to sync {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Records in the log, replayed ones included.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// The open file, positioned after the last intact record. A log that
+    /// holds none yet is started over with a fresh header.
+    fn file(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let file = if self.len == 0 {
+                let mut file = File::create(&self.path)
+                    .wrap_err_with(|| format!("Failed to create {}", self.path.display()))?;
+                file.write_all(WAL_MAGIC)?;
+                file.write_all(&self.generation.to_le_bytes())?;
+                self.len = HEADER_LEN as u64;
+                file
+            } else {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .open(&self.path)
+                    .wrap_err_with(|| format!("Failed to open {}", self.path.display()))?;
+                file.set_len(self.len)?;
+                file.seek(SeekFrom::End(0))?;
+                file
+            };
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().expect("opened above"))
+    }
+}
+
+/// The record at the start of `data` and the bytes it takes, `None` if it is
+/// cut short or doesn't parse.
+fn read_record<T: DeserializeOwned>(data: &[u8]) -> Option<(T, usize)> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let body = data.get(4..4 + len)?;
+    let body = crypt::open(body.to_vec()).ok()?;
+    let record = ciborium::from_reader(body.as_slice()).ok()?;
+    Some((record, 4 + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(wal: &std::path::Path, generation: u64) -> Vec<String> {
+        Wal::open::<String>(wal.to_path_buf(), generation)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn records_are_read_back_up_to_a_torn_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.wal");
+        let mut wal = Wal::start(path.clone(), 7).unwrap();
+        for record in ["one", "two", "three"] {
+            wal.append(&record).unwrap();
+        }
+        wal.sync().unwrap();
+        drop(wal);
+        assert_eq!(records(&path, 7), ["one", "two", "three"]);
+
+        // A crash in the middle of the last write.
+        let len = std::fs::metadata(&path).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+        let (mut wal, read) = Wal::open::<String>(path.clone(), 7).unwrap();
+        assert_eq!(read, ["one", "two"]);
+        assert_eq!(wal.records(), 2);
+        wal.append(&"four").unwrap();
+        drop(wal);
+        assert_eq!(records(&path, 7), ["one", "two", "four"]);
+    }
+
+    #[test]
+    fn a_log_of_another_generation_is_ignored_and_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.wal");
+        let mut wal = Wal::start(path.clone(), 7).unwrap();
+        wal.append(&"old").unwrap();
+        drop(wal);
+
+        let (mut wal, read) = Wal::open::<String>(path.clone(), 8).unwrap();
+        assert!(read.is_empty());
+        wal.append(&"new").unwrap();
+        drop(wal);
+        assert_eq!(records(&path, 8), ["new"]);
+        assert!(records(&path, 7).is_empty());
+
+        Wal::start(path.clone(), 9).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,195 @@
+use eyre::{Context, Result};
+use log::{debug, info, trace, warn};
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{DebounceEventResult, new_debouncer};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::lock::{LockError, MemoryLock};
+use crate::process::{ProcessMode, ProcessSummary, Processor, ProcessorConfig};
+use crate::shutdown::CancellationToken;
+use crate::storage::FileStorage;
+
+/// How often the event loop wakes up to check for an interrupt.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Versions everything under `paths`, then keeps watching them (directories
+/// recursively) and runs the pipeline again for each file that changes. Events
+/// for the same file within `debounce` are coalesced into a single run.
+/// `storage` supplies the ignore rules and should be empty.
+///
+/// Each run holds the memory lock only while it lasts, so other commands can
+/// use memory between changes. A batch that finds memory in use is kept and
+/// retried until the lock is free.
+///
+/// `on_change` is called after every run that stored a new version, e.g. to
+/// digest the changed files, with the lock still held. Returns once `cancel`
+/// fires.
+pub async fn watch(
+    paths: &[PathBuf],
+    storage: &FileStorage,
+    config: &ProcessorConfig,
+    debounce: Duration,
+    cancel: &CancellationToken,
+    mut on_change: impl FnMut(&ProcessSummary) -> Result<()>,
+) -> Result<()> {
+    let mut initial = storage.empty_like();
+    for path in paths {
+        initial.add(path).await;
+    }
+    info!("Versioning {} files before watching", initial.len());
+    let mut pending = initial;
+    if run(&pending, config, cancel, &mut on_change).await {
+        pending = storage.empty_like();
+    }
+
+    let memory_dir =
+        std::fs::canonicalize(&config.memory_dir).unwrap_or_else(|_| config.memory_dir.clone());
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(debounce, move |result: DebounceEventResult| {
+        // The receiver only goes away once watching has stopped.
+        let _ = tx.send(result);
+    })
+    .wrap_err("Failed to start file watcher")?;
+
+    let mut watched = 0;
+    for path in paths {
+        let root = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        match debouncer.watcher().watch(&root, RecursiveMode::Recursive) {
+            Ok(()) => watched += 1,
+            Err(e) => warn!("Failed to watch {}: {}", root.display(), e),
+        }
+    }
+    if watched == 0 {
+        warn!("Nothing to watch");
+        return Ok(());
+    }
+    info!(
+        "Watching {} paths for changes. Press Ctrl-C to stop.",
+        watched
+    );
+
+    let mut waiting = false;
+    while !cancel.is_cancelled() {
+        let events = match tokio::time::timeout(POLL_INTERVAL, rx.recv()).await {
+            // Nothing new, but a batch may be waiting for the lock.
+            Err(_) => Vec::new(),
+            Ok(None) => break,
+            Ok(Some(Ok(events))) => events,
+            Ok(Some(Err(e))) => {
+                warn!("File watcher error: {}", e);
+                continue;
+            }
+        };
+
+        for event in events {
+            if event.path.starts_with(&memory_dir) {
+                // Our own writes into intermediate memory must not trigger another run.
+                continue;
+            }
+            if !event.path.exists() {
+                debug!("{} was removed, nothing to version", event.path.display());
+                continue;
+            }
+            trace!("Change detected in {}", event.path.display());
+            pending.add(event.path).await;
+        }
+        if pending.is_empty() {
+            continue;
+        }
+        if run(&pending, config, cancel, &mut on_change).await {
+            pending = storage.empty_like();
+            waiting = false;
+        } else if !waiting {
+            info!(
+                "Memory is in use, {} changed files wait for it",
+                pending.len()
+            );
+            waiting = true;
+        }
+    }
+
+    info!("Stopped watching.");
+    Ok(())
+}
+
+/// Runs the pipeline over `storage` under the memory lock. Failures are
+/// logged rather than returned so one bad batch doesn't stop the watcher.
+/// Returns false, without running, if another process holds the lock.
+async fn run(
+    storage: &FileStorage,
+    config: &ProcessorConfig,
+    cancel: &CancellationToken,
+    on_change: &mut impl FnMut(&ProcessSummary) -> Result<()>,
+) -> bool {
+    if storage.is_empty() {
+        return true;
+    }
+    let _lock = match MemoryLock::acquire(&config.memory_dir) {
+        Ok(lock) => lock,
+        Err(e) if e.is::<LockError>() => {
+            debug!("{}", e);
+            return false;
+        }
+        Err(e) => {
+            warn!("Failed to lock memory: {:?}", e);
+            return true;
+        }
+    };
+    let summary =
+        match Processor::process_all(storage.paths(), ProcessMode::Full, config, cancel).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                warn!("Failed to process changed files: {:?}", e);
+                return true;
+            }
+        };
+    for path in &summary.busy {
+        warn!(
+            "{} is busy, it will be retried on its next change",
+            path.display()
+        );
+    }
+    if summary.changed() > 0 {
+        info!("Stored {} new versions", summary.changed());
+        if let Err(e) = on_change(&summary) {
+            warn!("Change handler failed: {:?}", e);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn batch_waits_for_the_memory_lock() {
+        let sources = tempfile::tempdir().unwrap();
+        let memory = tempfile::tempdir().unwrap();
+        let path = sources.path().join("notes.txt");
+        fs::write(&path, "notes").unwrap();
+        let mut storage = FileStorage::new();
+        storage.add(&path).await;
+        let config = ProcessorConfig::new(memory.path());
+        let cancel = CancellationToken::new();
+        let mut runs = 0;
+        let mut on_change = |_: &ProcessSummary| {
+            runs += 1;
+            Ok(())
+        };
+
+        let lock = MemoryLock::acquire(memory.path()).unwrap();
+        assert!(!run(&storage, &config, &cancel, &mut on_change).await);
+        assert!(Processor::tracked_files(memory.path()).unwrap().is_empty());
+
+        drop(lock);
+        assert!(run(&storage, &config, &cancel, &mut on_change).await);
+        assert_eq!(Processor::tracked_files(memory.path()).unwrap().len(), 1);
+        assert_eq!(runs, 1);
+        // Released again once the batch is done.
+        MemoryLock::acquire(memory.path()).unwrap();
+    }
+}